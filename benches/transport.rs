@@ -0,0 +1,78 @@
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use remus::{Message, MessageFlags, MessageType, Transport};
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Writer that accepts and discards everything, supporting vectored writes
+struct NullStream;
+
+impl AsyncRead for NullStream {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for NullStream {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(bufs.iter().map(|b| b.len()).sum()))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn bench_send(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group("send");
+
+    for size in [1024, 64 * 1024, 1024 * 1024] {
+        let payload = Bytes::from(vec![0u8; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        // Baseline: copy prefix, header and payload into one contiguous buffer
+        group.bench_with_input(BenchmarkId::new("contiguous", size), &payload, |b, payload| {
+            let mut stream = NullStream;
+            b.iter(|| {
+                let message = Message::new(MessageType::Event, MessageFlags::NONE, 1, payload.clone());
+                let encoded = message.encode();
+                let mut frame = Vec::with_capacity(4 + encoded.len());
+                frame.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+                frame.extend_from_slice(&encoded);
+                runtime.block_on(stream.write_all(&frame)).unwrap();
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("vectored", size), &payload, |b, payload| {
+            let mut transport = Transport::new(NullStream);
+            b.iter(|| {
+                let message = Message::new(MessageType::Event, MessageFlags::NONE, 1, payload.clone());
+                runtime.block_on(transport.send(message)).unwrap();
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_send);
+criterion_main!(benches);
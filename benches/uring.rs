@@ -0,0 +1,72 @@
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use remus::{Message, MessageFlags, MessageType, Transport, UringTransport};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+
+/// Blocking echo peer shared by both paths so only the client side differs
+fn spawn_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            stream.set_nodelay(true).unwrap();
+            std::thread::spawn(move || {
+                let mut frame = Vec::new();
+                loop {
+                    let mut prefix = [0u8; 4];
+                    if stream.read_exact(&mut prefix).is_err() {
+                        return;
+                    }
+                    frame.resize(u32::from_be_bytes(prefix) as usize, 0);
+                    stream.read_exact(&mut frame).unwrap();
+                    stream.write_all(&prefix).unwrap();
+                    stream.write_all(&frame).unwrap();
+                }
+            });
+        }
+    });
+    address
+}
+
+fn bench_round_trip(c: &mut Criterion) {
+    let address = spawn_echo_server();
+    let mut group = c.benchmark_group("round_trip");
+
+    for size in [1024, 64 * 1024] {
+        let message = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::from(vec![0u8; size]));
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("epoll", size), &message, |b, message| {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            let mut transport = runtime.block_on(async {
+                let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+                stream.set_nodelay(true).unwrap();
+                Transport::new(stream)
+            });
+            b.iter(|| {
+                runtime.block_on(async {
+                    transport.send(message.clone()).await.unwrap();
+                    transport.receive().await.unwrap()
+                })
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("io_uring", size), &message, |b, message| {
+            let runtime = tokio_uring::Runtime::new(&tokio_uring::builder()).unwrap();
+            let mut transport = runtime.block_on(UringTransport::connect(address)).unwrap();
+            b.iter(|| {
+                runtime.block_on(async {
+                    transport.send(message).await.unwrap();
+                    transport.receive().await.unwrap()
+                })
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_round_trip);
+criterion_main!(benches);
@@ -18,7 +18,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let payload = Bytes::from("Hello ".repeat(1000));
     let original_size = payload.len();
     
-    // Create a compressed message
+    // Create a message; the transport compresses it because of the COMPRESSED flag
     let mut message = Message::new(
         MessageType::Request,
         MessageFlags::COMPRESSED,
@@ -1,4 +1,4 @@
-use remus::{Message, MessageType, MessageFlags, Transport};
+use remus::{Encryptor, Message, MessageType, MessageFlags, Transport};
 use bytes::Bytes;
 use tokio::net::TcpListener;
 use std::error::Error;
@@ -10,11 +10,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let listener = TcpListener::bind("127.0.0.1:8081").await?;
     println!("Server listening on 127.0.0.1:8081");
 
+    // Both peers must share the key; generate one for this demo
+    let key = Encryptor::generate_key();
+
     // Accept connection
     let (stream, _) = listener.accept().await?;
-    let mut transport = Transport::new(stream);
+    let mut transport = Transport::new(stream).with_encryption(&key);
 
-    // Create an encrypted message
+    // Create a message; the transport encrypts it because of the ENCRYPTED flag
     let payload = Bytes::from("Secret message!");
     let mut message = Message::new(
         MessageType::Request,
@@ -31,7 +34,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let received = transport.receive().await?;
     
     if received.flags.contains(MessageFlags::ENCRYPTED) {
-        println!("Received encrypted message: {:?}", received);
+        println!("Received encrypted message, decrypted payload: {:?}", received.payload);
     }
 
     Ok(())
@@ -0,0 +1,198 @@
+//! Procedural macros for remus; use them through the re-exports in the `remus` crate.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, spanned::Spanned, FnArg, Ident, ItemTrait, LitStr, Pat, ReturnType, TraitItem, TraitItemFn, Type};
+
+/// Turns a trait of async methods into a remus service.
+///
+/// Every method takes `&self` and owned, serde-serializable arguments, and
+/// returns `Result<T, ProtocolError>` for a deserializable `T`. Method `m` of
+/// trait `Name` is served on route `Name/m`; `#[service(name = "...")]`
+/// replaces the `Name` prefix. The arguments travel as a JSON array.
+///
+/// Next to the trait, which gains `Send + Sync + 'static` bounds and `Send`
+/// method futures, the macro generates:
+///
+/// - `NameServer<S>`, whose `register` adds a route per method to a `Router`,
+///   calling into the implementation `S`
+/// - `NameClient`, a typed stub with the same methods, calling over a `RemusClient`
+///
+/// ```rust,ignore
+/// #[remus::service(name = "devices")]
+/// pub trait Devices {
+///     async fn rename(&self, id: u32, name: String) -> Result<Device, ProtocolError>;
+/// }
+///
+/// let router = DevicesServer::new(MyDevices::default()).register(Router::new());
+/// let device = DevicesClient::new(client).rename(7, "porch".into()).await?;
+/// ```
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<LitStr>()?.value());
+            Ok(())
+        } else {
+            Err(meta.error("Unsupported service attribute; expected `name = \"...\"`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+    let item = parse_macro_input!(item as ItemTrait);
+    expand(item, name).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+struct Method {
+    item: TraitItemFn,
+    route: String,
+    args: Vec<(Ident, Type)>,
+}
+
+fn expand(mut item: ItemTrait, name: Option<String>) -> syn::Result<proc_macro2::TokenStream> {
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new(item.generics.span(), "Service traits cannot be generic"));
+    }
+    let prefix = name.unwrap_or_else(|| item.ident.to_string());
+    let methods = item
+        .items
+        .iter()
+        .map(|trait_item| match trait_item {
+            TraitItem::Fn(method) => parse_method(method, &prefix),
+            other => Err(syn::Error::new(other.span(), "Service traits may only contain async methods")),
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    // `async fn` in a trait gives no Send bound, and handlers run on spawned tasks
+    for trait_item in &mut item.items {
+        if let TraitItem::Fn(method) = trait_item {
+            let output = match &method.sig.output {
+                ReturnType::Type(_, ty) => quote!(#ty),
+                ReturnType::Default => quote!(()),
+            };
+            method.sig.asyncness = None;
+            method.sig.output = syn::parse_quote!(-> impl ::core::future::Future<Output = #output> + ::core::marker::Send);
+        }
+    }
+    item.supertraits.push(syn::parse_quote!(::core::marker::Send));
+    item.supertraits.push(syn::parse_quote!(::core::marker::Sync));
+    item.supertraits.push(syn::parse_quote!('static));
+
+    let vis = &item.vis;
+    let trait_name = &item.ident;
+    let server = format_ident!("{}Server", trait_name);
+    let client = format_ident!("{}Client", trait_name);
+    let server_doc = format!("Serves a [`{trait_name}`] implementation on a `Router`");
+    let client_doc = format!("Typed client for the [`{trait_name}`] service");
+
+    let routes = methods.iter().map(|method| {
+        let ident = &method.item.sig.ident;
+        let route = &method.route;
+        let names: Vec<_> = method.args.iter().map(|(name, _)| name).collect();
+        let types: Vec<_> = method.args.iter().map(|(_, ty)| ty).collect();
+        quote! {
+            .with_route(#route, {
+                let __service = self.service.clone();
+                move |__request: ::remus::server::Request| {
+                    let __service = __service.clone();
+                    async move {
+                        let (#(#names,)*): (#(#types,)*) = __request.deserialize()?;
+                        let __output = __service.#ident(#(#names),*).await?;
+                        ::remus::__private::serde_json::to_vec(&__output)
+                            .map(::remus::__private::Bytes::from)
+                            .map_err(|e| ::remus::ProtocolError::InvalidFormat(e.to_string()))
+                    }
+                }
+            })
+        }
+    });
+
+    let calls = methods.iter().map(|method| {
+        let attrs = &method.item.attrs;
+        let ident = &method.item.sig.ident;
+        let output = &method.item.sig.output;
+        let route = &method.route;
+        let params = method.args.iter().map(|(name, ty)| quote!(#name: #ty));
+        let names = method.args.iter().map(|(name, _)| name);
+        quote! {
+            #(#attrs)*
+            pub async fn #ident(&self, #(#params),*) #output {
+                self.client.call(#route, &(#(#names,)*)).await
+            }
+        }
+    });
+
+    Ok(quote! {
+        #item
+
+        #[doc = #server_doc]
+        #vis struct #server<S> {
+            service: ::std::sync::Arc<S>,
+        }
+
+        impl<S: #trait_name> #server<S> {
+            pub fn new(service: S) -> Self {
+                Self::from_arc(::std::sync::Arc::new(service))
+            }
+
+            pub fn from_arc(service: ::std::sync::Arc<S>) -> Self {
+                Self { service }
+            }
+
+            /// Adds a route for each service method to `router`
+            pub fn register(self, router: ::remus::server::Router) -> ::remus::server::Router {
+                router #(#routes)*
+            }
+        }
+
+        #[doc = #client_doc]
+        #vis struct #client {
+            client: ::std::sync::Arc<::remus::RemusClient>,
+        }
+
+        impl #client {
+            pub fn new(client: ::std::sync::Arc<::remus::RemusClient>) -> Self {
+                Self { client }
+            }
+
+            #(#calls)*
+        }
+    })
+}
+
+fn parse_method(method: &TraitItemFn, prefix: &str) -> syn::Result<Method> {
+    let sig = &method.sig;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new(sig.fn_token.span(), "Service methods must be async"));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(sig.generics.span(), "Service methods cannot be generic"));
+    }
+    if method.default.is_some() {
+        return Err(syn::Error::new(sig.ident.span(), "Service methods cannot have a default body"));
+    }
+    if matches!(sig.output, ReturnType::Default) {
+        return Err(syn::Error::new(sig.span(), "Service methods must return Result<T, ProtocolError>"));
+    }
+
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+        _ => return Err(syn::Error::new(sig.ident.span(), "Service methods must take &self")),
+    }
+    let args = inputs
+        .map(|input| match input {
+            FnArg::Typed(arg) => match &*arg.pat {
+                Pat::Ident(pat) => Ok((pat.ident.clone(), (*arg.ty).clone())),
+                other => Err(syn::Error::new(other.span(), "Service arguments must be plain identifiers")),
+            },
+            FnArg::Receiver(receiver) => Err(syn::Error::new(receiver.span(), "Unexpected receiver")),
+        })
+        .collect::<syn::Result<_>>()?;
+
+    Ok(Method {
+        item: method.clone(),
+        route: format!("{prefix}/{}", sig.ident),
+        args,
+    })
+}
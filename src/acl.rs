@@ -0,0 +1,312 @@
+//! Authenticating peers by token, and per-route authorization: which peers
+//! may call which routes.
+//!
+//! Messages flagged `REQUIRES_AUTH` are only served on a connection with an
+//! identity. A client configured with
+//! [`RemusClient::with_credentials`](crate::RemusClient::with_credentials)
+//! sends its token once per connection, as the payload of a Handshake frame
+//! routed to `credentials` ahead of its other messages, so it is encrypted
+//! whenever the connection is. A server [`Authenticator`] set with
+//! [`Server::with_authenticator`](crate::Server::with_authenticator) turns
+//! the token into the connection's identity at its first such message.
+//!
+//! An [`AccessPolicy`] is server [`Middleware`](crate::Middleware) holding
+//! allow and deny rules. Each rule pairs a [`Principal`], matched against the
+//! connection's identity or negotiated capabilities, with a route pattern in
+//! [`Router`](crate::Router) syntax. A matching deny rule wins over any allow
+//! rule; a request no rule matches gets the policy's default decision, which
+//! is [`Decision::Deny`] unless changed. Workloads identified by a SPIFFE ID
+//! (see [`crate::spiffe`]) can be matched by trust domain and path.
+//!
+//! ```rust
+//! use remus::acl::{AccessPolicy, Principal};
+//!
+//! let policy = AccessPolicy::new()
+//!     .with_allow(Principal::Authenticated, "devices/{id}/telemetry")
+//!     .with_allow("operator", "devices/*")
+//!     .with_allow(Principal::Capability("admin".into()), "*")
+//!     .with_deny("sensor-7", "*");
+//! ```
+
+use crate::server::{match_pattern, parse_pattern, ConnectionContext, HandlerFuture, Middleware, Next, Request, Segment};
+use crate::spiffe::SpiffeId;
+use crate::{Message, MessageFlags, MessageType, ProtocolError};
+use bytes::Bytes;
+
+/// Routing info of the Handshake frame carrying a client's credential token
+pub(crate) const CREDENTIALS_ROUTE: &str = "credentials";
+
+/// The frame presenting `token` to the server, sealed like any other on an encrypted connection
+pub(crate) fn credentials_frame(token: &str) -> Message {
+    let mut frame = Message::new(MessageType::Handshake, MessageFlags::NONE, 0, Bytes::copy_from_slice(token.as_bytes()));
+    frame.routing_info = Some(CREDENTIALS_ROUTE.to_string());
+    frame
+}
+
+/// Checks a client's credential token and names the identity it proves.
+///
+/// Implemented for any `Fn(&str) -> Result<String, ProtocolError>`.
+pub trait Authenticator: Send + Sync {
+    /// Fails, typically with `ProtocolError::AuthenticationRequired`, if `token` proves nothing
+    fn authenticate(&self, token: &str) -> Result<String, ProtocolError>;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&str) -> Result<String, ProtocolError> + Send + Sync,
+{
+    fn authenticate(&self, token: &str) -> Result<String, ProtocolError> {
+        self(token)
+    }
+}
+
+/// Who an access rule applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    /// Every peer, authenticated or not
+    Anyone,
+    /// Any peer with an identity
+    Authenticated,
+    /// The peer that authenticated as exactly this identity
+    Identity(String),
+    /// Peers whose connection negotiated this capability
+    Capability(String),
+    /// Workloads whose SPIFFE ID is in this trust domain
+    TrustDomain(String),
+    /// Workloads whose SPIFFE ID matches this one, with a path pattern in
+    /// [`Router`](crate::Router) syntax, e.g. `spiffe://example.org/ns/{ns}/sa/*`
+    Workload(String),
+}
+
+impl Principal {
+    fn matches(&self, connection: &ConnectionContext) -> bool {
+        match self {
+            Principal::Anyone => true,
+            Principal::Authenticated => connection.identity().is_some(),
+            Principal::Identity(identity) => connection.identity().as_ref() == Some(identity),
+            Principal::Capability(capability) => connection.has_capability(capability),
+            Principal::TrustDomain(trust_domain) => connection.spiffe_id().is_some_and(|id| id.trust_domain() == trust_domain),
+            Principal::Workload(pattern) => connection.spiffe_id().is_some_and(|id| workload_matches(pattern, &id)),
+        }
+    }
+}
+
+fn workload_matches(pattern: &str, id: &SpiffeId) -> bool {
+    let Some(pattern) = pattern.strip_prefix("spiffe://") else {
+        return false;
+    };
+    let (trust_domain, path) = pattern.split_once('/').unwrap_or((pattern, ""));
+    trust_domain == id.trust_domain() && match_pattern(&parse_pattern(path), id.path().trim_start_matches('/')).is_some()
+}
+
+impl From<&str> for Principal {
+    fn from(identity: &str) -> Self {
+        Principal::Identity(identity.to_string())
+    }
+}
+
+impl From<String> for Principal {
+    fn from(identity: String) -> Self {
+        Principal::Identity(identity)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+struct Rule {
+    decision: Decision,
+    principal: Principal,
+    // Kept for logging
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+/// Allow and deny rules evaluated for every request; see the [module docs](self)
+pub struct AccessPolicy {
+    rules: Vec<Rule>,
+    default: Decision,
+}
+
+impl AccessPolicy {
+    /// Creates a policy that denies everything until rules allow it
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: Decision::Deny,
+        }
+    }
+
+    /// Lets `principal` call routes matching `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is malformed.
+    pub fn with_allow(self, principal: impl Into<Principal>, pattern: &str) -> Self {
+        self.with_rule(Decision::Allow, principal.into(), pattern)
+    }
+
+    /// Stops `principal` calling routes matching `pattern`, whatever other rules allow.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is malformed.
+    pub fn with_deny(self, principal: impl Into<Principal>, pattern: &str) -> Self {
+        self.with_rule(Decision::Deny, principal.into(), pattern)
+    }
+
+    /// Sets the decision for requests no rule matches
+    pub fn with_default(mut self, decision: Decision) -> Self {
+        self.default = decision;
+        self
+    }
+
+    fn with_rule(mut self, decision: Decision, principal: Principal, pattern: &str) -> Self {
+        self.rules.push(Rule {
+            decision,
+            principal,
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+        });
+        self
+    }
+
+    /// Decides whether the peer on `connection` may call `route`
+    pub fn decide(&self, connection: &ConnectionContext, route: &str) -> Decision {
+        self.matching_rule(connection, route).map_or(self.default, |rule| rule.decision)
+    }
+
+    // The first matching deny rule, else the first matching allow rule
+    fn matching_rule(&self, connection: &ConnectionContext, route: &str) -> Option<&Rule> {
+        let mut allowed = None;
+        for rule in &self.rules {
+            if !rule.principal.matches(connection) || match_pattern(&rule.segments, route).is_none() {
+                continue;
+            }
+            match rule.decision {
+                Decision::Deny => return Some(rule),
+                Decision::Allow => {
+                    allowed.get_or_insert(rule);
+                }
+            }
+        }
+        allowed
+    }
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for AccessPolicy {
+    fn handle(&self, request: Request, next: Next) -> HandlerFuture {
+        let connection = request.connection();
+        let identity = connection.identity();
+        let rule = self.matching_rule(connection, request.route());
+        let decision = rule.map_or(self.default, |rule| rule.decision);
+        let pattern = rule.map(|rule| rule.pattern.as_str());
+        if decision == Decision::Allow {
+            tracing::debug!(
+                target: "remus::acl",
+                route = request.route(),
+                identity = identity.as_deref(),
+                rule = pattern,
+                "access allowed"
+            );
+            return next.run(request);
+        }
+
+        tracing::info!(
+            target: "remus::acl",
+            route = request.route(),
+            identity = identity.as_deref(),
+            rule = pattern,
+            "access denied"
+        );
+        metrics::increment_counter!("remus_access_denied_total");
+        // An anonymous peer may be let in once it authenticates
+        let error = match identity {
+            Some(_) => ProtocolError::PermissionDenied(request.route().to_string()),
+            None => ProtocolError::AuthenticationRequired,
+        };
+        Box::pin(std::future::ready(Err(error)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Router;
+    use crate::{Message, MessageFlags, MessageType};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    fn connection(identity: Option<&str>, capabilities: &[&str]) -> ConnectionContext {
+        let connection = ConnectionContext::default();
+        if let Some(identity) = identity {
+            connection.set_identity(identity);
+        }
+        connection.set_capabilities(capabilities.iter().map(|capability| capability.to_string()).collect());
+        connection
+    }
+
+    #[test]
+    fn test_deny_rules_win_and_default_applies() {
+        let policy = AccessPolicy::new()
+            .with_allow(Principal::Authenticated, "devices/{id}/telemetry")
+            .with_allow(Principal::Capability("admin".into()), "*")
+            .with_deny("sensor-7", "devices/*");
+
+        let sensor = connection(Some("sensor-1"), &[]);
+        assert_eq!(policy.decide(&sensor, "devices/1/telemetry"), Decision::Allow);
+        assert_eq!(policy.decide(&sensor, "devices/1/config"), Decision::Deny);
+        assert_eq!(policy.decide(&connection(None, &[]), "devices/1/telemetry"), Decision::Deny);
+        assert_eq!(policy.decide(&connection(None, &["admin"]), "devices/1/config"), Decision::Allow);
+        assert_eq!(policy.decide(&connection(Some("sensor-7"), &["admin"]), "devices/7/telemetry"), Decision::Deny);
+
+        let open = AccessPolicy::new().with_default(Decision::Allow).with_deny(Principal::Anyone, "admin/*");
+        assert_eq!(open.decide(&sensor, "status"), Decision::Allow);
+        assert_eq!(open.decide(&sensor, "admin/users"), Decision::Deny);
+    }
+
+    #[test]
+    fn test_spiffe_principals_match_trust_domain_and_path() {
+        let policy = AccessPolicy::new()
+            .with_allow(Principal::Workload("spiffe://prod.example.org/ns/{ns}/sa/api".into()), "orders/*")
+            .with_allow(Principal::TrustDomain("prod.example.org".into()), "status");
+
+        let api = connection(Some("spiffe://prod.example.org/ns/payments/sa/api"), &[]);
+        let worker = connection(Some("spiffe://prod.example.org/ns/payments/sa/worker"), &[]);
+        let staging = connection(Some("spiffe://staging.example.org/ns/payments/sa/api"), &[]);
+        assert_eq!(policy.decide(&api, "orders/1"), Decision::Allow);
+        assert_eq!(policy.decide(&worker, "orders/1"), Decision::Deny);
+        assert_eq!(policy.decide(&worker, "status"), Decision::Allow);
+        assert_eq!(policy.decide(&staging, "orders/1"), Decision::Deny);
+        assert_eq!(policy.decide(&staging, "status"), Decision::Deny);
+        assert_eq!(policy.decide(&connection(Some("prod.example.org"), &[]), "status"), Decision::Deny);
+    }
+
+    #[tokio::test]
+    async fn test_policy_rejects_requests_before_routing() {
+        let router = Router::new()
+            .with_middleware(AccessPolicy::new().with_allow("operator", "devices/*"))
+            .with_route("devices/{id}", |_request: Request| async { Ok(Bytes::from("ok")) });
+        let request = |identity: Option<&str>| {
+            let mut message = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::new());
+            message.routing_info = Some("devices/1".to_string());
+            Request::new(message, Arc::new(connection(identity, &[])))
+        };
+
+        assert_eq!(router.dispatch(request(Some("operator"))).await.unwrap(), Bytes::from("ok"));
+        assert!(matches!(
+            router.dispatch(request(Some("sensor-1"))).await,
+            Err(ProtocolError::PermissionDenied(route)) if route == "devices/1"
+        ));
+        assert!(matches!(router.dispatch(request(None)).await, Err(ProtocolError::AuthenticationRequired)));
+    }
+}
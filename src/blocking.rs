@@ -0,0 +1,279 @@
+//! Synchronous facade over [`crate::RemusClient`] for code that does not run on tokio.
+//!
+//! Each client owns a small runtime that drives its connection in the
+//! background, so acks, keepalives and reconnects keep working between calls.
+//! Do not create or drop a blocking client from inside an async context.
+
+use crate::{
+    circuit::CircuitBreaker,
+    client::{ClientConfig, IntoAddresses, RequestOptions},
+    discovery::ServiceInfo,
+    encryption::{Cipher, Encryptor},
+    interceptor::Interceptor,
+    noise::NoiseConfig,
+    observability::Telemetry,
+    offline::OfflineQueue,
+    retry::RetryPolicy,
+    stream::MessageStream,
+    Message, ProtocolError,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// Blocking Remus client; see the [module docs](self)
+pub struct RemusClient {
+    // Dropped before the runtime so the connection shuts down while it still runs
+    inner: crate::RemusClient,
+    runtime: Arc<Runtime>,
+}
+
+impl RemusClient {
+    /// Creates a client that dials on the first request; see [`crate::RemusClient::new`]
+    pub fn new(config: ClientConfig) -> Result<Self, ProtocolError> {
+        let runtime = Arc::new(build_runtime()?);
+        Ok(Self {
+            inner: crate::RemusClient::new(config),
+            runtime,
+        })
+    }
+
+    /// Connects to the first of `addresses` that answers; see [`crate::RemusClient::connect`]
+    pub fn connect(addresses: impl IntoAddresses) -> Result<Self, ProtocolError> {
+        let runtime = Arc::new(build_runtime()?);
+        let inner = runtime.block_on(crate::RemusClient::connect(addresses))?;
+        Ok(Self { inner, runtime })
+    }
+
+    /// Enables encryption for all future communications
+    pub fn with_encryption(self, key: &[u8; 32]) -> Self {
+        self.map(|inner| inner.with_encryption(key))
+    }
+
+    /// Enables encryption with `cipher`; the server must use the same one
+    pub fn with_encryption_cipher(self, key: &[u8; 32], cipher: Cipher) -> Self {
+        self.map(|inner| inner.with_encryption_cipher(key, cipher))
+    }
+
+    /// Encrypts with `encryptor` on every connection; see [`crate::RemusClient::with_encryptor`]
+    pub fn with_encryptor(self, encryptor: Arc<Encryptor>) -> Self {
+        self.map(|inner| inner.with_encryptor(encryptor))
+    }
+
+    /// Handshakes with Noise on every connection; see [`crate::RemusClient::with_noise`]
+    pub fn with_noise(self, config: NoiseConfig) -> Self {
+        self.map(|inner| inner.with_noise(config))
+    }
+
+    /// Attaches `token` to messages flagged REQUIRES_AUTH; see [`crate::RemusClient::with_credentials`]
+    pub fn with_credentials(self, token: impl Into<String>) -> Self {
+        self.map(|inner| inner.with_credentials(token))
+    }
+
+    /// Reconnects when the connection drops; see [`crate::RemusClient::with_reconnect`]
+    pub fn with_reconnect(self, policy: RetryPolicy) -> Self {
+        self.map(|inner| inner.with_reconnect(policy))
+    }
+
+    /// Resumes the server's session on reconnect; see [`crate::RemusClient::with_session_resumption`]
+    pub fn with_session_resumption(self) -> Self {
+        self.map(|inner| inner.with_session_resumption())
+    }
+
+    /// Sets the timeout applied to each request attempt
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.map(|inner| inner.with_timeout(timeout))
+    }
+
+    /// Retries failed IDEMPOTENT requests according to `policy`
+    pub fn with_retry_policy(self, policy: RetryPolicy) -> Self {
+        self.map(|inner| inner.with_retry_policy(policy))
+    }
+
+    /// Sheds requests while `breaker` holds this client's circuit open
+    pub fn with_circuit_breaker(self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.map(|inner| inner.with_circuit_breaker(breaker))
+    }
+
+    /// Records request metrics into `telemetry`
+    pub fn with_telemetry(self, telemetry: Arc<Telemetry>) -> Self {
+        self.map(|inner| inner.with_telemetry(telemetry))
+    }
+
+    /// Queues events that cannot be delivered; see [`crate::RemusClient::with_offline_queue`]
+    pub fn with_offline_queue(self, queue: Arc<OfflineQueue>) -> Self {
+        self.map(|inner| inner.with_offline_queue(queue))
+    }
+
+    /// Adds an interceptor; interceptors run in the order they are added
+    pub fn with_interceptor(self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.map(|inner| inner.with_interceptor(interceptor))
+    }
+
+    fn map(self, f: impl FnOnce(crate::RemusClient) -> crate::RemusClient) -> Self {
+        Self {
+            inner: f(self.inner),
+            runtime: self.runtime,
+        }
+    }
+
+    /// Dials now if the client has no open connection
+    pub fn ensure_connected(&self) -> Result<(), ProtocolError> {
+        self.runtime.block_on(self.inner.ensure_connected())
+    }
+
+    /// Sends a request and waits for response
+    pub fn request(&self, payload: impl AsRef<[u8]>) -> Result<Bytes, ProtocolError> {
+        self.runtime.block_on(self.inner.request(payload))
+    }
+
+    /// Sends a request with per-request options and waits for response
+    pub fn request_with_options(&self, payload: impl AsRef<[u8]>, options: &RequestOptions) -> Result<Bytes, ProtocolError> {
+        self.runtime.block_on(self.inner.request_with_options(payload, options))
+    }
+
+    /// Calls `route` with a serialized request and deserializes the correlated response
+    pub fn call<T: Serialize, R: DeserializeOwned>(&self, route: &str, request: &T) -> Result<R, ProtocolError> {
+        self.runtime.block_on(self.inner.call(route, request))
+    }
+
+    /// Calls `route` with per-request options
+    pub fn call_with_options<T: Serialize, R: DeserializeOwned>(
+        &self,
+        route: &str,
+        request: &T,
+        options: &RequestOptions,
+    ) -> Result<R, ProtocolError> {
+        self.runtime.block_on(self.inner.call_with_options(route, request, options))
+    }
+
+    /// Creates a streaming request whose messages are read by iterating
+    pub fn stream(&self, payload: impl AsRef<[u8]>) -> Result<BlockingStream, ProtocolError> {
+        self.stream_with_options(payload, &RequestOptions::default())
+    }
+
+    /// Creates a streaming request with per-request options
+    pub fn stream_with_options(
+        &self,
+        payload: impl AsRef<[u8]>,
+        options: &RequestOptions,
+    ) -> Result<BlockingStream, ProtocolError> {
+        let stream = self.runtime.block_on(self.inner.stream_with_options(payload, options))?;
+        Ok(BlockingStream {
+            stream,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// Subscribes to Events matching `pattern`; see [`crate::RemusClient::subscribe`]
+    pub fn subscribe(&self, pattern: &str) -> Result<BlockingStream, ProtocolError> {
+        let stream = self.runtime.block_on(self.inner.subscribe(pattern))?;
+        Ok(BlockingStream {
+            stream,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// Sends an Event without waiting for any reply
+    pub fn notify(&self, payload: impl AsRef<[u8]>) -> Result<(), ProtocolError> {
+        self.runtime.block_on(self.inner.notify(payload))
+    }
+
+    /// Sends an Event flagged REQUIRES_ACK and waits for the peer's Ack or the TTL to expire
+    pub fn notify_with_ack(&self, payload: impl AsRef<[u8]>, options: &RequestOptions) -> Result<(), ProtocolError> {
+        self.runtime.block_on(async {
+            let ack = self.inner.notify_with_ack(payload, options).await?;
+            ack.await
+        })
+    }
+
+    /// Connects if necessary and sends every queued offline event
+    pub fn flush_offline_queue(&self) -> Result<usize, ProtocolError> {
+        self.runtime.block_on(self.inner.flush_offline_queue())
+    }
+
+    /// Discovers available services
+    pub fn discover_services(&self) -> Result<Vec<ServiceInfo>, ProtocolError> {
+        self.runtime.block_on(self.inner.discover_services())
+    }
+}
+
+/// Iterator over the messages of a streaming request; dropping it before the end cancels the stream
+pub struct BlockingStream {
+    stream: MessageStream,
+    runtime: Arc<Runtime>,
+}
+
+impl BlockingStream {
+    pub fn stream_id(&self) -> u64 {
+        self.stream.stream_id()
+    }
+}
+
+impl Iterator for BlockingStream {
+    type Item = Result<Message, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.stream.next())
+    }
+}
+
+// One worker is plenty for a single connection and keeps it running between calls
+fn build_runtime() -> Result<Runtime, ProtocolError> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("remus-blocking")
+        .enable_all()
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transport::Transport, MessageFlags, MessageType};
+
+    // Echo server on its own runtime; streams are answered with two frames
+    fn spawn_echo_server() -> (Runtime, String) {
+        let runtime = build_runtime().unwrap();
+        let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        runtime.spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(request) = transport.receive().await {
+                let replies = if request.msg_type == MessageType::Stream {
+                    vec![MessageType::Stream, MessageType::StreamEnd]
+                } else {
+                    vec![MessageType::Response]
+                };
+                for msg_type in replies {
+                    let reply = Message::new(msg_type, MessageFlags::NONE, request.request_id, request.payload.clone());
+                    transport.send(reply).await.unwrap();
+                }
+            }
+        });
+        (runtime, address)
+    }
+
+    #[test]
+    fn test_blocking_request_and_call() {
+        let (_server, address) = spawn_echo_server();
+        let client = RemusClient::connect(&address).unwrap().with_timeout(Duration::from_secs(5));
+
+        assert_eq!(client.request("hello").unwrap(), Bytes::from("hello"));
+        let echoed: String = client.call("echo", &"typed".to_string()).unwrap();
+        assert_eq!(echoed, "typed");
+    }
+
+    #[test]
+    fn test_blocking_stream_iterates_to_end() {
+        let (_server, address) = spawn_echo_server();
+        let client = RemusClient::new(ClientConfig::new(address)).unwrap();
+
+        let types: Vec<MessageType> = client.stream("feed").unwrap().map(|message| message.unwrap().msg_type).collect();
+        assert_eq!(types, [MessageType::Stream, MessageType::StreamEnd]);
+    }
+}
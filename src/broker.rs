@@ -0,0 +1,255 @@
+use crate::{connection::topic_matches, Message, MessageFlags, MessageType};
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+/// What a [`Broker`] does with an Event for a connection whose send buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Evicts the oldest buffered Event to make room
+    DropOldest,
+    /// Discards the new Event
+    DropNewest,
+    /// Closes the connection; a reconnecting client resubscribes
+    Disconnect,
+}
+
+/// Fans Events published to a topic out to every connection subscribed to it.
+///
+/// Clients subscribe with [`RemusClient::subscribe`](crate::RemusClient::subscribe);
+/// the server answers their Subscribe frames and registers them here. Each
+/// connection buffers up to the configured number of undelivered Events, and
+/// the slow-consumer policy decides what happens beyond that. Share one broker
+/// between a [`Server`](crate::Server) and the handlers or background tasks
+/// that publish.
+pub struct Broker {
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    connections: Mutex<HashMap<u64, Subscriber>>,
+    next_connection: AtomicU64,
+    dropped: AtomicU64,
+}
+
+struct Subscriber {
+    outbox: Arc<Outbox>,
+    // Subscription id to topic pattern
+    topics: HashMap<u64, String>,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Self {
+            capacity: 256,
+            policy: SlowConsumerPolicy::DropOldest,
+            connections: Mutex::new(HashMap::new()),
+            next_connection: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets how many undelivered Events each connection buffers
+    pub fn with_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets what happens to Events for a connection whose buffer is full; the default drops the oldest
+    pub fn with_slow_consumer_policy(mut self, policy: SlowConsumerPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Queues an Event on `topic` for every matching subscription, returning how many were queued
+    pub fn publish(&self, topic: &str, payload: impl Into<Bytes>) -> usize {
+        let payload = payload.into();
+        let connections = self.connections.lock().unwrap();
+        let mut queued = 0;
+        for subscriber in connections.values() {
+            for (id, pattern) in &subscriber.topics {
+                if !topic_matches(pattern, topic) {
+                    continue;
+                }
+                let mut event = Message::new(MessageType::Event, MessageFlags::NONE, *id, payload.clone());
+                event.routing_info = Some(topic.to_string());
+                let (accepted, discarded) = subscriber.outbox.push(event);
+                queued += accepted as usize;
+                if discarded {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        queued
+    }
+
+    /// Returns how many Events were discarded because a connection's buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many subscriptions are registered across all connections
+    pub fn subscriptions(&self) -> usize {
+        self.connections.lock().unwrap().values().map(|subscriber| subscriber.topics.len()).sum()
+    }
+
+    /// Registers a connection; its subscriptions are removed when the attachment drops
+    pub(crate) fn attach(self: &Arc<Self>) -> Attachment {
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let outbox = Arc::new(Outbox {
+            queue: Mutex::new(VecDeque::new()),
+            capacity: self.capacity,
+            policy: self.policy,
+            ready: Notify::new(),
+            overflowed: CancellationToken::new(),
+        });
+        self.connections.lock().unwrap().insert(
+            connection,
+            Subscriber {
+                outbox: outbox.clone(),
+                topics: HashMap::new(),
+            },
+        );
+        Attachment {
+            broker: self.clone(),
+            connection,
+            outbox,
+        }
+    }
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One served connection's registration with a [`Broker`]
+pub(crate) struct Attachment {
+    broker: Arc<Broker>,
+    connection: u64,
+    outbox: Arc<Outbox>,
+}
+
+impl Attachment {
+    pub(crate) fn subscribe(&self, id: u64, pattern: String) {
+        if let Some(subscriber) = self.broker.connections.lock().unwrap().get_mut(&self.connection) {
+            subscriber.topics.insert(id, pattern);
+        }
+    }
+
+    pub(crate) fn unsubscribe(&self, id: u64) {
+        if let Some(subscriber) = self.broker.connections.lock().unwrap().get_mut(&self.connection) {
+            subscriber.topics.remove(&id);
+        }
+    }
+
+    /// Waits for buffered Events and takes all of them
+    pub(crate) async fn next_batch(&self) -> Vec<Message> {
+        loop {
+            {
+                let mut queue = self.outbox.queue.lock().unwrap();
+                if !queue.is_empty() {
+                    return queue.drain(..).collect();
+                }
+            }
+            self.outbox.ready.notified().await;
+        }
+    }
+
+    /// Resolves once the Disconnect policy has given up on this connection
+    pub(crate) fn overflowed(&self) -> WaitForCancellationFuture<'_> {
+        self.outbox.overflowed.cancelled()
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        self.broker.connections.lock().unwrap().remove(&self.connection);
+    }
+}
+
+struct Outbox {
+    queue: Mutex<VecDeque<Message>>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    ready: Notify,
+    overflowed: CancellationToken,
+}
+
+impl Outbox {
+    // Returns whether the Event was queued and whether an Event was discarded
+    fn push(&self, event: Message) -> (bool, bool) {
+        let mut queue = self.queue.lock().unwrap();
+        let mut discarded = false;
+        if queue.len() >= self.capacity {
+            match self.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    queue.pop_front();
+                    discarded = true;
+                }
+                SlowConsumerPolicy::DropNewest => return (false, true),
+                SlowConsumerPolicy::Disconnect => {
+                    self.overflowed.cancel();
+                    return (false, true);
+                }
+            }
+        }
+        queue.push_back(event);
+        self.ready.notify_one();
+        (true, discarded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn payloads(batch: Vec<Message>) -> Vec<Bytes> {
+        batch.into_iter().map(|event| event.payload).collect()
+    }
+
+    #[tokio::test]
+    async fn test_publish_reaches_matching_subscriptions() {
+        let broker = Arc::new(Broker::new());
+        let first = broker.attach();
+        let second = broker.attach();
+        first.subscribe(1, "sensors/*/temp".into());
+        second.subscribe(2, "logs/**".into());
+
+        assert_eq!(broker.publish("sensors/3/temp", "warm"), 1);
+        let batch = first.next_batch().await;
+        assert_eq!((batch[0].request_id, batch[0].routing_info.as_deref()), (1, Some("sensors/3/temp")));
+
+        second.unsubscribe(2);
+        assert_eq!(broker.publish("logs/app", "line"), 0);
+        drop(first);
+        assert_eq!(broker.subscriptions(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_policies() {
+        for (policy, kept) in [
+            (SlowConsumerPolicy::DropOldest, ["b", "c"]),
+            (SlowConsumerPolicy::DropNewest, ["a", "b"]),
+        ] {
+            let broker = Arc::new(Broker::new().with_buffer_capacity(2).with_slow_consumer_policy(policy));
+            let attachment = broker.attach();
+            attachment.subscribe(1, "feed".into());
+            for payload in ["a", "b", "c"] {
+                broker.publish("feed", payload);
+            }
+            assert_eq!(broker.dropped(), 1);
+            assert_eq!(payloads(attachment.next_batch().await), kept.map(Bytes::from));
+        }
+
+        let broker = Arc::new(Broker::new().with_buffer_capacity(1).with_slow_consumer_policy(SlowConsumerPolicy::Disconnect));
+        let attachment = broker.attach();
+        attachment.subscribe(1, "feed".into());
+        broker.publish("feed", "a");
+        broker.publish("feed", "b");
+        tokio::time::timeout(Duration::from_secs(1), attachment.overflowed()).await.unwrap();
+    }
+}
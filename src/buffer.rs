@@ -0,0 +1,112 @@
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+struct SizeClass {
+    capacity: usize,
+    free: Mutex<Vec<BytesMut>>,
+}
+
+/// Shared pool of reusable I/O buffers grouped into fixed slab sizes
+pub struct BufferPool {
+    classes: Vec<SizeClass>,
+    max_per_class: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool with the given slab sizes, retaining at most `max_per_class` free buffers of each
+    pub fn new(slab_sizes: &[usize], max_per_class: usize) -> Self {
+        let mut sizes = slab_sizes.to_vec();
+        sizes.sort_unstable();
+        sizes.dedup();
+        Self {
+            classes: sizes
+                .into_iter()
+                .map(|capacity| SizeClass {
+                    capacity,
+                    free: Mutex::new(Vec::new()),
+                })
+                .collect(),
+            max_per_class,
+        }
+    }
+
+    /// Takes an empty buffer with at least `min_capacity` bytes of capacity.
+    ///
+    /// Requests larger than the biggest slab size are allocated directly.
+    pub fn acquire(&self, min_capacity: usize) -> BytesMut {
+        match self.classes.iter().find(|class| class.capacity >= min_capacity) {
+            Some(class) => class
+                .free
+                .lock()
+                .unwrap()
+                .pop()
+                .unwrap_or_else(|| BytesMut::with_capacity(class.capacity)),
+            None => BytesMut::with_capacity(min_capacity),
+        }
+    }
+
+    /// Returns a buffer to the largest slab size it can still serve
+    pub fn release(&self, mut buf: BytesMut) {
+        buf.clear();
+        let capacity = buf.capacity();
+        if let Some(class) = self.classes.iter().rev().find(|class| class.capacity <= capacity) {
+            let mut free = class.free.lock().unwrap();
+            if free.len() < self.max_per_class {
+                free.push(buf);
+            }
+        }
+    }
+
+    /// Returns the number of free buffers currently held by the pool
+    pub fn pooled_count(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| class.free.lock().unwrap().len())
+            .sum()
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(&[4 * 1024, 16 * 1024, 64 * 1024], 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool_reuses_buffers() {
+        let pool = BufferPool::new(&[1024, 4096], 4);
+
+        let buf = pool.acquire(100);
+        assert!(buf.capacity() >= 1024);
+        let ptr = buf.as_ptr();
+        pool.release(buf);
+        assert_eq!(pool.pooled_count(), 1);
+
+        let reused = pool.acquire(512);
+        assert_eq!(reused.as_ptr(), ptr);
+        assert!(reused.is_empty());
+        assert_eq!(pool.pooled_count(), 0);
+    }
+
+    #[test]
+    fn test_buffer_pool_limits() {
+        let pool = BufferPool::new(&[1024], 1);
+
+        // Oversized requests bypass the slabs
+        let large = pool.acquire(10_000);
+        assert!(large.capacity() >= 10_000);
+
+        // Buffers too small for any slab are dropped
+        pool.release(BytesMut::with_capacity(16));
+        assert_eq!(pool.pooled_count(), 0);
+
+        pool.release(pool.acquire(1024));
+        pool.release(pool.acquire(1024));
+        pool.release(BytesMut::with_capacity(1024));
+        assert_eq!(pool.pooled_count(), 1);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{retry::is_transient, ProtocolError};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// State of the circuit for one endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests flow normally while outcomes are tracked
+    Closed,
+    /// Requests are rejected immediately until the open duration passes
+    Open,
+    /// A limited number of trial requests probe whether the endpoint recovered
+    HalfOpen,
+}
+
+struct Circuit {
+    state: CircuitState,
+    outcomes: VecDeque<bool>,
+    opened_at: Instant,
+    trials_started: u32,
+    trials_succeeded: u32,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            outcomes: VecDeque::new(),
+            opened_at: Instant::now(),
+            trials_started: 0,
+            trials_succeeded: 0,
+        }
+    }
+
+    fn open(&mut self) {
+        self.state = CircuitState::Open;
+        self.opened_at = Instant::now();
+        self.outcomes.clear();
+    }
+}
+
+/// Per-endpoint circuit breaker shared by every client talking to the same backends.
+///
+/// A circuit opens once the failure rate over the last `window` requests reaches
+/// the threshold, sheds requests for the open duration, then lets a few trial
+/// requests through and closes again only if they all succeed.
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    min_requests: usize,
+    window: usize,
+    open_duration: Duration,
+    half_open_trials: u32,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    /// Opens a circuit when at least `failure_rate` (0.0-1.0) of the last
+    /// requests failed, once `min_requests` outcomes have been seen
+    pub fn new(failure_rate: f64, min_requests: usize) -> Self {
+        Self {
+            failure_rate,
+            min_requests: min_requests.max(1),
+            window: min_requests.max(20),
+            open_duration: Duration::from_secs(30),
+            half_open_trials: 1,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how many recent outcomes the failure rate is computed over
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(self.min_requests);
+        self
+    }
+
+    /// Sets how long an open circuit rejects requests before probing again
+    pub fn with_open_duration(mut self, duration: Duration) -> Self {
+        self.open_duration = duration;
+        self
+    }
+
+    /// Sets how many trial requests a half-open circuit admits
+    pub fn with_half_open_trials(mut self, trials: u32) -> Self {
+        self.half_open_trials = trials.max(1);
+        self
+    }
+
+    /// Admits a request to `endpoint`, or fails fast with `ProtocolError::CircuitOpen`
+    pub fn try_acquire(&self, endpoint: &str) -> Result<(), ProtocolError> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(endpoint.to_string()).or_insert_with(Circuit::new);
+        if circuit.state == CircuitState::Open && circuit.opened_at.elapsed() >= self.open_duration {
+            circuit.state = CircuitState::HalfOpen;
+            circuit.trials_started = 0;
+            circuit.trials_succeeded = 0;
+        }
+        match circuit.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::HalfOpen if circuit.trials_started < self.half_open_trials => {
+                circuit.trials_started += 1;
+                Ok(())
+            }
+            _ => Err(ProtocolError::CircuitOpen(endpoint.to_string())),
+        }
+    }
+
+    /// Like [`try_acquire`](Self::try_acquire), returning a permit that records the outcome
+    pub fn admit(&self, endpoint: &str) -> Result<CircuitPermit<'_>, ProtocolError> {
+        self.try_acquire(endpoint)?;
+        Ok(CircuitPermit {
+            breaker: self,
+            endpoint: endpoint.to_string(),
+            recorded: false,
+        })
+    }
+
+    /// Records that an admitted request to `endpoint` succeeded
+    pub fn record_success(&self, endpoint: &str) {
+        self.record(endpoint, true);
+    }
+
+    /// Records that an admitted request to `endpoint` failed
+    pub fn record_failure(&self, endpoint: &str) {
+        self.record(endpoint, false);
+    }
+
+    // Hands back the half-open trial slot of a request that ended without an outcome
+    fn abandon(&self, endpoint: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(endpoint) {
+            if circuit.state == CircuitState::HalfOpen {
+                circuit.trials_started = circuit.trials_started.saturating_sub(1);
+            }
+        }
+    }
+
+    fn record(&self, endpoint: &str, success: bool) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(endpoint.to_string()).or_insert_with(Circuit::new);
+        match circuit.state {
+            CircuitState::Closed => {
+                circuit.outcomes.push_back(success);
+                if circuit.outcomes.len() > self.window {
+                    circuit.outcomes.pop_front();
+                }
+                let failures = circuit.outcomes.iter().filter(|ok| !**ok).count();
+                if circuit.outcomes.len() >= self.min_requests
+                    && failures as f64 / circuit.outcomes.len() as f64 >= self.failure_rate
+                {
+                    circuit.open();
+                }
+            }
+            CircuitState::HalfOpen if success => {
+                circuit.trials_succeeded += 1;
+                if circuit.trials_succeeded >= self.half_open_trials {
+                    circuit.state = CircuitState::Closed;
+                }
+            }
+            CircuitState::HalfOpen => circuit.open(),
+            CircuitState::Open => {}
+        }
+    }
+
+    /// Returns the current state of the circuit for `endpoint`
+    pub fn state(&self, endpoint: &str) -> CircuitState {
+        let circuits = self.circuits.lock().unwrap();
+        match circuits.get(endpoint) {
+            Some(circuit) if circuit.state == CircuitState::Open && circuit.opened_at.elapsed() >= self.open_duration => {
+                CircuitState::HalfOpen
+            }
+            Some(circuit) => circuit.state,
+            None => CircuitState::Closed,
+        }
+    }
+
+    /// Returns whether requests to `endpoint` are currently shed
+    pub fn is_open(&self, endpoint: &str) -> bool {
+        self.state(endpoint) == CircuitState::Open
+    }
+}
+
+/// A request admitted by [`CircuitBreaker::admit`].
+///
+/// Dropping the permit without recording an outcome, e.g. when the request is
+/// cancelled, abandons it: a half-open trial slot is freed and nothing is counted.
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    endpoint: String,
+    recorded: bool,
+}
+
+impl CircuitPermit<'_> {
+    /// Records how the request ended. Transient errors, as classified by
+    /// [`is_transient`], count as failures; `Cancelled` abandons the request;
+    /// anything else, including an error the peer answered with, means the
+    /// endpoint is up and counts as a success.
+    pub fn record<T>(mut self, result: &Result<T, ProtocolError>) {
+        match result {
+            Err(ProtocolError::Cancelled) => return,
+            Err(e) if is_transient(e) => self.breaker.record_failure(&self.endpoint),
+            _ => self.breaker.record_success(&self.endpoint),
+        }
+        self.recorded = true;
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.abandon(&self.endpoint);
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(0.5, 10)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_opens_on_failure_rate() {
+        let breaker = CircuitBreaker::new(0.5, 4);
+
+        for _ in 0..3 {
+            breaker.try_acquire("a").unwrap();
+            breaker.record_failure("a");
+        }
+        // Not enough outcomes yet to judge
+        assert_eq!(breaker.state("a"), CircuitState::Closed);
+
+        breaker.try_acquire("a").unwrap();
+        breaker.record_success("a");
+        assert_eq!(breaker.state("a"), CircuitState::Open);
+        assert!(matches!(breaker.try_acquire("a"), Err(ProtocolError::CircuitOpen(_))));
+
+        // Other endpoints are unaffected
+        assert!(breaker.try_acquire("b").is_ok());
+    }
+
+    #[test]
+    fn test_half_open_trial_closes_or_reopens() {
+        let breaker = CircuitBreaker::new(0.5, 1).with_open_duration(Duration::from_millis(10));
+
+        breaker.record_failure("a");
+        assert!(breaker.is_open("a"));
+        std::thread::sleep(Duration::from_millis(15));
+
+        // One trial admitted, the next is shed while it is in flight
+        breaker.try_acquire("a").unwrap();
+        assert!(breaker.try_acquire("a").is_err());
+        breaker.record_failure("a");
+        assert!(breaker.is_open("a"));
+
+        std::thread::sleep(Duration::from_millis(15));
+        breaker.try_acquire("a").unwrap();
+        breaker.record_success("a");
+        assert_eq!(breaker.state("a"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_abandoned_trial_frees_its_slot() {
+        let breaker = CircuitBreaker::new(0.5, 1).with_open_duration(Duration::from_millis(10));
+        breaker.record_failure("a");
+        std::thread::sleep(Duration::from_millis(15));
+
+        let trial = breaker.admit("a").unwrap();
+        assert!(breaker.admit("a").is_err());
+        drop(trial);
+        breaker.admit("a").unwrap().record(&Err::<(), _>(ProtocolError::Cancelled));
+
+        breaker.admit("a").unwrap().record(&Ok(()));
+        assert_eq!(breaker.state("a"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_only_transient_errors_count_as_failures() {
+        let breaker = CircuitBreaker::new(0.5, 2);
+        for error in [ProtocolError::Remote("no such order".into()), ProtocolError::Cancelled] {
+            breaker.admit("a").unwrap().record(&Err::<(), _>(error));
+        }
+        assert_eq!(breaker.state("a"), CircuitState::Closed);
+
+        // The answered request is one success alongside this failure
+        breaker.admit("a").unwrap().record(&Err::<(), _>(ProtocolError::Timeout("slow".into())));
+        assert!(breaker.is_open("a"));
+    }
+}
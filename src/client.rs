@@ -1,37 +1,234 @@
 use crate::{
-    Message, MessageFlags, MessageType, ProtocolError,
-    compression::compress_if_beneficial,
-    discovery::{HealthStatus, ServiceInfo, ServiceRegistry},
-    encryption::Encryptor,
-    transport::Transport,
+    Message, MessageExt, MessageFlags, MessageType, ProtocolError,
+    acl,
+    circuit::CircuitBreaker,
+    compression::{self, Compression, CompressionConfig},
+    connection::{Connection, Connector, Reconnect, ResponseFuture},
+    discovery::{RegistryRequest, ServiceInfo, ServiceRegistry, DISCOVERY_ROUTE},
+    encryption::{Cipher, Encryptor},
+    interceptor::Interceptor,
+    noise::{self, NoiseConfig},
+    observability::Telemetry,
+    offline::OfflineQueue,
+    proxy::ProxyConfig,
+    retry::{is_transient, RetryPolicy},
+    selector::Selector,
+    server::ErrorPayload,
+    socket::SocketConfig,
+    stream::MessageStream,
+    transport::{Transport, TransportStats},
 };
 use bytes::Bytes;
-use std::time::Duration;
+use futures::{future::BoxFuture, Sink};
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 /// High-level client for the Remus protocol
+///
+/// Requests are correlated with their responses by request id, so one client
+/// can be shared between tasks and have many requests in flight at once.
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+/// use remus::{Encryptor, RemusClient};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize)]
+/// struct GetUser {
+///     id: u64,
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct User {
+///     name: String,
+/// }
+/// 
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     // Connect to a Remus server
+///     let client = RemusClient::connect("localhost:8080")
+///         .await?
+///         .with_encryption(&Encryptor::generate_key());
+/// 
+///     // Send a simple request
+///     let response = client.request("Hello, World!").await?;
+///     println!("Got response: {:?}", response);
+///
+///     // Call a route with typed request and response
+///     let user: User = client.call("users.get", &GetUser { id: 7 }).await?;
+///     println!("Got user: {}", user.name);
+/// 
+///     // Create a stream
+///     let mut stream = client.stream("Start streaming").await?;
+///     while let Some(msg) = stream.next().await {
+///         println!("Got stream message: {:?}", msg);
+///     }
+/// 
+///     // Discover services
+///     let services = client.discover_services().await?;
+///     for service in services {
+///         println!("Found service: {} at {}", service.name, service.address);
+///     }
+/// 
+///     Ok(())
+/// }
+/// ```
 pub struct RemusClient {
-    transport: Transport<TcpStream>,
-    encryptor: Option<Encryptor>,
+    dialer: Dialer,
+    // Dialed eagerly by the `connect*` constructors, configured by the builders,
+    // then handed to the connection driver on first use
+    transport: Mutex<Option<Transport<TcpStream>>>,
+    connection: Mutex<Option<Connection>>,
+    reconnect: Option<RetryPolicy>,
+    resume_sessions: bool,
     service_registry: ServiceRegistry,
     request_timeout: Duration,
+    retry_policy: Option<RetryPolicy>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    telemetry: Option<Arc<Telemetry>>,
+    offline_queue: Option<Arc<OfflineQueue>>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    credentials: Option<String>,
 }
 
 impl RemusClient {
-    /// Creates a new client with default configuration
-    pub async fn connect(address: &str) -> Result<Self, ProtocolError> {
-        let stream = TcpStream::connect(address).await?;
-        Ok(Self {
-            transport: Transport::new(stream),
-            encryptor: None,
+    /// Creates a client that does not dial until the first request or [`RemusClient::ensure_connected`].
+    ///
+    /// Needs no runtime, so it can be built in non-async contexts and before the
+    /// server is up. Whenever the connection closes, the next request dials again.
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            dialer: Dialer::new(config),
+            transport: Mutex::new(None),
+            connection: Mutex::new(None),
+            reconnect: None,
+            resume_sessions: false,
             service_registry: ServiceRegistry::new(Duration::from_secs(30)),
             request_timeout: Duration::from_secs(30),
-        })
+            retry_policy: None,
+            circuit_breaker: None,
+            telemetry: None,
+            offline_queue: None,
+            interceptors: Vec::new(),
+            credentials: None,
+        }
+    }
+
+    /// Creates a new client with default configuration.
+    ///
+    /// Given several addresses, the client connects to the first that answers
+    /// and fails over to the others; see [`ClientConfig::from_addresses`].
+    pub async fn connect(addresses: impl IntoAddresses) -> Result<Self, ProtocolError> {
+        Self::dial(ClientConfig::from_addresses(addresses)?).await
+    }
+
+    /// Creates a new client whose connection uses the given socket options
+    pub async fn connect_with_socket_config(
+        addresses: impl IntoAddresses,
+        config: &SocketConfig,
+    ) -> Result<Self, ProtocolError> {
+        Self::dial(ClientConfig::from_addresses(addresses)?.with_socket_config(config.clone())).await
+    }
+
+    /// Creates a new client that tunnels its connection through an outbound proxy
+    pub async fn connect_via_proxy(addresses: impl IntoAddresses, proxy: &ProxyConfig) -> Result<Self, ProtocolError> {
+        Self::dial(ClientConfig::from_addresses(addresses)?.with_proxy(proxy.clone())).await
+    }
+
+    async fn dial(config: ClientConfig) -> Result<Self, ProtocolError> {
+        let client = Self::new(config);
+        *client.transport.lock().unwrap() = Some(client.dialer.connect().await?);
+        Ok(client)
+    }
+
+    /// Dials now if the client has no open connection, surfacing connect errors up front
+    pub async fn ensure_connected(&self) -> Result<(), ProtocolError> {
+        if self.has_connection() {
+            return Ok(());
+        }
+        let transport = self.dialer.connect().await?;
+        let mut connection = self.connection.lock().unwrap();
+        if connection.as_ref().is_none_or(Connection::is_closed) {
+            *connection = Some(self.spawn_connection(Some(transport), false));
+        }
+        Ok(())
+    }
+
+    fn has_connection(&self) -> bool {
+        let connection = self.connection.lock().unwrap();
+        connection.as_ref().is_some_and(|connection| !connection.is_closed()) || self.transport.lock().unwrap().is_some()
     }
 
     /// Enables encryption for all future communications
-    pub fn with_encryption(mut self, key: &[u8; 32]) -> Self {
-        self.encryptor = Some(Encryptor::new(key));
+    pub fn with_encryption(self, key: &[u8; 32]) -> Self {
+        self.with_encryption_cipher(key, Cipher::Aes256Gcm)
+    }
+
+    /// Enables encryption with `cipher`; the server must use the same one
+    pub fn with_encryption_cipher(self, key: &[u8; 32], cipher: Cipher) -> Self {
+        self.with_encryptor(Arc::new(Encryptor::with_cipher(key, cipher)))
+    }
+
+    /// Encrypts with `encryptor` on this and every later connection, so a
+    /// session encryptor keeps counting nonces across reconnects
+    pub fn with_encryptor(mut self, encryptor: Arc<Encryptor>) -> Self {
+        self.configure_transport(|transport| transport.with_encryptor(encryptor.clone()));
+        self.dialer.encryption = Some(encryptor);
+        self
+    }
+
+    /// Runs the initiator side of a Noise handshake on every connection and
+    /// encrypts with the session keys, in place of any pre-shared key.
+    ///
+    /// A connection already opened by a `connect*` constructor is dropped, so
+    /// the next request dials again and handshakes.
+    pub fn with_noise(mut self, config: NoiseConfig) -> Self {
+        *self.transport.get_mut().unwrap() = None;
+        self.dialer.noise = Some(Arc::new(config));
+        self
+    }
+
+    /// Negotiates compression on every connection, offering `supported`, most
+    /// preferred first, at the level of `config`, whose other settings then
+    /// apply as they are; the server must enable it too.
+    ///
+    /// A connection already opened by a `connect*` constructor is dropped, so
+    /// the next request dials again and negotiates.
+    pub fn with_compression_negotiation(mut self, supported: &[Compression], config: CompressionConfig) -> Self {
+        *self.transport.get_mut().unwrap() = None;
+        self.dialer.compression = Some((supported.to_vec(), config));
+        self
+    }
+
+    /// Reconnects when the connection drops, making up to `policy.max_attempts()`
+    /// attempts with its backoff.
+    ///
+    /// In-flight IDEMPOTENT requests are replayed on the new connection; other
+    /// in-flight requests fail with `ProtocolError::ConnectionReset`, and open
+    /// streams end with an Error message carrying it.
+    pub fn with_reconnect(mut self, policy: RetryPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Resumes the server-side session when reconnecting, so requests, streams
+    /// and subscriptions survive the connection dropping, e.g. when a mobile
+    /// client changes networks; takes effect with [`RemusClient::with_reconnect`].
+    ///
+    /// Requests the server is still answering are answered on the new
+    /// connection. Only requests it never received are replayed or fail as
+    /// without resumption. The server must enable it with
+    /// [`Server::with_session_resumption`](crate::Server::with_session_resumption).
+    pub fn with_session_resumption(mut self) -> Self {
+        self.resume_sessions = true;
         self
     }
 
@@ -41,86 +238,1655 @@ impl RemusClient {
         self
     }
 
+    /// Retries failed IDEMPOTENT requests according to `policy`
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Sheds requests to this client's endpoint while `breaker` holds its circuit open.
+    ///
+    /// Share one breaker between clients so they all see the same endpoint health.
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Records request latency, errors by variant and bytes in/out into `telemetry`.
+    ///
+    /// Each request also emits a Trace span when the telemetry has request traces enabled.
+    pub fn with_telemetry(mut self, telemetry: Arc<Telemetry>) -> Self {
+        let stats = telemetry.transport_stats().clone();
+        self.configure_transport(|transport| transport.with_stats(stats.clone()));
+        self.dialer.stats = Some(stats);
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    /// Queues events that `notify` cannot deliver and flushes them on the next connection.
+    ///
+    /// An event is queued when the connection was never established, or when it
+    /// is IDEMPOTENT, since it may already have reached the peer before the link
+    /// dropped. IDEMPOTENT requests that fail because the link is down are queued
+    /// too, and their response arrives once a flush has sent them, bounded by
+    /// the request deadline if one is set. Flushing also happens on
+    /// [`RemusClient::flush_offline_queue`].
+    pub fn with_offline_queue(mut self, queue: Arc<OfflineQueue>) -> Self {
+        self.offline_queue = Some(queue);
+        self
+    }
+
+    /// Adds an interceptor; interceptors run in the order they are added
+    pub fn with_interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Presents `token` on every connection, for the server's
+    /// [`Authenticator`](crate::acl::Authenticator) to check when a message
+    /// flagged REQUIRES_AUTH arrives.
+    ///
+    /// Without credentials or a Noise handshake, sending such a message fails
+    /// with `ProtocolError::AuthenticationRequired`. The token travels in a
+    /// payload, encrypted when the connection uses Noise or a shared key;
+    /// otherwise only send one over a connection the acceptor secures, e.g. TLS.
+    pub fn with_credentials(mut self, token: impl Into<String>) -> Self {
+        self.credentials = Some(token.into());
+        self
+    }
+
+    fn configure_transport(&mut self, f: impl FnOnce(Transport<TcpStream>) -> Transport<TcpStream>) {
+        let slot = self.transport.get_mut().unwrap();
+        *slot = slot.take().map(f);
+    }
+
+    // Returns the open connection, starting a driver for it on first use or after the last one closed
+    fn connection(&self) -> Connection {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(connection) = connection.as_ref().filter(|connection| !connection.is_closed()) {
+            return connection.clone();
+        }
+        // After a mid-session drop, try the other endpoints before the one that failed
+        let failover = connection.as_ref().is_some_and(Connection::was_connected);
+        let transport = self.transport.lock().unwrap().take();
+        let spawned = self.spawn_connection(transport, failover);
+        *connection = Some(spawned.clone());
+        // Hand queued events to the new connection; they wait in its queue while it dials
+        if let Some(queue) = self.offline_queue.clone().filter(|queue| !queue.is_empty()) {
+            let spawned = spawned.clone();
+            tokio::spawn(async move {
+                let _ = queue.flush(&spawned).await;
+            });
+        }
+        spawned
+    }
+
+    /// Connects if necessary and sends every queued offline event, returning how many were sent
+    pub async fn flush_offline_queue(&self) -> Result<usize, ProtocolError> {
+        let Some(queue) = &self.offline_queue else {
+            return Ok(0);
+        };
+        self.ensure_connected().await?;
+        queue.flush(&self.connection()).await
+    }
+
+    // Drives `transport`, or dials a new one in the driver when there is none yet
+    fn spawn_connection(&self, transport: Option<Transport<TcpStream>>, failover: bool) -> Connection {
+        let reconnect = self.reconnect.as_ref().map(|policy| Reconnect {
+            connector: self.dialer.connector(),
+            policy: policy.clone(),
+            resume: self.resume_sessions,
+        });
+        let connect: BoxFuture<'static, Result<Transport<TcpStream>, ProtocolError>> = match transport {
+            Some(transport) => Box::pin(async { Ok(transport) }),
+            None => {
+                let dialer = self.dialer.clone();
+                Box::pin(async move { dialer.dial(failover).await })
+            }
+        };
+        let greeting = self.credentials.iter().map(|token| acl::credentials_frame(token)).collect();
+        Connection::spawn(connect, reconnect, greeting)
+    }
+
+    // Flags asking the transport to compress; an encrypted transport seals every frame unasked
+    fn payload_flags(&self) -> MessageFlags {
+        MessageFlags::COMPRESSED
+    }
+
     /// Sends a request and waits for response
-    pub async fn request(&mut self, payload: impl AsRef<[u8]>) -> Result<Bytes, ProtocolError> {
-        let payload = self.prepare_payload(payload.as_ref())?;
-        
-        let request = Message::new(
+    pub async fn request(&self, payload: impl AsRef<[u8]>) -> Result<Bytes, ProtocolError> {
+        self.request_with_options(payload, &RequestOptions::default()).await
+    }
+
+    /// Sends a request with per-request options and waits for response
+    pub async fn request_with_options(
+        &self,
+        payload: impl AsRef<[u8]>,
+        options: &RequestOptions,
+    ) -> Result<Bytes, ProtocolError> {
+        let mut request = Message::new(
             MessageType::Request,
             MessageFlags::IDEMPOTENT,
             rand::random(),
-            payload,
+            Bytes::copy_from_slice(payload.as_ref()),
         );
+        options.apply(&mut request);
 
-        self.transport.send(request).await?;
+        Ok(self.send_request(request, options).await?.payload)
+    }
+
+    /// Calls `route` with a serialized request and deserializes the correlated response
+    pub async fn call<T: Serialize, R: DeserializeOwned>(&self, route: &str, request: &T) -> Result<R, ProtocolError> {
+        self.call_with_options(route, request, &RequestOptions::default()).await
+    }
 
-        let response = tokio::time::timeout(
-            self.request_timeout,
-            self.transport.receive()
-        ).await.map_err(|_| ProtocolError::InvalidFormat("Request timeout".into()))??;
+    /// Calls `route` with per-request options; `route` takes precedence over the options' routing info
+    pub async fn call_with_options<T: Serialize, R: DeserializeOwned>(
+        &self,
+        route: &str,
+        request: &T,
+        options: &RequestOptions,
+    ) -> Result<R, ProtocolError> {
+        let mut message = Message::request(request)?;
+        options.apply(&mut message);
+        message.routing_info = Some(route.to_string());
 
-        Ok(response.payload)
+        self.send_request(message, options).await?.deserialize()
+    }
+
+    /// Asks the peer's registry for the services `selector` matches
+    pub async fn discover(&self, selector: &Selector) -> Result<Vec<ServiceInfo>, ProtocolError> {
+        self.call(DISCOVERY_ROUTE, &RegistryRequest::Query(selector.clone())).await
+    }
+
+    async fn send_request(&self, request: Message, options: &RequestOptions) -> Result<Message, ProtocolError> {
+        let Some(telemetry) = &self.telemetry else {
+            return self.send_cancellable(request, options).await;
+        };
+        let name = request.routing_info.clone().unwrap_or_else(|| "remus.request".to_string());
+        let started = SystemTime::now();
+        let start = Instant::now();
+        let result = self.send_cancellable(request, options).await;
+        telemetry.record_request(&name, started, start.elapsed(), result.as_ref().map(|_| ()));
+        result
+    }
+
+    // Cancelling drops the in-flight attempt, which sends the peer a Cancel frame
+    async fn send_cancellable(&self, request: Message, options: &RequestOptions) -> Result<Message, ProtocolError> {
+        let attempts = self.send_or_queue(request, options.deadline);
+        match &options.cancellation {
+            Some(token) => tokio::select! {
+                result = attempts => result,
+                _ = token.cancelled() => Err(ProtocolError::Cancelled),
+            },
+            None => attempts.await,
+        }
+    }
+
+    // Sends `request`; with an offline queue, an IDEMPOTENT request that fails
+    // because the link is down is queued and answered after a later flush
+    async fn send_or_queue(&self, request: Message, deadline: Option<Duration>) -> Result<Message, ProtocolError> {
+        let queue = self.offline_queue.as_ref().filter(|_| request.flags.contains(MessageFlags::IDEMPOTENT));
+        let Some(queue) = queue else {
+            return self.send_with_retries(request, deadline).await;
+        };
+        let started = Instant::now();
+        let error = match self.send_with_retries(request.clone(), deadline).await {
+            Err(error) => error,
+            result => return result,
+        };
+        let cause = match &error {
+            ProtocolError::RetriesExhausted { last, .. } => last.as_ref(),
+            error => error,
+        };
+        if !is_transient(cause) || !self.link_down() {
+            return Err(error);
+        }
+
+        let mut queued = request;
+        queued.flags |= self.payload_flags();
+        self.before_send(&mut queued)?;
+        let flushed = queue.push_request(queued)?;
+        let response = async {
+            let response = flushed.await.map_err(|_| ProtocolError::ConnectionClosed)??;
+            let mut response = wait_response(response, self.request_timeout, &self.interceptors).await?;
+            after_receive(&self.interceptors, &mut response)?;
+            Ok(response)
+        };
+        match deadline {
+            Some(deadline) => tokio::time::timeout(deadline.saturating_sub(started.elapsed()), response)
+                .await
+                .map_err(|_| ProtocolError::Timeout("Request deadline passed while queued offline".into()))?,
+            None => response.await,
+        }
+    }
+
+    // Whether the last connection is gone, so nothing can be sent until the next one
+    fn link_down(&self) -> bool {
+        self.connection.lock().unwrap().as_ref().is_none_or(Connection::is_closed)
+    }
+
+    // Sends `request`, retrying per the retry policy, and returns the response.
+    // A deadline bounds all attempts together and replaces the per-attempt timeout.
+    async fn send_with_retries(&self, mut request: Message, deadline: Option<Duration>) -> Result<Message, ProtocolError> {
+        request.flags |= self.payload_flags();
+        let deadline = deadline.map(|deadline| Instant::now() + deadline);
+
+        let mut attempt = 1;
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => self.request_timeout,
+            };
+            let error = match self.round_trip(&request, timeout).await {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            };
+            if let Some(policy) = &self.retry_policy {
+                let backoff = policy.backoff(attempt);
+                let within_deadline = deadline.is_none_or(|deadline| Instant::now() + backoff < deadline);
+                if policy.should_retry(&request, &error, attempt) && within_deadline {
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                    continue;
+                }
+            }
+            if attempt > 1 {
+                return Err(ProtocolError::RetriesExhausted {
+                    attempts: attempt,
+                    last: Box::new(error),
+                });
+            }
+            return Err(error);
+        }
+    }
+
+    // Sends one attempt of `request` and waits for the response carrying its id.
+    // Retries reuse the id, so a late response to an earlier attempt is accepted.
+    async fn round_trip(&self, request: &Message, timeout: Duration) -> Result<Message, ProtocolError> {
+        let Some(breaker) = &self.circuit_breaker else {
+            return self.send_and_wait(request, timeout).await;
+        };
+        // Dropped unrecorded if the attempt is cancelled mid-flight
+        let permit = breaker.admit(&endpoint_key(self.dialer.current_address()))?;
+        let result = self.send_and_wait(request, timeout).await;
+        permit.record(&result);
+        result
+    }
+
+    async fn send_and_wait(&self, request: &Message, timeout: Duration) -> Result<Message, ProtocolError> {
+        let mut request = request.clone();
+        self.before_send(&mut request)?;
+        let response = self.connection().request(request).await?;
+        let mut response = wait_response(response, timeout, &self.interceptors).await?;
+        after_receive(&self.interceptors, &mut response)?;
+        Ok(response)
+    }
+
+    fn before_send(&self, message: &mut Message) -> Result<(), ProtocolError> {
+        self.authorize(message)?;
+        for interceptor in &self.interceptors {
+            interceptor.before_send(message)?;
+        }
+        Ok(())
+    }
+
+    // A message flagged REQUIRES_AUTH may only be sent by a client that can
+    // prove who it is, with its credentials or its Noise static key
+    fn authorize(&self, message: &Message) -> Result<(), ProtocolError> {
+        let provable = self.credentials.is_some() || self.dialer.noise.is_some();
+        if message.flags.contains(MessageFlags::REQUIRES_AUTH) && !provable {
+            return Err(ProtocolError::AuthenticationRequired);
+        }
+        Ok(())
+    }
+
+    /// Starts a batch of pipelined requests written to the connection together
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            client: self,
+            messages: Vec::new(),
+        }
     }
 
     /// Creates a streaming request
-    pub async fn stream(&mut self, payload: impl AsRef<[u8]>) -> Result<MessageStream, ProtocolError> {
-        let payload = self.prepare_payload(payload.as_ref())?;
-        let (tx, stream) = MessageStream::new(32);
+    pub async fn stream(&self, payload: impl AsRef<[u8]>) -> Result<MessageStream, ProtocolError> {
+        self.stream_with_options(payload, &RequestOptions::default()).await
+    }
+
+    /// Creates a streaming request with per-request options; the deadline does not apply to streams.
+    ///
+    /// Dropping the stream or cancelling its token before it ends sends the peer a Cancel frame.
+    pub async fn stream_with_options(
+        &self,
+        payload: impl AsRef<[u8]>,
+        options: &RequestOptions,
+    ) -> Result<MessageStream, ProtocolError> {
+        let (tx, mut stream) = MessageStream::new(32);
 
-        let request = Message::new(
+        let mut request = Message::new(
             MessageType::Stream,
-            MessageFlags::STREAM_END,
+            MessageFlags::STREAM_END | self.payload_flags(),
             stream.stream_id(),
-            payload,
+            Bytes::copy_from_slice(payload.as_ref()),
+        );
+        options.apply(&mut request);
+        self.before_send(&mut request)?;
+
+        let connection = self.connection();
+        connection.open_stream(stream.stream_id(), tx)?;
+        connection.send(request).await?;
+
+        let stream_id = stream.stream_id();
+        stream.on_cancel(move || connection.cancel_stream(stream_id));
+        if let Some(token) = &options.cancellation {
+            stream.cancel_on(token.clone());
+        }
+        Ok(stream)
+    }
+
+    /// Subscribes to Events whose topic matches `pattern`, once the peer confirms.
+    ///
+    /// Topics are `/`-separated; `*` matches one segment and a trailing `**`
+    /// the rest. With [`with_reconnect`](Self::with_reconnect) the subscription
+    /// is renewed on every new connection; otherwise the stream ends when the
+    /// connection closes. Dropping the stream unsubscribes.
+    pub async fn subscribe(&self, pattern: &str) -> Result<MessageStream, ProtocolError> {
+        let (tx, mut stream) = MessageStream::new(32);
+        let subscription_id = stream.stream_id();
+
+        let mut request = Message::new(
+            MessageType::Subscribe,
+            MessageFlags::NONE,
+            subscription_id,
+            Bytes::new(),
         );
+        request.routing_info = Some(pattern.to_string());
+        self.before_send(&mut request)?;
 
-        self.transport.send(request).await?;
+        let connection = self.connection();
+        connection.subscribe(subscription_id, pattern.to_string(), tx)?;
+        stream.on_cancel({
+            let connection = connection.clone();
+            move || connection.unsubscribe(subscription_id)
+        });
+        let confirmation = connection.request(request).await?;
+        wait_response(confirmation, self.request_timeout, &[]).await?;
         Ok(stream)
     }
 
-    /// Discovers available services
+    /// Starts a streaming upload; see [`UploadSink`]
+    pub fn stream_upload(&self) -> Result<UploadSink, ProtocolError> {
+        self.stream_upload_with_options(&RequestOptions::default())
+    }
+
+    /// Starts a streaming upload whose frames all carry `options`; the deadline does not apply
+    pub fn stream_upload_with_options(&self, options: &RequestOptions) -> Result<UploadSink, ProtocolError> {
+        let mut template = Message::new(MessageType::Stream, self.payload_flags(), rand::random(), Bytes::new());
+        options.apply(&mut template);
+        self.authorize(&template)?;
+
+        let response = self.connection().expect_response(&template)?;
+        Ok(UploadSink {
+            sink: StreamSink::new(self, template),
+            timeout: self.request_timeout,
+            response,
+        })
+    }
+
+    /// Opens a bidirectional stream to `route`.
+    ///
+    /// Chunks pushed into the sink go out as `Stream` frames and every frame the
+    /// peer sends on the same stream id arrives on the stream, so the two sides
+    /// can interleave freely. Each direction ends with its own `StreamEnd`. A
+    /// [`Server`](crate::Server) runs one handler for the whole stream, which
+    /// reads the chunks from its [`RequestStream`](crate::RequestStream) and
+    /// answers through its [`ResponseSink`](crate::ResponseSink).
+    pub fn bidi(&self, route: &str) -> Result<(StreamSink, MessageStream), ProtocolError> {
+        let (tx, mut stream) = MessageStream::new(32);
+        let stream_id = stream.stream_id();
+        let mut template = Message::new(MessageType::Stream, self.payload_flags(), stream_id, Bytes::new());
+        template.routing_info = Some(route.to_string());
+
+        let connection = self.connection();
+        connection.open_stream(stream_id, tx)?;
+        stream.on_cancel(move || connection.cancel_stream(stream_id));
+        Ok((StreamSink::new(self, template), stream))
+    }
+
+    /// Sends an Event without waiting for any reply, queueing it if an offline queue is configured
+    /// and the link is down
+    pub async fn notify(&self, payload: impl AsRef<[u8]>) -> Result<(), ProtocolError> {
+        let mut event = Message::new(
+            MessageType::Event,
+            self.payload_flags(),
+            rand::random(),
+            Bytes::copy_from_slice(payload.as_ref()),
+        );
+        self.before_send(&mut event)?;
+        let Some(queue) = &self.offline_queue else {
+            return self.connection().send(event).await;
+        };
+        let connection = self.connection();
+        match connection.send(event.clone()).await {
+            Err(e) if is_transient(&e) && (!connection.was_connected() || event.flags.contains(MessageFlags::IDEMPOTENT)) => {
+                queue.push(event)
+            }
+            result => result,
+        }
+    }
+
+    /// Sends an Event flagged REQUIRES_ACK, returning a future that resolves when
+    /// the peer's Ack arrives or fails with `ProtocolError::Timeout` once the
+    /// message TTL expires
+    pub async fn notify_with_ack(
+        &self,
+        payload: impl AsRef<[u8]>,
+        options: &RequestOptions,
+    ) -> Result<impl Future<Output = Result<(), ProtocolError>> + Send + 'static, ProtocolError> {
+        let mut event = Message::new(
+            MessageType::Event,
+            MessageFlags::REQUIRES_ACK | self.payload_flags(),
+            rand::random(),
+            Bytes::copy_from_slice(payload.as_ref()),
+        );
+        options.apply(&mut event);
+        self.before_send(&mut event)?;
+
+        let ttl = Duration::from_millis(event.ttl as u64);
+        let ack = self.connection().request(event).await?;
+        Ok(async move {
+            match tokio::time::timeout(ttl, ack).await {
+                Ok(Ok(message)) if message.msg_type == MessageType::Ack => Ok(()),
+                Ok(Ok(message)) => Err(ProtocolError::InvalidFormat(format!(
+                    "Expected Ack, got {:?}",
+                    message.msg_type
+                ))),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(ProtocolError::Timeout("Ack not received within TTL".into())),
+            }
+        })
+    }
+
+    /// Discovers available services, skipping those whose circuit is open
     pub async fn discover_services(&self) -> Result<Vec<ServiceInfo>, ProtocolError> {
-        Ok(self.service_registry.get_healthy_services().await)
+        let mut services = self.service_registry.get_healthy_services().await;
+        if let Some(breaker) = &self.circuit_breaker {
+            services.retain(|service| !breaker.is_open(&endpoint_key(&service.address.to_string())));
+        }
+        Ok(services)
+    }
+}
+
+// Circuit breaker key for an address, so a configured address and a discovered
+// service's socket address name the same circuit
+fn endpoint_key(address: &str) -> String {
+    match address.parse::<std::net::SocketAddr>() {
+        Ok(address) => address.to_string(),
+        Err(_) => address.to_ascii_lowercase(),
+    }
+}
+
+/// Addresses a client can connect to, in order of preference
+pub trait IntoAddresses {
+    fn into_addresses(self) -> Vec<String>;
+}
+
+impl IntoAddresses for &str {
+    fn into_addresses(self) -> Vec<String> {
+        vec![self.to_string()]
+    }
+}
+
+impl IntoAddresses for String {
+    fn into_addresses(self) -> Vec<String> {
+        vec![self]
+    }
+}
+
+impl IntoAddresses for &String {
+    fn into_addresses(self) -> Vec<String> {
+        vec![self.clone()]
+    }
+}
+
+impl<S: AsRef<str>> IntoAddresses for &[S] {
+    fn into_addresses(self) -> Vec<String> {
+        self.iter().map(|address| address.as_ref().to_string()).collect()
+    }
+}
+
+impl<S: AsRef<str>, const N: usize> IntoAddresses for [S; N] {
+    fn into_addresses(self) -> Vec<String> {
+        self.iter().map(|address| address.as_ref().to_string()).collect()
+    }
+}
+
+impl<S: AsRef<str>> IntoAddresses for Vec<S> {
+    fn into_addresses(self) -> Vec<String> {
+        self.iter().map(|address| address.as_ref().to_string()).collect()
+    }
+}
+
+/// Where and how a [`RemusClient`] connects
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    addresses: Vec<String>,
+    socket_config: SocketConfig,
+    proxy: Option<ProxyConfig>,
+}
+
+impl ClientConfig {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            addresses: vec![address.into()],
+            socket_config: SocketConfig::default(),
+            proxy: None,
+        }
+    }
+
+    /// Creates a config failing over between `addresses`.
+    ///
+    /// Connecting tries the last address that worked first, then the rest in
+    /// order. After the connection drops mid-session, the other addresses are
+    /// tried before the one that dropped.
+    pub fn from_addresses(addresses: impl IntoAddresses) -> Result<Self, ProtocolError> {
+        let addresses = addresses.into_addresses();
+        if addresses.is_empty() {
+            return Err(ProtocolError::InvalidFormat("No addresses to connect to".into()));
+        }
+        Ok(Self {
+            addresses,
+            socket_config: SocketConfig::default(),
+            proxy: None,
+        })
     }
 
-    // Helper method to prepare payload with compression and encryption
-    fn prepare_payload(&self, data: &[u8]) -> Result<Bytes, ProtocolError> {
-        let compressed = compress_if_beneficial(data)?;
-        if let Some(encryptor) = &self.encryptor {
-            encryptor.encrypt(&compressed)
-        } else {
-            Ok(compressed)
+    /// Creates a config failing over between the healthy instances of the service `name`
+    pub async fn for_service(registry: &ServiceRegistry, name: &str) -> Result<Self, ProtocolError> {
+        let addresses: Vec<String> = registry
+            .get_healthy_services()
+            .await
+            .into_iter()
+            .filter(|service| service.name == name)
+            .map(|service| service.address.to_string())
+            .collect();
+        if addresses.is_empty() {
+            return Err(ProtocolError::InvalidFormat(format!("No healthy instances of {}", name)));
         }
+        Self::from_addresses(addresses)
+    }
+
+    /// Adds an address to fail over to after those already configured
+    pub fn with_fallback(mut self, address: impl Into<String>) -> Self {
+        self.addresses.push(address.into());
+        self
+    }
+
+    /// Sets the socket options for the connection
+    pub fn with_socket_config(mut self, socket_config: SocketConfig) -> Self {
+        self.socket_config = socket_config;
+        self
+    }
+
+    /// Tunnels the connection through an outbound proxy
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
     }
 }
 
-/// Example usage in documentation:
-/// ```rust,no_run
-/// use remus::RemusClient;
-/// 
-/// #[tokio::main]
-/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     // Connect to a Remus server
-///     let mut client = RemusClient::connect("localhost:8080")
-///         .await?
-///         .with_encryption(&Encryptor::generate_key());
-/// 
-///     // Send a simple request
-///     let response = client.request("Hello, World!").await?;
-///     println!("Got response: {:?}", response);
-/// 
-///     // Create a stream
-///     let mut stream = client.stream("Start streaming").await?;
-///     while let Some(msg) = stream.next().await {
-///         println!("Got stream message: {:?}", msg);
-///     }
-/// 
-///     // Discover services
-///     let services = client.discover_services().await?;
-///     for service in services {
-///         println!("Found service: {} at {}", service.name, service.address);
-///     }
-/// 
-///     Ok(())
-/// }
-/// ``` 
\ No newline at end of file
+// Everything needed to open the client's connection again
+#[derive(Clone)]
+struct Dialer {
+    config: ClientConfig,
+    encryption: Option<Arc<Encryptor>>,
+    noise: Option<Arc<NoiseConfig>>,
+    // Algorithms offered, most preferred first, and the settings to apply
+    compression: Option<(Vec<Compression>, CompressionConfig)>,
+    stats: Option<Arc<TransportStats>>,
+    // Index of the last address that accepted a connection, shared by every clone
+    preferred: Arc<AtomicUsize>,
+}
+
+impl Dialer {
+    fn new(config: ClientConfig) -> Self {
+        Self {
+            config,
+            encryption: None,
+            noise: None,
+            compression: None,
+            stats: None,
+            preferred: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn current_address(&self) -> &str {
+        &self.config.addresses[self.preferred.load(Ordering::Relaxed)]
+    }
+
+    async fn connect(&self) -> Result<Transport<TcpStream>, ProtocolError> {
+        self.dial(false).await
+    }
+
+    // Tries every address once, starting with the preferred one, or with the one
+    // after it when `failover` is set so the preferred address is tried last
+    async fn dial(&self, failover: bool) -> Result<Transport<TcpStream>, ProtocolError> {
+        let count = self.config.addresses.len();
+        let start = self.preferred.load(Ordering::Relaxed) + failover as usize;
+        let mut last_error = None;
+        for index in (start..start + count).map(|i| i % count) {
+            match self.connect_to(&self.config.addresses[index]).await {
+                Ok(transport) => {
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return Ok(transport);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("config has at least one address"))
+    }
+
+    async fn connect_to(&self, address: &str) -> Result<Transport<TcpStream>, ProtocolError> {
+        let config = &self.config;
+        let stream = match &config.proxy {
+            Some(proxy) => {
+                let stream = proxy.connect(address).await?;
+                config.socket_config.apply(&stream)?;
+                stream
+            }
+            None => config.socket_config.connect(address).await?,
+        };
+        let mut transport = Transport::new(stream);
+        if let Some(stats) = &self.stats {
+            transport = transport.with_stats(stats.clone());
+        }
+        if let Some(config) = &self.noise {
+            let session = noise::initiate(&mut transport, config).await?;
+            transport = transport.with_encryptor(Arc::new(session.into_encryptor()));
+        } else if let Some(encryptor) = &self.encryption {
+            transport = transport.with_encryptor(encryptor.clone());
+        }
+        if let Some((supported, config)) = &self.compression {
+            let negotiated = compression::initiate(&mut transport, supported, config).await?;
+            transport.set_compression(negotiated);
+        }
+        Ok(transport)
+    }
+
+    // Reconnects only happen after a drop, so they fail over
+    fn connector(&self) -> Connector<TcpStream> {
+        let dialer = Arc::new(self.clone());
+        Arc::new(move || {
+            let dialer = dialer.clone();
+            Box::pin(async move { dialer.dial(true).await })
+        })
+    }
+}
+
+/// Per-request settings layered over the client defaults
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    deadline: Option<Duration>,
+    priority: Option<u8>,
+    ttl: Option<Duration>,
+    routing_info: Option<String>,
+    flags: MessageFlags,
+    idempotent: Option<bool>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl RequestOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bounds the whole request, retries included, overriding the client's request timeout
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the message priority
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Sets how long the message stays valid in transit
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the routing info used by the peer to dispatch the message
+    pub fn with_routing_info(mut self, routing_info: impl Into<String>) -> Self {
+        self.routing_info = Some(routing_info.into());
+        self
+    }
+
+    /// Adds flags on top of those the client sets itself
+    pub fn with_flags(mut self, flags: MessageFlags) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// Marks the request IDEMPOTENT or clears the flag. Only IDEMPOTENT requests
+    /// are retried or replayed after a reconnect; requests default to IDEMPOTENT
+    pub fn idempotent(mut self, idempotent: bool) -> Self {
+        self.idempotent = Some(idempotent);
+        self
+    }
+
+    /// Fails the request or ends the stream with `ProtocolError::Cancelled` once
+    /// `token` is cancelled, sending the peer a Cancel frame
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    fn apply(&self, message: &mut Message) {
+        if let Some(priority) = self.priority {
+            message.priority = priority;
+        }
+        if let Some(ttl) = self.ttl {
+            message.ttl = ttl.as_millis().min(u32::MAX as u128) as u32;
+        }
+        if let Some(routing_info) = &self.routing_info {
+            message.routing_info = Some(routing_info.clone());
+        }
+        message.flags |= self.flags;
+        if let Some(idempotent) = self.idempotent {
+            message.flags.set(MessageFlags::IDEMPOTENT, idempotent);
+        }
+    }
+}
+
+fn after_receive(interceptors: &[Arc<dyn Interceptor>], message: &mut Message) -> Result<(), ProtocolError> {
+    for interceptor in interceptors.iter().rev() {
+        interceptor.after_receive(message)?;
+    }
+    Ok(())
+}
+
+// Waits for a correlated response; an Error response from the peer surfaces as
+// `ProtocolError::Remote` carrying its message, or `Overloaded` if the server shed it
+async fn wait_response(response: ResponseFuture, timeout: Duration, interceptors: &[Arc<dyn Interceptor>]) -> Result<Message, ProtocolError> {
+    let response = tokio::time::timeout(timeout, response)
+        .await
+        .map_err(|_| ProtocolError::Timeout("Request timeout".into()))??;
+    if response.msg_type == MessageType::Error {
+        for interceptor in interceptors.iter().rev() {
+            interceptor.on_error_response(&response);
+        }
+        return Err(match ErrorPayload::decode(&response) {
+            Some(error) => error.into_error(),
+            None => ProtocolError::Remote(String::from_utf8_lossy(&response.payload).into_owned()),
+        });
+    }
+    Ok(response)
+}
+
+/// Requests queued on a [`RemusClient`] and sent in one write by [`Batch::send`].
+///
+/// Each queued request returns a future for its response. The futures resolve
+/// only after `send`, and each times out after the client's request timeout.
+/// Batched requests are not retried and do not update the circuit breaker.
+pub struct Batch<'a> {
+    client: &'a RemusClient,
+    messages: Vec<Message>,
+}
+
+impl Batch<'_> {
+    /// Queues a request, returning a future for its response payload
+    pub fn request(
+        &mut self,
+        payload: impl AsRef<[u8]>,
+    ) -> Result<impl Future<Output = Result<Bytes, ProtocolError>> + Send + 'static, ProtocolError> {
+        self.request_with_options(payload, &RequestOptions::default())
+    }
+
+    /// Queues a request with per-request options; deadlines and cancellation do not apply to batches
+    pub fn request_with_options(
+        &mut self,
+        payload: impl AsRef<[u8]>,
+        options: &RequestOptions,
+    ) -> Result<impl Future<Output = Result<Bytes, ProtocolError>> + Send + 'static, ProtocolError> {
+        let mut request = Message::new(
+            MessageType::Request,
+            MessageFlags::IDEMPOTENT,
+            rand::random(),
+            Bytes::copy_from_slice(payload.as_ref()),
+        );
+        options.apply(&mut request);
+        let response = self.queue(request)?;
+        Ok(async move { Ok(response.await?.payload) })
+    }
+
+    /// Queues a typed call to `route`, returning a future for its deserialized response
+    pub fn call<T: Serialize, R: DeserializeOwned>(
+        &mut self,
+        route: &str,
+        request: &T,
+    ) -> Result<impl Future<Output = Result<R, ProtocolError>> + Send + 'static, ProtocolError> {
+        let mut message = Message::request(request)?;
+        message.routing_info = Some(route.to_string());
+        let response = self.queue(message)?;
+        Ok(async move { response.await?.deserialize() })
+    }
+
+    fn queue(
+        &mut self,
+        mut message: Message,
+    ) -> Result<impl Future<Output = Result<Message, ProtocolError>> + Send + 'static, ProtocolError> {
+        message.flags |= self.client.payload_flags();
+        self.client.before_send(&mut message)?;
+        let response = self.client.connection().expect_response(&message)?;
+        self.messages.push(message);
+
+        let timeout = self.client.request_timeout;
+        let interceptors = self.client.interceptors.clone();
+        Ok(async move {
+            let mut response = wait_response(response, timeout, &interceptors).await?;
+            after_receive(&interceptors, &mut response)?;
+            Ok(response)
+        })
+    }
+
+    /// Returns the number of queued requests
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Writes every queued request to the connection and flushes once
+    pub async fn send(self) -> Result<(), ProtocolError> {
+        if self.messages.is_empty() {
+            return Ok(());
+        }
+        self.client.connection().send_all(self.messages).await
+    }
+}
+
+/// Sink sending each chunk pushed into it as a `Stream` frame on one stream id.
+///
+/// Only one frame is in flight at a time: the sink is not ready for the next
+/// chunk until the previous one has been written to the connection, so a slow
+/// connection holds back the producer. Closing the sink sends `StreamEnd`.
+pub struct StreamSink {
+    connection: Connection,
+    template: Message,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    in_flight: Option<BoxFuture<'static, Result<(), ProtocolError>>>,
+    ended: bool,
+}
+
+impl StreamSink {
+    fn new(client: &RemusClient, template: Message) -> Self {
+        Self {
+            connection: client.connection(),
+            template,
+            interceptors: client.interceptors.clone(),
+            in_flight: None,
+            ended: false,
+        }
+    }
+
+    pub fn stream_id(&self) -> u64 {
+        self.template.request_id
+    }
+
+    fn start_frame(&mut self, msg_type: MessageType, payload: Bytes) -> Result<(), ProtocolError> {
+        let mut message = self.template.clone();
+        message.msg_type = msg_type;
+        message.payload = payload;
+        for interceptor in &self.interceptors {
+            interceptor.before_send(&mut message)?;
+        }
+        let connection = self.connection.clone();
+        self.in_flight = Some(Box::pin(async move { connection.send(message).await }));
+        Ok(())
+    }
+
+    fn poll_in_flight(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        if let Some(in_flight) = &mut self.in_flight {
+            let result = ready!(in_flight.as_mut().poll(cx));
+            self.in_flight = None;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Sink<Bytes> for StreamSink {
+    type Error = ProtocolError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        self.get_mut().poll_in_flight(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, chunk: Bytes) -> Result<(), ProtocolError> {
+        let this = self.get_mut();
+        if this.ended {
+            return Err(ProtocolError::InvalidFormat("Stream already ended".into()));
+        }
+        this.start_frame(MessageType::Stream, chunk)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        self.get_mut().poll_in_flight(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        let this = self.get_mut();
+        ready!(this.poll_in_flight(cx))?;
+        if !this.ended {
+            this.ended = true;
+            this.start_frame(MessageType::StreamEnd, Bytes::new())?;
+            ready!(this.poll_in_flight(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// [`StreamSink`] for an upload answered by a single response.
+///
+/// Closing the sink sends `StreamEnd`, and [`UploadSink::finish`] then waits
+/// for the peer's response.
+pub struct UploadSink {
+    sink: StreamSink,
+    timeout: Duration,
+    response: ResponseFuture,
+}
+
+impl UploadSink {
+    pub fn stream_id(&self) -> u64 {
+        self.sink.stream_id()
+    }
+
+    /// Ends the upload and waits for the peer's response, bounded by the client's request timeout
+    pub async fn finish(mut self) -> Result<Bytes, ProtocolError> {
+        futures::SinkExt::close(&mut self.sink).await?;
+        let mut response = wait_response(self.response, self.timeout, &self.sink.interceptors).await?;
+        after_receive(&self.sink.interceptors, &mut response)?;
+        Ok(response.payload)
+    }
+}
+
+impl Sink<Bytes> for UploadSink {
+    type Error = ProtocolError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, chunk: Bytes) -> Result<(), ProtocolError> {
+        Pin::new(&mut self.sink).start_send(chunk)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        Pin::new(&mut self.sink).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        Pin::new(&mut self.sink).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+
+    // Server that drops the first `ignore` requests it receives and echoes the rest
+    async fn spawn_flaky_server(ignore: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            let mut seen = 0;
+            while let Ok(request) = transport.receive().await {
+                seen += 1;
+                if seen > ignore {
+                    let response = Message::new(MessageType::Response, MessageFlags::NONE, request.request_id, request.payload);
+                    transport.send(response).await.unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_typed_call_and_concurrent_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            // Answer pairs of requests in reverse order
+            loop {
+                let Ok(a) = transport.receive().await else { return };
+                let Ok(b) = transport.receive().await else { return };
+                for request in [b, a] {
+                    let n: u32 = request.deserialize().unwrap();
+                    let route = request.routing_info.unwrap();
+                    let response = Message::response(request.request_id, &format!("{}:{}", route, n * 2)).unwrap();
+                    transport.send(response).await.unwrap();
+                }
+            }
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        let (first, second) = tokio::join!(
+            client.call::<u32, String>("double", &1),
+            client.call::<u32, String>("double", &2),
+        );
+        assert_eq!(first.unwrap(), "double:2");
+        assert_eq!(second.unwrap(), "double:4");
+    }
+
+    #[tokio::test]
+    async fn test_batch_resolves_each_response() {
+        let address = spawn_flaky_server(0).await;
+        let client = RemusClient::connect(&address).await.unwrap();
+
+        let mut batch = client.batch();
+        let first = batch.request("one").unwrap();
+        let second = batch.call::<String, String>("echo", &"two".to_string()).unwrap();
+        let third = batch.request("three").unwrap();
+        assert_eq!(batch.len(), 3);
+        batch.send().await.unwrap();
+
+        assert_eq!(first.await.unwrap(), Bytes::from("one"));
+        assert_eq!(second.await.unwrap(), "two");
+        assert_eq!(third.await.unwrap(), Bytes::from("three"));
+    }
+
+    #[tokio::test]
+    async fn test_request_options_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            let request = transport.receive().await.unwrap();
+            let summary = format!(
+                "{}/{}/{}/{}",
+                request.priority,
+                request.ttl,
+                request.routing_info.unwrap(),
+                request.flags.contains(MessageFlags::URGENT)
+            );
+            let response = Message::new(MessageType::Response, MessageFlags::NONE, request.request_id, Bytes::from(summary));
+            transport.send(response).await.unwrap();
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        let options = RequestOptions::new()
+            .with_priority(9)
+            .with_ttl(Duration::from_secs(5))
+            .with_routing_info("orders")
+            .with_flags(MessageFlags::URGENT);
+        let response = client.request_with_options("hi", &options).await.unwrap();
+        assert_eq!(response, Bytes::from("9/5000/orders/true"));
+    }
+
+    #[tokio::test]
+    async fn test_deadline_bounds_retries() {
+        let address = spawn_flaky_server(usize::MAX).await;
+        let policy = RetryPolicy::new(10).with_backoff(Duration::from_millis(5), Duration::from_millis(5));
+        let client = RemusClient::connect(&address).await.unwrap().with_retry_policy(policy);
+
+        let start = std::time::Instant::now();
+        let options = RequestOptions::new().with_deadline(Duration::from_millis(50));
+        assert!(client.request_with_options("hi", &options).await.is_err());
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    struct Tag(&'static str);
+
+    impl Interceptor for Tag {
+        fn before_send(&self, message: &mut Message) -> Result<(), ProtocolError> {
+            let context = message.context.take().unwrap_or_default();
+            message.context = Some(format!("{}{}", context, self.0));
+            Ok(())
+        }
+
+        fn after_receive(&self, message: &mut Message) -> Result<(), ProtocolError> {
+            let mut payload = message.payload.to_vec();
+            payload.extend_from_slice(self.0.as_bytes());
+            message.payload = Bytes::from(payload);
+            Ok(())
+        }
+    }
+
+    struct Deny;
+
+    impl Interceptor for Deny {
+        fn before_send(&self, _message: &mut Message) -> Result<(), ProtocolError> {
+            Err(ProtocolError::AuthenticationRequired)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_credentials_travel_encrypted_ahead_of_requests() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let client = RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_encryption(&Encryptor::generate_key())
+            .with_credentials("s3cret-token");
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let options = RequestOptions::new().with_flags(MessageFlags::REQUIRES_AUTH);
+        let call = tokio::spawn(async move { client.request_with_options("x", &options).await });
+
+        // Read the greeting and the request as they appear on the wire
+        let mut wire = Vec::new();
+        let mut frames = Vec::new();
+        while frames.len() < 2 {
+            let mut chunk = [0u8; 1024];
+            let read = stream.read(&mut chunk).await.unwrap();
+            wire.extend_from_slice(&chunk[..read]);
+            frames.clear();
+            let mut rest = &wire[..];
+            while rest.len() >= 4 {
+                let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+                if rest.len() < 4 + len {
+                    break;
+                }
+                frames.push(Message::decode(&rest[4..4 + len]).unwrap());
+                rest = &rest[4 + len..];
+            }
+        }
+        call.abort();
+
+        assert!(!wire.windows(12).any(|window| window == b"s3cret-token"));
+        assert_eq!((frames[0].msg_type, frames[0].routing_info.as_deref()), (MessageType::Handshake, Some(acl::CREDENTIALS_ROUTE)));
+        assert!(frames[0].flags.contains(MessageFlags::ENCRYPTED));
+        assert_eq!((frames[1].msg_type, frames[1].context.as_deref()), (MessageType::Request, None));
+    }
+
+    #[tokio::test]
+    async fn test_interceptors_run_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(request) = transport.receive().await {
+                let payload = Bytes::from(request.context.unwrap_or_default());
+                let response = Message::new(MessageType::Response, MessageFlags::NONE, request.request_id, payload);
+                transport.send(response).await.unwrap();
+            }
+        });
+
+        let client = RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_interceptor(Arc::new(Tag("a")))
+            .with_interceptor(Arc::new(Tag("b")));
+        // Sent context is "ab", then after_receive appends in reverse order
+        assert_eq!(client.request("x").await.unwrap(), Bytes::from("abba"));
+
+        let client = client.with_interceptor(Arc::new(Deny));
+        assert!(matches!(client.request("x").await, Err(ProtocolError::AuthenticationRequired)));
+    }
+
+    #[tokio::test]
+    async fn test_notify_and_ack() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(message) = transport.receive().await {
+                tx.send(message).await.unwrap();
+            }
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        client.notify("fire").await.unwrap();
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.msg_type, MessageType::Event);
+        assert!(!event.flags.contains(MessageFlags::REQUIRES_ACK));
+
+        let ack = client.notify_with_ack("tracked", &RequestOptions::default()).await.unwrap();
+        ack.await.unwrap();
+        assert!(rx.recv().await.unwrap().flags.contains(MessageFlags::REQUIRES_ACK));
+    }
+
+    #[tokio::test]
+    async fn test_ack_times_out_after_ttl() {
+        // A raw peer that reads but never acknowledges
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        let options = RequestOptions::new().with_ttl(Duration::from_millis(20));
+        let ack = client.notify_with_ack("lost", &options).await.unwrap();
+        assert!(matches!(ack.await, Err(ProtocolError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_replays_in_flight_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            // First connection drops as soon as a request arrives
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            transport.receive().await.unwrap();
+            drop(transport);
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(request) = transport.receive().await {
+                let response = Message::new(MessageType::Response, MessageFlags::NONE, request.request_id, request.payload);
+                transport.send(response).await.unwrap();
+            }
+        });
+
+        let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RemusClient::connect(&address).await.unwrap().with_reconnect(policy);
+        assert_eq!(client.request("replayed").await.unwrap(), Bytes::from("replayed"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_resets_non_idempotent_requests_and_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            // First connection drops once the request and the stream have arrived
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            transport.receive().await.unwrap();
+            transport.receive().await.unwrap();
+            drop(transport);
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while transport.receive().await.is_ok() {}
+        });
+
+        let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RemusClient::connect(&address).await.unwrap().with_reconnect(policy);
+        let options = RequestOptions::new().idempotent(false);
+        let request = client.request_with_options("once", &options);
+        let mut stream = client.stream("feed").await.unwrap();
+
+        assert!(matches!(request.await, Err(ProtocolError::ConnectionReset)));
+        let end = stream.next().await.unwrap().unwrap();
+        assert_eq!((end.msg_type, ErrorPayload::decode(&end).unwrap().code.as_str()), (MessageType::Error, "ConnectionReset"));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_filters_and_survives_reconnect() {
+        fn event(topic: &str, payload: &'static str) -> Message {
+            let mut event = Message::new(MessageType::Event, MessageFlags::NONE, rand::random(), Bytes::from(payload));
+            event.routing_info = Some(topic.to_string());
+            event
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            for payload in ["before", "after"] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut transport = Transport::new(stream);
+                let subscribe = transport.receive().await.unwrap();
+                assert_eq!(subscribe.msg_type, MessageType::Subscribe);
+                assert_eq!(subscribe.routing_info.as_deref(), Some("sensors/*/temp"));
+                let confirm = Message::new(MessageType::Response, MessageFlags::NONE, subscribe.request_id, Bytes::new());
+                transport.send(confirm).await.unwrap();
+                transport.send(event("logs/app", "unrelated")).await.unwrap();
+                transport.send(event("sensors/2/temp", payload)).await.unwrap();
+            }
+            // Keep the second connection open until the client goes away
+            std::future::pending::<()>().await;
+        });
+
+        let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RemusClient::connect(&address).await.unwrap().with_reconnect(policy);
+        let mut events = client.subscribe("sensors/*/temp").await.unwrap();
+
+        for expected in ["before", "after"] {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(event.payload, Bytes::from(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_idempotent_in_flight_fails_with_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut prefix = [0u8; 4];
+            tokio::io::AsyncReadExt::read_exact(&mut stream, &mut prefix).await.unwrap();
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        let ack = client.notify_with_ack("once", &RequestOptions::default()).await.unwrap();
+        assert!(matches!(ack.await, Err(ProtocolError::ConnectionReset)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_upload_sends_frames_then_end() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            // Reply with every frame type seen and the total bytes received
+            let mut summary = String::new();
+            let mut total = 0;
+            loop {
+                let frame = transport.receive().await.unwrap();
+                total += frame.payload.len();
+                summary.push_str(&format!("{:?},", frame.msg_type));
+                if frame.msg_type == MessageType::StreamEnd {
+                    summary.push_str(&total.to_string());
+                    let response = Message::new(MessageType::Response, MessageFlags::NONE, frame.request_id, summary.into());
+                    transport.send(response).await.unwrap();
+                    return;
+                }
+            }
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        let mut upload = client.stream_upload().unwrap();
+        let mut chunks = futures::stream::iter((0..3).map(|_| Ok(Bytes::from(vec![7u8; 1000]))));
+        upload.send_all(&mut chunks).await.unwrap();
+
+        let summary = upload.finish().await.unwrap();
+        assert_eq!(summary, Bytes::from("Stream,Stream,Stream,StreamEnd,3000"));
+    }
+
+    #[tokio::test]
+    async fn test_bidi_interleaves_both_directions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            // Answer each frame as it arrives, ending our side when the client ends its side
+            loop {
+                let frame = transport.receive().await.unwrap();
+                assert_eq!(frame.routing_info.as_deref(), Some("session"));
+                let reply = Message::new(frame.msg_type, MessageFlags::NONE, frame.request_id, frame.payload);
+                transport.send(reply).await.unwrap();
+                if frame.msg_type == MessageType::StreamEnd {
+                    return;
+                }
+            }
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        let (mut sink, mut stream) = client.bidi("session").unwrap();
+        for turn in ["ping", "pong"] {
+            sink.send(Bytes::from(turn)).await.unwrap();
+            let reply = stream.next().await.unwrap().unwrap();
+            assert_eq!(reply.payload, Bytes::from(turn));
+        }
+        sink.close().await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().msg_type, MessageType::StreamEnd);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_sends_cancel_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (seen_tx, mut seen) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(frame) = transport.receive().await {
+                let _ = seen_tx.send((frame.msg_type, frame.request_id));
+            }
+        });
+
+        let client = RemusClient::connect(&address).await.unwrap();
+        let token = CancellationToken::new();
+        let options = RequestOptions::new().with_cancellation(token.clone());
+        let (result, ()) = tokio::join!(client.request_with_options("slow", &options), async {
+            let _ = seen.recv().await;
+            token.cancel();
+        });
+        assert!(matches!(result, Err(ProtocolError::Cancelled)));
+        let (msg_type, _) = seen.recv().await.unwrap();
+        assert_eq!(msg_type, MessageType::Cancel);
+
+        // Dropping a stream before its end cancels it too
+        let stream = client.stream("feed").await.unwrap();
+        let stream_id = stream.stream_id();
+        assert_eq!(seen.recv().await.unwrap(), (MessageType::Stream, stream_id));
+        drop(stream);
+        assert_eq!(seen.recv().await.unwrap(), (MessageType::Cancel, stream_id));
+    }
+
+    #[tokio::test]
+    async fn test_offline_queue_flushes_when_link_returns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let queue = Arc::new(OfflineQueue::new(16));
+        let client = RemusClient::new(ClientConfig::new(address.clone())).with_offline_queue(queue.clone());
+        client.notify("reading 1").await.unwrap();
+        client.notify("reading 2").await.unwrap();
+        assert_eq!(queue.len(), 2);
+        assert!(client.flush_offline_queue().await.is_err());
+        assert_eq!(queue.len(), 2);
+
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let (received_tx, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(event) = transport.receive().await {
+                let _ = received_tx.send(event.payload);
+            }
+        });
+        assert_eq!(client.flush_offline_queue().await.unwrap(), 2);
+        assert_eq!(received.recv().await.unwrap(), Bytes::from("reading 1"));
+        assert_eq!(received.recv().await.unwrap(), Bytes::from("reading 2"));
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_offline_queue_holds_idempotent_requests_until_flushed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let queue = Arc::new(OfflineQueue::new(16));
+        let client = Arc::new(RemusClient::new(ClientConfig::new(address.clone())).with_offline_queue(queue.clone()));
+        let requester = client.clone();
+        let response = tokio::spawn(async move { requester.request("queued").await });
+        let options = RequestOptions::new().idempotent(false);
+        assert!(client.request_with_options("once", &options).await.is_err());
+        while queue.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(queue.len(), 1);
+
+        let listener = TcpListener::bind(&address).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(request) = transport.receive().await {
+                let response = Message::new(MessageType::Response, MessageFlags::NONE, request.request_id, request.payload);
+                transport.send(response).await.unwrap();
+            }
+        });
+        assert_eq!(client.flush_offline_queue().await.unwrap(), 1);
+        assert_eq!(response.await.unwrap().unwrap(), Bytes::from("queued"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_over_to_next_address() {
+        let down = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let up = spawn_flaky_server(0).await;
+
+        let client = RemusClient::connect([down.as_str(), up.as_str()]).await.unwrap();
+        assert_eq!(client.dialer.current_address(), up);
+        assert_eq!(client.request("hello").await.unwrap(), Bytes::from("hello"));
+        assert!(matches!(RemusClient::connect(Vec::<String>::new()).await, Err(ProtocolError::InvalidFormat(_))));
+    }
+
+    #[tokio::test]
+    async fn test_mid_session_drop_fails_over_to_other_address() {
+        // The first endpoint keeps accepting but drops every connection once a request arrives
+        let flaky = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let flaky_address = flaky.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = flaky.accept().await.unwrap();
+                let mut transport = Transport::new(stream);
+                let _ = transport.receive().await;
+            }
+        });
+        let healthy = spawn_flaky_server(0).await;
+
+        let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RemusClient::connect([flaky_address, healthy.clone()]).await.unwrap().with_reconnect(policy);
+        assert_eq!(client.request("replayed").await.unwrap(), Bytes::from("replayed"));
+        assert_eq!(client.dialer.current_address(), healthy);
+    }
+
+    #[test]
+    fn test_lazy_client_builds_without_runtime() {
+        let client = RemusClient::new(ClientConfig::new("127.0.0.1:1")).with_timeout(Duration::from_secs(1));
+        assert!(!client.has_connection());
+    }
+
+    #[tokio::test]
+    async fn test_lazy_client_dials_on_first_request_and_redials() {
+        // Reserve a port nobody is listening on yet
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let client = RemusClient::new(ClientConfig::new(address.clone()));
+        assert!(matches!(client.ensure_connected().await, Err(ProtocolError::IoError(_))));
+        assert!(matches!(client.request("early").await, Err(ProtocolError::IoError(_))));
+
+        let listener = TcpListener::bind(&address).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Transport::new(stream);
+            while let Ok(request) = transport.receive().await {
+                let response = Message::new(MessageType::Response, MessageFlags::NONE, request.request_id, request.payload);
+                transport.send(response).await.unwrap();
+            }
+        });
+        assert_eq!(client.request("late").await.unwrap(), Bytes::from("late"));
+        client.ensure_connected().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_telemetry_records_requests() {
+        let address = spawn_flaky_server(1).await;
+        let (telemetry, _metrics, mut traces) = Telemetry::new(8, 8);
+        let telemetry = Arc::new(telemetry.with_request_traces(true));
+        let client = RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(50))
+            .with_telemetry(telemetry.clone());
+
+        assert!(matches!(client.request("dropped").await, Err(ProtocolError::Timeout(_))));
+        client.call::<u32, u32>("echo", &7).await.unwrap();
+
+        assert_eq!(telemetry.get_request_count(), 2);
+        assert_eq!(telemetry.error_counts().get("Timeout"), Some(&1));
+        assert_eq!(telemetry.request_latency().count(), 2);
+        let stats = telemetry.transport_stats().snapshot();
+        assert!(stats.bytes_sent > 0 && stats.bytes_received > 0);
+
+        assert_eq!(traces.recv().await.unwrap().name, "remus.request");
+        assert_eq!(traces.recv().await.unwrap().name, "echo");
+    }
+
+    #[tokio::test]
+    async fn test_request_retries_idempotent_timeouts() {
+        let address = spawn_flaky_server(2).await;
+        let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(50))
+            .with_retry_policy(policy);
+
+        assert!(client.request("hello").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_non_idempotent_request_is_not_retried() {
+        let address = spawn_flaky_server(1).await;
+        let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(50))
+            .with_retry_policy(policy);
+
+        let options = RequestOptions::new().idempotent(false);
+        assert!(matches!(client.request_with_options("once", &options).await, Err(ProtocolError::Timeout(_))));
+        assert!(client.request("twice").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_reports_attempts_when_exhausted() {
+        let address = spawn_flaky_server(usize::MAX).await;
+        let policy = RetryPolicy::new(2).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(20))
+            .with_retry_policy(policy);
+
+        match client.request("hello").await {
+            Err(ProtocolError::RetriesExhausted { attempts, last }) => {
+                assert_eq!(attempts, 2);
+                assert!(matches!(*last, ProtocolError::Timeout(_)));
+            }
+            other => panic!("expected RetriesExhausted, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_fails_fast() {
+        let address = spawn_flaky_server(usize::MAX).await;
+        let breaker = Arc::new(CircuitBreaker::new(0.5, 2));
+        let client = RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(20))
+            .with_circuit_breaker(breaker.clone());
+
+        for _ in 0..2 {
+            assert!(matches!(client.request("hello").await, Err(ProtocolError::Timeout(_))));
+        }
+        assert!(breaker.is_open(&address));
+
+        let start = std::time::Instant::now();
+        assert!(matches!(client.request("hello").await, Err(ProtocolError::CircuitOpen(_))));
+        assert!(start.elapsed() < Duration::from_millis(20));
+    }
+}
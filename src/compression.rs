@@ -1,10 +1,281 @@
-use crate::ProtocolError;
-use bytes::Bytes;
+//! Payload compression.
+//!
+//! [`compress`] and [`decompress`] are plain zstd. Payloads the transport
+//! compresses are tagged instead: [`compress_tagged`] opens them with a byte
+//! naming the [`Compression`] algorithm, so the sender may pick zstd, LZ4
+//! or Snappy for each message and [`decompress_tagged`] undoes any of them.
+//! A varint of the original size follows the byte, so the receiver can
+//! allocate the whole buffer up front, refuse payloads claiming more than it
+//! will hold, and reject payloads that decompress to anything else.
+//!
+//! A [`CompressionConfig`] sets the algorithm and level a transport uses,
+//! the size below which payloads are sent as they are, and [`ContentRule`]s
+//! for content types that call for something else. The content type is
+//! sniffed from the payload's leading bytes by [`sniff_content_type`]. Media
+//! and archives, which are compressed already, are skipped by default, as
+//! are payloads whose sampled byte entropy is close to random.
+//!
+//! Both ends of a connection can settle on an algorithm with [`initiate`]
+//! and [`respond`], which exchange the algorithms each supports in a
+//! Handshake frame: the initiator's most preferred algorithm the responder
+//! also supports wins, at the lower of the two levels, and no compression if
+//! they share none.
+//!
+//! Small payloads that look alike, such as short JSON documents, barely
+//! compress on their own. A zstd [`Dictionary`] trained on samples of them
+//! fixes that: added to the config with
+//! [`with_dictionary`](CompressionConfig::with_dictionary), it compresses
+//! zstd payloads, which are then tagged with its ID. The peer must hold the
+//! same dictionary to decompress them.
+
+use crate::{flags::CapabilityFlags, transport::Transport, Message, MessageFlags, MessageType, ProtocolError};
+use bytes::{BufMut, Bytes};
+use std::collections::HashMap;
+use std::fmt;
 use std::io::prelude::*;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use zstd;
 
+/// zstd level used unless a [`CompressionConfig`] sets another
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Payloads shorter than this are not worth compressing unless a [`CompressionConfig`] says otherwise
+pub const DEFAULT_MIN_SIZE: usize = 32;
+
+/// Sampled entropy, in bits per byte, above which payloads are taken to be incompressible
+pub const DEFAULT_ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// Content types a [`CompressionConfig`] skips unless given a rule of its own for them
+pub const INCOMPRESSIBLE_CONTENT_TYPES: &[&str] = &["image/*", "video/*", "application/gzip", "application/zstd", "application/zip"];
+
+// Bytes read to estimate a payload's entropy, in evenly spaced runs
+const ENTROPY_SAMPLE_RUNS: usize = 16;
+const ENTROPY_RUN_LEN: usize = 64;
+
+// Tags a zstd payload compressed with a dictionary, whose ID follows the size
+const DICTIONARY_TAG: u8 = 3;
+// Opens every dictionary zstd trains
+const DICTIONARY_MAGIC: u32 = 0xEC30A437;
+// Most a size hint is trusted to pre-allocate, so a forged one cannot exhaust memory
+const MAX_PREALLOCATION: usize = 16 * 1024 * 1024;
+// Most an LZ4 block can expand, so an original size claimed beyond it is forged
+const LZ4_MAX_EXPANSION: usize = 255;
+
+/// Algorithm a tagged payload is compressed with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    #[default]
+    Zstd,
+    /// Several times faster than zstd at a lower ratio, for latency-sensitive paths
+    Lz4,
+    /// Faster still, at the lowest ratio, for bulk traffic such as log shipping
+    Snappy,
+}
+
+impl Compression {
+    /// The byte identifying the algorithm at the start of a tagged payload
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+            Compression::Snappy => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            4 => Some(Compression::Snappy),
+            _ => None,
+        }
+    }
+
+    /// Capability string naming the algorithm, e.g. in `ConnectionContext::capabilities`
+    pub fn capability(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "compression/zstd",
+            Compression::Lz4 => "compression/lz4",
+            Compression::Snappy => "compression/snappy",
+        }
+    }
+
+    /// The capability flag advertising support for the algorithm
+    pub fn flag(&self) -> CapabilityFlags {
+        match self {
+            Compression::Zstd => CapabilityFlags::COMPRESSION_ZSTD,
+            Compression::Lz4 => CapabilityFlags::COMPRESSION_LZ4,
+            Compression::Snappy => CapabilityFlags::COMPRESSION_SNAPPY,
+        }
+    }
+
+    /// Compresses `data`, without the identifying byte
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Compression::Zstd => compress_zstd(data, DEFAULT_LEVEL),
+            // The block format prepends the original size
+            Compression::Lz4 => Ok(lz4::block::compress(data, None, true)?),
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(data)
+                .map_err(|e| ProtocolError::CompressionError(e.to_string())),
+        }
+    }
+
+    /// Reverses [`compress`](Self::compress)
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        self.decompress_bounded(data, usize::MAX)
+    }
+
+    // Refuses LZ4 and Snappy data whose own size prefix claims more than `max_size`
+    fn decompress_bounded(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Compression::Zstd => decompress(data),
+            Compression::Lz4 => decompress_lz4(data, max_size),
+            Compression::Snappy => {
+                let corrupt = || ProtocolError::InvalidFormat("Corrupt Snappy payload".into());
+                match snap::raw::decompress_len(data) {
+                    Ok(size) if size <= max_size => snap::raw::Decoder::new().decompress_vec(data).map_err(|_| corrupt()),
+                    _ => Err(corrupt()),
+                }
+            }
+        }
+    }
+}
+
+// Checks the size the block format prepends before the lz4 crate allocates that much
+fn decompress_lz4(data: &[u8], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+    let corrupt = || ProtocolError::InvalidFormat("Corrupt LZ4 payload".into());
+    let (size, block) = data.split_first_chunk::<4>().ok_or_else(corrupt)?;
+    let size = u32::from_le_bytes(*size) as usize;
+    if size > max_size || size > block.len().saturating_mul(LZ4_MAX_EXPANSION) || size > i32::MAX as usize {
+        return Err(corrupt());
+    }
+    Ok(lz4::block::decompress(block, Some(size as i32))?)
+}
+
+/// Compresses `data` with `algorithm`, prefixed by its identifying byte and size
+pub fn compress_tagged(algorithm: Compression, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let mut tagged = vec![algorithm.id()];
+    put_varint(&mut tagged, data.len());
+    tagged.extend_from_slice(&algorithm.compress(data)?);
+    Ok(tagged)
+}
+
+/// Decompresses a payload from [`compress_tagged`], whichever algorithm produced it
+pub fn decompress_tagged(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    decompress_with(data, &[], usize::MAX)
+}
+
+fn decompress_with(data: &[u8], dictionaries: &[Arc<Dictionary>], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+    let truncated = || ProtocolError::InvalidFormat("Truncated compressed payload".into());
+    let (&id, rest) = data
+        .split_first()
+        .ok_or_else(|| ProtocolError::InvalidFormat("Empty compressed payload".into()))?;
+    let (size, compressed) = read_varint(rest).ok_or_else(truncated)?;
+    // Checked before decompressing, so a small payload claiming a huge size cannot exhaust memory
+    if size > max_size {
+        return Err(ProtocolError::InvalidFormat(format!("Compressed payload claims {size} bytes, more than the {max_size} allowed")));
+    }
+    let buf = match id {
+        DICTIONARY_TAG => {
+            if compressed.len() < 4 {
+                return Err(truncated());
+            }
+            let (dictionary_id, compressed) = compressed.split_at(4);
+            let dictionary_id = u32::from_be_bytes(dictionary_id.try_into().unwrap());
+            let dictionary = dictionaries
+                .iter()
+                .find(|dictionary| dictionary.id == dictionary_id)
+                .ok_or_else(|| ProtocolError::InvalidFormat(format!("Unknown compression dictionary {dictionary_id}")))?;
+            read_bounded(zstd::Decoder::with_dictionary(compressed, &dictionary.bytes)?, size)?
+        }
+        id if id == Compression::Zstd.id() => read_bounded(zstd::Decoder::new(compressed)?, size)?,
+        id => Compression::from_id(id)
+            .ok_or_else(|| ProtocolError::InvalidFormat(format!("Unknown compression algorithm {id}")))?
+            .decompress_bounded(compressed, size)?,
+    };
+    if buf.len() != size {
+        return Err(ProtocolError::InvalidFormat(format!("Compressed payload is {} bytes, not the {size} it claims", buf.len())));
+    }
+    Ok(buf)
+}
+
+// Reading one byte past the size hint is enough to tell it was wrong
+fn read_bounded(reader: impl Read, size: usize) -> Result<Vec<u8>, ProtocolError> {
+    let mut buf = Vec::with_capacity(size.min(MAX_PREALLOCATION));
+    reader.take(size as u64 + 1).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(data: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0usize;
+    for (i, &byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as usize).checked_shl(7 * i as u32)?;
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+    None
+}
+
+/// A zstd dictionary, trained on payloads like those it will compress
+#[derive(Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    id: u32,
+    bytes: Vec<u8>,
+}
+
+impl Dictionary {
+    /// Trains a dictionary of at most `max_size` bytes on `samples`.
+    ///
+    /// zstd suggests around a hundred times as many sample bytes as the
+    /// dictionary holds; training fails when there are too few.
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Self, ProtocolError> {
+        Self::from_bytes(zstd::dict::from_samples(samples, max_size)?)
+    }
+
+    /// Loads a dictionary zstd trained, e.g. one saved from [`as_bytes`](Self::as_bytes)
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ProtocolError> {
+        let invalid = || ProtocolError::InvalidFormat("Not a zstd dictionary".into());
+        let header = bytes.get(..8).ok_or_else(invalid)?;
+        if u32::from_le_bytes(header[..4].try_into().unwrap()) != DICTIONARY_MAGIC {
+            return Err(invalid());
+        }
+        let id = u32::from_le_bytes(header[4..].try_into().unwrap());
+        Ok(Self { id, bytes })
+    }
+
+    /// The ID zstd chose when training, tagging the payloads compressed with the dictionary
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary").field("id", &self.id).field("len", &self.bytes.len()).finish()
+    }
+}
+
 pub fn compress(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
-    let mut encoder = zstd::Encoder::new(Vec::new(), 3)?;
+    compress_zstd(data, DEFAULT_LEVEL)
+}
+
+fn compress_zstd(data: &[u8], level: i32) -> Result<Vec<u8>, ProtocolError> {
+    let mut encoder = zstd::Encoder::new(Vec::new(), level)?;
     encoder.write_all(data)?;
     Ok(encoder.finish()?)
 }
@@ -16,6 +287,294 @@ pub fn decompress(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
     Ok(buf)
 }
 
+/// How payloads of one content type are compressed, overriding a [`CompressionConfig`]'s defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentRule {
+    /// Never compress them, e.g. media that is compressed already
+    Skip,
+    /// Compress them with the algorithm at the zstd level
+    Use(Compression, i32),
+}
+
+/// The algorithm and level payloads are compressed with, the dictionaries
+/// to compress and decompress them with, and which payloads to leave alone
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    algorithm: Option<Compression>,
+    level: i32,
+    min_size: usize,
+    entropy_threshold: f64,
+    rules: HashMap<String, ContentRule>,
+    dictionaries: Vec<Arc<Dictionary>>,
+}
+
+impl CompressionConfig {
+    /// Compresses with `algorithm` at [`DEFAULT_LEVEL`]
+    pub fn new(algorithm: Compression) -> Self {
+        Self {
+            algorithm: Some(algorithm),
+            level: DEFAULT_LEVEL,
+            min_size: DEFAULT_MIN_SIZE,
+            entropy_threshold: DEFAULT_ENTROPY_THRESHOLD,
+            rules: INCOMPRESSIBLE_CONTENT_TYPES
+                .iter()
+                .map(|content_type| (content_type.to_string(), ContentRule::Skip))
+                .collect(),
+            dictionaries: Vec::new(),
+        }
+    }
+
+    /// Sends every payload uncompressed, clearing its COMPRESSED flag
+    pub fn disabled() -> Self {
+        Self {
+            algorithm: None,
+            ..Self::new(Compression::default())
+        }
+    }
+
+    /// Sets the zstd level, from 1 (fastest) to 22; LZ4 always uses its fast mode
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level.clamp(1, 22);
+        self
+    }
+
+    /// Sends payloads shorter than `bytes` uncompressed
+    pub fn with_min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Skips payloads whose sampled entropy exceeds `bits` per byte; 8 or more never skips
+    pub fn with_entropy_threshold(mut self, bits: f64) -> Self {
+        self.entropy_threshold = bits;
+        self
+    }
+
+    /// Compresses payloads sniffed as `content_type` as `rule` says. A
+    /// `type/*` content type covers every subtype without a rule of its own.
+    pub fn with_content_rule(mut self, content_type: impl Into<String>, rule: ContentRule) -> Self {
+        self.rules.insert(content_type.into(), rule);
+        self
+    }
+
+    /// Adds a dictionary payloads may be decompressed with. The dictionary
+    /// added last also compresses zstd payloads.
+    pub fn with_dictionary(mut self, dictionary: Arc<Dictionary>) -> Self {
+        self.dictionaries.retain(|known| known.id != dictionary.id);
+        self.dictionaries.push(dictionary);
+        self
+    }
+
+    pub fn algorithm(&self) -> Option<Compression> {
+        self.algorithm
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    // The algorithm and level for `data`, or `None` to send it as it is
+    fn choose(&self, data: &[u8]) -> Option<(Compression, i32)> {
+        if data.len() < self.min_size {
+            return None;
+        }
+        let rule = sniff_content_type(data).and_then(|content_type| {
+            let wildcard = content_type.split_once('/').map(|(kind, _)| format!("{kind}/*"));
+            self.rules.get(content_type).or_else(|| self.rules.get(&wildcard?))
+        });
+        let (algorithm, level) = match rule {
+            Some(ContentRule::Skip) => return None,
+            Some(ContentRule::Use(algorithm, level)) => (*algorithm, *level),
+            None => (self.algorithm?, self.level),
+        };
+        (self.entropy_threshold >= 8.0 || sampled_entropy(data) <= self.entropy_threshold).then_some((algorithm, level))
+    }
+
+    /// Decompresses a tagged payload, using the config's dictionaries if it needs one
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        self.decompress_within(data, usize::MAX)
+    }
+
+    /// Decompresses a tagged payload, refusing one that claims more than `max_size` bytes before decompressing it
+    pub fn decompress_within(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+        decompress_with(data, &self.dictionaries, max_size)
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self::new(Compression::default())
+    }
+}
+
+impl From<Compression> for CompressionConfig {
+    fn from(algorithm: Compression) -> Self {
+        Self::new(algorithm)
+    }
+}
+
+/// Compresses `data` as `config` says, tagged with the algorithm; `None`
+/// when the config leaves it alone or compression would not make it smaller
+pub fn compress_if_beneficial(data: &[u8], config: &CompressionConfig) -> Result<Option<Bytes>, ProtocolError> {
+    let Some((algorithm, level)) = config.choose(data) else {
+        return Ok(None);
+    };
+    let dictionary = config.dictionaries.last().filter(|_| algorithm == Compression::Zstd);
+    let mut tagged = vec![if dictionary.is_some() { DICTIONARY_TAG } else { algorithm.id() }];
+    put_varint(&mut tagged, data.len());
+    match (algorithm, dictionary) {
+        (Compression::Zstd, Some(dictionary)) => {
+            tagged.put_u32(dictionary.id);
+            tagged.extend_from_slice(&zstd::bulk::Compressor::with_dictionary(level, &dictionary.bytes)?.compress(data)?);
+        }
+        (Compression::Zstd, None) => tagged.extend_from_slice(&compress_zstd(data, level)?),
+        (Compression::Lz4 | Compression::Snappy, _) => tagged.extend_from_slice(&algorithm.compress(data)?),
+    }
+    Ok((tagged.len() < data.len()).then(|| Bytes::from(tagged)))
+}
+
+// Shannon entropy, in bits per byte, of evenly spaced runs of `data`
+fn sampled_entropy(data: &[u8]) -> f64 {
+    let mut counts = [0u32; 256];
+    let mut sampled = 0;
+    let mut count = |run: &[u8]| {
+        run.iter().for_each(|&byte| counts[byte as usize] += 1);
+        sampled += run.len();
+    };
+    if data.len() <= ENTROPY_SAMPLE_RUNS * ENTROPY_RUN_LEN {
+        count(data);
+    } else {
+        let stride = (data.len() - ENTROPY_RUN_LEN) / (ENTROPY_SAMPLE_RUNS - 1);
+        (0..ENTROPY_SAMPLE_RUNS).for_each(|run| count(&data[run * stride..run * stride + ENTROPY_RUN_LEN]));
+    }
+    counts
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f64 / sampled as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Identifies well-known formats by their leading bytes, e.g. `image/png`;
+/// text opening with `{` or `[` passes for `application/json`
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF8", "image/gif"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"%PDF", "application/pdf"),
+    ];
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(signature, _)| data.starts_with(signature)) {
+        return Some(content_type);
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        return Some("image/webp");
+    }
+    if data.get(4..8) == Some(b"ftyp") {
+        return Some("video/mp4");
+    }
+    match data.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(b'{' | b'[') => Some("application/json"),
+        _ => None,
+    }
+}
+
+/// Offers the algorithms `supported` lists, most preferred first, at the
+/// level of `config`, and returns `config` with the algorithm and level the
+/// responder chose
+pub async fn initiate<T>(
+    transport: &mut Transport<T>,
+    supported: &[Compression],
+    config: &CompressionConfig,
+) -> Result<CompressionConfig, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut offer = Vec::with_capacity(4 + supported.len());
+    offer.put_i32(config.level);
+    offer.extend(supported.iter().map(Compression::id));
+    send_frame(transport, offer).await?;
+
+    let answer = receive_frame(transport).await?;
+    if answer.len() != 5 {
+        return Err(negotiation_error("Malformed compression answer"));
+    }
+    let level = i32::from_be_bytes(answer[1..].try_into().unwrap());
+    let algorithm = match Compression::from_id(answer[0]) {
+        Some(algorithm) if supported.contains(&algorithm) => Some(algorithm),
+        None if answer[0] == 0 => None,
+        _ => return Err(negotiation_error("Peer chose a compression algorithm that was not offered")),
+    };
+    Ok(CompressionConfig {
+        algorithm,
+        ..config.clone().with_level(level)
+    })
+}
+
+/// Answers the initiator's offer with its most preferred algorithm among
+/// `supported`, at the lower of the two levels, and returns `config` with
+/// that algorithm and level
+pub async fn respond<T>(
+    transport: &mut Transport<T>,
+    supported: &[Compression],
+    config: &CompressionConfig,
+) -> Result<CompressionConfig, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let offer = receive_frame(transport).await?;
+    if offer.len() < 4 {
+        return Err(negotiation_error("Malformed compression offer"));
+    }
+    let (offered_level, offered) = offer.split_at(4);
+    let level = i32::from_be_bytes(offered_level.try_into().unwrap()).min(config.level);
+    let chosen = offered
+        .iter()
+        .filter_map(|&id| Compression::from_id(id))
+        .find(|algorithm| supported.contains(algorithm));
+
+    let mut answer = vec![chosen.map_or(0, |algorithm| algorithm.id())];
+    answer.put_i32(level);
+    send_frame(transport, answer).await?;
+    Ok(CompressionConfig {
+        algorithm: chosen,
+        ..config.clone().with_level(level)
+    })
+}
+
+async fn send_frame<T>(transport: &mut Transport<T>, payload: Vec<u8>) -> Result<(), ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    transport
+        .send(Message::new(MessageType::Handshake, MessageFlags::NONE, 0, Bytes::from(payload)))
+        .await
+}
+
+async fn receive_frame<T>(transport: &mut Transport<T>) -> Result<Bytes, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let message = transport.receive().await?;
+    match message.msg_type {
+        MessageType::Handshake => Ok(message.payload),
+        other => Err(negotiation_error(&format!("Expected a handshake message, got {other:?}"))),
+    }
+}
+
+fn negotiation_error(reason: &str) -> ProtocolError {
+    ProtocolError::HandshakeFailed(reason.into())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -53,14 +612,144 @@ mod tests {
         
         assert_eq!(original, decompressed);
     }
-}
 
-// Helper functions
-pub fn compress_if_beneficial(data: &[u8]) -> Result<Bytes, ProtocolError> {
-    let compressed = compress(data)?;
-    if compressed.len() < data.len() {
-        Ok(Bytes::from(compressed))
-    } else {
-        Ok(Bytes::from(data.to_vec()))
+    #[test]
+    fn test_tagged_payloads_name_their_algorithm() {
+        let original = b"temperature=21.5;".repeat(100);
+        for algorithm in [Compression::Zstd, Compression::Lz4, Compression::Snappy] {
+            let tagged = compress_tagged(algorithm, &original).unwrap();
+            assert_eq!(tagged[0], algorithm.id());
+            assert!(tagged.len() < original.len());
+            assert_eq!(decompress_tagged(&tagged).unwrap(), original);
+        }
+        assert_eq!(Compression::Lz4.decompress(&Compression::Lz4.compress(b"").unwrap()).unwrap(), b"");
+        assert_eq!(Compression::from_id(Compression::Snappy.id()), Some(Compression::Snappy));
+        assert!(decompress_tagged(&[9, 1, 2]).is_err());
+        assert!(decompress_tagged(&[]).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_snappy_decodes_reference_encoding_and_rejects_corruption() {
+        // "abcabcabcabc" as the reference encoder writes it: a literal, then an overlapping copy
+        let encoded = [12, 2 << 2, b'a', b'b', b'c', 1 | (5 << 2), 3];
+        assert_eq!(Compression::Snappy.decompress(&encoded).unwrap(), b"abcabcabcabc");
+
+        assert!(Compression::Snappy.decompress(&[]).is_err());
+        assert!(Compression::Snappy.decompress(&[12, 2 << 2, b'a', b'b', b'c', 1 | (5 << 2), 9]).is_err());
+        assert!(Compression::Snappy.decompress(&[13, 2 << 2, b'a', b'b', b'c', 1 | (5 << 2), 3]).is_err());
+        assert!(Compression::Snappy.decompress_bounded(&encoded, 11).is_err());
+    }
+
+    #[test]
+    fn test_tagged_payloads_carry_their_original_size() {
+        let original = b"temperature=21.5;".repeat(100);
+        for algorithm in [Compression::Zstd, Compression::Lz4, Compression::Snappy] {
+            let tagged = compress_tagged(algorithm, &original).unwrap();
+            assert_eq!(read_varint(&tagged[1..]).unwrap().0, original.len());
+
+            // A hint that disagrees with the payload is rejected rather than trusted
+            let mut lying = vec![algorithm.id()];
+            put_varint(&mut lying, original.len() - 1);
+            lying.extend_from_slice(read_varint(&tagged[1..]).unwrap().1);
+            assert!(decompress_tagged(&lying).is_err());
+        }
+        assert!(decompress_tagged(&[Compression::Zstd.id(), 0x80]).is_err());
+
+        // An LZ4 block claiming far more than it can expand to is rejected before allocating
+        let mut forged = vec![Compression::Lz4.id()];
+        put_varint(&mut forged, 0x7FFF_0000);
+        forged.extend_from_slice(&0x7FFF_0000u32.to_le_bytes());
+        forged.extend_from_slice(&[0x10, b'x']);
+        assert!(decompress_tagged(&forged).is_err());
+        assert!(Compression::Lz4.decompress(&[1, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_negotiation_picks_a_shared_algorithm() {
+        async fn negotiate(
+            initiator: &[Compression],
+            responder: &'static [Compression],
+        ) -> (CompressionConfig, CompressionConfig) {
+            let (a, b) = tokio::io::duplex(1024);
+            let (mut a, mut b) = (Transport::new(a), Transport::new(b));
+            let responding = tokio::spawn(async move { respond(&mut b, responder, &CompressionConfig::default().with_level(9)).await });
+            let initiated = initiate(&mut a, initiator, &CompressionConfig::default().with_level(6)).await.unwrap();
+            (initiated, responding.await.unwrap().unwrap())
+        }
+
+        let (initiated, responded) = negotiate(&[Compression::Lz4, Compression::Zstd], &[Compression::Zstd, Compression::Lz4]).await;
+        assert_eq!(initiated, responded);
+        assert_eq!((initiated.algorithm(), initiated.level()), (Some(Compression::Lz4), 6));
+
+        let (initiated, responded) = negotiate(&[Compression::Lz4], &[Compression::Zstd]).await;
+        assert_eq!((initiated.algorithm(), responded.algorithm()), (None, None));
+
+        let text = b"reading=42;".repeat(50);
+        let compressed = compress_if_beneficial(&text, &CompressionConfig::new(Compression::Lz4)).unwrap().unwrap();
+        assert_eq!(decompress_tagged(&compressed).unwrap(), text);
+        assert!(compress_if_beneficial(&text, &CompressionConfig::disabled()).unwrap().is_none());
+        assert!(compress_if_beneficial(b"x", &CompressionConfig::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_config_applies_min_size_and_content_rules() {
+        let json = br#"{"status":"ok","values":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]}"#;
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&[0; 200]);
+        assert_eq!(sniff_content_type(json), Some("application/json"));
+        assert_eq!(sniff_content_type(&png), Some("image/png"));
+        assert_eq!(sniff_content_type(b"plain"), None);
+
+        let config = CompressionConfig::default()
+            .with_min_size(100)
+            .with_content_rule("image/*", ContentRule::Skip)
+            .with_content_rule("application/json", ContentRule::Use(Compression::Lz4, 1));
+        assert!(compress_if_beneficial(&json[..], &config).unwrap().is_none());
+        assert!(compress_if_beneficial(&png, &config).unwrap().is_none());
+        let config = config.with_min_size(0);
+        assert_eq!(compress_if_beneficial(&json[..], &config).unwrap().unwrap()[0], Compression::Lz4.id());
+        assert!(compress_if_beneficial(&png, &config).unwrap().is_none());
+        assert_eq!(compress_if_beneficial(&[0; 200], &config).unwrap().unwrap()[0], Compression::Zstd.id());
+    }
+
+    #[test]
+    fn test_incompressible_payloads_are_skipped_untried() {
+        use rand::RngCore;
+
+        let mut random = vec![0u8; 64 * 1024];
+        rand::thread_rng().fill_bytes(&mut random);
+        assert!(sampled_entropy(&random) > DEFAULT_ENTROPY_THRESHOLD);
+        assert!(sampled_entropy(&b"Hello ".repeat(10_000)) < 3.0);
+        assert!(CompressionConfig::default().choose(&random).is_none());
+        assert!(CompressionConfig::default().with_entropy_threshold(8.0).choose(&random).is_some());
+
+        // Already compressed formats, whatever their entropy
+        let gzip = [&[0x1f, 0x8b][..], &[0; 500]].concat();
+        assert!(CompressionConfig::default().choose(&gzip).is_none());
+        let config = CompressionConfig::default().with_content_rule("application/gzip", ContentRule::Use(Compression::Zstd, 1));
+        assert_eq!(config.choose(&gzip), Some((Compression::Zstd, 1)));
+    }
+
+    #[test]
+    fn test_dictionary_compresses_small_similar_payloads() {
+        let document = |i: usize| format!(r#"{{"sensor":"greenhouse-{}","temperature":{}.{},"humidity":{}}}"#, i % 40, 15 + i % 12, i % 10, 40 + i % 30);
+        let samples: Vec<String> = (0..2000).map(document).collect();
+        let dictionary = Arc::new(Dictionary::train(&samples, 4096).unwrap());
+        assert_eq!(Dictionary::from_bytes(dictionary.as_bytes().to_vec()).unwrap(), *dictionary);
+
+        let payload = document(12345);
+        let config = CompressionConfig::default().with_dictionary(dictionary.clone());
+        let compressed = compress_if_beneficial(payload.as_bytes(), &config).unwrap().unwrap();
+        assert_eq!(compressed[0], DICTIONARY_TAG);
+        let (size, rest) = read_varint(&compressed[1..]).unwrap();
+        assert_eq!((size, u32::from_be_bytes(rest[..4].try_into().unwrap())), (payload.len(), dictionary.id()));
+        assert!(compressed.len() < payload.len() * 2 / 3, "{} of {} bytes", compressed.len(), payload.len());
+        // Alone the payload does not compress at all
+        assert!(compress_if_beneficial(payload.as_bytes(), &CompressionConfig::default()).unwrap().is_none());
+        assert_eq!(config.decompress(&compressed).unwrap(), payload.as_bytes());
+
+        // Without the dictionary the payload cannot be read
+        assert!(decompress_tagged(&compressed).is_err());
+        assert!(Dictionary::from_bytes(b"plain text".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,674 @@
+use crate::{retry::RetryPolicy, server::ErrorPayload, session::Resumption, transport::Transport, Message, MessageFlags, MessageType, ProtocolError};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+
+/// Outbound messages buffered ahead of the driver task
+const OUTBOUND_CAPACITY: usize = 1024;
+
+type Reply = oneshot::Sender<Result<Message, ProtocolError>>;
+
+struct Waiter {
+    token: u64,
+    reply: Reply,
+    // Kept for replay after a reconnect
+    message: Message,
+    sent: bool,
+}
+
+struct Subscription {
+    pattern: String,
+    tx: mpsc::Sender<Message>,
+}
+
+#[derive(Default)]
+struct Pending {
+    connected: bool,
+    closed: bool,
+    next_token: u64,
+    waiters: HashMap<u64, Waiter>,
+    streams: HashMap<u64, mpsc::Sender<Message>>,
+    // Survive reconnects and are re-sent on every new transport
+    subscriptions: HashMap<u64, Subscription>,
+}
+
+/// Opens a replacement transport after the connection drops
+pub(crate) type Connector<T> = Arc<dyn Fn() -> BoxFuture<'static, Result<Transport<T>, ProtocolError>> + Send + Sync>;
+
+/// How the driver re-establishes a dropped connection
+pub(crate) struct Reconnect<T> {
+    pub connector: Connector<T>,
+    pub policy: RetryPolicy,
+    /// Whether each transport resumes the server's session rather than starting over
+    pub resume: bool,
+}
+
+impl<T> Reconnect<T> {
+    async fn connect(&self) -> Result<Transport<T>, ProtocolError> {
+        let mut attempt = 1;
+        loop {
+            match (self.connector)().await {
+                Ok(transport) => return Ok(transport),
+                Err(e) if attempt >= self.policy.max_attempts() => return Err(e),
+                Err(_) => {
+                    tokio::time::sleep(self.policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+struct Outbound {
+    messages: Vec<Message>,
+    sent: oneshot::Sender<Result<(), ProtocolError>>,
+}
+
+/// Multiplexes concurrent requests over one transport.
+///
+/// A driver task owns the transport, writes outbound messages in order and
+/// routes each inbound message to the waiter or stream registered for its
+/// request id. Inbound messages nobody is waiting for are dropped.
+///
+/// Events are routed to the subscription whose id they carry, or else to every
+/// subscription whose topic pattern matches their routing info.
+///
+/// Dropping a [`ResponseFuture`] or cancelling a stream whose request was
+/// already written sends the peer a Cancel frame for its request id.
+///
+/// When the connection drops, requests already written fail with
+/// `ProtocolError::ConnectionReset` and open streams end with an Error message
+/// carrying it. With reconnect enabled, the driver opens a
+/// new transport instead and replays written IDEMPOTENT requests on it. When it
+/// also resumes the session, requests and streams the server is still
+/// answering carry on over the new transport as if nothing had happened.
+#[derive(Clone)]
+pub(crate) struct Connection {
+    outbound: mpsc::Sender<Outbound>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Connection {
+    /// Spawns the driver task, which first awaits `connect`; messages sent
+    /// meanwhile wait in the queue. It exits when the connection fails or every
+    /// handle has been dropped.
+    ///
+    /// A failed first connect is handled like a dropped connection. With
+    /// `reconnect`, the driver reconnects through it when the transport fails.
+    /// `greeting` is written first on every transport, e.g. to present credentials.
+    pub fn spawn<T>(
+        connect: BoxFuture<'static, Result<Transport<T>, ProtocolError>>,
+        reconnect: Option<Reconnect<T>>,
+        greeting: Vec<Message>,
+    ) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outbound, rx) = mpsc::channel(OUTBOUND_CAPACITY);
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        tokio::spawn(drive(connect, rx, pending.clone(), reconnect, greeting));
+        Self { outbound, pending }
+    }
+
+    /// Returns whether a transport was ever established; until then nothing can have reached the peer
+    pub fn was_connected(&self) -> bool {
+        self.pending.lock().unwrap().connected
+    }
+
+    /// Returns whether the driver has exited; a closed connection accepts no more messages
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    /// Registers interest in the response to `message`, which the caller then sends.
+    ///
+    /// Register before sending so a fast response cannot be missed.
+    pub fn expect_response(&self, message: &Message) -> Result<ResponseFuture, ProtocolError> {
+        let (reply, rx) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        if pending.closed {
+            return Err(ProtocolError::ConnectionClosed);
+        }
+        pending.next_token += 1;
+        let token = pending.next_token;
+        let waiter = Waiter {
+            token,
+            reply,
+            message: message.clone(),
+            sent: false,
+        };
+        pending.waiters.insert(message.request_id, waiter);
+        Ok(ResponseFuture {
+            rx,
+            request_id: message.request_id,
+            token,
+            pending: self.pending.clone(),
+            outbound: self.outbound.clone(),
+        })
+    }
+
+    /// Routes every inbound message for `request_id` to `tx` until a `StreamEnd`
+    pub fn open_stream(&self, request_id: u64, tx: mpsc::Sender<Message>) -> Result<(), ProtocolError> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.closed {
+            return Err(ProtocolError::ConnectionClosed);
+        }
+        pending.streams.insert(request_id, tx);
+        Ok(())
+    }
+
+    /// Stops routing messages for the stream `request_id` and tells the peer to stop sending
+    pub fn cancel_stream(&self, request_id: u64) {
+        let open = self.pending.lock().unwrap().streams.remove(&request_id).is_some();
+        if open {
+            try_send(&self.outbound, cancel_frame(request_id));
+        }
+    }
+
+    /// Routes inbound Events for subscription `id` or matching `pattern` to `tx` until unsubscribed.
+    ///
+    /// The caller sends the Subscribe frame; after a reconnect the driver re-sends it.
+    pub fn subscribe(&self, id: u64, pattern: String, tx: mpsc::Sender<Message>) -> Result<(), ProtocolError> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.closed {
+            return Err(ProtocolError::ConnectionClosed);
+        }
+        pending.subscriptions.insert(id, Subscription { pattern, tx });
+        Ok(())
+    }
+
+    /// Drops subscription `id` and tells the peer with an Unsubscribe frame
+    pub fn unsubscribe(&self, id: u64) {
+        let subscription = self.pending.lock().unwrap().subscriptions.remove(&id);
+        if let Some(subscription) = subscription {
+            let mut unsubscribe = Message::new(MessageType::Unsubscribe, MessageFlags::NONE, id, Bytes::new());
+            unsubscribe.routing_info = Some(subscription.pattern);
+            try_send(&self.outbound, unsubscribe);
+        }
+    }
+
+    /// Sends a message, returning once the driver has written it to the transport
+    pub async fn send(&self, message: Message) -> Result<(), ProtocolError> {
+        self.send_all(vec![message]).await
+    }
+
+    /// Sends several messages back to back and flushes them in one write where possible
+    pub async fn send_all(&self, messages: Vec<Message>) -> Result<(), ProtocolError> {
+        let (sent, done) = oneshot::channel();
+        self.outbound
+            .send(Outbound { messages, sent })
+            .await
+            .map_err(|_| ProtocolError::ConnectionClosed)?;
+        done.await.map_err(|_| ProtocolError::ConnectionClosed)?
+    }
+
+    /// Sends a message and returns a future for its correlated response
+    pub async fn request(&self, message: Message) -> Result<ResponseFuture, ProtocolError> {
+        let response = self.expect_response(&message)?;
+        self.send(message).await?;
+        Ok(response)
+    }
+}
+
+/// Resolves to the response correlated with a request; dropping it stops
+/// waiting and cancels the request at the peer if it was already written
+pub(crate) struct ResponseFuture {
+    rx: oneshot::Receiver<Result<Message, ProtocolError>>,
+    request_id: u64,
+    token: u64,
+    pending: Arc<Mutex<Pending>>,
+    outbound: mpsc::Sender<Outbound>,
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Message, ProtocolError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = ready!(Pin::new(&mut self.rx).poll(cx));
+        Poll::Ready(result.unwrap_or(Err(ProtocolError::ConnectionClosed)))
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        let in_flight = {
+            let mut pending = self.pending.lock().unwrap();
+            // A retry may have re-registered the same request id; leave its waiter alone
+            if pending.waiters.get(&self.request_id).is_some_and(|waiter| waiter.token == self.token) {
+                pending.waiters.remove(&self.request_id).is_some_and(|waiter| waiter.sent)
+            } else {
+                false
+            }
+        };
+        if in_flight {
+            try_send(&self.outbound, cancel_frame(self.request_id));
+        }
+    }
+}
+
+fn cancel_frame(request_id: u64) -> Message {
+    Message::new(MessageType::Cancel, MessageFlags::NONE, request_id, Bytes::new())
+}
+
+// Queues a control frame without waiting; it is dropped if the outbound queue is full
+fn try_send(outbound: &mpsc::Sender<Outbound>, message: Message) {
+    let (sent, _) = oneshot::channel();
+    let _ = outbound.try_send(Outbound {
+        messages: vec![message],
+        sent,
+    });
+}
+
+async fn drive<T>(
+    connect: BoxFuture<'static, Result<Transport<T>, ProtocolError>>,
+    mut outbound: mpsc::Receiver<Outbound>,
+    pending: Arc<Mutex<Pending>>,
+    reconnect: Option<Reconnect<T>>,
+    greeting: Vec<Message>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let connected = match (connect.await, &reconnect) {
+        (Ok(transport), _) => Ok(transport),
+        (Err(_), Some(reconnect)) => reconnect.connect().await,
+        (Err(e), None) => Err(e),
+    };
+    let mut resumption = reconnect.as_ref().filter(|reconnect| reconnect.resume).map(|_| Resumption::default());
+    let error = match connected {
+        Ok(mut transport) => match start(&mut transport, resumption.as_mut(), &greeting).await {
+            Ok(_) => {
+                pending.lock().unwrap().connected = true;
+                drive_transport(transport, &mut outbound, &pending, reconnect.as_ref(), resumption.as_mut(), &greeting).await
+            }
+            Err(e) => e,
+        },
+        Err(e) => e,
+    };
+
+    reset_in_flight(&pending, false, &[]);
+    let waiters = {
+        let mut pending = pending.lock().unwrap();
+        pending.closed = true;
+        pending.subscriptions.clear();
+        std::mem::take(&mut pending.waiters)
+    };
+    for (_, waiter) in waiters {
+        let _ = waiter.reply.send(Err(connection_error(&error)));
+    }
+    outbound.close();
+    while let Ok(Outbound { sent, .. }) = outbound.try_recv() {
+        let _ = sent.send(Err(connection_error(&error)));
+    }
+}
+
+// Runs the connection, reconnecting if configured, and returns the error that finally ended it
+async fn drive_transport<T>(
+    mut transport: Transport<T>,
+    outbound: &mut mpsc::Receiver<Outbound>,
+    pending: &Mutex<Pending>,
+    reconnect: Option<&Reconnect<T>>,
+    mut resumption: Option<&mut Resumption>,
+    greeting: &[Message],
+) -> ProtocolError
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let Some(error) = run(&mut transport, outbound, pending).await else {
+            return ProtocolError::ConnectionClosed;
+        };
+        let Some(reconnect) = reconnect else {
+            return error;
+        };
+        match reconnect.connect().await {
+            Ok(replacement) => {
+                transport = replacement;
+                let replays = match start(&mut transport, resumption.as_deref_mut(), greeting).await {
+                    // The server kept the subscriptions along with the session
+                    Ok(Some(in_progress)) => reset_in_flight(pending, true, &in_progress),
+                    Ok(None) => {
+                        let mut replays = subscribe_frames(pending);
+                        replays.extend(reset_in_flight(pending, true, &[]));
+                        replays
+                    }
+                    Err(e) => return e,
+                };
+                // A failed replay surfaces as a receive error on the next pass
+                if !replays.is_empty() {
+                    let _ = transport.send_all(replays).await;
+                }
+            }
+            Err(e) => return e,
+        }
+    }
+}
+
+// Asks the server for the session back when resuming, then writes the
+// greeting. Returns the ids of the requests the server is still answering
+// if it resumed the session.
+async fn start<T>(transport: &mut Transport<T>, resumption: Option<&mut Resumption>, greeting: &[Message]) -> Result<Option<Vec<u64>>, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let resumed = match resumption {
+        Some(resumption) => resumption.initiate(transport).await?,
+        None => None,
+    };
+    if !greeting.is_empty() {
+        transport.send_all(greeting.to_vec()).await?;
+    }
+    Ok(resumed)
+}
+
+// Drives one transport until it fails, returning the error, or until every
+// connection handle is dropped, returning None
+async fn run<T>(
+    transport: &mut Transport<T>,
+    outbound: &mut mpsc::Receiver<Outbound>,
+    pending: &Mutex<Pending>,
+) -> Option<ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        tokio::select! {
+            command = outbound.recv() => match command {
+                Some(Outbound { mut messages, sent }) => {
+                    // A Cancel must not mark a retry re-registered under the same id as sent
+                    let ids: Vec<u64> = messages
+                        .iter()
+                        .filter(|message| message.msg_type != MessageType::Cancel)
+                        .map(|message| message.request_id)
+                        .collect();
+                    let result = if messages.len() == 1 {
+                        transport.send(messages.pop().unwrap()).await
+                    } else {
+                        transport.send_all(messages).await
+                    };
+                    if result.is_ok() {
+                        mark_sent(pending, &ids);
+                    }
+                    let _ = sent.send(result);
+                }
+                None => return None,
+            },
+            received = transport.receive() => match received {
+                Ok(message) => dispatch(pending, message).await,
+                Err(e) => return Some(e),
+            },
+        }
+    }
+}
+
+fn mark_sent(pending: &Mutex<Pending>, ids: &[u64]) {
+    let mut pending = pending.lock().unwrap();
+    for id in ids {
+        if let Some(waiter) = pending.waiters.get_mut(id) {
+            waiter.sent = true;
+        }
+    }
+}
+
+// Handles requests that were written to a connection that has since dropped.
+// Requests and streams in `in_progress`, which a resumed session is still
+// answering, carry on; other streams end with a ConnectionReset Error. IDEMPOTENT
+// requests are returned for replay when `replay` is set; every other written
+// request fails with ConnectionReset.
+fn reset_in_flight(pending: &Mutex<Pending>, replay: bool, in_progress: &[u64]) -> Vec<Message> {
+    let mut pending = pending.lock().unwrap();
+    let reset = ErrorPayload::from_error(&ProtocolError::ConnectionReset);
+    let broken: Vec<u64> = pending.streams.keys().filter(|id| !in_progress.contains(id)).copied().collect();
+    for id in broken {
+        let tx = pending.streams.remove(&id).unwrap();
+        let mut end = reset.to_message(id);
+        end.flags |= MessageFlags::STREAM_END;
+        // A full channel must not hold the lock, so the Error waits its turn on a task
+        if let Err(mpsc::error::TrySendError::Full(end)) = tx.try_send(end) {
+            tokio::spawn(async move { tx.send(end).await });
+        }
+    }
+
+    let mut replays = Vec::new();
+    let in_flight: Vec<u64> = pending
+        .waiters
+        .iter()
+        .filter(|(id, waiter)| waiter.sent && !in_progress.contains(id))
+        .map(|(id, _)| *id)
+        .collect();
+    for id in in_flight {
+        let idempotent = pending.waiters[&id].message.flags.contains(MessageFlags::IDEMPOTENT);
+        if replay && idempotent {
+            replays.push(pending.waiters[&id].message.clone());
+        } else if let Some(waiter) = pending.waiters.remove(&id) {
+            let _ = waiter.reply.send(Err(ProtocolError::ConnectionReset));
+        }
+    }
+    replays
+}
+
+// Subscribe frames re-establishing every subscription on a new transport
+fn subscribe_frames(pending: &Mutex<Pending>) -> Vec<Message> {
+    let pending = pending.lock().unwrap();
+    pending
+        .subscriptions
+        .iter()
+        .map(|(id, subscription)| {
+            let mut subscribe = Message::new(MessageType::Subscribe, MessageFlags::NONE, *id, Bytes::new());
+            subscribe.routing_info = Some(subscription.pattern.clone());
+            subscribe
+        })
+        .collect()
+}
+
+/// Returns whether `topic` matches `pattern`, comparing `/`-separated segments.
+///
+/// `*` matches any one segment and a trailing `**` matches all remaining segments.
+pub(crate) fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for segment in pattern.split('/') {
+        if segment == "**" {
+            return true;
+        }
+        match topic.next() {
+            Some(part) if segment == "*" || segment == part => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+async fn dispatch(pending: &Mutex<Pending>, message: Message) {
+    let receivers = {
+        let mut pending = pending.lock().unwrap();
+        if let Some(waiter) = pending.waiters.remove(&message.request_id) {
+            let _ = waiter.reply.send(Ok(message));
+            return;
+        }
+        let ends = message.msg_type == MessageType::StreamEnd || message.flags.contains(MessageFlags::STREAM_END);
+        if message.msg_type == MessageType::Event {
+            subscribers(&pending, &message)
+        } else if ends {
+            pending.streams.remove(&message.request_id).into_iter().collect()
+        } else {
+            pending.streams.get(&message.request_id).cloned().into_iter().collect()
+        }
+    };
+    // Waiting here applies each consumer's backpressure to the connection
+    for tx in receivers {
+        let _ = tx.send(message.clone()).await;
+    }
+}
+
+fn subscribers(pending: &Pending, event: &Message) -> Vec<mpsc::Sender<Message>> {
+    if let Some(subscription) = pending.subscriptions.get(&event.request_id) {
+        return vec![subscription.tx.clone()];
+    }
+    let Some(topic) = &event.routing_info else {
+        return Vec::new();
+    };
+    pending
+        .subscriptions
+        .values()
+        .filter(|subscription| topic_matches(&subscription.pattern, topic))
+        .map(|subscription| subscription.tx.clone())
+        .collect()
+}
+
+// ProtocolError is not Clone; give every waiter an equivalent error
+fn connection_error(error: &ProtocolError) -> ProtocolError {
+    match error {
+        ProtocolError::GoAway(reason) => ProtocolError::GoAway(reason.clone()),
+        ProtocolError::Timeout(reason) => ProtocolError::Timeout(reason.clone()),
+        ProtocolError::IoError(e) => std::io::Error::new(e.kind(), e.to_string()).into(),
+        _ => ProtocolError::ConnectionClosed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn spawn<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(transport: Transport<T>) -> Connection {
+        Connection::spawn(Box::pin(async { Ok(transport) }), None, Vec::new())
+    }
+
+    #[tokio::test]
+    async fn test_responses_correlated_out_of_order() {
+        let (client, server) = duplex(64 * 1024);
+        let connection = spawn(Transport::new(client));
+        let mut server = Transport::new(server);
+
+        let first = connection
+            .request(Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::from("one")))
+            .await
+            .unwrap();
+        let second = connection
+            .request(Message::new(MessageType::Request, MessageFlags::NONE, 2, Bytes::from("two")))
+            .await
+            .unwrap();
+
+        let a = server.receive().await.unwrap();
+        let b = server.receive().await.unwrap();
+        for request in [b, a] {
+            server
+                .send(Message::new(MessageType::Response, MessageFlags::NONE, request.request_id, request.payload))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(second.await.unwrap().payload, Bytes::from("two"));
+        assert_eq!(first.await.unwrap().payload, Bytes::from("one"));
+    }
+
+    #[tokio::test]
+    async fn test_pending_fail_when_connection_drops() {
+        let (client, server) = duplex(1024);
+        let connection = spawn(Transport::new(client));
+
+        let response = connection
+            .request(Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::new()))
+            .await
+            .unwrap();
+        drop(server);
+
+        assert!(matches!(response.await, Err(ProtocolError::ConnectionReset)));
+        // The driver marks the connection closed before failing waiters
+        let next = Message::new(MessageType::Request, MessageFlags::NONE, 2, Bytes::new());
+        assert!(connection.expect_response(&next).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_response_cancels_only_written_requests() {
+        let (client, server) = duplex(1024);
+        let connection = spawn(Transport::new(client));
+        let mut server = Transport::new(server);
+
+        let written = connection
+            .request(Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::new()))
+            .await
+            .unwrap();
+        let unwritten = connection
+            .expect_response(&Message::new(MessageType::Request, MessageFlags::NONE, 2, Bytes::new()))
+            .unwrap();
+        drop(unwritten);
+        drop(written);
+        assert!(connection.pending.lock().unwrap().waiters.is_empty());
+
+        assert_eq!(server.receive().await.unwrap().msg_type, MessageType::Request);
+        let cancel = server.receive().await.unwrap();
+        assert_eq!((cancel.msg_type, cancel.request_id), (MessageType::Cancel, 1));
+    }
+
+    #[test]
+    fn test_topic_patterns() {
+        assert!(topic_matches("devices/1/alerts", "devices/1/alerts"));
+        assert!(topic_matches("devices/*/alerts", "devices/7/alerts"));
+        assert!(!topic_matches("devices/*/alerts", "devices/7/telemetry"));
+        assert!(!topic_matches("devices/*", "devices/7/alerts"));
+        assert!(topic_matches("devices/**", "devices/7/alerts"));
+        assert!(!topic_matches("devices/1/alerts", "devices/1"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_messages_routed_until_end() {
+        let (client, server) = duplex(1024);
+        let connection = spawn(Transport::new(client));
+        let mut server = Transport::new(server);
+
+        let (tx, mut rx) = mpsc::channel(8);
+        connection.open_stream(5, tx).unwrap();
+        for msg_type in [MessageType::Stream, MessageType::StreamEnd, MessageType::Stream] {
+            server.send(Message::new(msg_type, MessageFlags::NONE, 5, Bytes::new())).await.unwrap();
+        }
+
+        assert_eq!(rx.recv().await.unwrap().msg_type, MessageType::Stream);
+        assert_eq!(rx.recv().await.unwrap().msg_type, MessageType::StreamEnd);
+        // The stream was unregistered at its end, closing the channel
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_replays_idempotent_requests() {
+        let (first_client, first_server) = duplex(1024);
+        let (second_client, second_server) = duplex(1024);
+        let spare = Arc::new(Mutex::new(Some(Transport::new(second_client))));
+        let reconnect = Reconnect {
+            connector: Arc::new(move || {
+                let transport = spare.lock().unwrap().take();
+                Box::pin(async move { transport.ok_or(ProtocolError::ConnectionClosed) })
+            }),
+            policy: RetryPolicy::new(1),
+            resume: false,
+        };
+        let first = Transport::new(first_client);
+        let connection = Connection::spawn(Box::pin(async { Ok(first) }), Some(reconnect), Vec::new());
+
+        let idempotent = connection
+            .request(Message::new(MessageType::Request, MessageFlags::IDEMPOTENT, 1, Bytes::from("again")))
+            .await
+            .unwrap();
+        let once = connection
+            .request(Message::new(MessageType::Request, MessageFlags::NONE, 2, Bytes::from("once")))
+            .await
+            .unwrap();
+        drop(first_server);
+
+        assert!(matches!(once.await, Err(ProtocolError::ConnectionReset)));
+
+        let mut server = Transport::new(second_server);
+        let replayed = server.receive().await.unwrap();
+        assert_eq!(replayed.request_id, 1);
+        server
+            .send(Message::new(MessageType::Response, MessageFlags::NONE, 1, Bytes::from("done")))
+            .await
+            .unwrap();
+        assert_eq!(idempotent.await.unwrap().payload, Bytes::from("done"));
+    }
+}
\ No newline at end of file
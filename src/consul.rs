@@ -0,0 +1,264 @@
+//! A [`DiscoveryBackend`] storing services in Consul.
+//!
+//! Services are registered with the local Consul agent's HTTP API and listed
+//! from the catalog with their health checks, so every node in the Consul
+//! cluster sees them. [`ConsulBackend::watch`] uses blocking queries, waking as
+//! soon as the catalog changes.
+
+use crate::{
+    discovery::{DiscoveryBackend, HealthStatus, ServiceInfo},
+    http::{self, Connector},
+    server::Io,
+    ProtocolError,
+};
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+/// Address of a Consul agent on the same host, as Consul installs it
+pub const DEFAULT_AGENT_ADDRESS: &str = "127.0.0.1:8500";
+
+// How long a blocking query waits for the catalog to change
+const BLOCKING_WAIT: &str = "60s";
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// Keys carrying the ServiceInfo fields Consul has no place for
+const VERSION_META: &str = "remus_version";
+const PUBLIC_KEY_META: &str = "remus_public_key";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    node: Node,
+    service: AgentService,
+    #[serde(default)]
+    checks: Vec<Check>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentService {
+    #[serde(rename = "ID")]
+    id: String,
+    service: String,
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    meta: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    status: String,
+}
+
+/// Registers and lists services through a Consul agent
+pub struct ConsulBackend {
+    address: String,
+    token: Option<String>,
+    connector: Connector,
+}
+
+impl ConsulBackend {
+    /// Talks to the agent at `address`, e.g. [`DEFAULT_AGENT_ADDRESS`]
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            token: None,
+            connector: http::tcp_connector(),
+        }
+    }
+
+    /// Sends an ACL token with every request
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Opens connections to the agent with `connect`, e.g. to speak TLS; it is given the agent's `host:port`
+    pub fn with_connector<F, Fut>(mut self, connect: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Box<dyn Io>, ProtocolError>> + Send + 'static,
+    {
+        self.connector = http::connector(connect);
+        self
+    }
+
+    async fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<http::Response, ProtocolError> {
+        let headers: Vec<(&str, &str)> = self.token.iter().map(|token| ("X-Consul-Token", token.as_str())).collect();
+        let response = http::request(&self.connector, &self.address, method, path, &headers, body).await?;
+        if !response.is_success() {
+            return Err(ProtocolError::DiscoveryError(format!(
+                "Consul answered {} to {method} {path}: {}",
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            )));
+        }
+        Ok(response)
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<(T, Option<u64>), ProtocolError> {
+        let response = self.request("GET", path, None).await?;
+        let index = response.header("X-Consul-Index").and_then(|index| index.parse().ok());
+        let value = serde_json::from_slice(&response.body).map_err(|e| ProtocolError::DiscoveryError(format!("Unexpected Consul response: {e}")))?;
+        Ok((value, index))
+    }
+
+    async fn list_services(&self) -> Result<Vec<ServiceInfo>, ProtocolError> {
+        let (names, _): (HashMap<String, serde_json::Value>, _) = self.get("/v1/catalog/services").await?;
+        let mut services = Vec::new();
+        // Consul lists its own servers as the service "consul"
+        for name in names.keys().filter(|name| *name != "consul") {
+            let (entries, _): (Vec<HealthEntry>, _) = self.get(&format!("/v1/health/service/{name}")).await?;
+            services.extend(entries.into_iter().filter_map(service_info));
+        }
+        Ok(services)
+    }
+}
+
+impl DiscoveryBackend for ConsulBackend {
+    fn register(&self, service: ServiceInfo) -> BoxFuture<'_, Result<(), ProtocolError>> {
+        Box::pin(async move {
+            let mut meta = service.metadata.clone();
+            meta.insert(VERSION_META.to_string(), service.version.clone());
+            if let Some(key) = &service.public_key {
+                meta.insert(PUBLIC_KEY_META.to_string(), key.to_string());
+            }
+            let registration = serde_json::json!({
+                "ID": service.id,
+                "Name": service.name,
+                "Address": service.address.ip().to_string(),
+                "Port": service.address.port(),
+                "Tags": service.capabilities,
+                "Meta": meta,
+            });
+            self.request("PUT", "/v1/agent/service/register", Some(registration.to_string().as_bytes())).await?;
+            Ok(())
+        })
+    }
+
+    fn deregister<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        Box::pin(async move {
+            self.request("PUT", &format!("/v1/agent/service/deregister/{id}"), None).await?;
+            Ok(())
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        Box::pin(self.list_services())
+    }
+
+    /// Yields the services, then again whenever a blocking query sees the catalog change
+    fn watch(&self) -> BoxStream<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        stream::unfold(None, move |index: Option<u64>| async move {
+            loop {
+                let path = match index {
+                    Some(index) => format!("/v1/catalog/services?index={index}&wait={BLOCKING_WAIT}"),
+                    None => "/v1/catalog/services".to_string(),
+                };
+                let changed = match self.get::<serde_json::Value>(&path).await {
+                    Ok((_, changed)) => changed,
+                    Err(e) => {
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        return Some((Err(e), index));
+                    }
+                };
+                // A blocking query that timed out returns the index it was given
+                if index.is_some() && changed == index {
+                    continue;
+                }
+                return Some((self.list_services().await, changed));
+            }
+        })
+        .boxed()
+    }
+}
+
+fn service_info(entry: HealthEntry) -> Option<ServiceInfo> {
+    let service = entry.service;
+    let host = if service.address.is_empty() { &entry.node.address } else { &service.address };
+    let Ok(ip) = host.parse::<IpAddr>() else {
+        tracing::debug!("Skipping Consul service {} at non-IP address {}", service.id, host);
+        return None;
+    };
+    let mut metadata = service.meta.unwrap_or_default();
+    let version = metadata.remove(VERSION_META).unwrap_or_default();
+    let public_key = metadata.remove(PUBLIC_KEY_META).and_then(|key| key.parse().ok());
+    let health_status = match entry.checks.iter().map(|check| check.status.as_str()).collect::<Vec<_>>() {
+        statuses if statuses.contains(&"critical") => HealthStatus::Unhealthy,
+        statuses if statuses.contains(&"warning") => HealthStatus::Degraded,
+        _ => HealthStatus::Healthy,
+    };
+    Some(ServiceInfo {
+        id: service.id,
+        name: service.service,
+        version,
+        capabilities: service.tags.unwrap_or_default(),
+        address: SocketAddr::new(ip, service.port),
+        metadata,
+        last_seen: SystemTime::now(),
+        health_status,
+        public_key,
+        health_check: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{discovery::tests::create_test_service, flags::CapabilityFlags, http::serve_canned};
+
+    fn service() -> ServiceInfo {
+        create_test_service("sensors-1")
+            .with_version("1.2.0")
+            .with_address("10.0.0.5:7000")
+            .with_capabilities(CapabilityFlags::STREAMING, None::<String>)
+            .with_metadata(&[("zone", "greenhouse")])
+    }
+
+    #[tokio::test]
+    async fn test_services_register_with_the_agent_and_list_from_the_catalog() {
+        let health = r#"[
+            {"Node": {"Address": "10.0.0.9"},
+             "Service": {"ID": "sensors-1", "Service": "sensors", "Address": "", "Port": 7000, "Tags": ["streaming"], "Meta": {"zone": "greenhouse", "remus_version": "1.2.0"}},
+             "Checks": [{"Status": "passing"}, {"Status": "warning"}]}
+        ]"#;
+        let (address, mut requests) = serve_canned(vec![
+            ("PUT /v1/agent/service/register ", 200, String::new()),
+            ("PUT /v1/agent/service/deregister/sensors-1 ", 200, String::new()),
+            ("GET /v1/catalog/services ", 200, r#"{"consul": [], "sensors": ["streaming"]}"#.to_string()),
+            ("GET /v1/health/service/sensors ", 200, health.to_string()),
+        ])
+        .await;
+        let consul = ConsulBackend::new(&address).with_token("secret");
+
+        consul.register(service()).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("X-Consul-Token: secret\r\n"));
+        let body: serde_json::Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!((body["ID"].as_str(), body["Address"].as_str(), body["Port"].as_u64()), (Some("sensors-1"), Some("10.0.0.5"), Some(7000)));
+        assert_eq!(body["Meta"]["remus_version"], "1.2.0");
+
+        let services = consul.list().await.unwrap();
+        assert_eq!(services.len(), 1);
+        let listed = &services[0];
+        assert_eq!((listed.id.as_str(), listed.version.as_str(), listed.address), ("sensors-1", "1.2.0", "10.0.0.9:7000".parse().unwrap()));
+        assert_eq!((listed.health_status, &listed.metadata), (HealthStatus::Degraded, &service().metadata));
+
+        consul.deregister("sensors-1").await.unwrap();
+        assert!(consul.deregister("unknown").await.is_err());
+    }
+}
@@ -1,9 +1,82 @@
-use crate::ProtocolError;
+use crate::{broker::Broker, flags::CapabilityFlags, health::HealthCheck, identity::PublicKey, interceptor::Interceptor, selector::Selector, server::{Handler, Request, ServerHandle}, Message, ProtocolError};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, StreamExt};
+use futures::FutureExt;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs;
 use std::net::SocketAddr;
+use std::ops::Deref;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::{Duration, SystemTime};
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// How often the default [`DiscoveryBackend::watch`] lists the services again
+pub const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Metadata key holding an instance's weight for [`Strategy::Weighted`]; instances without one weigh 1
+pub const WEIGHT_METADATA_KEY: &str = "weight";
+
+/// Route [`RegistryRequest`]s are sent to; mount [`ServiceRegistry::handler`] on it
+pub const DISCOVERY_ROUTE: &str = "remus/discovery";
+
+/// What remote clients ask of a registry served at [`DISCOVERY_ROUTE`].
+///
+/// Each is answered with JSON: `Register` with the lease TTL in
+/// milliseconds, `Renew` and `Deregister` with `null`, `Query` with the
+/// matching services and `Sync` with a [`Synced`]. `Renew` and
+/// `Deregister` only accept services registered through the handler.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RegistryRequest {
+    /// Registers a service under a lease the client must renew
+    Register(Box<ServiceInfo>),
+    Renew(String),
+    Deregister(String),
+    Query(Selector),
+    /// Lists every service, unless the registry is still at `version`
+    Sync { version: Option<u64> },
+}
+
+/// Topic prefix registry changes are published under, as `remus/registry/<service name>`; see [`ServiceRegistry::publish_events`]
+pub const REGISTRY_EVENTS_TOPIC: &str = "remus/registry";
+
+/// A change to a registry, published as the JSON payload of an Event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RegistryEvent {
+    /// A service was registered, or its registration replaced
+    Registered(Box<ServiceInfo>),
+    Deregistered { id: String, name: String },
+    /// A service's lease lapsed
+    Expired { id: String, name: String },
+    HealthChanged { id: String, name: String, health: HealthStatus },
+}
+
+impl RegistryEvent {
+    fn topic(&self) -> String {
+        let name = match self {
+            RegistryEvent::Registered(service) => &service.name,
+            RegistryEvent::Deregistered { name, .. } | RegistryEvent::Expired { name, .. } | RegistryEvent::HealthChanged { name, .. } => name,
+        };
+        format!("{REGISTRY_EVENTS_TOPIC}/{name}")
+    }
+}
+
+/// The registry's reply to [`RegistryRequest::Sync`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Synced {
+    /// Changes whenever the services do
+    pub version: u64,
+    /// None if the registry was still at the version asked about
+    pub services: Option<Vec<ServiceInfo>>,
+}
 
 /// Represents the health status of a service
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -25,44 +98,687 @@ pub struct ServiceInfo {
     pub metadata: HashMap<String, String>,
     pub last_seen: SystemTime,
     pub health_status: HealthStatus,
+    /// Identity key the service signs with, so clients can verify its messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PublicKey>,
+    /// How a [`HealthProber`](crate::health::HealthProber) checks the service, for services reached over HTTP or a command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+}
+
+impl ServiceInfo {
+    /// Advertises `flags` by name alongside the `extensions`, replacing the capabilities
+    pub fn with_capabilities<S: Into<String>>(mut self, flags: CapabilityFlags, extensions: impl IntoIterator<Item = S>) -> Self {
+        self.capabilities = flags.names();
+        self.capabilities.extend(extensions.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_health_check(mut self, check: HealthCheck) -> Self {
+        self.health_check = Some(check);
+        self
+    }
+
+    /// The [`CapabilityFlags`] named among the capabilities
+    pub fn capability_flags(&self) -> CapabilityFlags {
+        self.capabilities.iter().filter_map(|name| CapabilityFlags::from_capability(name)).collect()
+    }
+
+    /// The capabilities not naming a [`CapabilityFlags`]
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.capabilities.iter().map(String::as_str).filter(|name| CapabilityFlags::from_capability(name).is_none())
+    }
+
+    /// Whether the service advertises all of `flags` and `extensions`
+    pub fn supports(&self, flags: CapabilityFlags, extensions: &[&str]) -> bool {
+        self.capability_flags().contains(flags) && extensions.iter().all(|extension| self.capabilities.iter().any(|c| c == extension))
+    }
+}
+
+/// Where service registrations are stored, e.g. Consul or the Kubernetes API.
+///
+/// [`ServiceRegistry`] is the in-process implementation; see
+/// [`ConsulBackend`](crate::consul::ConsulBackend) and
+/// [`KubernetesBackend`](crate::kubernetes::KubernetesBackend) for existing
+/// infrastructure.
+pub trait DiscoveryBackend: Send + Sync {
+    /// Adds `service`, or replaces the registration with its ID
+    fn register(&self, service: ServiceInfo) -> BoxFuture<'_, Result<(), ProtocolError>>;
+
+    fn deregister<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), ProtocolError>>;
+
+    /// Returns every registered service
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ServiceInfo>, ProtocolError>>;
+
+    /// Yields the current services, then the services again each time they change.
+    ///
+    /// The default lists them every [`WATCH_POLL_INTERVAL`], yielding when the
+    /// IDs, addresses or health differ from the last list.
+    fn watch(&self) -> BoxStream<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        stream::unfold((None, false), move |(mut last, mut polled)| async move {
+            loop {
+                if polled {
+                    tokio::time::sleep(WATCH_POLL_INTERVAL).await;
+                }
+                polled = true;
+                let services = match self.list().await {
+                    Ok(services) => services,
+                    Err(e) => return Some((Err(e), (last, polled))),
+                };
+                let current = fingerprint(&services);
+                if last.as_ref() != Some(&current) {
+                    last = Some(current);
+                    return Some((Ok(services), (last, polled)));
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+fn fingerprint(services: &[ServiceInfo]) -> Vec<(String, SocketAddr, HealthStatus)> {
+    let mut fingerprint: Vec<_> = services.iter().map(|service| (service.id.clone(), service.address, service.health_status)).collect();
+    fingerprint.sort_by(|a, b| a.0.cmp(&b.0));
+    fingerprint
+}
+
+/// How [`ServiceRegistry::select`] picks one of a service's healthy instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each instance in turn
+    RoundRobin,
+    Random,
+    /// The instance with the fewest selections still held, taking turns on ties
+    LeastOutstanding,
+    /// At random, in proportion to each instance's [`WEIGHT_METADATA_KEY`]
+    Weighted,
+}
+
+/// Shares a service's traffic between its versions, e.g. for a canary rollout.
+///
+/// Each version pattern gets traffic in proportion to its weight, with
+/// [`ServiceRegistry::select`] picking among that version's instances by its
+/// strategy. Patterns are versions whose segments may be `x` or `*`, and a
+/// pattern with fewer segments matches their prefix, so `2.0.x` and `2.0`
+/// both match `2.0.7`. An instance counts toward the first pattern it
+/// matches; instances matching none get no traffic, unless no pattern
+/// matches any healthy instance, in which case the split is ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficSplit {
+    versions: Vec<(String, u32)>,
+}
+
+impl TrafficSplit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends traffic to versions matching `pattern` in proportion to `weight`, e.g. 5 of every 100
+    pub fn with_version(mut self, pattern: &str, weight: u32) -> Self {
+        self.versions.push((pattern.to_string(), weight));
+        self
+    }
+
+    // Narrows `candidates` to the instances of one version, picked by weight
+    fn route(&self, candidates: Vec<ServiceInfo>) -> Vec<ServiceInfo> {
+        let group = |service: &ServiceInfo| self.versions.iter().position(|(pattern, _)| version_matches(pattern, &service.version));
+        let weights: Vec<u64> = (0..self.versions.len())
+            .map(|i| if candidates.iter().any(|s| group(s) == Some(i)) { self.versions[i].1 as u64 } else { 0 })
+            .collect();
+        let total: u64 = weights.iter().sum();
+        if total == 0 {
+            return candidates;
+        }
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        let chosen = weights.iter().position(|&weight| {
+            let chosen = pick < weight;
+            pick = pick.saturating_sub(weight);
+            chosen
+        });
+        candidates.into_iter().filter(|s| group(s) == chosen).collect()
+    }
+}
+
+fn version_matches(pattern: &str, version: &str) -> bool {
+    let mut segments = version.split('.');
+    pattern.split('.').all(|wanted| segments.next().is_some_and(|segment| matches!(wanted, "x" | "*") || wanted == segment))
+}
+
+/// One service calling another, as recorded in a [`DependencyGraph`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub caller: String,
+    pub callee: String,
+    pub calls: u64,
+    /// Calls answered with an error
+    pub failures: u64,
+    /// Healthy if every instance of the callee is, Degraded if some are,
+    /// Unhealthy if none are and Unknown if it has no instances
+    pub health: HealthStatus,
+}
+
+/// Which services call which, as [`ServiceRegistry::dependency_graph`] found them
+#[derive(Debug, Clone, Default)]
+pub struct DependencyGraph {
+    edges: Vec<Dependency>,
+}
+
+impl DependencyGraph {
+    pub fn edges(&self) -> &[Dependency] {
+        &self.edges
+    }
+
+    /// The services `service` calls
+    pub fn dependencies<'a>(&'a self, service: &'a str) -> impl Iterator<Item = &'a Dependency> {
+        self.edges.iter().filter(move |edge| edge.caller == service)
+    }
+
+    /// The services calling `service`
+    pub fn dependents<'a>(&'a self, service: &'a str) -> impl Iterator<Item = &'a Dependency> {
+        self.edges.iter().filter(move |edge| edge.callee == service)
+    }
+
+    /// Every service calling `service` directly or through others, i.e. those affected when it fails
+    pub fn impacted_by(&self, service: &str) -> BTreeSet<String> {
+        let mut impacted = BTreeSet::new();
+        let mut pending = VecDeque::from([service.to_string()]);
+        while let Some(callee) = pending.pop_front() {
+            for edge in self.dependents(&callee) {
+                if edge.caller != service && impacted.insert(edge.caller.clone()) {
+                    pending.push_back(edge.caller.clone());
+                }
+            }
+        }
+        impacted
+    }
+}
+
+/// Records the calls a client makes into a registry's dependency graph.
+///
+/// The callee is the service named by the route's first segment, as in
+/// `Name/method` routes served by [`service`](crate::service) traits.
+pub struct DependencyTracker {
+    registry: Arc<ServiceRegistry>,
+    caller: String,
+    // Callee of each request awaiting its response, for attributing failures
+    pending: Mutex<HashMap<u64, String>>,
+}
+
+// Requests remembered awaiting a response; past it, unanswered ones are forgotten
+const MAX_PENDING_CALLS: usize = 4096;
+
+impl Interceptor for DependencyTracker {
+    fn before_send(&self, message: &mut Message) -> Result<(), ProtocolError> {
+        let Some(route) = &message.routing_info else { return Ok(()) };
+        let callee = route.split('/').next().unwrap_or(route).to_string();
+        self.registry.record_call(&self.caller, &callee, true);
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING_CALLS {
+            pending.clear();
+        }
+        pending.insert(message.request_id, callee);
+        Ok(())
+    }
+
+    fn after_receive(&self, message: &mut Message) -> Result<(), ProtocolError> {
+        self.pending.lock().unwrap().remove(&message.request_id);
+        Ok(())
+    }
+
+    fn on_error_response(&self, message: &Message) {
+        let callee = self.pending.lock().unwrap().remove(&message.request_id);
+        if let Some(callee) = callee {
+            let mut dependencies = self.registry.dependencies.lock().unwrap();
+            if let Some(stats) = dependencies.get_mut(&(self.caller.clone(), callee)) {
+                stats.failures += 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct CallStats {
+    calls: u64,
+    failures: u64,
+}
+
+/// An instance picked by [`ServiceRegistry::select`].
+///
+/// It counts as an outstanding request on the instance until dropped, so
+/// hold it for as long as the request runs.
+#[derive(Debug)]
+pub struct Selection {
+    service: ServiceInfo,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl Selection {
+    pub fn into_service(self) -> ServiceInfo {
+        self.service.clone()
+    }
+}
+
+impl Deref for Selection {
+    type Target = ServiceInfo;
+
+    fn deref(&self) -> &ServiceInfo {
+        &self.service
+    }
+}
+
+impl Drop for Selection {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A registration's hold on its place in a [`ServiceRegistry`].
+///
+/// The service is removed, waking watchers, once the lease goes its TTL
+/// without being renewed. Dropping the lease does not end it; renew it with
+/// [`keep_alive`](Lease::keep_alive) for as long as the service runs.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    id: String,
+    generation: u64,
+    ttl: Duration,
+    shared: Weak<Shared>,
+}
+
+impl Lease {
+    /// ID of the service the lease keeps registered
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Extends the lease to its TTL from now; fails once it has expired, been revoked or the ID was registered again
+    pub fn renew(&self) -> Result<(), ProtocolError> {
+        let lapsed = || ProtocolError::DiscoveryError(format!("Lease on {:?} is no longer held", self.id));
+        let shared = self.shared.upgrade().ok_or_else(lapsed)?;
+        let mut leases = shared.leases.lock().unwrap();
+        match leases.get_mut(&self.id) {
+            Some(held) if held.generation == self.generation && held.deadline > Instant::now() => {
+                held.deadline = Instant::now() + self.ttl;
+                Ok(())
+            }
+            _ => Err(lapsed()),
+        }
+    }
+
+    /// Renews the lease three times per TTL until it is lost; abort the task to let the lease lapse
+    pub fn keep_alive(&self) -> JoinHandle<()> {
+        let lease = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval((lease.ttl / 3).max(Duration::from_millis(1)));
+            loop {
+                ticker.tick().await;
+                if let Err(e) = lease.renew() {
+                    tracing::debug!("Stopped renewing: {}", e);
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Ends the lease now, removing the service
+    pub async fn revoke(self) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.end_lease(&self.id, self.generation, false).await;
+        }
+    }
+
+    // Whether the lease still holds the service's registration
+    fn is_held(&self) -> bool {
+        let Some(shared) = self.shared.upgrade() else { return false };
+        let leases = shared.leases.lock().unwrap();
+        leases.get(&self.id).is_some_and(|held| held.generation == self.generation && held.deadline > Instant::now())
+    }
+
+    /// Keeps the lease alive until the returned guard deregisters the service
+    pub fn guard(self) -> Registration {
+        let heartbeat = self.keep_alive();
+        Registration {
+            id: self.id.clone(),
+            deregister: Some(Box::new(move || Box::pin(self.revoke()))),
+            heartbeat: Some(heartbeat),
+        }
+    }
+}
+
+/// Keeps a service registered while held, deregistering it and waking watchers once dropped.
+///
+/// Deregistering on drop runs on a spawned task, so it is skipped outside a
+/// Tokio runtime; prefer [`deregister`](Registration::deregister), or
+/// [`deregister_on_shutdown`](Registration::deregister_on_shutdown) to tie it
+/// to the server's shutdown. A process that crashes leaves its registration
+/// to expire, as before.
+pub struct Registration {
+    id: String,
+    deregister: Option<Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>>,
+    // Renews the registration's lease, for registries that lease
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl Registration {
+    /// Registers `service` with `backend` until the guard deregisters it
+    pub async fn register(backend: Arc<dyn DiscoveryBackend>, service: ServiceInfo) -> Result<Self, ProtocolError> {
+        let id = service.id.clone();
+        backend.register(service).await?;
+        let deregistered = id.clone();
+        Ok(Self {
+            id,
+            deregister: Some(Box::new(move || {
+                Box::pin(async move {
+                    if let Err(e) = backend.deregister(&deregistered).await {
+                        tracing::warn!("Failed to deregister {}: {}", deregistered, e);
+                    }
+                })
+            })),
+            heartbeat: None,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Deregisters the service now, waiting until it is done
+    pub async fn deregister(mut self) {
+        if let Some(deregister) = self.take() {
+            deregister().await;
+        }
+    }
+
+    /// Deregisters the service when `server` shuts down
+    pub fn deregister_on_shutdown(self, server: &ServerHandle) {
+        server.on_shutdown(move || self.deregister());
+    }
+
+    fn take(&mut self) -> Option<Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>> {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        self.deregister.take()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(deregister) = self.take() {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(deregister());
+            }
+        }
+    }
+}
+
+// The state leases and their expiry tasks reach without holding the registry
+struct Shared {
+    services: RwLock<HashMap<String, ServiceInfo>>,
+    // The lease each service is registered under
+    leases: Mutex<HashMap<String, Held>>,
+    generations: AtomicU64,
+    // Bumped on every change, waking watchers
+    changes: watch::Sender<u64>,
+    // Selections held per instance ID
+    outstanding: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+    // Where changes are published as Events
+    broker: Mutex<Option<Arc<Broker>>>,
+}
+
+struct Held {
+    generation: u64,
+    deadline: Instant,
+    // Whether maintenance marked the service Unknown for missing renewals
+    overdue: bool,
+}
+
+impl Shared {
+    fn changed(&self) {
+        self.changes.send_modify(|version| *version += 1);
+    }
+
+    // Called with the services locked, so Events go out in the order the changes were made
+    fn emit(&self, event: RegistryEvent) {
+        if let Some(broker) = &*self.broker.lock().unwrap() {
+            broker.publish(&event.topic(), serde_json::to_vec(&event).expect("registry event serializes"));
+        }
+    }
+
+    // Removes the service if it is still under lease `generation` and, when `lapsed`, the lease is overdue; otherwise returns its deadline
+    async fn end_lease(&self, id: &str, generation: u64, lapsed: bool) -> Option<Instant> {
+        let mut services = self.services.write().await;
+        {
+            let mut leases = self.leases.lock().unwrap();
+            match leases.get(id) {
+                Some(held) if held.generation == generation => {
+                    if lapsed && held.deadline > Instant::now() {
+                        return Some(held.deadline);
+                    }
+                    leases.remove(id);
+                }
+                _ => return None,
+            }
+        }
+        if let Some(service) = services.remove(id) {
+            let (id, name) = (service.id, service.name);
+            self.emit(if lapsed { RegistryEvent::Expired { id, name } } else { RegistryEvent::Deregistered { id, name } });
+        }
+        self.outstanding.lock().unwrap().remove(id);
+        self.changed();
+        None
+    }
+
+    // One maintenance pass: expires overdue leases, marks services missing renewals Unknown until renewed, and flushes
+    async fn maintain(&self, ttl: Duration, path: Option<&PathBuf>, flushed: &mut u64) -> Result<(), ProtocolError> {
+        let now = Instant::now();
+        let mut services = self.services.write().await;
+        let mut events = Vec::new();
+        {
+            let mut leases = self.leases.lock().unwrap();
+            let mut outstanding = self.outstanding.lock().unwrap();
+            services.retain(|id, service| {
+                let Some(held) = leases.get_mut(id) else { return true };
+                if held.deadline <= now {
+                    leases.remove(id);
+                    outstanding.remove(id);
+                    events.push(RegistryEvent::Expired { id: id.clone(), name: service.name.clone() });
+                    return false;
+                }
+                // Leases kept alive renew three times per TTL, so this one missed two renewals
+                let late = held.deadline - now < ttl / 3;
+                if late && !held.overdue && service.health_status == HealthStatus::Healthy {
+                    service.health_status = HealthStatus::Unknown;
+                    held.overdue = true;
+                } else if !late && held.overdue {
+                    service.health_status = HealthStatus::Healthy;
+                    held.overdue = false;
+                } else {
+                    return true;
+                }
+                events.push(RegistryEvent::HealthChanged { id: id.clone(), name: service.name.clone(), health: service.health_status });
+                true
+            });
+        }
+        if !events.is_empty() {
+            events.into_iter().for_each(|event| self.emit(event));
+            self.changed();
+        }
+
+        let version = *self.changes.borrow();
+        let Some(path) = path.filter(|_| version != *flushed) else {
+            return Ok(());
+        };
+        let contents = serde_json::to_vec(&services.values().collect::<Vec<_>>()).expect("services serialize");
+        drop(services);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)?;
+        *flushed = version;
+        Ok(())
+    }
+}
+
+/// Stops a registry's maintenance task; see [`ServiceRegistry::start_maintenance`]
+pub struct MaintenanceHandle {
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl MaintenanceHandle {
+    /// Stops the task after a last pass, waiting for it to finish
+    pub async fn shutdown(self) {
+        self.shutdown.cancel();
+        let _ = self.task.await;
+    }
+}
+
+// Waits out a lease, expiring the service unless the lease is renewed or ends first
+async fn expire_when_due(shared: Weak<Shared>, id: String, generation: u64, mut deadline: Instant) {
+    loop {
+        tokio::time::sleep_until(deadline).await;
+        let Some(shared) = shared.upgrade() else { return };
+        match shared.end_lease(&id, generation, true).await {
+            Some(renewed) => deadline = renewed,
+            None => return,
+        }
+    }
 }
 
 /// Registry for service discovery and health monitoring
 pub struct ServiceRegistry {
-    services: RwLock<HashMap<String, ServiceInfo>>,
+    shared: Arc<Shared>,
     ttl: Duration,
+    // Round-robin position per service name
+    cursors: Mutex<HashMap<String, usize>>,
+    // Traffic split between versions per service name
+    splits: Mutex<HashMap<String, TrafficSplit>>,
+    // Calls seen from each caller to each callee
+    dependencies: Mutex<HashMap<(String, String), CallStats>>,
+    // Backing file maintenance flushes the services to
+    path: Option<PathBuf>,
 }
 
 impl ServiceRegistry {
-    /// Creates a new registry with the specified TTL for services
+    /// Creates a new registry whose leases last `ttl`
     pub fn new(ttl: Duration) -> Self {
         Self {
-            services: RwLock::new(HashMap::new()),
+            shared: Arc::new(Shared {
+                services: RwLock::new(HashMap::new()),
+                leases: Mutex::new(HashMap::new()),
+                generations: AtomicU64::new(0),
+                changes: watch::Sender::new(0),
+                outstanding: Mutex::new(HashMap::new()),
+                broker: Mutex::new(None),
+            }),
             ttl,
+            cursors: Mutex::new(HashMap::new()),
+            splits: Mutex::new(HashMap::new()),
+            dependencies: Mutex::new(HashMap::new()),
+            path: None,
         }
     }
 
-    /// Registers a service in the registry
-    pub async fn register(&self, info: ServiceInfo) {
-        let mut services = self.services.write().await;
-        services.insert(info.id.clone(), info);
+    /// Opens a registry persisted at `path`, registering any services saved there under new leases.
+    ///
+    /// The services are saved by [`start_maintenance`](Self::start_maintenance),
+    /// so a restarted node knows its peers before they renew.
+    pub async fn open(path: impl Into<PathBuf>, ttl: Duration) -> Result<Self, ProtocolError> {
+        let path = path.into();
+        let mut registry = Self::new(ttl);
+        match fs::read(&path) {
+            Ok(contents) => {
+                let services: Vec<ServiceInfo> = serde_json::from_slice(&contents)
+                    .map_err(|e| ProtocolError::InvalidFormat(format!("Corrupt registry file {}: {e}", path.display())))?;
+                for service in services {
+                    registry.register(service).await;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        registry.path = Some(path);
+        Ok(registry)
+    }
+
+    /// Spawns a task maintaining the registry every `interval` until shut down.
+    ///
+    /// Each pass expires overdue leases, whose own expiry may have been lost
+    /// with the runtime it ran on, marks healthy services that missed two
+    /// renewals [`HealthStatus::Unknown`] until they renew, and flushes the
+    /// services to the registry's file if it was [`open`](Self::open)ed. A
+    /// pass that fails or panics is logged and the next one runs as usual.
+    pub fn start_maintenance(&self, interval: Duration) -> MaintenanceHandle {
+        let (shared, ttl, path) = (self.shared.clone(), self.ttl, self.path.clone());
+        let shutdown = CancellationToken::new();
+        let stopped = shutdown.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            let mut flushed = 0;
+            loop {
+                let last = tokio::select! {
+                    _ = ticker.tick() => false,
+                    _ = stopped.cancelled() => true,
+                };
+                match AssertUnwindSafe(shared.maintain(ttl, path.as_ref(), &mut flushed)).catch_unwind().await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::warn!("Registry maintenance failed: {}", e),
+                    Err(_) => tracing::error!("Registry maintenance panicked"),
+                }
+                if last {
+                    return;
+                }
+            }
+        });
+        MaintenanceHandle { shutdown, task }
     }
 
-    /// Removes a service from the registry
+    /// Registers a service, or replaces the one with its ID, under a new lease
+    pub async fn register(&self, info: ServiceInfo) -> Lease {
+        let id = info.id.clone();
+        let generation = self.shared.generations.fetch_add(1, Ordering::Relaxed);
+        let deadline = Instant::now() + self.ttl;
+        let mut services = self.shared.services.write().await;
+        self.shared.leases.lock().unwrap().insert(id.clone(), Held { generation, deadline, overdue: false });
+        self.shared.emit(RegistryEvent::Registered(Box::new(info.clone())));
+        services.insert(id.clone(), info);
+        self.shared.changed();
+        drop(services);
+
+        let shared = Arc::downgrade(&self.shared);
+        tokio::spawn(expire_when_due(shared.clone(), id.clone(), generation, deadline));
+        Lease { id, generation, ttl: self.ttl, shared }
+    }
+
+    /// Removes a service from the registry, ending its lease
     pub async fn unregister(&self, id: &str) {
-        let mut services = self.services.write().await;
-        services.remove(id);
+        let mut services = self.shared.services.write().await;
+        self.shared.leases.lock().unwrap().remove(id);
+        if let Some(service) = services.remove(id) {
+            self.shared.outstanding.lock().unwrap().remove(id);
+            self.shared.emit(RegistryEvent::Deregistered { id: service.id, name: service.name });
+            self.shared.changed();
+        }
+    }
+
+    /// Publishes every later change as a [`RegistryEvent`] on `broker`, under [`REGISTRY_EVENTS_TOPIC`]
+    pub fn publish_events(&self, broker: Arc<Broker>) {
+        *self.shared.broker.lock().unwrap() = Some(broker);
     }
 
     /// Retrieves information about a specific service
     pub async fn get_service(&self, id: &str) -> Option<ServiceInfo> {
-        let services = self.services.read().await;
+        let services = self.shared.services.read().await;
         services.get(id).cloned()
     }
 
     /// Queries services based on a filter function
     pub async fn query(&self, filter: impl Fn(&ServiceInfo) -> bool) -> Vec<ServiceInfo> {
-        let services = self.services.read().await;
+        let services = self.shared.services.read().await;
         services
             .values()
             .filter(|s| filter(s))
@@ -70,51 +786,288 @@ impl ServiceRegistry {
             .collect()
     }
 
-    /// Removes expired services based on TTL
-    pub async fn cleanup_expired(&self) {
-        let now = SystemTime::now();
-        let mut services = self.services.write().await;
-        services.retain(|_, info| {
-            info.last_seen
-                .elapsed()
-                .map(|elapsed| elapsed < self.ttl)
-                .unwrap_or(false)
-        });
+    /// Lists the healthy services supporting all of `flags` and `extensions`
+    pub async fn supporting(&self, flags: CapabilityFlags, extensions: &[&str]) -> Vec<ServiceInfo> {
+        self.query(|s| s.health_status == HealthStatus::Healthy && s.supports(flags, extensions)).await
+    }
+
+    /// Lists the services `selector` matches
+    pub async fn matching(&self, selector: &Selector) -> Vec<ServiceInfo> {
+        self.query(|s| selector.matches(s)).await
+    }
+
+    /// Serves the registry to remote clients; mount it at [`DISCOVERY_ROUTE`]
+    pub fn handler(self: &Arc<Self>) -> impl Handler + 'static {
+        let registry = self.clone();
+        // Leases held for remote registrations
+        let leases: Arc<Mutex<HashMap<String, Lease>>> = Arc::default();
+        move |request: Request| {
+            let (registry, leases) = (registry.clone(), leases.clone());
+            async move {
+                let reply = match request.deserialize()? {
+                    RegistryRequest::Register(service) => {
+                        let lease = registry.register(*service).await;
+                        let ttl = lease.ttl().as_millis() as u64;
+                        let mut leases = leases.lock().unwrap();
+                        leases.retain(|_, lease| lease.is_held());
+                        leases.insert(lease.id().to_string(), lease);
+                        serde_json::to_vec(&ttl)
+                    }
+                    RegistryRequest::Renew(id) => {
+                        let lease = leases.lock().unwrap().get(&id).cloned();
+                        let renewed = lease
+                            .ok_or_else(|| ProtocolError::DiscoveryError(format!("{id:?} is not registered")))
+                            .and_then(|lease| lease.renew());
+                        if renewed.is_err() {
+                            leases.lock().unwrap().remove(&id);
+                        }
+                        renewed?;
+                        serde_json::to_vec(&())
+                    }
+                    RegistryRequest::Deregister(id) => {
+                        // Remote clients may only remove what they registered
+                        if leases.lock().unwrap().remove(&id).is_none() {
+                            return Err(ProtocolError::DiscoveryError(format!("{id:?} is not registered")));
+                        }
+                        registry.unregister(&id).await;
+                        serde_json::to_vec(&())
+                    }
+                    RegistryRequest::Query(selector) => serde_json::to_vec(&registry.matching(&selector).await),
+                    RegistryRequest::Sync { version } => {
+                        let current = *registry.shared.changes.borrow();
+                        let services = match version {
+                            Some(version) if version == current => None,
+                            _ => Some(registry.query(|_| true).await),
+                        };
+                        serde_json::to_vec(&Synced { version: current, services })
+                    }
+                };
+                Ok(Bytes::from(reply.expect("registry reply serializes")))
+            }
+        }
     }
 
     /// Helper function to update service health status
     pub async fn update_health(&self, id: &str, status: HealthStatus) -> Result<(), ProtocolError> {
-        let mut services = self.services.write().await;
+        let mut services = self.shared.services.write().await;
         if let Some(service) = services.get_mut(id) {
+            if service.health_status != status {
+                self.shared.emit(RegistryEvent::HealthChanged { id: id.to_string(), name: service.name.clone(), health: status });
+            }
             service.health_status = status;
             service.last_seen = SystemTime::now();
+            // A health report shows the service is alive, renewing its lease
+            if let Some(held) = self.shared.leases.lock().unwrap().get_mut(id) {
+                held.deadline = Instant::now() + self.ttl;
+                held.overdue = false;
+            }
+            self.shared.changed();
             Ok(())
         } else {
             Err(ProtocolError::InvalidFormat("Service not found".into()))
         }
     }
 
+    /// Splits the traffic [`select`](Self::select)ed for service `name` between its versions, replacing any split it had
+    pub fn set_traffic_split(&self, name: &str, split: TrafficSplit) {
+        self.splits.lock().unwrap().insert(name.to_string(), split);
+    }
+
+    /// Goes back to selecting among all versions of service `name`
+    pub fn clear_traffic_split(&self, name: &str) {
+        self.splits.lock().unwrap().remove(name);
+    }
+
+    pub fn traffic_split(&self, name: &str) -> Option<TrafficSplit> {
+        self.splits.lock().unwrap().get(name).cloned()
+    }
+
+    /// Picks one of the healthy instances of service `name` by `strategy`, within the version its [`TrafficSplit`] picks
+    pub async fn select(&self, name: &str, strategy: Strategy) -> Option<Selection> {
+        let mut candidates = self.query(|s| s.name == name && s.health_status == HealthStatus::Healthy).await;
+        let split = self.splits.lock().unwrap().get(name).cloned();
+        if let Some(split) = split {
+            candidates = split.route(candidates);
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        // Sorted so turns go round the instances in a stable order
+        candidates.sort_by(|a, b| a.id.cmp(&b.id));
+        let counters: Vec<Arc<AtomicUsize>> = {
+            let mut outstanding = self.shared.outstanding.lock().unwrap();
+            candidates.iter().map(|s| outstanding.entry(s.id.clone()).or_default().clone()).collect()
+        };
+        let turn = || {
+            let mut cursors = self.cursors.lock().unwrap();
+            let cursor = cursors.entry(name.to_string()).or_default();
+            *cursor = cursor.wrapping_add(1);
+            cursor.wrapping_sub(1)
+        };
+
+        let index = match strategy {
+            Strategy::RoundRobin => turn() % candidates.len(),
+            Strategy::Random => rand::thread_rng().gen_range(0..candidates.len()),
+            Strategy::LeastOutstanding => {
+                let start = turn();
+                (0..candidates.len())
+                    .map(|offset| (start + offset) % candidates.len())
+                    .min_by_key(|&i| counters[i].load(Ordering::Relaxed))
+                    .unwrap()
+            }
+            Strategy::Weighted => {
+                let weights: Vec<u64> = candidates.iter().map(weight).collect();
+                // Saturates so huge weights can't overflow; the pick still lands within the weights
+                let total = weights.iter().fold(0u64, |total, &weight| total.saturating_add(weight));
+                if total == 0 {
+                    return None;
+                }
+                let mut pick = rand::thread_rng().gen_range(0..total);
+                weights
+                    .iter()
+                    .position(|&weight| {
+                        let chosen = pick < weight;
+                        pick = pick.saturating_sub(weight);
+                        chosen
+                    })
+                    .unwrap()
+            }
+        };
+        counters[index].fetch_add(1, Ordering::Relaxed);
+        Some(Selection {
+            service: candidates.swap_remove(index),
+            outstanding: counters[index].clone(),
+        })
+    }
+
+    /// Records a call from service `caller` to service `callee`, e.g. as seen by a server or proxy
+    pub fn record_call(&self, caller: &str, callee: &str, succeeded: bool) {
+        let mut dependencies = self.dependencies.lock().unwrap();
+        let stats = dependencies.entry((caller.to_string(), callee.to_string())).or_default();
+        stats.calls += 1;
+        stats.failures += u64::from(!succeeded);
+    }
+
+    /// Returns an interceptor recording the calls of a client in service `caller`
+    pub fn dependency_tracker(self: &Arc<Self>, caller: &str) -> Arc<DependencyTracker> {
+        Arc::new(DependencyTracker {
+            registry: self.clone(),
+            caller: caller.to_string(),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The calls recorded so far, with each callee's health as it is now
+    pub async fn dependency_graph(&self) -> DependencyGraph {
+        let recorded: Vec<_> = self.dependencies.lock().unwrap().iter().map(|(edge, stats)| (edge.clone(), *stats)).collect();
+        let services = self.query(|_| true).await;
+        let mut edges: Vec<Dependency> = recorded
+            .into_iter()
+            .map(|((caller, callee), stats)| {
+                let instances: Vec<_> = services.iter().filter(|s| s.name == callee).collect();
+                let healthy = instances.iter().filter(|s| s.health_status == HealthStatus::Healthy).count();
+                let health = match (healthy, instances.len()) {
+                    (_, 0) => HealthStatus::Unknown,
+                    (healthy, all) if healthy == all => HealthStatus::Healthy,
+                    (0, _) => HealthStatus::Unhealthy,
+                    _ => HealthStatus::Degraded,
+                };
+                Dependency { caller, callee, calls: stats.calls, failures: stats.failures, health }
+            })
+            .collect();
+        edges.sort_by(|a, b| (&a.caller, &a.callee).cmp(&(&b.caller, &b.callee)));
+        DependencyGraph { edges }
+    }
+
     /// Helper function to get all healthy services
     pub async fn get_healthy_services(&self) -> Vec<ServiceInfo> {
         self.query(|s| s.health_status == HealthStatus::Healthy).await
     }
 }
 
+
+fn weight(service: &ServiceInfo) -> u64 {
+    service.metadata.get(WEIGHT_METADATA_KEY).and_then(|weight| weight.parse().ok()).unwrap_or(1)
+}
+
+impl DiscoveryBackend for ServiceRegistry {
+    fn register(&self, service: ServiceInfo) -> BoxFuture<'_, Result<(), ProtocolError>> {
+        Box::pin(async move {
+            ServiceRegistry::register(self, service).await;
+            Ok(())
+        })
+    }
+
+    fn deregister<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        Box::pin(async move {
+            self.unregister(id).await;
+            Ok(())
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        Box::pin(async move { Ok(self.query(|_| true).await) })
+    }
+
+    /// Yields the services whenever one is registered, removed, expires or changes health
+    fn watch(&self) -> BoxStream<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        let changes = self.shared.changes.subscribe();
+        stream::unfold((changes, true), move |(mut changes, first)| async move {
+            if !first {
+                changes.changed().await.ok()?;
+            }
+            changes.mark_unchanged();
+            Some((Ok(self.query(|_| true).await), (changes, false)))
+        })
+        .boxed()
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use std::net::{IpAddr, Ipv4Addr};
 
-    fn create_test_service(id: &str) -> ServiceInfo {
+    /// A healthy `sensors` 1.0.0 service at 127.0.0.1:7000, shared by the
+    /// tests of every discovery backend; adjust it with the builders below
+    pub(crate) fn create_test_service(id: &str) -> ServiceInfo {
         ServiceInfo {
             id: id.to_string(),
-            name: "test_service".to_string(),
+            name: "sensors".to_string(),
             version: "1.0.0".to_string(),
-            capabilities: vec!["test".to_string()],
-            address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            capabilities: Vec::new(),
+            address: "127.0.0.1:7000".parse().unwrap(),
             metadata: HashMap::new(),
             last_seen: SystemTime::now(),
             health_status: HealthStatus::Healthy,
+            public_key: None,
+            health_check: None,
+        }
+    }
+
+    impl ServiceInfo {
+        pub(crate) fn with_version(mut self, version: &str) -> Self {
+            self.version = version.to_string();
+            self
+        }
+
+        pub(crate) fn with_address(mut self, address: &str) -> Self {
+            self.address = address.parse().unwrap();
+            self
+        }
+
+        pub(crate) fn with_metadata(mut self, metadata: &[(&str, &str)]) -> Self {
+            self.metadata = metadata.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            self
+        }
+
+        pub(crate) fn with_health(mut self, status: HealthStatus) -> Self {
+            self.health_status = status;
+            self
+        }
+
+        pub(crate) fn with_public_key(mut self, key: PublicKey) -> Self {
+            self.public_key = Some(key);
+            self
         }
     }
 
@@ -132,14 +1085,197 @@ mod tests {
 
     #[tokio::test]
     async fn test_service_expiration() {
-        let registry = ServiceRegistry::new(Duration::from_nanos(1));
-        let service = create_test_service("test2");
-        
-        registry.register(service).await;
-        tokio::time::sleep(Duration::from_millis(1)).await;
-        
-        registry.cleanup_expired().await;
-        assert!(registry.get_service("test2").await.is_none());
+        let registry = ServiceRegistry::new(Duration::from_millis(100));
+        let lapsing = registry.register(create_test_service("test2")).await;
+        let kept = registry.register(create_test_service("test4")).await;
+        let heartbeat = kept.keep_alive();
+        let mut changes = DiscoveryBackend::watch(&registry);
+        assert_eq!(changes.next().await.unwrap().unwrap().len(), 2);
+
+        // The unrenewed lease lapses, waking the watcher
+        let services = tokio::time::timeout(Duration::from_secs(1), changes.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(services.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), ["test4"]);
+        assert!(lapsing.renew().is_err());
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(registry.get_service("test4").await.is_some());
+        heartbeat.abort();
+        tokio::time::timeout(Duration::from_secs(1), changes.next()).await.unwrap();
+        assert!(registry.get_service("test4").await.is_none());
+
+        // Registering the ID again supersedes its lease, and revoking ends one at once
+        let stale = registry.register(create_test_service("test5")).await;
+        let current = registry.register(create_test_service("test5")).await;
+        assert!(stale.renew().is_err());
+        stale.revoke().await;
+        assert!(registry.get_service("test5").await.is_some());
+        current.revoke().await;
+        assert!(registry.get_service("test5").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_marks_lapsing_services_and_persists() {
+        let path = std::env::temp_dir().join(format!("remus-registry-{}.json", rand::random::<u64>()));
+        let registry = ServiceRegistry::open(&path, Duration::from_millis(600)).await.unwrap();
+        let kept = registry.register(create_test_service("kept")).await;
+        let _heartbeat = kept.keep_alive();
+        registry.register(create_test_service("lapsing")).await;
+        let maintenance = registry.start_maintenance(Duration::from_millis(20));
+
+        // Past two missed renewals but short of expiry
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(registry.get_service("lapsing").await.unwrap().health_status, HealthStatus::Unknown);
+        assert_eq!(registry.get_service("kept").await.unwrap().health_status, HealthStatus::Healthy);
+        maintenance.shutdown().await;
+
+        let reopened = ServiceRegistry::open(&path, Duration::from_secs(60)).await.unwrap();
+        let mut ids: Vec<String> = reopened.query(|_| true).await.into_iter().map(|s| s.id).collect();
+        ids.sort();
+        assert_eq!(ids, ["kept", "lapsing"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_registrations_deregister_on_drop_and_shutdown() {
+        use crate::server::{Router, Server};
+
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        let mut changes = DiscoveryBackend::watch(&*registry);
+        changes.next().await.unwrap().unwrap();
+
+        let dropped = registry.register(create_test_service("dropped")).await.guard();
+        changes.next().await.unwrap().unwrap();
+        drop(dropped);
+        assert!(changes.next().await.unwrap().unwrap().is_empty());
+
+        let server = Server::new(Router::new());
+        let handle = server.handle();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let serving = tokio::spawn(async move { server.serve(listener).await });
+        let backend: Arc<dyn DiscoveryBackend> = registry.clone();
+        Registration::register(backend, create_test_service("served")).await.unwrap().deregister_on_shutdown(&handle);
+        assert!(registry.get_service("served").await.is_some());
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+        assert!(registry.get_service("served").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_supporting_matches_capability_flags() {
+        let registry = ServiceRegistry::new(Duration::from_secs(60));
+        let edge = create_test_service("edge").with_capabilities(CapabilityFlags::STREAMING | CapabilityFlags::EDGE_COMPUTE, ["wasm/v1"]);
+        assert_eq!(edge.capabilities, ["streaming", "edge_compute", "wasm/v1"]);
+        assert_eq!(edge.extensions().collect::<Vec<_>>(), ["wasm/v1"]);
+        registry.register(edge).await;
+        registry.register(create_test_service("plain").with_capabilities(CapabilityFlags::STREAMING, ["wasm/v1"])).await;
+        let mut down = create_test_service("down").with_capabilities(CapabilityFlags::all(), None::<String>);
+        down.health_status = HealthStatus::Unhealthy;
+        registry.register(down).await;
+
+        let found = registry.supporting(CapabilityFlags::STREAMING | CapabilityFlags::EDGE_COMPUTE, &[]).await;
+        assert_eq!(found.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), ["edge"]);
+        assert_eq!(registry.supporting(CapabilityFlags::STREAMING, &["wasm/v1"]).await.len(), 2);
+        assert!(registry.supporting(CapabilityFlags::empty(), &["wasm/v2"]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_traffic_splits_between_versions() {
+        let registry = ServiceRegistry::new(Duration::from_secs(60));
+        for (id, version) in [("stable-a", "2.0.3"), ("stable-b", "2.0.4"), ("canary", "2.1.0"), ("old", "1.9.0")] {
+            let mut service = create_test_service(id);
+            service.name = "api".to_string();
+            service.version = version.to_string();
+            registry.register(service).await;
+        }
+        let versions = |picks: &[String]| ["2.1.0", "2.0.3", "2.0.4", "1.9.0"].map(|v| picks.iter().filter(|p| *p == v).count());
+        let mut picks = Vec::new();
+        for _ in 0..1000 {
+            picks.push(registry.select("api", Strategy::RoundRobin).await.unwrap().version.clone());
+        }
+        assert!(versions(&picks).iter().all(|&n| n == 250));
+
+        registry.set_traffic_split("api", TrafficSplit::new().with_version("2.1.0", 20).with_version("2.0.x", 80));
+        picks.clear();
+        for _ in 0..1000 {
+            picks.push(registry.select("api", Strategy::RoundRobin).await.unwrap().version.clone());
+        }
+        let [canary, stable_a, stable_b, old] = versions(&picks);
+        assert!((120..280).contains(&canary), "{canary}");
+        assert_eq!((stable_a + stable_b + canary, old), (1000, 0));
+
+        // Adjusted at runtime; a split matching nothing healthy falls back to every instance
+        registry.set_traffic_split("api", TrafficSplit::new().with_version("2.1", 0).with_version("2.0.4", 1));
+        assert_eq!(registry.select("api", Strategy::Random).await.unwrap().id, "stable-b");
+        registry.set_traffic_split("api", TrafficSplit::new().with_version("3.x", 1));
+        assert!(registry.select("api", Strategy::Random).await.is_some());
+        registry.clear_traffic_split("api");
+        assert!(registry.traffic_split("api").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dependency_graph_tracks_calls_and_callee_health() {
+        use crate::{client::RemusClient, server::{Router, Server}};
+
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        for (id, name) in [("thermostat-1", "Thermostat"), ("thermostat-2", "Thermostat"), ("sensors-1", "sensors")] {
+            let mut service = create_test_service(id);
+            service.name = name.to_string();
+            registry.register(service).await;
+        }
+        registry.update_health("thermostat-2", HealthStatus::Unhealthy).await.unwrap();
+        let router = Router::new()
+            .with_route("Thermostat/read", |_request: Request| async { Ok(Bytes::from("21")) })
+            .with_route("Thermostat/fail", |_request: Request| async { Err::<Bytes, _>(ProtocolError::InvalidFormat("broken".into())) });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router);
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = RemusClient::connect(&address).await.unwrap().with_interceptor(registry.dependency_tracker("dashboard"));
+
+        client.call::<_, u32>("Thermostat/read", &()).await.unwrap();
+        assert!(client.call::<_, u32>("Thermostat/fail", &()).await.is_err());
+        registry.record_call("sensors", "dashboard", true);
+
+        let graph = registry.dependency_graph().await;
+        let thermostat = graph.dependencies("dashboard").next().unwrap();
+        assert_eq!((thermostat.callee.as_str(), thermostat.calls, thermostat.failures), ("Thermostat", 2, 1));
+        assert_eq!(thermostat.health, HealthStatus::Degraded);
+        assert_eq!(graph.dependents("dashboard").next().unwrap().health, HealthStatus::Unknown);
+        assert_eq!(graph.impacted_by("Thermostat").into_iter().collect::<Vec<_>>(), ["dashboard", "sensors"]);
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_published_as_events() {
+        use crate::{client::RemusClient, server::{Router, Server}};
+
+        let registry = ServiceRegistry::new(Duration::from_millis(200));
+        let broker = Arc::new(Broker::new());
+        registry.publish_events(broker.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(Router::new()).with_broker(broker.clone());
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = RemusClient::connect(&address).await.unwrap();
+        let mut events = client.subscribe(&format!("{REGISTRY_EVENTS_TOPIC}/**")).await.unwrap();
+        while broker.subscriptions() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        registry.register(create_test_service("test8")).await;
+        registry.update_health("test8", HealthStatus::Degraded).await.unwrap();
+        registry.register(create_test_service("test9")).await.guard().deregister().await;
+        let mut received = Vec::new();
+        for _ in 0..5 {
+            let event = tokio::time::timeout(Duration::from_secs(2), events.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(event.routing_info.as_deref(), Some("remus/registry/sensors"));
+            received.push(serde_json::from_slice::<RegistryEvent>(&event.payload).unwrap());
+        }
+        assert!(matches!(&received[0], RegistryEvent::Registered(service) if service.id == "test8"));
+        assert!(matches!(&received[1], RegistryEvent::HealthChanged { id, health: HealthStatus::Degraded, .. } if id == "test8"));
+        assert!(matches!(&received[2], RegistryEvent::Registered(service) if service.id == "test9"));
+        assert!(matches!(&received[3], RegistryEvent::Deregistered { id, name } if id == "test9" && name == "sensors"));
+        assert!(matches!(&received[4], RegistryEvent::Expired { id, .. } if id == "test8"));
     }
 
     #[tokio::test]
@@ -154,6 +1290,62 @@ mod tests {
         assert_eq!(updated.health_status, HealthStatus::Degraded);
     }
 
+    #[tokio::test]
+    async fn test_registry_watch_yields_on_changes() {
+        let registry = ServiceRegistry::new(Duration::from_secs(60));
+        registry.register(create_test_service("test6")).await;
+        let mut changes = DiscoveryBackend::watch(&registry);
+        assert_eq!(changes.next().await.unwrap().unwrap().len(), 1);
+
+        DiscoveryBackend::register(&registry, create_test_service("test7")).await.unwrap();
+        assert_eq!(changes.next().await.unwrap().unwrap().len(), 2);
+        registry.deregister("test6").await.unwrap();
+        let services = changes.next().await.unwrap().unwrap();
+        assert_eq!(services.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), ["test7"]);
+    }
+
+    #[tokio::test]
+    async fn test_select_strategies() {
+        let registry = ServiceRegistry::new(Duration::from_secs(60));
+        for (id, weight) in [("a", "1"), ("b", "3"), ("c", "0")] {
+            registry.register(create_test_service(id).with_metadata(&[(WEIGHT_METADATA_KEY, weight)])).await;
+        }
+        registry.update_health("c", HealthStatus::Unhealthy).await.unwrap();
+
+        let mut picks = Vec::new();
+        for _ in 0..4 {
+            picks.push(registry.select("sensors", Strategy::RoundRobin).await.unwrap().id.clone());
+        }
+        assert_eq!(picks, ["a", "b", "a", "b"]);
+
+        // Held selections count against their instance
+        let held = registry.select("sensors", Strategy::LeastOutstanding).await.unwrap();
+        let next = registry.select("sensors", Strategy::LeastOutstanding).await.unwrap();
+        assert_ne!(held.id, next.id);
+        drop(next);
+        assert_ne!(registry.select("sensors", Strategy::LeastOutstanding).await.unwrap().id, held.id);
+
+        let mut b = 0;
+        for _ in 0..1000 {
+            b += (registry.select("sensors", Strategy::Weighted).await.unwrap().id == "b") as usize;
+        }
+        assert!((650..850).contains(&b), "b picked {b} times");
+        assert!(registry.select("sensors", Strategy::Random).await.is_some());
+        assert!(registry.select("unknown", Strategy::Random).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_weighted_select_survives_huge_weights() {
+        let registry = ServiceRegistry::new(Duration::from_secs(60));
+        let max = u64::MAX.to_string();
+        for (id, weight) in [("a", max.as_str()), ("b", max.as_str()), ("c", "0")] {
+            registry.register(create_test_service(id).with_metadata(&[(WEIGHT_METADATA_KEY, weight)])).await;
+        }
+        for _ in 0..100 {
+            assert_ne!(registry.select("sensors", Strategy::Weighted).await.unwrap().id, "c");
+        }
+    }
+
     #[tokio::test]
     async fn test_service_query() {
         let registry = ServiceRegistry::new(Duration::from_secs(60));
@@ -164,4 +1356,46 @@ mod tests {
         let services = registry.query(|s| s.version == "1.0.0").await;
         assert_eq!(services.len(), 2);
     }
+
+    #[tokio::test]
+    async fn test_selector_queries_answer_remotely() {
+        use crate::{client::RemusClient, server::{Router, Server}};
+
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        for (id, zone) in [("a", "greenhouse"), ("b", "warehouse")] {
+            registry.register(create_test_service(id).with_metadata(&[("zone", zone)]).with_capabilities(CapabilityFlags::STREAMING, None::<String>)).await;
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(Router::new().with_route(DISCOVERY_ROUTE, registry.handler()));
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = RemusClient::connect(&address).await.unwrap();
+
+        let selector: Selector = "zone=greenhouse,capabilities=streaming".parse().unwrap();
+        assert_eq!(registry.matching(&selector).await.len(), 1);
+        let found = client.discover(&selector).await.unwrap();
+        assert_eq!(found.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), ["a"]);
+        assert!(client.discover(&"zone notin (greenhouse,warehouse)".parse().unwrap()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remote_deregister_only_removes_remote_registrations() {
+        use crate::{client::RemusClient, server::{Router, Server}};
+
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        let _local = registry.register(create_test_service("local")).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(Router::new().with_route(DISCOVERY_ROUTE, registry.handler()));
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = RemusClient::connect(&address).await.unwrap();
+
+        let refused: Result<(), _> = client.call(DISCOVERY_ROUTE, &RegistryRequest::Deregister("local".to_string())).await;
+        assert!(refused.is_err());
+        assert!(registry.get_service("local").await.is_some());
+
+        let _: u64 = client.call(DISCOVERY_ROUTE, &RegistryRequest::Register(Box::new(create_test_service("remote")))).await.unwrap();
+        let _: () = client.call(DISCOVERY_ROUTE, &RegistryRequest::Deregister("remote".to_string())).await.unwrap();
+        assert!(registry.get_service("remote").await.is_none());
+    }
 } 
\ No newline at end of file
@@ -1,7 +1,70 @@
-use crate::ProtocolError;
+//! Functions deployed to a node and run next to its data, enabled with the `edge` feature.
+//!
+//! Functions in the `wasm` runtime are WebAssembly modules compiled and run
+//! by wasmtime. A module exports `memory` and the function named by the
+//! `entry` config key, `run` by default, taking and returning nothing. It
+//! reads its input and writes its output through functions imported from the
+//! `remus` module:
+//!
+//! - `input_len() -> i32` is the input's length in bytes
+//! - `input_read(ptr: i32)` copies the input into memory at `ptr`
+//! - `output_write(ptr: i32, len: i32)` appends `len` bytes at `ptr` to the output
+//! - `state_get(key_ptr: i32, key_len: i32, ptr: i32, len: i32) -> i32` copies
+//!   up to `len` bytes of a key's value in the node's [`StateManager`] to
+//!   `ptr`, returning the value's whole length, or -1 if the key is unset
+//! - `state_append(key_ptr: i32, key_len: i32, ptr: i32, len: i32) -> i64`
+//!   applies `len` bytes at `ptr` as a delta to a key, returning the new state version
+//! - `emit(topic_ptr: i32, topic_len: i32, ptr: i32, len: i32) -> i32`
+//!   publishes an Event through the node's [`Broker`], returning how many
+//!   subscriptions it was queued for
+//! - `log(level: i32, ptr: i32, len: i32)` logs a message at `level`, 0 for
+//!   errors through 4 for traces
+//!
+//! Keys and topics are UTF-8. The state and Event functions trap unless the
+//! [`EdgeCompute`] was given a state manager or broker to use.
+//!
+//! A trap fails the execution, reported in its [`EdgeComputeResult`]. So does
+//! breaching one of the function's [`ResourceLimits`], which aborts it and
+//! names the [`Limit`] in the result. Fuel metering counts instructions, and
+//! an execution outrunning its timeout is interrupted at its next call or loop
+//! iteration, so even a function looping forever returns.
+//!
+//! Each function keeps every version registered under its ID. The latest
+//! registered is active and runs unless a version is asked for, and
+//! [`rollback`](EdgeCompute::rollback) reactivates the one before it.
+//!
+//! A version's module is compiled and linked once, when it is registered, and
+//! a pool of instances of it is kept ready so executions skip instantiation.
+//! Each instance serves a single execution, so none sees state another left
+//! behind, and the pool is refilled in the background. A pool unused for the
+//! idle timeout is emptied until its version runs again.
+//!
+//! [`Trigger`]s execute functions on a [`Schedule`] once
+//! [`start_scheduler`](EdgeCompute::start_scheduler) runs, each with its own
+//! input and [`OverlapPolicy`] for firings that come while it still runs.
+
+use crate::{broker::Broker, schedule::Schedule, state::StateManager, ProtocolError};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::runtime::Handle;
+use tokio::sync::{Notify, RwLock};
+use tokio::task::JoinHandle;
+use wasmtime::{Caller, Config, Engine, EngineWeak, Extern, Instance, InstancePre, Linker, Memory, Module, ResourceLimiter, Store, Trap};
+
+/// The runtime of WebAssembly functions
+pub const WASM_RUNTIME: &str = "wasm";
+/// The config key naming the export a function starts at
+pub const ENTRY_CONFIG: &str = "entry";
+const DEFAULT_ENTRY: &str = "run";
+// The module WebAssembly functions import the host ABI from
+const HOST_MODULE: &str = "remus";
+const DEFAULT_POOL_SIZE: usize = 4;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+// How often the engine's epoch advances, the granularity of timeouts
+const EPOCH_TICK: Duration = Duration::from_millis(10);
 
 #[derive(Debug, Clone)]
 pub struct EdgeFunction {
@@ -11,6 +74,7 @@ pub struct EdgeFunction {
     pub runtime: String,
     pub code: Vec<u8>,
     pub config: HashMap<String, String>,
+    pub limits: ResourceLimits,
 }
 
 impl Serialize for EdgeFunction {
@@ -19,13 +83,14 @@ impl Serialize for EdgeFunction {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("EdgeFunction", 6)?;
+        let mut state = serializer.serialize_struct("EdgeFunction", 7)?;
         state.serialize_field("id", &self.id)?;
         state.serialize_field("name", &self.name)?;
         state.serialize_field("version", &self.version)?;
         state.serialize_field("runtime", &self.runtime)?;
         state.serialize_field("code", &self.code)?;
         state.serialize_field("config", &self.config)?;
+        state.serialize_field("limits", &self.limits)?;
         state.end()
     }
 }
@@ -43,6 +108,8 @@ impl<'de> Deserialize<'de> for EdgeFunction {
             runtime: String,
             code: Vec<u8>,
             config: HashMap<String, String>,
+            #[serde(default)]
+            limits: ResourceLimits,
         }
         let helper = Helper::deserialize(deserializer)?;
         Ok(EdgeFunction {
@@ -52,6 +119,7 @@ impl<'de> Deserialize<'de> for EdgeFunction {
             runtime: helper.runtime,
             code: helper.code,
             config: helper.config,
+            limits: helper.limits,
         })
     }
 }
@@ -59,11 +127,15 @@ impl<'de> Deserialize<'de> for EdgeFunction {
 #[derive(Debug, Clone)]
 pub struct EdgeComputeResult {
     pub function_id: String,
+    /// The version of the function that ran
+    pub version: String,
     pub success: bool,
     pub output: Option<Vec<u8>>,
     pub error: Option<String>,
     pub execution_time: u64,
     pub resources_used: ResourceUsage,
+    /// The limit whose breach aborted the execution
+    pub limit_exceeded: Option<Limit>,
 }
 
 impl Serialize for EdgeComputeResult {
@@ -72,13 +144,15 @@ impl Serialize for EdgeComputeResult {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("EdgeComputeResult", 6)?;
+        let mut state = serializer.serialize_struct("EdgeComputeResult", 8)?;
         state.serialize_field("function_id", &self.function_id)?;
+        state.serialize_field("version", &self.version)?;
         state.serialize_field("success", &self.success)?;
         state.serialize_field("output", &self.output)?;
         state.serialize_field("error", &self.error)?;
         state.serialize_field("execution_time", &self.execution_time)?;
         state.serialize_field("resources_used", &self.resources_used)?;
+        state.serialize_field("limit_exceeded", &self.limit_exceeded)?;
         state.end()
     }
 }
@@ -91,20 +165,26 @@ impl<'de> Deserialize<'de> for EdgeComputeResult {
         #[derive(Deserialize)]
         struct Helper {
             function_id: String,
+            #[serde(default)]
+            version: String,
             success: bool,
             output: Option<Vec<u8>>,
             error: Option<String>,
             execution_time: u64,
             resources_used: ResourceUsage,
+            #[serde(default)]
+            limit_exceeded: Option<Limit>,
         }
         let helper = Helper::deserialize(deserializer)?;
         Ok(EdgeComputeResult {
             function_id: helper.function_id,
+            version: helper.version,
             success: helper.success,
             output: helper.output,
             error: helper.error,
             execution_time: helper.execution_time,
             resources_used: helper.resources_used,
+            limit_exceeded: helper.limit_exceeded,
         })
     }
 }
@@ -114,81 +194,825 @@ pub struct ResourceUsage {
     pub cpu_time_ms: u64,
     pub memory_bytes: u64,
     pub network_bytes: u64,
+    /// Fuel spent, about a unit per instruction executed
+    #[serde(default)]
+    pub fuel: u64,
+}
+
+/// Caps on what one execution of a function may use
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Linear memory, rounded down to whole 64 KiB pages
+    pub max_memory_bytes: u64,
+    /// Fuel, about a unit per instruction executed
+    pub max_fuel: u64,
+    pub max_output_bytes: u64,
+    /// Wall-clock time, which [`EdgeCompute::execute_function_with_timeout`] may override
+    pub timeout_ms: u64,
+}
+
+impl Default for ResourceLimits {
+    /// 64 MiB of memory, a billion units of fuel, 16 MiB of output and 30 seconds
+    fn default() -> Self {
+        Self { max_memory_bytes: 64 << 20, max_fuel: 1_000_000_000, max_output_bytes: 16 << 20, timeout_ms: 30_000 }
+    }
+}
+
+/// A [`ResourceLimits`] entry an execution breached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Limit {
+    Memory,
+    Fuel,
+    Output,
+    Time,
+}
+
+/// What a [`Trigger`] does when it fires while an execution it started still runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverlapPolicy {
+    /// Drops the firing
+    #[default]
+    Skip,
+    /// Executes once more after the running execution, however often it fired meanwhile
+    Queue,
+    /// Executes alongside
+    Allow,
+}
+
+/// Executes a function whenever a [`Schedule`] matches
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trigger {
+    pub id: String,
+    pub function_id: String,
+    pub schedule: Schedule,
+    /// The input of every execution
+    pub input: Vec<u8>,
+    pub overlap: OverlapPolicy,
+}
+
+impl Trigger {
+    /// Executes `function_id` on an empty input, skipping firings while it runs
+    pub fn new(id: &str, function_id: &str, schedule: Schedule) -> Self {
+        Self { id: id.to_string(), function_id: function_id.to_string(), schedule, input: Vec::new(), overlap: OverlapPolicy::default() }
+    }
+
+    pub fn with_input(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.input = input.into();
+        self
+    }
+
+    pub fn with_overlap(mut self, overlap: OverlapPolicy) -> Self {
+        self.overlap = overlap;
+        self
+    }
+}
+
+/// A [`Trigger`] and how its executions went
+#[derive(Debug, Clone)]
+pub struct TriggerStatus {
+    pub trigger: Trigger,
+    /// When it last started an execution
+    pub last_run: Option<SystemTime>,
+    /// When it fires next; `None` if its schedule never matches again
+    pub next_run: Option<SystemTime>,
+    /// Executions in progress
+    pub running: usize,
+    /// The last finished execution, or why it could not run
+    pub last_result: Option<Result<EdgeComputeResult, String>>,
+}
+
+// What functions reach of the node they run on
+#[derive(Clone, Default)]
+struct Node {
+    state: Option<Arc<StateManager>>,
+    broker: Option<Arc<Broker>>,
+}
+
+// What the host functions of one execution work on
+struct Invocation {
+    function_id: String,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    max_output: u64,
+    max_memory: u64,
+    // The limit a host function or memory growth found breached
+    exceeded: Option<Limit>,
+    node: Node,
+    // Runs the state manager's async calls from the blocking thread executing the function
+    runtime: Handle,
+}
+
+impl Invocation {
+    fn state(&self) -> wasmtime::Result<&StateManager> {
+        self.node.state.as_deref().ok_or_else(|| wasmtime::Error::msg("No state manager is attached"))
+    }
+}
+
+impl ResourceLimiter for Invocation {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> wasmtime::Result<bool> {
+        if desired as u64 > self.max_memory {
+            self.exceeded = Some(Limit::Memory);
+            return Err(wasmtime::Error::msg("Memory limit exceeded"));
+        }
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, _current: usize, _desired: usize, _maximum: Option<usize>) -> wasmtime::Result<bool> {
+        Ok(true)
+    }
+}
+
+fn memory(caller: &mut Caller<'_, Invocation>) -> wasmtime::Result<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory).ok_or_else(|| wasmtime::Error::msg("Module exports no memory"))
+}
+
+fn read(caller: &mut Caller<'_, Invocation>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    let memory = memory(caller)?;
+    memory.data(&caller).get(ptr..ptr + len).map(<[u8]>::to_vec).ok_or_else(|| Trap::MemoryOutOfBounds.into())
+}
+
+fn text(caller: &mut Caller<'_, Invocation>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    String::from_utf8(read(caller, ptr, len)?).map_err(|_| wasmtime::Error::msg("Keys and topics must be UTF-8"))
+}
+
+fn write(caller: &mut Caller<'_, Invocation>, ptr: i32, bytes: &[u8]) -> wasmtime::Result<()> {
+    memory(caller)?.write(caller, ptr as u32 as usize, bytes).map_err(|_| Trap::MemoryOutOfBounds.into())
+}
+
+fn input_len(caller: Caller<'_, Invocation>) -> i32 {
+    caller.data().input.len() as i32
+}
+
+fn input_read(mut caller: Caller<'_, Invocation>, ptr: i32) -> wasmtime::Result<()> {
+    let input = std::mem::take(&mut caller.data_mut().input);
+    let written = write(&mut caller, ptr, &input);
+    caller.data_mut().input = input;
+    written
+}
+
+fn output_write(mut caller: Caller<'_, Invocation>, ptr: i32, len: i32) -> wasmtime::Result<()> {
+    let invocation = caller.data_mut();
+    if invocation.output.len() as u64 + len as u32 as u64 > invocation.max_output {
+        invocation.exceeded = Some(Limit::Output);
+        return Err(wasmtime::Error::msg("Output limit exceeded"));
+    }
+    let bytes = read(&mut caller, ptr, len)?;
+    caller.data_mut().output.extend_from_slice(&bytes);
+    Ok(())
+}
+
+fn state_get(mut caller: Caller<'_, Invocation>, key_ptr: i32, key_len: i32, ptr: i32, len: i32) -> wasmtime::Result<i32> {
+    let key = text(&mut caller, key_ptr, key_len)?;
+    let invocation = caller.data();
+    let Some(value) = invocation.runtime.block_on(invocation.state()?.get_state(&key)) else {
+        return Ok(-1);
+    };
+    write(&mut caller, ptr, &value[..value.len().min(len as u32 as usize)])?;
+    Ok(value.len() as i32)
+}
+
+fn state_append(mut caller: Caller<'_, Invocation>, key_ptr: i32, key_len: i32, ptr: i32, len: i32) -> wasmtime::Result<i64> {
+    let key = text(&mut caller, key_ptr, key_len)?;
+    let delta = Bytes::from(read(&mut caller, ptr, len)?);
+    let invocation = caller.data();
+    let version = invocation.runtime.block_on(invocation.state()?.apply_delta(key, delta))?;
+    Ok(version.version as i64)
+}
+
+fn emit(mut caller: Caller<'_, Invocation>, topic_ptr: i32, topic_len: i32, ptr: i32, len: i32) -> wasmtime::Result<i32> {
+    let topic = text(&mut caller, topic_ptr, topic_len)?;
+    let payload = Bytes::from(read(&mut caller, ptr, len)?);
+    let broker = caller.data().node.broker.as_ref().ok_or_else(|| wasmtime::Error::msg("No broker is attached"))?;
+    Ok(broker.publish(&topic, payload) as i32)
+}
+
+fn log(mut caller: Caller<'_, Invocation>, level: i32, ptr: i32, len: i32) -> wasmtime::Result<()> {
+    let message = read(&mut caller, ptr, len)?;
+    let message = String::from_utf8_lossy(&message);
+    let function = &caller.data().function_id;
+    match level {
+        0 => tracing::error!(function, "{}", message),
+        1 => tracing::warn!(function, "{}", message),
+        2 => tracing::info!(function, "{}", message),
+        3 => tracing::debug!(function, "{}", message),
+        _ => tracing::trace!(function, "{}", message),
+    }
+    Ok(())
+}
+
+fn host_linker(engine: &Engine) -> wasmtime::Result<Linker<Invocation>> {
+    let mut linker = Linker::new(engine);
+    linker
+        .func_wrap(HOST_MODULE, "input_len", input_len)?
+        .func_wrap(HOST_MODULE, "input_read", input_read)?
+        .func_wrap(HOST_MODULE, "output_write", output_write)?
+        .func_wrap(HOST_MODULE, "state_get", state_get)?
+        .func_wrap(HOST_MODULE, "state_append", state_append)?
+        .func_wrap(HOST_MODULE, "emit", emit)?
+        .func_wrap(HOST_MODULE, "log", log)?;
+    Ok(linker)
+}
+
+// Advances the engine's epoch every tick, so deadlines in ticks interrupt executions, until the engine is dropped
+fn tick(engine: EngineWeak) {
+    loop {
+        std::thread::sleep(EPOCH_TICK);
+        let Some(engine) = engine.upgrade() else { return };
+        engine.increment_epoch();
+    }
+}
+
+// The epoch deadline that interrupts an execution no sooner than `timeout` from now
+fn deadline(timeout: Duration) -> u64 {
+    (timeout.as_millis().div_ceil(EPOCH_TICK.as_millis()) as u64).saturating_add(1)
+}
+
+fn invalid_module(e: wasmtime::Error) -> ProtocolError {
+    ProtocolError::InvalidFormat(format!("Invalid WebAssembly module: {e}"))
+}
+
+// An instance with the store it lives in
+struct Ready {
+    store: Store<Invocation>,
+    instance: Instance,
+}
+
+// Instances of a compiled module kept ready for executions
+struct Pool {
+    pre: InstancePre<Invocation>,
+    function_id: String,
+    limits: ResourceLimits,
+    node: Node,
+    runtime: Handle,
+    idle: Mutex<Idle>,
+}
+
+struct Idle {
+    instances: Vec<Ready>,
+    last_used: Instant,
+}
+
+impl Pool {
+    fn new(pre: InstancePre<Invocation>, function: &EdgeFunction, node: Node) -> Self {
+        Self {
+            pre,
+            function_id: function.id.clone(),
+            limits: function.limits.clone(),
+            node,
+            runtime: Handle::current(),
+            idle: Mutex::new(Idle { instances: Vec::new(), last_used: Instant::now() }),
+        }
+    }
+
+    fn instantiate(&self) -> Result<Ready, ProtocolError> {
+        let invocation = Invocation {
+            function_id: self.function_id.clone(),
+            input: Vec::new(),
+            output: Vec::new(),
+            max_output: self.limits.max_output_bytes,
+            max_memory: self.limits.max_memory_bytes,
+            exceeded: None,
+            node: self.node.clone(),
+            runtime: self.runtime.clone(),
+        };
+        let mut store = Store::new(self.pre.module().engine(), invocation);
+        store.limiter(|invocation| invocation as &mut dyn ResourceLimiter);
+        // A start function runs on the budget of an execution
+        store.set_fuel(self.limits.max_fuel).map_err(invalid_module)?;
+        store.set_epoch_deadline(deadline(Duration::from_millis(self.limits.timeout_ms)));
+        let instance = self.pre.instantiate(&mut store).map_err(|e| ProtocolError::InvalidFormat(format!("Cannot instantiate WebAssembly module: {e}")))?;
+        Ok(Ready { store, instance })
+    }
+
+    // Takes a ready instance, or instantiates one when none is
+    fn take(&self) -> Result<Ready, ProtocolError> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.last_used = Instant::now();
+        match idle.instances.pop() {
+            Some(ready) => Ok(ready),
+            None => {
+                drop(idle);
+                self.instantiate()
+            }
+        }
+    }
+
+    // Instantiates until `size` instances are ready, outside the lock so executions can take them meanwhile
+    fn refill(&self, size: usize) {
+        while self.warm() < size {
+            let Ok(ready) = self.instantiate() else { return };
+            let mut idle = self.idle.lock().unwrap();
+            if idle.instances.len() < size {
+                idle.instances.push(ready);
+            }
+        }
+    }
+
+    fn evict_if_idle(&self, timeout: Duration) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.last_used.elapsed() >= timeout {
+            idle.instances.clear();
+        }
+    }
+
+    fn warm(&self) -> usize {
+        self.idle.lock().unwrap().instances.len()
+    }
+}
+
+struct Version {
+    function: EdgeFunction,
+    pool: Arc<Pool>,
+}
+
+// The versions registered under one function ID, oldest first
+struct Versions {
+    versions: Vec<Version>,
+    active: usize,
+}
+
+impl Versions {
+    fn active(&self) -> &Version {
+        &self.versions[self.active]
+    }
+
+    fn position(&self, version: &str) -> Result<usize, ProtocolError> {
+        self.versions
+            .iter()
+            .position(|registered| registered.function.version == version)
+            .ok_or_else(|| ProtocolError::InvalidFormat(format!("Function has no version {version:?}")))
+    }
+}
+
+struct Scheduled {
+    status: TriggerStatus,
+    // Whether a firing waits for the running execution, under OverlapPolicy::Queue
+    queued: bool,
 }
 
 pub struct EdgeCompute {
-    functions: RwLock<HashMap<String, EdgeFunction>>,
+    functions: RwLock<HashMap<String, Versions>>,
+    triggers: Mutex<HashMap<String, Scheduled>>,
+    // Wakes the scheduler when triggers change
+    triggers_changed: Notify,
+    linker: Linker<Invocation>,
+    pool_size: usize,
+    idle_timeout: Duration,
+    node: Node,
+}
+
+fn not_found() -> ProtocolError {
+    ProtocolError::InvalidFormat("Function not found".into())
 }
 
 impl EdgeCompute {
     pub fn new() -> Self {
+        let mut config = Config::new();
+        config.consume_fuel(true).epoch_interruption(true);
+        let engine = Engine::new(&config).expect("fuel and epoch interruption are supported");
+        let weak = engine.weak();
+        std::thread::spawn(move || tick(weak));
         Self {
             functions: RwLock::new(HashMap::new()),
+            triggers: Mutex::new(HashMap::new()),
+            triggers_changed: Notify::new(),
+            linker: host_linker(&engine).expect("host functions are defined once"),
+            pool_size: DEFAULT_POOL_SIZE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            node: Node::default(),
         }
     }
 
+    /// Lets functions read and append to keys in `state`
+    pub fn with_state(mut self, state: Arc<StateManager>) -> Self {
+        self.node.state = Some(state);
+        self
+    }
+
+    /// Lets functions publish Events through `broker`
+    pub fn with_broker(mut self, broker: Arc<Broker>) -> Self {
+        self.node.broker = Some(broker);
+        self
+    }
+
+    /// Sets how many instances of each version are kept ready; 0 instantiates on every execution
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    /// Sets how long a version's pool may go unused before its instances are dropped
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Adds `function` as the active version of its ID, replacing a version registered under the same name.
+    ///
+    /// Fails if its module does not compile or imports what the host does not provide.
     pub async fn register_function(&self, function: EdgeFunction) -> Result<(), ProtocolError> {
+        if function.runtime != WASM_RUNTIME {
+            return Err(ProtocolError::InvalidFormat(format!("Unsupported runtime {:?}", function.runtime)));
+        }
+        let module = Module::new(self.linker.engine(), &function.code).map_err(invalid_module)?;
+        let pre = self.linker.instantiate_pre(&module).map_err(invalid_module)?;
+        let pool = Arc::new(Pool::new(pre, &function, self.node.clone()));
+        let ready = pool.instantiate()?;
+        if self.pool_size > 0 {
+            pool.idle.lock().unwrap().instances.push(ready);
+        }
+        let (warming, size) = (pool.clone(), self.pool_size);
+        tokio::task::spawn_blocking(move || warming.refill(size))
+            .await
+            .map_err(|e| ProtocolError::HandlerPanicked(e.to_string()))?;
+
         let mut functions = self.functions.write().await;
-        functions.insert(function.id.clone(), function);
+        let versions = functions.entry(function.id.clone()).or_insert(Versions { versions: Vec::new(), active: 0 });
+        versions.versions.retain(|registered| registered.function.version != function.version);
+        versions.versions.push(Version { function, pool });
+        versions.active = versions.versions.len() - 1;
         Ok(())
     }
 
+    /// Runs function `function_id` on `input`; a trap is reported as a failed result rather than an error
     pub async fn execute_function(
         &self,
         function_id: &str,
         input: Vec<u8>,
     ) -> Result<EdgeComputeResult, ProtocolError> {
-        let functions = self.functions.read().await;
-        let function = functions
-            .get(function_id)
-            .ok_or_else(|| ProtocolError::InvalidFormat("Function not found".into()))?;
-
-        let start_time = std::time::Instant::now();
-        
-        // Here you would implement actual function execution
-        // This is a placeholder that returns empty success
-        let result = EdgeComputeResult {
-            function_id: function_id.to_string(),
-            success: true,
-            output: Some(Vec::new()),
-            error: None,
-            execution_time: start_time.elapsed().as_millis() as u64,
-            resources_used: ResourceUsage {
-                cpu_time_ms: 0,
-                memory_bytes: 0,
-                network_bytes: 0,
-            },
+        self.execute(function_id, None, input, None).await
+    }
+
+    /// Executes a function, interrupting it after `timeout` instead of its own
+    pub async fn execute_function_with_timeout(&self, function_id: &str, input: Vec<u8>, timeout: Duration) -> Result<EdgeComputeResult, ProtocolError> {
+        self.execute(function_id, None, input, Some(timeout)).await
+    }
+
+    /// Executes `version` of a function rather than its active version
+    pub async fn execute_function_version(&self, function_id: &str, version: &str, input: Vec<u8>) -> Result<EdgeComputeResult, ProtocolError> {
+        self.execute(function_id, Some(version), input, None).await
+    }
+
+    async fn execute(&self, function_id: &str, version: Option<&str>, input: Vec<u8>, timeout: Option<Duration>) -> Result<EdgeComputeResult, ProtocolError> {
+        let start_time = Instant::now();
+        let (pool, entry, limits, version) = {
+            let functions = self.functions.read().await;
+            for registered in functions.values().flat_map(|versions| &versions.versions) {
+                registered.pool.evict_if_idle(self.idle_timeout);
+            }
+            let versions = functions.get(function_id).ok_or_else(not_found)?;
+            let Version { function, pool } = match version {
+                Some(version) => &versions.versions[versions.position(version)?],
+                None => versions.active(),
+            };
+            let entry = function.config.get(ENTRY_CONFIG).map_or(DEFAULT_ENTRY, String::as_str).to_string();
+            (pool.clone(), entry, function.limits.clone(), function.version.clone())
         };
+        let Ready { mut store, instance } = pool.take()?;
+        if self.pool_size > 0 {
+            let size = self.pool_size;
+            tokio::task::spawn_blocking(move || pool.refill(size));
+        }
+        let timeout = timeout.unwrap_or(Duration::from_millis(limits.timeout_ms));
 
-        Ok(result)
+        // Executing is CPU-bound, so it runs off the async workers
+        let function_id = function_id.to_string();
+        let result = tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            store.data_mut().input = input;
+            let outcome = store.set_fuel(limits.max_fuel).and_then(|()| {
+                store.set_epoch_deadline(deadline(timeout));
+                instance.get_typed_func::<(), ()>(&mut store, &entry)?.call(&mut store, ())
+            });
+            let resources_used = ResourceUsage {
+                cpu_time_ms: started.elapsed().as_millis() as u64,
+                memory_bytes: instance.get_memory(&mut store, "memory").map_or(0, |memory| memory.data_size(&store) as u64),
+                network_bytes: 0,
+                fuel: limits.max_fuel.saturating_sub(store.get_fuel().unwrap_or(0)),
+            };
+            let limit_exceeded = match &outcome {
+                Ok(()) => None,
+                Err(e) => match e.downcast_ref::<Trap>() {
+                    Some(Trap::OutOfFuel) => Some(Limit::Fuel),
+                    Some(Trap::Interrupt) => Some(Limit::Time),
+                    _ => store.data().exceeded,
+                },
+            };
+            let (success, output, error) = match outcome {
+                Ok(()) => (true, Some(std::mem::take(&mut store.data_mut().output)), None),
+                Err(e) => (false, None, Some(e.root_cause().to_string())),
+            };
+            EdgeComputeResult {
+                function_id,
+                version,
+                success,
+                output,
+                error,
+                execution_time: start_time.elapsed().as_millis() as u64,
+                resources_used,
+                limit_exceeded,
+            }
+        })
+        .await;
+        result.map_err(|e| ProtocolError::HandlerPanicked(e.to_string()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio_test::block_on;
+    use wasm_encoder::{
+        BlockType, CodeSection, ConstExpr, DataSection, EntityType, ExportKind, ExportSection, Function, FunctionSection, ImportSection, Instruction,
+        MemorySection, MemoryType, TypeSection, ValType,
+    };
+    use Instruction as I;
+    use ValType::{I32, I64};
 
-    #[tokio::test]
-    async fn test_edge_function_lifecycle() {
-        let compute = EdgeCompute::new();
-        
-        // Create test function
-        let function = EdgeFunction {
+    // A module importing `imports` from the host, with a page of memory holding `data`, exporting each of `functions`.
+    //
+    // Imported functions are called by their indices in `imports`; every function has one i32 local.
+    fn module(imports: &[(&str, &[ValType], &[ValType])], data: &[u8], functions: &[(&str, &[Instruction])]) -> Vec<u8> {
+        let (mut types, mut imported, mut declared, mut exports, mut code) =
+            (TypeSection::new(), ImportSection::new(), FunctionSection::new(), ExportSection::new(), CodeSection::new());
+        for (index, (name, params, results)) in imports.iter().enumerate() {
+            types.ty().function(params.iter().copied(), results.iter().copied());
+            imported.import("remus", name, EntityType::Function(index as u32));
+        }
+        let run_type = imports.len() as u32;
+        types.ty().function([], []);
+        for (index, (name, body)) in functions.iter().enumerate() {
+            declared.function(run_type);
+            exports.export(name, ExportKind::Func, run_type + index as u32);
+            let mut function = Function::new([(1, I32)]);
+            for instruction in body.iter() {
+                function.instruction(instruction);
+            }
+            code.function(function.instruction(&I::End));
+        }
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType { minimum: 1, maximum: None, memory64: false, shared: false, page_size_log2: None });
+        exports.export("memory", ExportKind::Memory, 0);
+        let mut segments = DataSection::new();
+        segments.active(0, &ConstExpr::i32_const(0), data.iter().copied());
+
+        let mut module = wasm_encoder::Module::new();
+        module.section(&types).section(&imported).section(&declared).section(&memories).section(&exports).section(&code).section(&segments);
+        module.finish()
+    }
+
+    const INPUT_LEN: u32 = 0;
+    const INPUT_READ: u32 = 1;
+    const OUTPUT_WRITE: u32 = 2;
+    const IO: &[(&str, &[ValType], &[ValType])] = &[("input_len", &[], &[I32]), ("input_read", &[I32], &[]), ("output_write", &[I32, I32], &[])];
+
+    // Echoes its input, prefixed with "echo: "
+    fn echo() -> Vec<u8> {
+        module(IO, b"echo: ", &[
+            ("run", &[
+                I::I32Const(0), I::I32Const(6), I::Call(OUTPUT_WRITE),
+                I::I32Const(16), I::Call(INPUT_READ),
+                I::I32Const(16), I::Call(INPUT_LEN), I::Call(OUTPUT_WRITE),
+            ]),
+            // Faults on a division by zero
+            ("fail", &[I::I32Const(1), I::I32Const(0), I::I32DivS, I::Drop]),
+            // Loop forever, growing memory or writing output as they go
+            ("spin", &[I::Loop(BlockType::Empty), I::Br(0), I::End]),
+            ("hog", &[I::Loop(BlockType::Empty), I::I32Const(1), I::MemoryGrow(0), I::Drop, I::Br(0), I::End]),
+            ("flood", &[I::Loop(BlockType::Empty), I::I32Const(0), I::I32Const(65536), I::Call(OUTPUT_WRITE), I::Br(0), I::End]),
+        ])
+    }
+
+    // Appends its input to key "hits", then outputs, emits on "edge/hits" and logs the key's value
+    fn visit() -> Vec<u8> {
+        let buffers = &[I32; 4][..];
+        let imports = [IO, &[("state_get", buffers, &[I32]), ("state_append", buffers, &[I64]), ("emit", buffers, &[I32]), ("log", &[I32; 3], &[])]].concat();
+        let (state_get, state_append, emit, log) = (3, 4, 5, 6);
+        module(&imports, b"hitsedge/hits", &[(
+            "run",
+            &[
+                I::I32Const(16), I::Call(INPUT_READ),
+                I::I32Const(0), I::I32Const(4), I::I32Const(16), I::Call(INPUT_LEN), I::Call(state_append), I::Drop,
+                I::I32Const(0), I::I32Const(4), I::I32Const(32), I::I32Const(200), I::Call(state_get), I::LocalSet(0),
+                I::I32Const(32), I::LocalGet(0), I::Call(OUTPUT_WRITE),
+                I::I32Const(4), I::I32Const(9), I::I32Const(32), I::LocalGet(0), I::Call(emit), I::Drop,
+                I::I32Const(2), I::I32Const(32), I::LocalGet(0), I::Call(log),
+            ],
+        )])
+    }
+
+    fn function(code: Vec<u8>, config: &[(&str, &str)]) -> EdgeFunction {
+        EdgeFunction {
             id: "test_func".to_string(),
             name: "Test Function".to_string(),
             version: "1.0.0".to_string(),
             runtime: "wasm".to_string(),
-            code: vec![0, 1, 2, 3],
-            config: HashMap::new(),
-        };
+            code,
+            config: config.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            limits: ResourceLimits::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_edge_function_lifecycle() {
+        let compute = EdgeCompute::new();
+        let function = function(echo(), &[]);
 
         // Register function
         compute.register_function(function.clone()).await.unwrap();
 
         // Execute function
-        let result = compute.execute_function(&function.id, vec![]).await.unwrap();
+        let result = compute.execute_function(&function.id, b"hello".to_vec()).await.unwrap();
         assert!(result.success);
+        assert_eq!(result.output.as_deref(), Some(&b"echo: hello"[..]));
+        assert_eq!(result.resources_used.memory_bytes, 65536);
+        assert!(result.resources_used.fuel > 0);
+    }
+
+    #[tokio::test]
+    async fn test_traps_and_bad_modules_fail_executions() {
+        let compute = EdgeCompute::new();
+        compute.register_function(function(echo(), &[("entry", "fail")])).await.unwrap();
+        let result = compute.execute_function("test_func", Vec::new()).await.unwrap();
+        assert!(!result.success);
+        assert_eq!(result.error.as_deref(), Some("wasm trap: integer divide by zero"));
+
+        assert_eq!(result.limit_exceeded, None);
+
+        // Modules that do not compile or link are rejected up front
+        assert!(compute.register_function(function(vec![0, 1, 2, 3], &[])).await.is_err());
+        assert!(compute.register_function(EdgeFunction { runtime: "js".to_string(), ..function(echo(), &[]) }).await.is_err());
+        assert!(compute.register_function(function(module(&[("missing", &[], &[])], b"", &[]), &[])).await.is_err());
+        assert_eq!(compute.list_versions("test_func").await.unwrap().len(), 1);
+
+        compute.register_function(function(echo(), &[("entry", "missing")])).await.unwrap();
+        assert!(!compute.execute_function("test_func", Vec::new()).await.unwrap().success);
+    }
+
+    #[tokio::test]
+    async fn test_limits_abort_runaway_functions() {
+        let compute = EdgeCompute::new();
+        for (entry, limit) in [("spin", Limit::Fuel), ("hog", Limit::Memory), ("flood", Limit::Output)] {
+            let mut function = function(echo(), &[("entry", entry)]);
+            function.limits = ResourceLimits { max_memory_bytes: 1 << 20, max_fuel: 100_000, max_output_bytes: 1 << 20, timeout_ms: 60_000 };
+            compute.register_function(function).await.unwrap();
+            let result = compute.execute_function("test_func", Vec::new()).await.unwrap();
+            assert!(!result.success, "{entry}");
+            assert_eq!(result.limit_exceeded, Some(limit), "{entry}");
+        }
+        let result = compute.execute_function("test_func", Vec::new()).await.unwrap();
+        assert!(result.resources_used.fuel < 100_000 && result.resources_used.memory_bytes <= 1 << 20);
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_interrupt_endless_functions() {
+        let compute = EdgeCompute::new();
+        let mut function = function(echo(), &[("entry", "spin")]);
+        function.limits = ResourceLimits { max_fuel: u64::MAX, timeout_ms: 50, ..ResourceLimits::default() };
+        compute.register_function(function.clone()).await.unwrap();
+        let result = compute.execute_function("test_func", Vec::new()).await.unwrap();
+        assert!(!result.success);
+        assert_eq!(result.limit_exceeded, Some(Limit::Time));
+
+        // An invocation's own timeout overrides the function's
+        function.limits.timeout_ms = 60_000;
+        compute.register_function(function).await.unwrap();
+        let started = Instant::now();
+        let result = compute.execute_function_with_timeout("test_func", Vec::new(), Duration::from_millis(50)).await.unwrap();
+        assert_eq!(result.limit_exceeded, Some(Limit::Time));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_versions_roll_back_and_run_on_request() {
+        let compute = EdgeCompute::new();
+        for (version, entry) in [("1.0.0", "run"), ("1.1.0", "fail"), ("1.2.0", "run")] {
+            compute.register_function(EdgeFunction { version: version.to_string(), ..function(echo(), &[("entry", entry)]) }).await.unwrap();
+        }
+        assert_eq!(compute.list_versions("test_func").await.unwrap(), ["1.0.0", "1.1.0", "1.2.0"]);
+        assert_eq!(compute.execute_function("test_func", Vec::new()).await.unwrap().version, "1.2.0");
+
+        assert_eq!(compute.rollback("test_func").await.unwrap(), "1.1.0");
+        let result = compute.execute_function("test_func", Vec::new()).await.unwrap();
+        assert!(!result.success && result.version == "1.1.0");
+        assert_eq!(compute.list_functions().await[0].version, "1.1.0");
+        assert!(compute.execute_function_version("test_func", "1.2.0", Vec::new()).await.unwrap().success);
+        assert!(compute.execute_function_version("test_func", "2.0.0", Vec::new()).await.is_err());
+
+        assert_eq!(compute.rollback("test_func").await.unwrap(), "1.0.0");
+        assert!(compute.rollback("test_func").await.is_err());
+        compute.activate_version("test_func", "1.2.0").await.unwrap();
+        assert_eq!(compute.active_version("test_func").await.unwrap(), "1.2.0");
+
+        // Registering a version again replaces it and makes it active
+        compute.register_function(EdgeFunction { version: "1.0.0".to_string(), ..function(echo(), &[]) }).await.unwrap();
+        assert_eq!(compute.list_versions("test_func").await.unwrap(), ["1.1.0", "1.2.0", "1.0.0"]);
+        assert_eq!(compute.active_version("test_func").await.unwrap(), "1.0.0");
+    }
+
+    #[tokio::test]
+    async fn test_pools_stay_warm_until_idle() {
+        let compute = EdgeCompute::new().with_pool_size(2).with_idle_timeout(Duration::from_millis(200));
+        compute.register_function(function(echo(), &[])).await.unwrap();
+        compute.register_function(EdgeFunction { id: "other".to_string(), ..function(echo(), &[]) }).await.unwrap();
+        assert_eq!(compute.warm_instances("test_func").await.unwrap(), 2);
+
+        // Executions take a ready instance, which is replaced in the background
+        for _ in 0..3 {
+            assert!(compute.execute_function("test_func", b"hi".to_vec()).await.unwrap().success);
+        }
+        let refilled = async {
+            while compute.warm_instances("test_func").await.unwrap() < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), refilled).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(compute.execute_function("other", Vec::new()).await.unwrap().success);
+        assert_eq!(compute.warm_instances("test_func").await.unwrap(), 0);
+        let unpooled = EdgeCompute::new().with_pool_size(0);
+        unpooled.register_function(function(echo(), &[])).await.unwrap();
+        assert!(unpooled.execute_function("test_func", b"hi".to_vec()).await.unwrap().success);
+    }
+
+    #[tokio::test]
+    async fn test_functions_reach_state_and_events() {
+        let state = Arc::new(StateManager::new(16));
+        let broker = Arc::new(Broker::new());
+        let subscriber = broker.attach();
+        subscriber.subscribe(1, "edge/*".into());
+        let compute = EdgeCompute::new().with_state(state.clone()).with_broker(broker.clone());
+        compute.register_function(function(visit(), &[])).await.unwrap();
+
+        for (input, seen) in [("ab", "ab"), ("cd", "abcd")] {
+            let result = compute.execute_function("test_func", input.into()).await.unwrap();
+            assert_eq!(result.output.as_deref(), Some(seen.as_bytes()));
+        }
+        assert_eq!(state.get_state("hits").await.as_deref(), Some(&b"abcd"[..]));
+        let events = subscriber.next_batch().await;
+        let events: Vec<_> = events.iter().map(|event| (event.routing_info.as_deref(), &event.payload[..])).collect();
+        assert_eq!(events, [(Some("edge/hits"), &b"ab"[..]), (Some("edge/hits"), &b"abcd"[..])]);
+
+        // Without a state manager the state functions trap
+        let detached = EdgeCompute::new();
+        detached.register_function(function(visit(), &[])).await.unwrap();
+        let result = detached.execute_function("test_func", Vec::new()).await.unwrap();
+        assert_eq!(result.error.as_deref(), Some("No state manager is attached"));
+    }
+
+    #[tokio::test]
+    async fn test_triggers_execute_on_their_schedule() {
+        let compute = Arc::new(EdgeCompute::new());
+        compute.register_function(function(echo(), &[])).await.unwrap();
+        let every_second = Trigger::new("tick", "test_func", "* * * * * *".parse().unwrap()).with_input("tick");
+        assert!(compute.add_trigger(Trigger { function_id: "missing".to_string(), ..every_second.clone() }).await.is_err());
+        compute.add_trigger(every_second).await.unwrap();
+        assert!(compute.trigger_status("tick").unwrap().next_run.unwrap() > SystemTime::now());
+        let scheduler = compute.start_scheduler();
+
+        let ran = async {
+            loop {
+                if let Some(Ok(result)) = compute.trigger_status("tick").unwrap().last_result {
+                    return result;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let result = tokio::time::timeout(Duration::from_secs(3), ran).await.unwrap();
+        assert_eq!(result.output.as_deref(), Some(&b"echo: tick"[..]));
+        assert!(compute.trigger_status("tick").unwrap().last_run.is_some());
+
+        compute.remove_trigger("tick").unwrap();
+        assert!(compute.triggers().is_empty());
+        scheduler.abort();
+    }
+
+    #[tokio::test]
+    async fn test_overlap_policies_decide_what_firings_run() {
+        for (overlap, running) in [(OverlapPolicy::Skip, 1), (OverlapPolicy::Queue, 1), (OverlapPolicy::Allow, 3)] {
+            let compute = Arc::new(EdgeCompute::new());
+            let mut spin = function(echo(), &[("entry", "spin")]);
+            spin.limits = ResourceLimits { max_fuel: u64::MAX, timeout_ms: 200, ..ResourceLimits::default() };
+            compute.register_function(spin).await.unwrap();
+            compute.add_trigger(Trigger::new("spin", "test_func", "@yearly".parse().unwrap()).with_overlap(overlap)).await.unwrap();
+
+            let started = Instant::now();
+            for _ in 0..3 {
+                compute.fire("spin");
+            }
+            assert_eq!(compute.trigger_status("spin").unwrap().running, running, "{overlap:?}");
+            while compute.trigger_status("spin").unwrap().running > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // The queued firing ran after the first execution timed out
+            if overlap == OverlapPolicy::Queue {
+                assert!(started.elapsed() >= Duration::from_millis(400));
+            }
+            let result = compute.trigger_status("spin").unwrap().last_result.unwrap().unwrap();
+            assert_eq!(result.limit_exceeded, Some(Limit::Time));
+        }
     }
 
     #[test]
@@ -200,6 +1024,7 @@ mod tests {
             runtime: "wasm".to_string(),
             code: vec![0, 1, 2, 3],
             config: HashMap::new(),
+            limits: ResourceLimits::default(),
         };
 
         let serialized = serde_json::to_string(&function).unwrap();
@@ -211,16 +1036,156 @@ mod tests {
 
 // Helper functions
 impl EdgeCompute {
+    /// The active version of each function
     pub async fn list_functions(&self) -> Vec<EdgeFunction> {
         let functions = self.functions.read().await;
-        functions.values().cloned().collect()
+        functions.values().map(|versions| versions.active().function.clone()).collect()
     }
 
+    /// The versions of function `id` in the order they were registered
+    pub async fn list_versions(&self, id: &str) -> Result<Vec<String>, ProtocolError> {
+        let functions = self.functions.read().await;
+        let versions = functions.get(id).ok_or_else(not_found)?;
+        Ok(versions.versions.iter().map(|registered| registered.function.version.clone()).collect())
+    }
+
+    pub async fn active_version(&self, id: &str) -> Result<String, ProtocolError> {
+        let functions = self.functions.read().await;
+        Ok(functions.get(id).ok_or_else(not_found)?.active().function.version.clone())
+    }
+
+    /// Makes `version` of function `id` the one executions run
+    pub async fn activate_version(&self, id: &str, version: &str) -> Result<(), ProtocolError> {
+        let mut functions = self.functions.write().await;
+        let versions = functions.get_mut(id).ok_or_else(not_found)?;
+        versions.active = versions.position(version)?;
+        Ok(())
+    }
+
+    /// Activates the version registered before the active one, returning it
+    pub async fn rollback(&self, id: &str) -> Result<String, ProtocolError> {
+        let mut functions = self.functions.write().await;
+        let versions = functions.get_mut(id).ok_or_else(not_found)?;
+        if versions.active == 0 {
+            return Err(ProtocolError::InvalidFormat(format!("Function has no version before {:?}", versions.active().function.version)));
+        }
+        versions.active -= 1;
+        Ok(versions.active().function.version.clone())
+    }
+
+    /// How many instances of the active version of function `id` are ready to execute
+    pub async fn warm_instances(&self, id: &str) -> Result<usize, ProtocolError> {
+        let functions = self.functions.read().await;
+        Ok(functions.get(id).ok_or_else(not_found)?.active().pool.warm())
+    }
+
+    /// Removes function `id` with all its versions
     pub async fn remove_function(&self, id: &str) -> Result<(), ProtocolError> {
         let mut functions = self.functions.write().await;
-        functions.remove(id).ok_or_else(|| {
-            ProtocolError::InvalidFormat("Function not found".into())
-        })?;
+        functions.remove(id).ok_or_else(not_found)?;
+        Ok(())
+    }
+
+    /// Schedules `trigger` from now on, replacing the trigger with its ID but keeping that one's history
+    pub async fn add_trigger(&self, trigger: Trigger) -> Result<(), ProtocolError> {
+        if !self.functions.read().await.contains_key(&trigger.function_id) {
+            return Err(not_found());
+        }
+        let next_run = trigger.schedule.next_after(SystemTime::now());
+        let mut triggers = self.triggers.lock().unwrap();
+        let (last_run, running, last_result) = match triggers.remove(&trigger.id) {
+            Some(replaced) => (replaced.status.last_run, replaced.status.running, replaced.status.last_result),
+            None => (None, 0, None),
+        };
+        let status = TriggerStatus { trigger, last_run, next_run, running, last_result };
+        triggers.insert(status.trigger.id.clone(), Scheduled { status, queued: false });
+        self.triggers_changed.notify_one();
+        Ok(())
+    }
+
+    /// Unschedules trigger `id`; executions it started run to completion
+    pub fn remove_trigger(&self, id: &str) -> Result<(), ProtocolError> {
+        self.triggers.lock().unwrap().remove(id).ok_or_else(|| ProtocolError::InvalidFormat("Trigger not found".into()))?;
+        self.triggers_changed.notify_one();
         Ok(())
     }
+
+    pub fn trigger_status(&self, id: &str) -> Option<TriggerStatus> {
+        self.triggers.lock().unwrap().get(id).map(|scheduled| scheduled.status.clone())
+    }
+
+    pub fn triggers(&self) -> Vec<TriggerStatus> {
+        self.triggers.lock().unwrap().values().map(|scheduled| scheduled.status.clone()).collect()
+    }
+
+    /// Fires triggers as their schedules come due until the returned task is aborted
+    pub fn start_scheduler(self: &Arc<Self>) -> JoinHandle<()> {
+        let compute = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let changed = compute.triggers_changed.notified();
+                let now = SystemTime::now();
+                let (mut due, mut wake) = (Vec::new(), None);
+                for (id, scheduled) in compute.triggers.lock().unwrap().iter_mut() {
+                    let status = &mut scheduled.status;
+                    if status.next_run.is_some_and(|next_run| next_run <= now) {
+                        status.next_run = status.trigger.schedule.next_after(now);
+                        due.push(id.clone());
+                    }
+                    wake = wake.into_iter().chain(status.next_run).min();
+                }
+                for id in due {
+                    compute.fire(&id);
+                }
+                match wake {
+                    Some(wake) => {
+                        tokio::select! {
+                            _ = tokio::time::sleep(wake.duration_since(SystemTime::now()).unwrap_or_default()) => {}
+                            _ = changed => {}
+                        }
+                    }
+                    None => changed.await,
+                }
+            }
+        })
+    }
+
+    // Starts an execution of trigger `id`, as its overlap policy allows
+    fn fire(self: &Arc<Self>, id: &str) {
+        let mut triggers = self.triggers.lock().unwrap();
+        let Some(scheduled) = triggers.get_mut(id) else { return };
+        if scheduled.status.running > 0 {
+            match scheduled.status.trigger.overlap {
+                OverlapPolicy::Skip => {
+                    tracing::debug!("Trigger {} fired while still running, skipping", id);
+                    return;
+                }
+                OverlapPolicy::Queue => {
+                    scheduled.queued = true;
+                    return;
+                }
+                OverlapPolicy::Allow => {}
+            }
+        }
+        scheduled.status.running += 1;
+        scheduled.status.last_run = Some(SystemTime::now());
+        tokio::spawn(run_trigger(self.clone(), id.to_string(), scheduled.status.trigger.clone()));
+    }
+}
+
+// Executes `trigger`, again while a firing was queued meanwhile
+async fn run_trigger(compute: Arc<EdgeCompute>, id: String, mut trigger: Trigger) {
+    loop {
+        let result = compute.execute_function(&trigger.function_id, trigger.input).await;
+        let mut triggers = compute.triggers.lock().unwrap();
+        let Some(scheduled) = triggers.get_mut(&id) else { return };
+        scheduled.status.last_result = Some(result.map_err(|e| e.to_string()));
+        if !scheduled.queued {
+            scheduled.status.running = scheduled.status.running.saturating_sub(1);
+            return;
+        }
+        scheduled.queued = false;
+        scheduled.status.last_run = Some(SystemTime::now());
+        trigger = scheduled.status.trigger.clone();
+    }
 }
\ No newline at end of file
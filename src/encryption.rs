@@ -1,35 +1,466 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
-use crate::ProtocolError;
+use chacha20poly1305::ChaCha20Poly1305;
+use crate::{flags::ExtensionFlags, kdf::hmac_sha256, keys::KeyProvider, secret::SecretKey, ProtocolError};
 use bytes::Bytes;
 use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
-/// Handles encryption and decryption of messages using AES-256-GCM
+/// Messages an encryptor seals with session nonces before it needs a new key
+pub const DEFAULT_REKEY_THRESHOLD: u64 = 1 << 32;
+
+/// How far behind the newest counter a session message may arrive and still be accepted
+pub const REPLAY_WINDOW: u64 = 1024;
+
+/// Length of the random header opening the first chunk of a chunked stream
+pub const STREAM_HEADER_LEN: usize = 32;
+
+// Separates chunked-stream keys from the message key they are derived from
+const STREAM_LABEL: &[u8] = b"remus/v1/stream";
+const HANDSHAKE_LABEL: &[u8] = b"remus/v1/handshake";
+
+/// AEAD algorithm an [`Encryptor`] seals payloads with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Cipher {
+    #[default]
+    Aes256Gcm,
+    /// Faster than AES-256-GCM on CPUs without AES instructions, e.g. many ARM boards
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Every supported cipher, most preferred first on this host
+    pub fn supported() -> [Cipher; 2] {
+        match Self::preferred() {
+            Cipher::Aes256Gcm => [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305],
+            Cipher::ChaCha20Poly1305 => [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm],
+        }
+    }
+
+    /// AES-256-GCM when the CPU accelerates AES, ChaCha20-Poly1305 otherwise
+    pub fn preferred() -> Cipher {
+        if aes_accelerated() {
+            Cipher::Aes256Gcm
+        } else {
+            Cipher::ChaCha20Poly1305
+        }
+    }
+
+    /// Picks the first of our `offered` ciphers that the peer also supports
+    pub fn negotiate(offered: &[Cipher], supported: &[Cipher]) -> Option<Cipher> {
+        offered.iter().copied().find(|cipher| supported.contains(cipher))
+    }
+
+    /// Capability string advertising the cipher, e.g. in `ServiceInfo::capabilities`
+    pub fn capability(&self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => "cipher/aes-256-gcm",
+            Cipher::ChaCha20Poly1305 => "cipher/chacha20-poly1305",
+        }
+    }
+
+    /// Collects the ciphers advertised among `capabilities`, keeping their order
+    pub fn from_capabilities<S: AsRef<str>>(capabilities: &[S]) -> Vec<Cipher> {
+        capabilities
+            .iter()
+            .filter_map(|capability| match capability.as_ref() {
+                "cipher/aes-256-gcm" => Some(Cipher::Aes256Gcm),
+                "cipher/chacha20-poly1305" => Some(Cipher::ChaCha20Poly1305),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The AEAD an [`Encryptor`] seals with, and the key derivation ending a
+/// handshake, for deployments that must use their own crypto module.
+///
+/// Implement it over a FIPS-validated module or a national algorithm such as
+/// SM4-GCM and pass it to [`Encryptor::with_provider`]; nonces, sessions,
+/// replay protection and chunked streams stay with the encryptor. Keys are
+/// 32 bytes and nonces 12, and `seal` must authenticate `aad`. [`Cipher`]
+/// is the built-in provider, AES-256-GCM by default.
+pub trait CryptoProvider: Send + Sync {
+    /// Capability string naming the algorithm, advertised like [`Cipher::capability`]
+    fn name(&self) -> &str;
+
+    /// Encrypts `data` and authenticates it along with `aad`, returning the ciphertext and tag
+    fn seal(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, ProtocolError>;
+
+    /// Reverses [`seal`](Self::seal), failing unless the ciphertext and `aad` authenticate
+    fn open(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, ProtocolError>;
+
+    /// Derives the initiator's and the responder's sending keys from the
+    /// `secret` a key exchange agreed on and the `transcript` it committed to.
+    ///
+    /// Defaults to HMAC-SHA256; override it to keep key derivation inside the module.
+    fn handshake(&self, secret: &[u8; 32], transcript: &[u8]) -> Result<(SecretKey, SecretKey), ProtocolError> {
+        Ok((
+            SecretKey::new(hmac_sha256(secret, &[HANDSHAKE_LABEL, transcript, b"initiator"])),
+            SecretKey::new(hmac_sha256(secret, &[HANDSHAKE_LABEL, transcript, b"responder"])),
+        ))
+    }
+}
+
+impl CryptoProvider for Cipher {
+    fn name(&self) -> &str {
+        self.capability()
+    }
+
+    fn seal(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        Backend::new(key, &Algorithm::Builtin(*self)).seal(nonce, aad, data)
+    }
+
+    fn open(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        Backend::new(key, &Algorithm::Builtin(*self)).open(nonce, aad, ciphertext)
+    }
+}
+
+fn aes_accelerated() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    return std::arch::is_x86_feature_detected!("aes");
+    #[cfg(target_arch = "aarch64")]
+    return std::arch::is_aarch64_feature_detected!("aes");
+    #[allow(unreachable_code)]
+    false
+}
+
+#[derive(Clone)]
+enum Algorithm {
+    Builtin(Cipher),
+    Custom(Arc<dyn CryptoProvider>),
+}
+
+enum Backend {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+    Custom(Arc<dyn CryptoProvider>, SecretKey),
+}
+
+impl Backend {
+    fn new(key: &[u8; 32], algorithm: &Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Builtin(Cipher::Aes256Gcm) => Backend::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            Algorithm::Builtin(Cipher::ChaCha20Poly1305) => Backend::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(key.into()))),
+            Algorithm::Custom(provider) => Backend::Custom(provider.clone(), SecretKey::new(*key)),
+        }
+    }
+
+    fn seal(&self, nonce: &[u8; 12], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Backend::Aes256Gcm(cipher) => cipher
+                .encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+                .map_err(|e| ProtocolError::EncryptionError(e.to_string())),
+            Backend::ChaCha20Poly1305(cipher) => cipher
+                .encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+                .map_err(|e| ProtocolError::EncryptionError(e.to_string())),
+            Backend::Custom(provider, key) => provider.seal(key, nonce, aad, data),
+        }
+    }
+
+    fn open(&self, nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Backend::Aes256Gcm(cipher) => cipher
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+                .map_err(|e| ProtocolError::EncryptionError(e.to_string())),
+            Backend::ChaCha20Poly1305(cipher) => cipher
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+                .map_err(|e| ProtocolError::EncryptionError(e.to_string())),
+            Backend::Custom(provider, key) => provider.open(key, nonce, aad, ciphertext),
+        }
+    }
+}
+
+/// Which end of a session an encryptor seals for; the two ends draw nonces from disjoint ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceDirection {
+    Initiator,
+    Responder,
+}
+
+impl NonceDirection {
+    fn prefix(self) -> [u8; 4] {
+        match self {
+            NonceDirection::Initiator => [0, 0, 0, 1],
+            NonceDirection::Responder => [0, 0, 0, 2],
+        }
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            NonceDirection::Initiator => NonceDirection::Responder,
+            NonceDirection::Responder => NonceDirection::Initiator,
+        }
+    }
+}
+
+struct Session {
+    direction: NonceDirection,
+    sealed: AtomicU64,
+    rekey_threshold: u64,
+    received: Mutex<ReplayWindow>,
+    replays_rejected: AtomicU64,
+}
+
+impl Session {
+    fn replay(&self) -> ProtocolError {
+        self.replays_rejected.fetch_add(1, Ordering::Relaxed);
+        metrics::increment_counter!("remus_replays_rejected_total");
+        ProtocolError::Replayed
+    }
+}
+
+/// Sliding-window replay detector over message counters.
+///
+/// Accepts each counter once, in any order, as long as it is less than
+/// [`REPLAY_WINDOW`] behind the highest counter accepted so far.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    // One past the highest accepted counter; zero before the first
+    next: u64,
+    seen: [u64; (REPLAY_WINDOW / 64) as usize],
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            next: 0,
+            seen: [0; (REPLAY_WINDOW / 64) as usize],
+        }
+    }
+
+    /// Returns whether `counter` would be accepted, without recording it
+    pub fn check(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        self.next - counter <= REPLAY_WINDOW && !self.bit(counter)
+    }
+
+    /// Records `counter`, returning false if it is a replay or too old
+    pub fn accept(&mut self, counter: u64) -> bool {
+        if !self.check(counter) {
+            return false;
+        }
+        if counter >= self.next {
+            // Forget the counters the window slides past
+            if counter - self.next >= REPLAY_WINDOW {
+                self.seen = [0; (REPLAY_WINDOW / 64) as usize];
+            } else {
+                for skipped in self.next..counter {
+                    self.set_bit(skipped, false);
+                }
+            }
+            self.next = counter + 1;
+        }
+        self.set_bit(counter, true);
+        true
+    }
+
+    fn bit(&self, counter: u64) -> bool {
+        let index = counter % REPLAY_WINDOW;
+        self.seen[(index / 64) as usize] & (1 << (index % 64)) != 0
+    }
+
+    fn set_bit(&mut self, counter: u64, value: bool) {
+        let index = counter % REPLAY_WINDOW;
+        let word = &mut self.seen[(index / 64) as usize];
+        if value {
+            *word |= 1 << (index % 64);
+        } else {
+            *word &= !(1 << (index % 64));
+        }
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handles encryption and decryption of messages using AES-256-GCM or ChaCha20-Poly1305.
+///
+/// Nonces are random by default. In session mode, set with
+/// [`with_session_nonces`](Self::with_session_nonces), each nonce is the
+/// direction's prefix followed by a message counter, so nonces never repeat
+/// under one key however many messages are sent. Once the rekey threshold is
+/// reached encryption fails until the encryptor is replaced by one with a new
+/// key. Share a session encryptor, e.g. across reconnects, instead of creating
+/// another one with the same key, which would start counting from zero again.
+///
+/// Session encryptors also reject replayed messages: each peer counter is
+/// accepted once, within a [`ReplayWindow`], and only after the message
+/// authenticates.
+///
+/// ChaCha20-Poly1305 key material is wiped when the encryptor is dropped; the
+/// AES-256-GCM key schedule belongs to the `aes-gcm` crate and is not.
+///
+/// [`with_provider`](Self::with_provider) seals with a custom
+/// [`CryptoProvider`] instead of a built-in cipher.
 pub struct Encryptor {
-    cipher: Aes256Gcm,
+    algorithm: Algorithm,
+    cipher: Backend,
+    // Set when the peer seals with a key of its own
+    receive: Option<Backend>,
+    session: Option<Session>,
+    // Keys the per-stream keys of chunked streams are derived from
+    stream_key: SecretKey,
+    receive_stream_key: Option<SecretKey>,
 }
 
 impl Encryptor {
-    /// Creates a new encryptor with the given 32-byte key
+    /// Creates a new AES-256-GCM encryptor with the given 32-byte key
     pub fn new(key: &[u8; 32]) -> Self {
+        Self::with_cipher(key, Cipher::Aes256Gcm)
+    }
+
+    /// Creates an encryptor using `cipher`; both peers must use the same one
+    pub fn with_cipher(key: &[u8; 32], cipher: Cipher) -> Self {
+        Self::with_algorithm(key, Algorithm::Builtin(cipher))
+    }
+
+    /// Creates an encryptor sealing with `provider`; both peers must use the same algorithm
+    pub fn with_provider(key: &[u8; 32], provider: Arc<dyn CryptoProvider>) -> Self {
+        Self::with_algorithm(key, Algorithm::Custom(provider))
+    }
+
+    /// Creates the `direction` end of a session from a key exchange's shared
+    /// `secret` and `transcript`, deriving each direction's key with
+    /// [`CryptoProvider::handshake`]
+    pub fn from_handshake(
+        provider: Arc<dyn CryptoProvider>,
+        secret: &[u8; 32],
+        transcript: &[u8],
+        direction: NonceDirection,
+    ) -> Result<Self, ProtocolError> {
+        let (initiator_key, responder_key) = provider.handshake(secret, transcript)?;
+        let (send_key, receive_key) = match direction {
+            NonceDirection::Initiator => (initiator_key, responder_key),
+            NonceDirection::Responder => (responder_key, initiator_key),
+        };
+        Ok(Self::with_provider(&send_key, provider)
+            .with_receive_key(&receive_key)
+            .with_session_nonces(direction))
+    }
+
+    fn with_algorithm(key: &[u8; 32], algorithm: Algorithm) -> Self {
         Self {
-            cipher: Aes256Gcm::new(key.into()),
+            cipher: Backend::new(key, &algorithm),
+            algorithm,
+            receive: None,
+            session: None,
+            stream_key: SecretKey::new(hmac_sha256(key, &[STREAM_LABEL])),
+            receive_stream_key: None,
+        }
+    }
+
+    /// Creates an encryptor using `cipher` with the current version of the provider's key `name`
+    pub async fn from_provider(provider: &dyn KeyProvider, name: &str, cipher: Cipher) -> Result<Self, ProtocolError> {
+        let key = provider.get_key(name).await?;
+        Ok(Self::with_cipher(&key, cipher))
+    }
+
+    /// Decrypts with `key` rather than the encryption key, for sessions whose
+    /// two directions each have their own key
+    pub fn with_receive_key(mut self, key: &[u8; 32]) -> Self {
+        self.receive = Some(Backend::new(key, &self.algorithm));
+        self.receive_stream_key = Some(SecretKey::new(hmac_sha256(key, &[STREAM_LABEL])));
+        self
+    }
+
+    /// Switches to counter nonces for the `direction` end of a session; the
+    /// peer must use the opposite direction with the same key
+    pub fn with_session_nonces(mut self, direction: NonceDirection) -> Self {
+        self.session = Some(Session {
+            direction,
+            sealed: AtomicU64::new(0),
+            rekey_threshold: DEFAULT_REKEY_THRESHOLD,
+            received: Mutex::new(ReplayWindow::new()),
+            replays_rejected: AtomicU64::new(0),
+        });
+        self
+    }
+
+    /// Sets how many messages a session encryptor seals before requiring a new key
+    pub fn with_rekey_threshold(mut self, messages: u64) -> Self {
+        if let Some(session) = &mut self.session {
+            session.rekey_threshold = messages;
+        }
+        self
+    }
+
+    /// Returns how many messages were sealed with session nonces
+    pub fn messages_sealed(&self) -> u64 {
+        self.session.as_ref().map_or(0, |session| session.sealed.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many replayed or outdated messages decryption rejected
+    pub fn replays_rejected(&self) -> u64 {
+        self.session.as_ref().map_or(0, |session| session.replays_rejected.load(Ordering::Relaxed))
+    }
+
+    /// Returns whether the session has used up its nonces and encryption now fails
+    pub fn needs_rekey(&self) -> bool {
+        self.session
+            .as_ref()
+            .is_some_and(|session| session.sealed.load(Ordering::Relaxed) >= session.rekey_threshold)
+    }
+
+    fn next_nonce(&self) -> Result<[u8; 12], ProtocolError> {
+        let mut nonce = [0u8; 12];
+        let Some(session) = &self.session else {
+            rand::thread_rng().fill(&mut nonce);
+            return Ok(nonce);
+        };
+        let counter = session
+            .sealed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sealed| {
+                (sealed < session.rekey_threshold).then_some(sealed + 1)
+            })
+            .map_err(|_| ProtocolError::EncryptionError("Rekey threshold reached; a new key is required".into()))?;
+        nonce[..4].copy_from_slice(&session.direction.prefix());
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        Ok(nonce)
+    }
+
+    /// The built-in cipher, or `None` when sealing with a custom [`CryptoProvider`]
+    pub fn cipher(&self) -> Option<Cipher> {
+        match self.algorithm {
+            Algorithm::Builtin(cipher) => Some(cipher),
+            Algorithm::Custom(_) => None,
+        }
+    }
+
+    /// Capability string naming the algorithm, for peers to check they match
+    pub fn capability(&self) -> &str {
+        match &self.algorithm {
+            Algorithm::Builtin(cipher) => cipher.capability(),
+            Algorithm::Custom(provider) => provider.name(),
+        }
+    }
+
+    /// [`ExtensionFlags::CUSTOM_CRYPTO`] when sealing with a custom [`CryptoProvider`]
+    pub fn extensions(&self) -> ExtensionFlags {
+        match self.algorithm {
+            Algorithm::Builtin(_) => ExtensionFlags::empty(),
+            Algorithm::Custom(_) => ExtensionFlags::CUSTOM_CRYPTO,
         }
     }
 
     /// Encrypts data with a random nonce and returns the concatenated nonce + ciphertext
     pub fn encrypt(&self, data: &[u8]) -> Result<Bytes, ProtocolError> {
-        let mut rng = rand::thread_rng();
-        let mut nonce_bytes = [0u8; 12];
-        rng.fill(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+        self.encrypt_with_aad(data, &[])
+    }
 
-        let ciphertext = self
-            .cipher
-            .encrypt(nonce, data)
-            .map_err(|e| ProtocolError::EncryptionError(e.to_string()))?;
+    /// Like [`encrypt`](Self::encrypt), also authenticating `aad`; decrypting
+    /// fails unless the same associated data is supplied
+    pub fn encrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<Bytes, ProtocolError> {
+        let nonce_bytes = self.next_nonce()?;
+        let ciphertext = self.cipher.seal(&nonce_bytes, aad, data)?;
 
         // Combine nonce and ciphertext
         let mut result = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
@@ -41,26 +472,162 @@ impl Encryptor {
 
     /// Decrypts data that was encrypted with encrypt()
     pub fn decrypt(&self, data: &[u8]) -> Result<Bytes, ProtocolError> {
+        self.decrypt_with_aad(data, &[])
+    }
+
+    /// Decrypts data that was encrypted with encrypt_with_aad() and the same `aad`
+    pub fn decrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<Bytes, ProtocolError> {
         if data.len() < 12 {
             return Err(ProtocolError::EncryptionError("Data too short".into()));
         }
 
         let (nonce_bytes, ciphertext) = data.split_at(12);
-        let nonce = Nonce::from_slice(nonce_bytes);
+        let nonce_bytes: &[u8; 12] = nonce_bytes.try_into().unwrap();
+        // A frame sealed by this end and reflected back carries our own prefix
+        let counter = u64::from_be_bytes(nonce_bytes[4..].try_into().unwrap());
+        if let Some(session) = &self.session {
+            if nonce_bytes[..4] != session.direction.peer().prefix() {
+                return Err(ProtocolError::EncryptionError("Nonce from the wrong session direction".into()));
+            }
+            if !session.received.lock().unwrap().check(counter) {
+                return Err(session.replay());
+            }
+        }
+
+        let plaintext = self.receive.as_ref().unwrap_or(&self.cipher).open(nonce_bytes, aad, ciphertext)?;
+
+        // Recorded only once authentic, so forged frames cannot move the window
+        if let Some(session) = &self.session {
+            if !session.received.lock().unwrap().accept(counter) {
+                return Err(session.replay());
+            }
+        }
+
+        Ok(Bytes::from(plaintext))
+    }
+
+    /// Starts sealing a payload too large for one message as a chunked stream
+    pub fn stream_sealer(&self) -> StreamSealer {
+        let mut header = [0u8; STREAM_HEADER_LEN];
+        rand::thread_rng().fill(&mut header);
+        StreamSealer {
+            backend: stream_backend(&self.stream_key, &header, &self.algorithm),
+            header: Some(header),
+            chunks: ChunkCounter::default(),
+        }
+    }
+
+    /// Starts opening a chunked stream sealed by the peer's [`stream_sealer`](Self::stream_sealer)
+    pub fn stream_opener(&self) -> StreamOpener {
+        StreamOpener {
+            stream_key: self.receive_stream_key.as_ref().unwrap_or(&self.stream_key).clone(),
+            algorithm: self.algorithm.clone(),
+            backend: None,
+            chunks: ChunkCounter::default(),
+        }
+    }
+
+    /// Generates a random encryption key, wiped from memory when dropped
+    pub fn generate_key() -> SecretKey {
+        SecretKey::generate()
+    }
+}
+
+fn stream_backend(stream_key: &SecretKey, header: &[u8; STREAM_HEADER_LEN], algorithm: &Algorithm) -> Backend {
+    Backend::new(&SecretKey::new(hmac_sha256(stream_key.as_bytes(), &[header])), algorithm)
+}
+
+// Position in a chunked stream; each nonce is the chunk index and a final-chunk marker
+#[derive(Default)]
+struct ChunkCounter {
+    next: u64,
+    finished: bool,
+}
+
+impl ChunkCounter {
+    fn nonce(&self, last: bool) -> Result<[u8; 12], ProtocolError> {
+        if self.finished {
+            return Err(ProtocolError::EncryptionError("Chunked stream already finished".into()));
+        }
+        let mut nonce = [0u8; 12];
+        nonce[3..11].copy_from_slice(&self.next.to_be_bytes());
+        nonce[11] = last as u8;
+        Ok(nonce)
+    }
+
+    fn advance(&mut self, last: bool) {
+        self.next += 1;
+        self.finished = last;
+    }
+}
+
+/// Seals a payload as a sequence of chunks, using the STREAM construction.
+///
+/// Every chunk is a separate AEAD message with its own tag, under a key
+/// derived for this stream alone. Its nonce encodes the chunk's index and
+/// whether it is the last, so a [`StreamOpener`] detects chunks that were
+/// reordered, dropped, or cut off before the final one. The first chunk
+/// starts with [`STREAM_HEADER_LEN`] random bytes the stream key is derived
+/// from.
+pub struct StreamSealer {
+    backend: Backend,
+    // Sent ahead of the first chunk
+    header: Option<[u8; STREAM_HEADER_LEN]>,
+    chunks: ChunkCounter,
+}
 
-        let plaintext = self
-            .cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| ProtocolError::EncryptionError(e.to_string()))?;
+impl StreamSealer {
+    /// Seals the next chunk; `last` marks the final one, after which sealing fails
+    pub fn seal(&mut self, chunk: &[u8], aad: &[u8], last: bool) -> Result<Bytes, ProtocolError> {
+        let ciphertext = self.backend.seal(&self.chunks.nonce(last)?, aad, chunk)?;
+        self.chunks.advance(last);
+        let Some(header) = self.header.take() else {
+            return Ok(Bytes::from(ciphertext));
+        };
+        let mut sealed = Vec::with_capacity(STREAM_HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(&header);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(Bytes::from(sealed))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.chunks.finished
+    }
+}
 
+/// Opens the chunks of a stream sealed by a [`StreamSealer`], in order
+pub struct StreamOpener {
+    stream_key: SecretKey,
+    algorithm: Algorithm,
+    // Derived once the first chunk brings the stream header
+    backend: Option<Backend>,
+    chunks: ChunkCounter,
+}
+
+impl StreamOpener {
+    /// Opens the next chunk, which must have been sealed with the same `aad` and `last`
+    pub fn open(&mut self, chunk: &[u8], aad: &[u8], last: bool) -> Result<Bytes, ProtocolError> {
+        let nonce = self.chunks.nonce(last)?;
+        let plaintext = match &self.backend {
+            Some(backend) => backend.open(&nonce, aad, chunk)?,
+            None => {
+                if chunk.len() < STREAM_HEADER_LEN {
+                    return Err(ProtocolError::EncryptionError("Data too short".into()));
+                }
+                let (header, ciphertext) = chunk.split_at(STREAM_HEADER_LEN);
+                let backend = stream_backend(&self.stream_key, header.try_into().unwrap(), &self.algorithm);
+                let plaintext = backend.open(&nonce, aad, ciphertext)?;
+                self.backend = Some(backend);
+                plaintext
+            }
+        };
+        self.chunks.advance(last);
         Ok(Bytes::from(plaintext))
     }
 
-    /// Helper function to generate a random encryption key
-    pub fn generate_key() -> [u8; 32] {
-        let mut key = [0u8; 32];
-        rand::thread_rng().fill(&mut key);
-        key
+    /// Returns whether the final chunk has been opened; until then the payload may be cut short
+    pub fn is_finished(&self) -> bool {
+        self.chunks.finished
     }
 }
 
@@ -116,6 +683,145 @@ mod tests {
         assert!(encryptor.decrypt(&[1, 2, 3]).is_err());
     }
 
+    #[test]
+    fn test_associated_data_must_match() {
+        let key = Encryptor::generate_key();
+        for cipher in Cipher::supported() {
+            let encryptor = Encryptor::with_cipher(&key, cipher);
+            let encrypted = encryptor.encrypt_with_aad(b"payload", b"header").unwrap();
+            assert_eq!(encryptor.decrypt_with_aad(&encrypted, b"header").unwrap(), Bytes::from("payload"));
+            assert!(encryptor.decrypt_with_aad(&encrypted, b"other").is_err());
+            assert!(encryptor.decrypt(&encrypted).is_err());
+        }
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_cipher() {
+        let key = Encryptor::generate_key();
+        let encryptor = Encryptor::with_cipher(&key, Cipher::ChaCha20Poly1305);
+        let encrypted = encryptor.encrypt(b"edge telemetry").unwrap();
+        assert_eq!(encryptor.decrypt(&encrypted).unwrap(), Bytes::from("edge telemetry"));
+
+        // The ciphers are not interchangeable
+        assert!(Encryptor::new(&key).decrypt(&encrypted).is_err());
+
+        let advertised = Cipher::from_capabilities(&["compression", Cipher::ChaCha20Poly1305.capability()]);
+        assert_eq!(Cipher::negotiate(&Cipher::supported(), &advertised), Some(Cipher::ChaCha20Poly1305));
+        assert_eq!(Cipher::negotiate(&[Cipher::Aes256Gcm], &advertised), None);
+    }
+
+    #[test]
+    fn test_session_nonces_count_per_direction() {
+        let key = Encryptor::generate_key();
+        let client = Encryptor::new(&key).with_session_nonces(NonceDirection::Initiator).with_rekey_threshold(2);
+        let server = Encryptor::new(&key).with_session_nonces(NonceDirection::Responder);
+
+        let first = client.encrypt(b"one").unwrap();
+        let second = client.encrypt(b"two").unwrap();
+        assert_eq!(first[..12], [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(second[..12], [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(server.decrypt(&second).unwrap(), Bytes::from("two"));
+
+        // Our own frames reflected back are rejected
+        assert!(client.decrypt(&first).is_err());
+
+        assert!(client.needs_rekey());
+        assert!(client.encrypt(b"three").is_err());
+        assert_eq!(client.messages_sealed(), 2);
+    }
+
+    #[test]
+    fn test_session_rejects_replays() {
+        let key = Encryptor::generate_key();
+        let client = Encryptor::new(&key).with_session_nonces(NonceDirection::Initiator);
+        let server = Encryptor::new(&key).with_session_nonces(NonceDirection::Responder);
+
+        let sealed: Vec<Bytes> = (0..REPLAY_WINDOW + 2).map(|_| client.encrypt(b"frame").unwrap()).collect();
+        server.decrypt(&sealed[1]).unwrap();
+        server.decrypt(&sealed[0]).unwrap();
+        assert!(matches!(server.decrypt(&sealed[1]), Err(ProtocolError::Replayed)));
+
+        server.decrypt(&sealed[REPLAY_WINDOW as usize + 1]).unwrap();
+        // sealed[1] is now exactly a window behind, sealed[2] still inside it
+        assert!(server.decrypt(&sealed[2]).is_ok());
+        assert!(server.decrypt(&sealed[0]).is_err());
+        assert_eq!(server.replays_rejected(), 2);
+
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(5) && window.accept(3) && !window.accept(3));
+        assert!(window.accept(5000) && !window.check(5000 - REPLAY_WINDOW) && window.check(5000 - REPLAY_WINDOW + 1));
+    }
+
+    #[test]
+    fn test_chunked_stream_detects_reordering_and_truncation() {
+        let encryptor = Encryptor::with_cipher(&Encryptor::generate_key(), Cipher::ChaCha20Poly1305);
+        let mut sealer = encryptor.stream_sealer();
+        let chunks = [b"first".as_slice(), b"second", b"last"];
+        let sealed: Vec<Bytes> = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| sealer.seal(chunk, b"aad", i == 2).unwrap())
+            .collect();
+        assert!(sealer.is_finished());
+        assert!(sealer.seal(b"more", b"aad", true).is_err());
+
+        let mut opener = encryptor.stream_opener();
+        for (i, chunk) in sealed.iter().enumerate() {
+            assert_eq!(opener.open(chunk, b"aad", i == 2).unwrap(), Bytes::from(chunks[i]));
+        }
+        assert!(opener.is_finished());
+
+        // Skipping a chunk, or passing off a middle chunk as the last, fails
+        let mut opener = encryptor.stream_opener();
+        opener.open(&sealed[0], b"aad", false).unwrap();
+        assert!(opener.open(&sealed[2], b"aad", true).is_err());
+        assert!(opener.open(&sealed[1], b"aad", true).is_err());
+        assert!(!opener.is_finished());
+    }
+
+    // Seals with ChaCha20-Poly1305 under its own name, counting calls
+    #[derive(Default)]
+    struct CountingProvider(AtomicU64);
+
+    impl CryptoProvider for CountingProvider {
+        fn name(&self) -> &str {
+            "cipher/test"
+        }
+
+        fn seal(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Cipher::ChaCha20Poly1305.seal(key, nonce, aad, data)
+        }
+
+        fn open(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Cipher::ChaCha20Poly1305.open(key, nonce, aad, ciphertext)
+        }
+    }
+
+    #[test]
+    fn test_custom_provider_seals_sessions_and_streams() {
+        let provider = Arc::new(CountingProvider::default());
+        let secret = Encryptor::generate_key();
+        let client = Encryptor::from_handshake(provider.clone(), &secret, b"transcript", NonceDirection::Initiator).unwrap();
+        let server = Encryptor::from_handshake(provider.clone(), &secret, b"transcript", NonceDirection::Responder).unwrap();
+        assert_eq!((client.cipher(), client.capability()), (None, "cipher/test"));
+        assert_eq!(client.extensions(), ExtensionFlags::CUSTOM_CRYPTO);
+        assert!(Encryptor::new(&secret).extensions().is_empty());
+
+        let sealed = client.encrypt_with_aad(b"reading", b"aad").unwrap();
+        assert_eq!(server.decrypt_with_aad(&sealed, b"aad").unwrap(), Bytes::from("reading"));
+        // Each direction has its own key
+        let reply = server.encrypt(b"ack").unwrap();
+        assert_eq!(client.decrypt(&reply).unwrap(), Bytes::from("ack"));
+        let other = Encryptor::from_handshake(provider.clone(), &secret, b"other", NonceDirection::Responder).unwrap();
+        assert!(other.decrypt(&client.encrypt(b"reading").unwrap()).is_err());
+
+        let chunk = client.stream_sealer().seal(b"chunk", b"", true).unwrap();
+        assert_eq!(server.stream_opener().open(&chunk, b"", true).unwrap(), Bytes::from("chunk"));
+        assert_eq!(provider.0.load(Ordering::Relaxed), 8);
+    }
+
     #[test]
     fn test_key_generation() {
         let key1 = Encryptor::generate_key();
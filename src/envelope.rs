@@ -0,0 +1,156 @@
+//! Multi-recipient envelope encryption.
+//!
+//! An [`EnvelopeSealer`] encrypts a payload once under a fresh content key,
+//! then wraps that key for each recipient's X25519 public key, the
+//! [`NoiseKeypair`] they already hold. Each recipient opens the envelope with
+//! [`open_envelope`]. Sealing an Event before
+//! [`Broker::publish`](crate::Broker::publish) therefore keeps it confidential
+//! to its subscribers without re-encrypting it per subscriber.
+//!
+//! An envelope is laid out as follows; the body authenticates everything
+//! before it, so recipients cannot be added, removed or swapped.
+//!
+//! ```text
+//! ephemeral public key (32) | recipient count (u16)
+//! { recipient public key (32) | wrapped content key (48) } per recipient
+//! ChaCha20-Poly1305 body
+//! ```
+
+use crate::{kdf::hmac_sha256, noise::NoiseKeypair, ProtocolError};
+use bytes::{BufMut, Bytes};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use rand::Rng;
+use zeroize::Zeroize;
+
+const WRAP_LABEL: &[u8] = b"remus/envelope/v1/wrap";
+const KEY_LEN: usize = 32;
+const WRAPPED_LEN: usize = KEY_LEN + 16;
+const HEADER_LEN: usize = KEY_LEN + 2;
+const RECIPIENT_LEN: usize = KEY_LEN + WRAPPED_LEN;
+// Every key is used for one message, so a fixed nonce is safe
+const NONCE: [u8; 12] = [0; 12];
+
+/// Seals payloads for a fixed set of recipients
+#[derive(Debug, Clone, Default)]
+pub struct EnvelopeSealer {
+    recipients: Vec<[u8; KEY_LEN]>,
+}
+
+impl EnvelopeSealer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the recipient holding the keypair whose public key is `public_key`
+    pub fn with_recipient(mut self, public_key: [u8; KEY_LEN]) -> Self {
+        if !self.recipients.contains(&public_key) {
+            self.recipients.push(public_key);
+        }
+        self
+    }
+
+    /// Encrypts `payload` once for every recipient, binding `aad` to it.
+    ///
+    /// Fails if there are no recipients, more than `u16::MAX`, or one of
+    /// them is a low-order point.
+    pub fn seal(&self, payload: &[u8], aad: &[u8]) -> Result<Bytes, ProtocolError> {
+        if self.recipients.is_empty() || self.recipients.len() > u16::MAX as usize {
+            return Err(envelope_error("Envelope needs between 1 and 65535 recipients"));
+        }
+        let mut content_key = [0u8; KEY_LEN];
+        rand::thread_rng().fill(&mut content_key);
+        let ephemeral = NoiseKeypair::generate();
+
+        let mut envelope = Vec::with_capacity(HEADER_LEN + self.recipients.len() * RECIPIENT_LEN + payload.len() + 16);
+        envelope.extend_from_slice(&ephemeral.public_key());
+        envelope.put_u16(self.recipients.len() as u16);
+        for recipient in &self.recipients {
+            let shared = ephemeral.dh(recipient).map_err(|_| envelope_error("Recipient key is a low-order point"))?;
+            let wrap_key = wrap_key(shared, &ephemeral.public_key(), recipient);
+            envelope.extend_from_slice(recipient);
+            envelope.extend_from_slice(&seal(&wrap_key, WRAP_LABEL, &content_key)?);
+        }
+        let body = seal(&content_key, &[&envelope[..], aad].concat(), payload);
+        content_key.zeroize();
+        envelope.extend_from_slice(&body?);
+        Ok(Bytes::from(envelope))
+    }
+}
+
+/// Opens an envelope sealed for `keypair` with the same `aad`
+pub fn open_envelope(keypair: &NoiseKeypair, envelope: &[u8], aad: &[u8]) -> Result<Bytes, ProtocolError> {
+    if envelope.len() < HEADER_LEN {
+        return Err(envelope_error("Truncated envelope"));
+    }
+    let ephemeral: [u8; KEY_LEN] = envelope[..KEY_LEN].try_into().unwrap();
+    let count = u16::from_be_bytes([envelope[KEY_LEN], envelope[KEY_LEN + 1]]) as usize;
+    let body_start = HEADER_LEN + count * RECIPIENT_LEN;
+    if envelope.len() < body_start {
+        return Err(envelope_error("Truncated envelope"));
+    }
+    let public_key = keypair.public_key();
+    let wrapped = envelope[HEADER_LEN..body_start]
+        .chunks_exact(RECIPIENT_LEN)
+        .find(|entry| entry[..KEY_LEN] == public_key)
+        .map(|entry| &entry[KEY_LEN..])
+        .ok_or_else(|| envelope_error("Envelope is not sealed for this key"))?;
+
+    let shared = keypair.dh(&ephemeral).map_err(|_| envelope_error("Malformed envelope"))?;
+    let wrap_key = wrap_key(shared, &ephemeral, &public_key);
+    let mut content_key: [u8; KEY_LEN] = open(&wrap_key, WRAP_LABEL, wrapped)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| envelope_error("Content key does not authenticate"))?;
+    let (header, body) = envelope.split_at(body_start);
+    let payload = open(&content_key, &[header, aad].concat(), body);
+    content_key.zeroize();
+    payload.map(Bytes::from).ok_or_else(|| envelope_error("Envelope does not authenticate"))
+}
+
+fn seal(key: &[u8; KEY_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(&NONCE.into(), Payload { msg: plaintext, aad })
+        .map_err(|_| envelope_error("Payload too large to seal"))
+}
+
+fn open(key: &[u8; KEY_LEN], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into()).decrypt(&NONCE.into(), Payload { msg: sealed, aad }).ok()
+}
+
+fn wrap_key(mut shared: [u8; KEY_LEN], ephemeral: &[u8; KEY_LEN], recipient: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let key = hmac_sha256(&shared, &[WRAP_LABEL, ephemeral, recipient]);
+    shared.zeroize();
+    key
+}
+
+fn envelope_error(reason: &str) -> ProtocolError {
+    ProtocolError::EncryptionError(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_recipient_opens_the_same_envelope() {
+        let (alice, bob, eve) = (NoiseKeypair::generate(), NoiseKeypair::generate(), NoiseKeypair::generate());
+        let sealer = EnvelopeSealer::new().with_recipient(alice.public_key()).with_recipient(bob.public_key());
+        let envelope = sealer.seal(b"price update", b"prices").unwrap();
+
+        assert_eq!(&open_envelope(&alice, &envelope, b"prices").unwrap()[..], b"price update");
+        assert_eq!(&open_envelope(&bob, &envelope, b"prices").unwrap()[..], b"price update");
+        assert!(open_envelope(&eve, &envelope, b"prices").is_err());
+        assert!(open_envelope(&alice, &envelope, b"orders").is_err());
+
+        // Dropping a recipient breaks the body's authentication
+        let mut stripped = envelope[..KEY_LEN].to_vec();
+        stripped.put_u16(1);
+        stripped.extend_from_slice(&envelope[HEADER_LEN..HEADER_LEN + RECIPIENT_LEN]);
+        stripped.extend_from_slice(&envelope[HEADER_LEN + 2 * RECIPIENT_LEN..]);
+        assert!(open_envelope(&alice, &stripped, b"prices").is_err());
+
+        assert!(EnvelopeSealer::new().seal(b"nobody", b"").is_err());
+    }
+}
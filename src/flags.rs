@@ -18,6 +18,11 @@ bitflags! {
         const COMPRESSION_LZ4  = 0x1000;
         const TLS_1_3         = 0x2000;
         const QUIC            = 0x4000;
+        const NOISE_XX        = 0x8000;
+        const NOISE_IK        = 0x10000;
+        const SESSION_RESUMPTION = 0x20000;
+        const HYBRID_PQ       = 0x40000;
+        const COMPRESSION_SNAPPY = 0x80000;
     }
 
     #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -46,4 +51,15 @@ impl ProtocolVersion {
     pub fn is_compatible(&self, other: &Self) -> bool {
         self.major == other.major && self.minor >= other.minor
     }
-} 
\ No newline at end of file
+} 
+impl CapabilityFlags {
+    /// The flags' names as services advertise them, e.g. `edge_compute`
+    pub fn names(&self) -> Vec<String> {
+        self.iter_names().map(|(name, _)| name.to_ascii_lowercase()).collect()
+    }
+
+    /// The flag called `name`, in any case
+    pub fn from_capability(name: &str) -> Option<Self> {
+        Self::from_name(&name.to_ascii_uppercase())
+    }
+}
@@ -0,0 +1,269 @@
+//! Registry synchronization by anti-entropy gossip.
+//!
+//! A [`Gossip`] keeps its node's [`ServiceRegistry`] converging on the same
+//! membership as its peers' without a registry server. Every round it picks
+//! one peer at random and reconciles with it over [`GOSSIP_ROUTE`]: it sends a
+//! digest of the version it holds of each service, the peer answers with the
+//! entries it holds newer and names the ones it is missing, and those are
+//! pushed back. A peer that cannot be reached is skipped until a later round.
+//!
+//! A service's version is its `last_seen`. Nodes refresh the services they
+//! [`advertise`](Gossip::advertise) every round, so a node that fails stops
+//! refreshing them and they expire everywhere by the registry's TTL. Services
+//! [`withdraw`](Gossip::withdraw)n leave a tombstone that gossips like any
+//! other entry. Versions are compared across nodes, so their clocks should
+//! roughly agree.
+
+use crate::{
+    client::RemusClient,
+    discovery::{ServiceInfo, ServiceRegistry},
+    server::{Handler, Request},
+    ProtocolError,
+};
+use bytes::Bytes;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Route gossip exchanges are sent to; mount [`Gossip::handler`] on it
+pub const GOSSIP_ROUTE: &str = "remus/gossip";
+
+const DEFAULT_TOMBSTONE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Exchange {
+    // The version held of each service and tombstone
+    Digest(HashMap<String, u64>),
+    Delta { entries: Vec<Entry>, wanted: Vec<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    id: String,
+    version: u64,
+    // None for a withdrawn service
+    service: Option<ServiceInfo>,
+}
+
+/// Gossips a registry's membership with peer nodes
+pub struct Gossip {
+    registry: Arc<ServiceRegistry>,
+    local: Mutex<HashSet<String>>,
+    tombstones: Mutex<HashMap<String, u64>>,
+    tombstone_ttl: Duration,
+}
+
+impl Gossip {
+    pub fn new(registry: Arc<ServiceRegistry>) -> Self {
+        Self {
+            registry,
+            local: Mutex::new(HashSet::new()),
+            tombstones: Mutex::new(HashMap::new()),
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+        }
+    }
+
+    /// Sets how long withdrawn services are remembered, which should exceed the time a change takes to spread
+    pub fn with_tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// Registers a service this node runs, keeping it alive for as long as the node gossips
+    pub async fn advertise(&self, mut service: ServiceInfo) {
+        service.last_seen = SystemTime::now();
+        self.tombstones.lock().unwrap().remove(&service.id);
+        self.local.lock().unwrap().insert(service.id.clone());
+        self.registry.register(service).await;
+    }
+
+    /// Removes a service everywhere the registry is gossiped
+    pub async fn withdraw(&self, id: &str) {
+        self.local.lock().unwrap().remove(id);
+        self.tombstones.lock().unwrap().insert(id.to_string(), millis(SystemTime::now()));
+        self.registry.unregister(id).await;
+    }
+
+    /// Gossips with a random one of `peers` every `interval`, forever
+    pub async fn run(&self, peers: &[RemusClient], interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(peer) = peers.choose(&mut rand::thread_rng()) else {
+                continue;
+            };
+            if let Err(e) = self.sync_with(peer).await {
+                tracing::debug!("Gossip round failed: {}", e);
+            }
+        }
+    }
+
+    /// Reconciles the registry with one peer's, in both directions
+    pub async fn sync_with(&self, peer: &RemusClient) -> Result<(), ProtocolError> {
+        self.refresh_local().await;
+        let Exchange::Delta { entries, wanted } = peer.call(GOSSIP_ROUTE, &Exchange::Digest(self.digest().await)).await? else {
+            return Err(ProtocolError::InvalidFormat("Gossip peer answered a digest with a digest".into()));
+        };
+        self.merge(entries).await;
+        if !wanted.is_empty() {
+            let push = Exchange::Delta { entries: self.entries(&wanted).await, wanted: Vec::new() };
+            peer.call::<_, Exchange>(GOSSIP_ROUTE, &push).await?;
+        }
+        Ok(())
+    }
+
+    /// Answers peers' gossip; mount it at [`GOSSIP_ROUTE`] on the node's router
+    pub fn handler(self: &Arc<Self>) -> impl Handler + 'static {
+        let gossip = self.clone();
+        move |request: Request| {
+            let gossip = gossip.clone();
+            async move {
+                let reply = match request.deserialize()? {
+                    Exchange::Digest(theirs) => gossip.answer(theirs).await,
+                    Exchange::Delta { entries, .. } => {
+                        gossip.merge(entries).await;
+                        Exchange::Delta { entries: Vec::new(), wanted: Vec::new() }
+                    }
+                };
+                Ok(Bytes::from(serde_json::to_vec(&reply).expect("gossip exchange serializes")))
+            }
+        }
+    }
+
+    // The entries we hold newer than `theirs`, and the IDs they hold newer than us
+    async fn answer(&self, theirs: HashMap<String, u64>) -> Exchange {
+        self.refresh_local().await;
+        let ours = self.digest().await;
+        let newer: Vec<String> = ours.iter().filter(|(id, version)| theirs.get(*id).is_none_or(|v| v < version)).map(|(id, _)| id.clone()).collect();
+        let wanted = theirs.into_iter().filter(|(id, version)| ours.get(id).is_none_or(|v| v < version)).map(|(id, _)| id).collect();
+        Exchange::Delta { entries: self.entries(&newer).await, wanted }
+    }
+
+    async fn digest(&self) -> HashMap<String, u64> {
+        let mut digest: HashMap<String, u64> = self.live_tombstones().into_iter().collect();
+        for service in self.registry.query(|_| true).await {
+            digest.insert(service.id.clone(), millis(service.last_seen));
+        }
+        digest
+    }
+
+    async fn entries(&self, ids: &[String]) -> Vec<Entry> {
+        let tombstones = self.live_tombstones();
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            let entry = match self.registry.get_service(id).await {
+                Some(service) => Entry { id: id.clone(), version: millis(service.last_seen), service: Some(service) },
+                None => match tombstones.get(id) {
+                    Some(&version) => Entry { id: id.clone(), version, service: None },
+                    None => continue,
+                },
+            };
+            entries.push(entry);
+        }
+        entries
+    }
+
+    // Keeps each entry newer than what we hold; our own services only we update
+    async fn merge(&self, entries: Vec<Entry>) {
+        for entry in entries {
+            if self.local.lock().unwrap().contains(&entry.id) {
+                continue;
+            }
+            let held = match self.registry.get_service(&entry.id).await {
+                Some(service) => Some(millis(service.last_seen)),
+                None => self.tombstones.lock().unwrap().get(&entry.id).copied(),
+            };
+            if held.is_some_and(|held| held >= entry.version) {
+                continue;
+            }
+            match entry.service {
+                Some(service) => {
+                    self.tombstones.lock().unwrap().remove(&entry.id);
+                    self.registry.register(service).await;
+                }
+                None => {
+                    self.tombstones.lock().unwrap().insert(entry.id.clone(), entry.version);
+                    self.registry.unregister(&entry.id).await;
+                }
+            }
+        }
+    }
+
+    async fn refresh_local(&self) {
+        let local: Vec<String> = self.local.lock().unwrap().iter().cloned().collect();
+        for id in local {
+            if let Some(mut service) = self.registry.get_service(&id).await {
+                service.last_seen = SystemTime::now();
+                self.registry.register(service).await;
+            }
+        }
+    }
+
+    // Forgets tombstones older than their TTL, returning the rest
+    fn live_tombstones(&self) -> HashMap<String, u64> {
+        let oldest = millis(SystemTime::now()).saturating_sub(self.tombstone_ttl.as_millis() as u64);
+        let mut tombstones = self.tombstones.lock().unwrap();
+        tombstones.retain(|_, removed| *removed >= oldest);
+        tombstones.clone()
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::ClientConfig, discovery::tests::create_test_service, server::{Router, Server}};
+    use tokio::net::TcpListener;
+
+    async fn node() -> (Arc<Gossip>, RemusClient) {
+        let gossip = Arc::new(Gossip::new(Arc::new(ServiceRegistry::new(Duration::from_secs(60)))));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(Router::new().with_route(GOSSIP_ROUTE, gossip.handler()));
+        tokio::spawn(async move { server.serve(listener).await });
+        (gossip, RemusClient::connect(&address).await.unwrap())
+    }
+
+    async fn ids(gossip: &Gossip) -> Vec<String> {
+        let mut ids: Vec<String> = gossip.registry.query(|_| true).await.into_iter().map(|service| service.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_registries_converge_and_withdrawals_spread() {
+        let (a, _) = node().await;
+        let (b, b_client) = node().await;
+        let (c, c_client) = node().await;
+        a.advertise(create_test_service("a")).await;
+        b.advertise(create_test_service("b")).await;
+        c.advertise(create_test_service("c")).await;
+
+        // a learns b's view and hands over its own, then does the same with c
+        a.sync_with(&b_client).await.unwrap();
+        a.sync_with(&c_client).await.unwrap();
+        assert_eq!(ids(&a).await, ["a", "b", "c"]);
+        assert_eq!(ids(&c).await, ["a", "b", "c"]);
+        assert_eq!(ids(&b).await, ["a", "b"]);
+        a.sync_with(&b_client).await.unwrap();
+        assert_eq!(ids(&b).await, ["a", "b", "c"]);
+
+        c.withdraw("c").await;
+        a.sync_with(&c_client).await.unwrap();
+        a.sync_with(&b_client).await.unwrap();
+        assert_eq!(ids(&b).await, ["a", "b"]);
+
+        // An unreachable peer fails the round without touching the registry
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let gone = RemusClient::new(ClientConfig::from_addresses(&address).unwrap()).with_timeout(Duration::from_millis(500));
+        assert!(a.sync_with(&gone).await.is_err());
+        assert_eq!(ids(&a).await, ["a", "b"]);
+    }
+}
@@ -0,0 +1,258 @@
+//! Health checks run against services from outside the protocol.
+//!
+//! A [`ServiceInfo`] may carry a [`HealthCheck`]: an HTTP endpoint expected to
+//! answer with some status, such as an existing `/healthz`, or a command
+//! exiting 0 when healthy, 1 when degraded and otherwise when unhealthy. A
+//! [`HealthProber`] runs each registered service's check every interval and
+//! reports the result to the [`ServiceRegistry`], so services need not answer
+//! Remus pings. Each report renews the service's lease, so a checked service
+//! stays registered, as unhealthy if its check fails, until it is deregistered.
+
+use crate::{
+    discovery::{DiscoveryBackend, HealthStatus, ServiceInfo, ServiceRegistry},
+    http::{self, Connector},
+    server::Io,
+    ProtocolError,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{Entry, HashMap};
+use std::future::Future;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a [`HealthCheck`] runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Probe {
+    /// GETs an `http://` or `https://` URL, healthy when it answers `expected_status`
+    Http { url: String, expected_status: u16 },
+    /// Runs `program`, healthy when it exits 0 and degraded when it exits 1
+    Command { program: String, args: Vec<String> },
+}
+
+/// How and how often a service's health is checked
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub probe: Probe,
+    pub interval_ms: u64,
+    /// A check taking longer fails
+    pub timeout_ms: u64,
+}
+
+impl HealthCheck {
+    /// GETs `url` every 10 seconds, expecting a 200 within 2 seconds
+    pub fn http(url: &str) -> Self {
+        Self::new(Probe::Http { url: url.to_string(), expected_status: 200 })
+    }
+
+    /// Runs `program` with `args` every 10 seconds, expecting it to exit within 2 seconds
+    pub fn command<S: Into<String>>(program: &str, args: impl IntoIterator<Item = S>) -> Self {
+        Self::new(Probe::Command { program: program.to_string(), args: args.into_iter().map(Into::into).collect() })
+    }
+
+    fn new(probe: Probe) -> Self {
+        Self { probe, interval_ms: DEFAULT_INTERVAL.as_millis() as u64, timeout_ms: DEFAULT_TIMEOUT.as_millis() as u64 }
+    }
+
+    /// Expects an HTTP check's URL to answer `status`; commands are unaffected
+    pub fn with_expected_status(mut self, status: u16) -> Self {
+        if let Probe::Http { expected_status, .. } = &mut self.probe {
+            *expected_status = status;
+        }
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval_ms = interval.as_millis() as u64;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout_ms = timeout.as_millis() as u64;
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+// Aborts a service's probing when it is dropped, along with the prober
+struct Probing(JoinHandle<()>);
+
+impl Drop for Probing {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs the [`HealthCheck`]s of a registry's services, reporting their health to it
+pub struct HealthProber {
+    registry: Arc<ServiceRegistry>,
+    connector: Connector,
+}
+
+impl HealthProber {
+    pub fn new(registry: Arc<ServiceRegistry>) -> Self {
+        Self { registry, connector: http::tcp_connector() }
+    }
+
+    /// Opens connections for HTTP checks with `connect`, e.g. to speak TLS to `https://` URLs; it is given the URL's `host:port`
+    pub fn with_connector<F, Fut>(mut self, connect: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Box<dyn Io>, ProtocolError>> + Send + 'static,
+    {
+        self.connector = http::connector(connect);
+        self
+    }
+
+    /// Checks every registered service carrying a health check until the returned task is aborted
+    pub fn start(self) -> JoinHandle<()> {
+        let prober = Arc::new(self);
+        tokio::spawn(async move {
+            let mut probing: HashMap<String, (HealthCheck, Probing)> = HashMap::new();
+            let mut changes = prober.registry.watch();
+            while let Some(Ok(services)) = changes.next().await {
+                let checked: HashMap<String, HealthCheck> = services.into_iter().filter_map(|service: ServiceInfo| Some((service.id, service.health_check?))).collect();
+                probing.retain(|id, (check, _)| checked.get(id) == Some(check));
+                for (id, check) in checked {
+                    if let Entry::Vacant(entry) = probing.entry(id) {
+                        let task = tokio::spawn(probe_every(prober.clone(), entry.key().clone(), check.clone()));
+                        entry.insert((check, Probing(task)));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Runs `check` once
+    pub async fn check(&self, check: &HealthCheck) -> HealthStatus {
+        match tokio::time::timeout(check.timeout(), self.run(&check.probe)).await {
+            Ok(Ok(status)) => status,
+            Ok(Err(e)) => {
+                tracing::debug!("Health check {:?} failed: {}", check.probe, e);
+                HealthStatus::Unhealthy
+            }
+            Err(_) => HealthStatus::Unhealthy,
+        }
+    }
+
+    async fn run(&self, probe: &Probe) -> Result<HealthStatus, ProtocolError> {
+        match probe {
+            Probe::Http { url, expected_status } => {
+                let (address, path) = split_url(url)?;
+                let response = http::request(&self.connector, &address, "GET", &path, &[], None).await?;
+                Ok(if response.status == *expected_status { HealthStatus::Healthy } else { HealthStatus::Unhealthy })
+            }
+            Probe::Command { program, args } => {
+                let status = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await?;
+                Ok(match status.code() {
+                    Some(0) => HealthStatus::Healthy,
+                    Some(1) => HealthStatus::Degraded,
+                    _ => HealthStatus::Unhealthy,
+                })
+            }
+        }
+    }
+}
+
+// Checks service `id` every interval until it is no longer registered
+async fn probe_every(prober: Arc<HealthProber>, id: String, check: HealthCheck) {
+    let mut ticker = tokio::time::interval(check.interval().max(Duration::from_millis(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let status = prober.check(&check).await;
+        if prober.registry.update_health(&id, status).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Splits `http://host[:port][/path]` into the `host:port` to connect to and the path to GET
+fn split_url(url: &str) -> Result<(String, String), ProtocolError> {
+    let invalid = || ProtocolError::InvalidFormat(format!("Health check URL {url:?} is not http:// or https://"));
+    let (rest, port) = match url.split_once("://").ok_or_else(invalid)? {
+        (scheme, rest) if scheme.eq_ignore_ascii_case("http") => (rest, 80),
+        (scheme, rest) if scheme.eq_ignore_ascii_case("https") => (rest, 443),
+        _ => return Err(invalid()),
+    };
+    let (host, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    // A port follows the last colon, unless it is inside a bracketed IPv6 address
+    let address = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => host.to_string(),
+        _ => format!("{host}:{port}"),
+    };
+    Ok((address, path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{discovery::tests::create_test_service, http::serve_canned};
+
+    #[tokio::test]
+    async fn test_prober_reports_http_checks_to_the_registry() {
+        let (address, _requests) = serve_canned(vec![
+            ("GET /healthz ", 200, "ok".to_string()),
+            ("GET /ready ", 503, String::new()),
+        ])
+        .await;
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        let check = |path: &str| HealthCheck::http(&format!("http://{address}{path}")).with_interval(Duration::from_millis(20));
+        registry.register(create_test_service("sensors-1").with_health(HealthStatus::Unknown).with_health_check(check("/healthz"))).await;
+        registry.register(create_test_service("sensors-2").with_health(HealthStatus::Unknown).with_health_check(check("/ready"))).await;
+        registry.register(create_test_service("sensors-3").with_health(HealthStatus::Unknown).with_health_check(check("/ready").with_expected_status(503))).await;
+        let prober = HealthProber::new(registry.clone()).start();
+
+        let health = |id: &'static str| {
+            let registry = registry.clone();
+            async move { registry.get_service(id).await.unwrap().health_status }
+        };
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while [health("sensors-1").await, health("sensors-2").await, health("sensors-3").await] != [HealthStatus::Healthy, HealthStatus::Unhealthy, HealthStatus::Healthy] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        prober.abort();
+    }
+
+    #[tokio::test]
+    async fn test_commands_and_urls_check_as_specified() {
+        let prober = HealthProber::new(Arc::new(ServiceRegistry::new(Duration::from_secs(60))));
+        assert_eq!(prober.check(&HealthCheck::command("sh", ["-c", "exit 0"])).await, HealthStatus::Healthy);
+        assert_eq!(prober.check(&HealthCheck::command("sh", ["-c", "exit 1"])).await, HealthStatus::Degraded);
+        assert_eq!(prober.check(&HealthCheck::command("sh", ["-c", "exit 2"])).await, HealthStatus::Unhealthy);
+        let slow = HealthCheck::command("sleep", ["5"]).with_timeout(Duration::from_millis(50));
+        assert_eq!(prober.check(&slow).await, HealthStatus::Unhealthy);
+
+        assert_eq!(split_url("http://10.0.0.5:8080/healthz?full=1").unwrap(), ("10.0.0.5:8080".into(), "/healthz?full=1".into()));
+        assert_eq!(split_url("https://sensors.local").unwrap(), ("sensors.local:443".into(), "/".into()));
+        assert_eq!(split_url("http://[::1]/healthz").unwrap(), ("[::1]:80".into(), "/healthz".into()));
+        assert!(split_url("ftp://sensors.local/").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! A minimal HTTP/1.1 client for the REST APIs discovery backends talk to.
+//!
+//! One request per connection: requests ask the server to close the
+//! connection, and the response is read to its end. Bodies may be sent with a
+//! length or chunked.
+
+use crate::{server::Io, ProtocolError};
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Upper bound on a response, so a misbehaving server cannot exhaust memory
+const MAX_RESPONSE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Opens the stream a request is sent over, e.g. wrapping TCP in TLS
+pub(crate) type Connector = Arc<dyn Fn(String) -> BoxFuture<'static, Result<Box<dyn Io>, ProtocolError>> + Send + Sync>;
+
+/// Connects over plain TCP to the `host:port` it is given
+pub(crate) fn tcp_connector() -> Connector {
+    Arc::new(|address| Box::pin(async move { Ok(Box::new(TcpStream::connect(address).await?) as Box<dyn Io>) }))
+}
+
+pub(crate) fn connector<F, Fut>(connect: F) -> Connector
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Box<dyn Io>, ProtocolError>> + Send + 'static,
+{
+    Arc::new(move |address| Box::pin(connect(address)))
+}
+
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends one request to `address` and reads the whole response
+pub(crate) async fn request(
+    connector: &Connector,
+    address: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<Response, ProtocolError> {
+    let mut stream = connector(address.to_string()).await?;
+    let mut head = format!("{method} {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\nAccept: application/json\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if let Some(body) = body {
+        head.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    if let Some(body) = body {
+        stream.write_all(body).await?;
+    }
+    stream.flush().await?;
+
+    let mut raw = Vec::new();
+    (&mut stream).take(MAX_RESPONSE_LEN).read_to_end(&mut raw).await?;
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response, ProtocolError> {
+    let malformed = || ProtocolError::InvalidFormat("Malformed HTTP response".into());
+    let split = raw.windows(4).position(|window| window == b"\r\n\r\n").ok_or_else(malformed)?;
+    let head = std::str::from_utf8(&raw[..split]).map_err(|_| malformed())?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(malformed)?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut response = Response { status, headers, body: raw[split + 4..].to_vec() };
+
+    if response.header("transfer-encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+        response.body = dechunk(&response.body).ok_or_else(malformed)?;
+    } else if let Some(length) = response.header("content-length").and_then(|length| length.parse().ok()) {
+        response.body.truncate(length);
+    }
+    Ok(response)
+}
+
+fn dechunk(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&data[..line_end]).ok()?;
+        // Chunk extensions follow a semicolon
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(data.get(..size)?);
+        data = data.get(size + 2..)?;
+    }
+}
+
+/// Serves canned responses, keyed by the start of the request line, reporting each request it receives
+#[cfg(test)]
+pub(crate) async fn serve_canned(routes: Vec<(&'static str, u16, String)>) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (requests, received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut raw = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Read the head, then as much body as it declares
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                raw.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&raw).to_string();
+                if let Some(split) = text.find("\r\n\r\n") {
+                    let length = text[..split]
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map_or(0, |length| length.parse().unwrap());
+                    if raw.len() >= split + 4 + length || n == 0 {
+                        break;
+                    }
+                }
+            }
+            let text = String::from_utf8_lossy(&raw).to_string();
+            let (status, body) = routes
+                .iter()
+                .find(|(prefix, _, _)| text.starts_with(prefix))
+                .map_or((404, String::new()), |(_, status, body)| (*status, body.clone()));
+            requests.send(text).unwrap();
+            let response = format!("HTTP/1.1 {status} OK\r\nContent-Length: {}\r\nX-Consul-Index: 7\r\n\r\n{body}", body.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    (address, received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responses_parse_with_length_or_chunks() {
+        let response = parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Index: 3\r\n\r\nhello, extra").unwrap();
+        assert_eq!((response.status, response.header("x-index"), &response.body[..]), (200, Some("3"), &b"hello"[..]));
+
+        let response = parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3;ext=1\r\n:1}\r\n0\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"{\"a\":1}");
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").is_err());
+        assert!(parse_response(b"not http").is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Ed25519 identity keys and signatures.
+//!
+//! Each node holds an [`IdentityKey`] and publishes its [`PublicKey`], e.g. in
+//! [`ServiceInfo::public_key`](crate::ServiceInfo::public_key), so peers can
+//! check that messages really come from it rather than from anyone holding a
+//! shared symmetric key. [`MessageSigner`] and [`MessageVerifier`] sign and
+//! check every application message a transport carries.
+
+use crate::{middleware::TransportMiddleware, Message, ProtocolError};
+use base64::Engine;
+use bytes::{BufMut, Bytes};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+pub const SIGNATURE_LEN: usize = 64;
+
+// Separates message signatures from signatures the same key makes over other data
+const MESSAGE_CONTEXT: &[u8] = b"remus/message/v1";
+
+/// An Ed25519 signing key
+pub struct IdentityKey {
+    // Wiped on drop
+    key: SigningKey,
+    public: PublicKey,
+}
+
+impl IdentityKey {
+    pub fn generate() -> Self {
+        let mut seed = Zeroizing::new([0u8; 32]);
+        rand::thread_rng().fill(&mut *seed);
+        Self::from_seed(&seed)
+    }
+
+    /// Restores the key whose [`seed`](Self::seed) was stored
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let key = SigningKey::from_bytes(seed);
+        let public = PublicKey(key.verifying_key().to_bytes());
+        Self { key, public }
+    }
+
+    /// Returns the 32 secret bytes the key is derived from; keep them private
+    pub fn seed(&self) -> &[u8; 32] {
+        self.key.as_bytes()
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        Signature(self.key.sign(data).to_bytes())
+    }
+
+    /// Signs the message's type, request id, timestamp, routing info and payload
+    pub fn sign_message(&self, message: &Message) -> Signature {
+        self.sign(&signed_content(message))
+    }
+}
+
+impl fmt::Debug for IdentityKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdentityKey").field("public", &self.public).finish_non_exhaustive()
+    }
+}
+
+pub(crate) fn signed_content(message: &Message) -> Vec<u8> {
+    let routing_info = message.routing_info.as_deref().unwrap_or("").as_bytes();
+    let mut content = Vec::with_capacity(MESSAGE_CONTEXT.len() + 19 + routing_info.len() + message.payload.len());
+    content.put_slice(MESSAGE_CONTEXT);
+    content.put_u8(message.msg_type as u8);
+    content.put_u64(message.request_id);
+    content.put_u64(message.timestamp);
+    content.put_u16(routing_info.len() as u16);
+    content.put_slice(routing_info);
+    content.put_slice(&message.payload);
+    content
+}
+
+/// An Ed25519 public key, shown and serialized as base64
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// Fails unless `bytes` encode a point on the curve
+    pub fn from_bytes(bytes: &[u8; 32]) -> Result<Self, ProtocolError> {
+        VerifyingKey::from_bytes(bytes).map_err(|_| ProtocolError::InvalidSignature("Malformed public key".into()))?;
+        Ok(Self(*bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &Signature) -> Result<(), ProtocolError> {
+        let invalid = |_| ProtocolError::InvalidSignature("Signature does not match".into());
+        let public = VerifyingKey::from_bytes(&self.0).map_err(invalid)?;
+        public.verify(data, &ed25519_dalek::Signature::from_bytes(&signature.0)).map_err(invalid)
+    }
+
+    pub fn verify_message(&self, message: &Message, signature: &Signature) -> Result<(), ProtocolError> {
+        self.verify(&signed_content(message), signature)
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({self})")
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&base64::engine::general_purpose::STANDARD.encode(self.0))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = ProtocolError;
+
+    fn from_str(encoded: &str) -> Result<Self, ProtocolError> {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| ProtocolError::InvalidSignature(format!("Malformed public key: {e}")))?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| ProtocolError::InvalidSignature("Public key must be 32 bytes".into()))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature([u8; SIGNATURE_LEN]);
+
+impl Signature {
+    pub fn from_bytes(bytes: &[u8; SIGNATURE_LEN]) -> Self {
+        Self(*bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; SIGNATURE_LEN] {
+        self.0
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signature({})", base64::engine::general_purpose::STANDARD.encode(self.0))
+    }
+}
+
+/// Transport middleware appending a signature to every outgoing payload
+pub struct MessageSigner {
+    key: IdentityKey,
+}
+
+impl MessageSigner {
+    pub fn new(key: IdentityKey) -> Self {
+        Self { key }
+    }
+}
+
+impl TransportMiddleware for MessageSigner {
+    fn on_send(&self, message: &mut Message) -> Result<(), ProtocolError> {
+        let signature = self.key.sign_message(message);
+        let mut payload = Vec::with_capacity(message.payload.len() + SIGNATURE_LEN);
+        payload.extend_from_slice(&message.payload);
+        payload.extend_from_slice(&signature.0);
+        message.payload = Bytes::from(payload);
+        Ok(())
+    }
+}
+
+/// Transport middleware checking and stripping the signature [`MessageSigner`] appends.
+///
+/// Messages without a valid signature from `key` are rejected with
+/// `ProtocolError::InvalidSignature`.
+pub struct MessageVerifier {
+    key: PublicKey,
+}
+
+impl MessageVerifier {
+    pub fn new(key: PublicKey) -> Self {
+        Self { key }
+    }
+}
+
+impl TransportMiddleware for MessageVerifier {
+    fn on_receive(&self, message: &mut Message) -> Result<(), ProtocolError> {
+        let split = message
+            .payload
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .ok_or_else(|| ProtocolError::InvalidSignature("Message is not signed".into()))?;
+        let signature = Signature(message.payload[split..].try_into().unwrap());
+        message.payload.truncate(split);
+        self.key.verify_message(message, &signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageFlags, MessageType, Transport};
+    use std::sync::Arc;
+
+    fn hex(data: &str) -> Vec<u8> {
+        (0..data.len()).step_by(2).map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_rfc8032_vectors() {
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (seed, public, message, signature) in vectors {
+            let key = IdentityKey::from_seed(&hex(seed).try_into().unwrap());
+            assert_eq!(key.public_key().to_bytes().to_vec(), hex(public));
+            let signed = key.sign(&hex(message));
+            assert_eq!(signed.to_bytes().to_vec(), hex(signature));
+            key.public_key().verify(&hex(message), &signed).unwrap();
+            assert!(key.public_key().verify(b"other", &signed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_public_key_round_trips_as_base64() {
+        let public = IdentityKey::generate().public_key();
+        let json = serde_json::to_string(&public).unwrap();
+        assert_eq!(serde_json::from_str::<PublicKey>(&json).unwrap(), public);
+        assert!("AAAA".parse::<PublicKey>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signed_messages_verified_by_peer() {
+        let key = IdentityKey::generate();
+        let public = key.public_key();
+        let (client, server) = tokio::io::duplex(4096);
+        let mut client = Transport::new(client).with_middleware(Arc::new(MessageSigner::new(key)));
+        let mut server = Transport::new(server).with_middleware(Arc::new(MessageVerifier::new(public)));
+
+        client.send(Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::from("hi"))).await.unwrap();
+        assert_eq!(server.receive().await.unwrap().payload, Bytes::from("hi"));
+
+        let verifier = MessageVerifier::new(public);
+        let mut forged = Message::new(MessageType::Request, MessageFlags::NONE, 2, Bytes::from("hi"));
+        MessageSigner::new(IdentityKey::generate()).on_send(&mut forged).unwrap();
+        assert!(matches!(verifier.on_receive(&mut forged), Err(ProtocolError::InvalidSignature(_))));
+
+        let mut unsigned = Message::new(MessageType::Request, MessageFlags::NONE, 3, Bytes::from("hi"));
+        assert!(matches!(verifier.on_receive(&mut unsigned), Err(ProtocolError::InvalidSignature(_))));
+    }
+}
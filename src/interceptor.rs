@@ -0,0 +1,22 @@
+use crate::{Message, ProtocolError};
+
+/// Hook into every request a `RemusClient` sends and every response it receives.
+///
+/// Interceptors run in registration order before send and in reverse order after
+/// receive. `before_send` runs once per attempt on a fresh copy of the request,
+/// so a retried request is intercepted again rather than transformed twice.
+/// Returning an error aborts the request with that error.
+pub trait Interceptor: Send + Sync {
+    /// Called before a request, call, stream or batch message is sent
+    fn before_send(&self, _message: &mut Message) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Called on each response to a request, call or batch message
+    fn after_receive(&self, _message: &mut Message) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Called instead of `after_receive` on each error response, before it fails the request
+    fn on_error_response(&self, _message: &Message) {}
+}
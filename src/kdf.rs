@@ -0,0 +1,199 @@
+//! Key derivation from pre-shared secrets and passphrases.
+//!
+//! [`KeyDerivation`] runs HKDF-SHA256 (RFC 5869) over a master secret, so one
+//! secret yields independent keys per purpose, selected by a context label.
+//! Passphrases are first stretched with Argon2id (RFC 9106) to slow down
+//! guessing.
+
+use crate::{
+    encryption::Cipher,
+    identity::IdentityKey,
+    secret::SecretKey,
+    Encryptor, ProtocolError,
+};
+use argon2::{Algorithm, Argon2, AssociatedData, ParamsBuilder, Version};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Label [`KeyDerivation::encryptor`] derives its key under
+pub const ENCRYPTION_LABEL: &str = "remus/v1/encryption";
+/// Label [`KeyDerivation::identity_key`] derives its seed under
+pub const SIGNING_LABEL: &str = "remus/v1/signing";
+
+const HASH_LEN: usize = 32;
+
+/// Derives purpose-specific keys from one master secret
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct KeyDerivation {
+    prk: [u8; HASH_LEN],
+}
+
+impl KeyDerivation {
+    /// Starts from a high-entropy secret, e.g. a pre-shared key
+    pub fn new(secret: &[u8]) -> Self {
+        Self::salted(&[], secret)
+    }
+
+    /// Starts from `secret`, extracted with a non-secret `salt`
+    pub fn salted(salt: &[u8], secret: &[u8]) -> Self {
+        let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), secret);
+        Self { prk: prk.into() }
+    }
+
+    /// Starts from a passphrase, stretched with Argon2id.
+    ///
+    /// `salt` must be at least 8 bytes; store it next to the config, as the
+    /// same passphrase, salt and parameters are needed to derive the same keys.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8], params: &Argon2Params) -> Result<Self, ProtocolError> {
+        let mut secret = Zeroizing::new([0u8; HASH_LEN]);
+        argon2id(passphrase.as_bytes(), salt, &[], &[], params, &mut *secret)?;
+        Ok(Self::salted(salt, &*secret))
+    }
+
+    /// Fills `output` with key material bound to `info`; at most 8160 bytes
+    pub fn expand(&self, info: &[u8], output: &mut [u8]) -> Result<(), ProtocolError> {
+        Hkdf::<Sha256>::from_prk(&self.prk)
+            .expect("a SHA-256 sized PRK")
+            .expand(info, output)
+            .map_err(|_| ProtocolError::EncryptionError("Cannot derive more than 8160 bytes".into()))
+    }
+
+    /// Derives a 32-byte key bound to `label`
+    pub fn derive_key(&self, label: &str) -> SecretKey {
+        let mut key = SecretKey::new([0; 32]);
+        self.expand(label.as_bytes(), key.as_mut_bytes()).expect("32 bytes is within the HKDF limit");
+        key
+    }
+
+    /// Creates an encryptor with the key derived under [`ENCRYPTION_LABEL`]
+    pub fn encryptor(&self, cipher: Cipher) -> Encryptor {
+        Encryptor::with_cipher(&self.derive_key(ENCRYPTION_LABEL), cipher)
+    }
+
+    /// Creates the identity key seeded from [`SIGNING_LABEL`]
+    pub fn identity_key(&self) -> IdentityKey {
+        IdentityKey::from_seed(&self.derive_key(SIGNING_LABEL))
+    }
+}
+
+impl fmt::Debug for KeyDerivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyDerivation(..)")
+    }
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in data {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Cost parameters for Argon2id passphrase stretching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl Argon2Params {
+    /// Creates the OWASP-recommended parameters: 19 MiB of memory, 2 passes, 1 lane
+    pub fn new() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+
+    pub fn with_memory_kib(mut self, memory_kib: u32) -> Self {
+        self.memory_kib = memory_kib;
+        self
+    }
+
+    pub fn with_iterations(mut self, iterations: u32) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Sets the number of lanes
+    pub fn with_parallelism(mut self, parallelism: u32) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn argon2id(password: &[u8], salt: &[u8], secret: &[u8], associated_data: &[u8], params: &Argon2Params, output: &mut [u8]) -> Result<(), ProtocolError> {
+    let invalid = |e: argon2::Error| ProtocolError::EncryptionError(format!("Invalid Argon2 parameters: {e}"));
+    let params = ParamsBuilder::new()
+        .m_cost(params.memory_kib)
+        .t_cost(params.iterations)
+        .p_cost(params.parallelism)
+        .output_len(output.len())
+        .data(AssociatedData::new(associated_data).map_err(invalid)?)
+        .build()
+        .map_err(invalid)?;
+    Argon2::new_with_secret(secret, Algorithm::Argon2id, Version::V0x13, params)
+        .and_then(|argon2| argon2.hash_password_into(password, salt, output))
+        .map_err(|e| match e {
+            argon2::Error::SaltTooShort => ProtocolError::EncryptionError("Argon2 salt must be at least 8 bytes".into()),
+            e => invalid(e),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &str) -> Vec<u8> {
+        (0..data.len()).step_by(2).map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_hkdf_rfc5869_vector() {
+        let derivation = KeyDerivation::salted(&hex("000102030405060708090a0b0c"), &[0x0b; 22]);
+        let mut okm = [0u8; 42];
+        derivation.expand(&hex("f0f1f2f3f4f5f6f7f8f9"), &mut okm).unwrap();
+        assert_eq!(
+            okm.to_vec(),
+            hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865")
+        );
+        assert!(derivation.expand(b"too long", &mut [0u8; 8161]).is_err());
+    }
+
+    #[test]
+    fn test_argon2id_rfc9106_vector() {
+        let params = Argon2Params::new().with_memory_kib(32).with_iterations(3).with_parallelism(4);
+        let mut tag = [0u8; 32];
+        argon2id(&[1; 32], &[2; 16], &[3; 8], &[4; 12], &params, &mut tag).unwrap();
+        assert_eq!(tag.to_vec(), hex("0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659"));
+    }
+
+    #[test]
+    fn test_labels_derive_independent_keys() {
+        let params = Argon2Params::new().with_memory_kib(64).with_iterations(1);
+        let first = KeyDerivation::from_passphrase("correct horse", b"node-salt", &params).unwrap();
+        let again = KeyDerivation::from_passphrase("correct horse", b"node-salt", &params).unwrap();
+        let other = KeyDerivation::from_passphrase("battery staple", b"node-salt", &params).unwrap();
+
+        assert_eq!(first.derive_key(ENCRYPTION_LABEL), again.derive_key(ENCRYPTION_LABEL));
+        assert_ne!(first.derive_key(ENCRYPTION_LABEL), first.derive_key(SIGNING_LABEL));
+        assert_ne!(first.derive_key(ENCRYPTION_LABEL), other.derive_key(ENCRYPTION_LABEL));
+        assert!(KeyDerivation::from_passphrase("short salt", b"salt", &params).is_err());
+
+        let sealed = first.encryptor(Cipher::ChaCha20Poly1305).encrypt(b"hello").unwrap();
+        assert_eq!(&again.encryptor(Cipher::ChaCha20Poly1305).decrypt(&sealed).unwrap()[..], b"hello");
+        assert_eq!(first.identity_key().public_key(), again.identity_key().public_key());
+    }
+}
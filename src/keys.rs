@@ -0,0 +1,167 @@
+//! Pluggable key storage.
+//!
+//! A [`KeyProvider`] stands between the code that encrypts or signs and the
+//! keys it uses, so deployments can keep keys in a KMS, Vault or a PKCS#11
+//! HSM rather than in process memory. Keys are looked up by name; signing
+//! happens inside the provider, so signing keys need never leave it.
+//!
+//! [`LocalKeyProvider`] keeps keys in process, deriving each name's
+//! encryption and signing keys from one secret with [`KeyDerivation`].
+//!
+//! Transport middleware runs synchronously, so a provider that signs remotely
+//! is used with [`sign_message`] before handing the message to the transport;
+//! the peer checks it with [`MessageVerifier`](crate::MessageVerifier) as usual.
+//!
+//! ```rust
+//! use remus::keys::{KeyProvider, LocalKeyProvider};
+//! use remus::{Cipher, Encryptor};
+//!
+//! # tokio_test::block_on(async {
+//! let provider = LocalKeyProvider::new().with_generated_key("payments");
+//! let encryptor = Encryptor::from_provider(&provider, "payments", Cipher::ChaCha20Poly1305).await?;
+//! let signature = provider.sign("payments", b"statement").await?;
+//! provider.public_key("payments").await?.verify(b"statement", &signature)?;
+//! # Ok::<_, remus::ProtocolError>(())
+//! # }).unwrap();
+//! ```
+
+use crate::{
+    identity::{signed_content, SIGNATURE_LEN},
+    kdf::ENCRYPTION_LABEL,
+    KeyDerivation, Message, ProtocolError, PublicKey, SecretKey, Signature,
+};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Named keys held outside the code that uses them
+pub trait KeyProvider: Send + Sync {
+    /// Returns the current version of the symmetric key `name`, e.g. a data key the KMS unwraps
+    fn get_key<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<SecretKey, ProtocolError>>;
+
+    /// Signs `data` with the current version of the Ed25519 key `name`
+    fn sign<'a>(&'a self, name: &'a str, data: &'a [u8]) -> BoxFuture<'a, Result<Signature, ProtocolError>>;
+
+    /// Returns the public half of the signing key `name`, for peers to verify with
+    fn public_key<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<PublicKey, ProtocolError>>;
+
+    /// Replaces the keys named `name` with a new version, returning its number.
+    ///
+    /// Encryptors and signatures made with earlier versions are not updated;
+    /// fetch the key again to use the new version.
+    fn rotate<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<u32, ProtocolError>>;
+}
+
+/// Signs `message` with the provider's key `name`, appending the signature
+/// the way [`MessageSigner`](crate::MessageSigner) does
+pub async fn sign_message(provider: &dyn KeyProvider, name: &str, message: &mut Message) -> Result<(), ProtocolError> {
+    let signature = provider.sign(name, &signed_content(message)).await?;
+    let mut payload = Vec::with_capacity(message.payload.len() + SIGNATURE_LEN);
+    payload.extend_from_slice(&message.payload);
+    payload.extend_from_slice(&signature.to_bytes());
+    message.payload = Bytes::from(payload);
+    Ok(())
+}
+
+struct LocalKey {
+    version: u32,
+    keys: KeyDerivation,
+}
+
+/// A [`KeyProvider`] holding its keys in process memory
+#[derive(Default)]
+pub struct LocalKeyProvider {
+    keys: RwLock<HashMap<String, LocalKey>>,
+}
+
+impl LocalKeyProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the keys named `name`, derived from `secret`
+    pub fn with_key(self, name: &str, secret: &[u8]) -> Self {
+        self.keys.write().unwrap().insert(
+            name.to_string(),
+            LocalKey {
+                version: 1,
+                keys: KeyDerivation::new(secret),
+            },
+        );
+        self
+    }
+
+    /// Adds the keys named `name`, derived from a random secret
+    pub fn with_generated_key(self, name: &str) -> Self {
+        self.with_key(name, SecretKey::generate().as_bytes())
+    }
+
+    /// Returns the current version of the keys named `name`
+    pub fn version(&self, name: &str) -> Option<u32> {
+        self.keys.read().unwrap().get(name).map(|key| key.version)
+    }
+
+    fn keys(&self, name: &str) -> Result<KeyDerivation, ProtocolError> {
+        let keys = self.keys.read().unwrap();
+        let key = keys.get(name).ok_or_else(|| ProtocolError::KeyUnavailable(name.to_string()))?;
+        Ok(key.keys.clone())
+    }
+}
+
+impl KeyProvider for LocalKeyProvider {
+    fn get_key<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<SecretKey, ProtocolError>> {
+        let key = self.keys(name).map(|keys| keys.derive_key(ENCRYPTION_LABEL));
+        Box::pin(std::future::ready(key))
+    }
+
+    fn sign<'a>(&'a self, name: &'a str, data: &'a [u8]) -> BoxFuture<'a, Result<Signature, ProtocolError>> {
+        let signature = self.keys(name).map(|keys| keys.identity_key().sign(data));
+        Box::pin(std::future::ready(signature))
+    }
+
+    fn public_key<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<PublicKey, ProtocolError>> {
+        let public_key = self.keys(name).map(|keys| keys.identity_key().public_key());
+        Box::pin(std::future::ready(public_key))
+    }
+
+    fn rotate<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<u32, ProtocolError>> {
+        let mut keys = self.keys.write().unwrap();
+        let version = match keys.get_mut(name) {
+            Some(key) => {
+                key.version += 1;
+                key.keys = KeyDerivation::new(SecretKey::generate().as_bytes());
+                Ok(key.version)
+            }
+            None => Err(ProtocolError::KeyUnavailable(name.to_string())),
+        };
+        Box::pin(std::future::ready(version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cipher, Encryptor, MessageFlags, MessageType, MessageVerifier, TransportMiddleware};
+
+    #[tokio::test]
+    async fn test_local_provider_signs_and_rotates() {
+        let provider = LocalKeyProvider::new().with_key("node", b"master secret");
+        let sealed = Encryptor::from_provider(&provider, "node", Cipher::Aes256Gcm).await.unwrap().encrypt(b"data").unwrap();
+
+        let mut message = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::from("hello"));
+        sign_message(&provider, "node", &mut message).await.unwrap();
+        let public_key = provider.public_key("node").await.unwrap();
+        MessageVerifier::new(public_key).on_receive(&mut message).unwrap();
+        assert_eq!(&message.payload[..], b"hello");
+
+        assert_eq!(provider.rotate("node").await.unwrap(), 2);
+        assert_eq!(provider.version("node"), Some(2));
+        let rotated = Encryptor::from_provider(&provider, "node", Cipher::Aes256Gcm).await.unwrap();
+        assert!(rotated.decrypt(&sealed).is_err());
+        assert_ne!(provider.public_key("node").await.unwrap(), public_key);
+
+        assert!(matches!(provider.get_key("missing").await, Err(ProtocolError::KeyUnavailable(name)) if name == "missing"));
+        assert!(matches!(provider.rotate("missing").await, Err(ProtocolError::KeyUnavailable(_))));
+    }
+}
@@ -0,0 +1,243 @@
+//! A [`DiscoveryBackend`] reading services from the Kubernetes Endpoints API.
+//!
+//! Each address the Endpoints of a watched Service list becomes a
+//! [`ServiceInfo`] with the ID `<service>/<pod>`: ready addresses are healthy
+//! and not-ready ones unhealthy. Kubernetes derives Endpoints from pod
+//! readiness itself, so [`register`](DiscoveryBackend::register) and
+//! [`deregister`](DiscoveryBackend::deregister) fail; deploy a Service for the
+//! pods instead.
+//!
+//! The API server speaks HTTPS. Without a TLS library of its own the backend
+//! connects through [`with_connector`](KubernetesBackend::with_connector),
+//! which should verify the cluster CA, or in plain HTTP to `kubectl proxy`.
+
+use crate::{
+    discovery::{DiscoveryBackend, HealthStatus, ServiceInfo},
+    http::{self, Connector},
+    server::Io,
+    ProtocolError,
+};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+
+/// Where pods find their service account's credentials
+pub const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+#[derive(Deserialize)]
+struct Endpoints {
+    #[serde(default)]
+    subsets: Vec<Subset>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Subset {
+    #[serde(default)]
+    addresses: Vec<EndpointAddress>,
+    #[serde(default)]
+    not_ready_addresses: Vec<EndpointAddress>,
+    #[serde(default)]
+    ports: Vec<EndpointPort>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointAddress {
+    ip: String,
+    target_ref: Option<TargetRef>,
+}
+
+#[derive(Deserialize)]
+struct TargetRef {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct EndpointPort {
+    name: Option<String>,
+    port: u16,
+}
+
+/// Lists the endpoints of Kubernetes Services
+pub struct KubernetesBackend {
+    address: String,
+    namespace: String,
+    token: Option<String>,
+    services: Vec<String>,
+    port_name: Option<String>,
+    connector: Connector,
+}
+
+impl KubernetesBackend {
+    /// Talks to the API at `address`, e.g. `127.0.0.1:8001` for `kubectl proxy`, in the `default` namespace
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            namespace: "default".to_string(),
+            token: None,
+            services: Vec::new(),
+            port_name: None,
+            connector: http::tcp_connector(),
+        }
+    }
+
+    /// Talks to the API server of the cluster the process runs in, as its pod's service account.
+    ///
+    /// Reads the API address from the environment and the token and namespace
+    /// from [`SERVICE_ACCOUNT_DIR`]. A TLS connector trusting the `ca.crt`
+    /// there is still needed.
+    pub fn in_cluster() -> Result<Self, ProtocolError> {
+        let missing = |what: &str| ProtocolError::DiscoveryError(format!("Not running in a Kubernetes pod: {what} is not set"));
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| missing("KUBERNETES_SERVICE_HOST"))?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").map_err(|_| missing("KUBERNETES_SERVICE_PORT"))?;
+        let token = std::fs::read_to_string(format!("{SERVICE_ACCOUNT_DIR}/token"))?;
+        let namespace = std::fs::read_to_string(format!("{SERVICE_ACCOUNT_DIR}/namespace"))?;
+        let address = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{host}]:{port}"),
+            _ => format!("{host}:{port}"),
+        };
+        Ok(Self::new(&address).with_token(token.trim()).with_namespace(namespace.trim()))
+    }
+
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_string();
+        self
+    }
+
+    /// Authenticates with a bearer token
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Lists the endpoints of Service `name`
+    pub fn with_service(mut self, name: &str) -> Self {
+        self.services.push(name.to_string());
+        self
+    }
+
+    /// Picks the endpoint port named `name`; otherwise the first port is used
+    pub fn with_port_name(mut self, name: &str) -> Self {
+        self.port_name = Some(name.to_string());
+        self
+    }
+
+    /// Opens connections to the API with `connect`, e.g. to speak TLS; it is given the API's `host:port`
+    pub fn with_connector<F, Fut>(mut self, connect: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Box<dyn Io>, ProtocolError>> + Send + 'static,
+    {
+        self.connector = http::connector(connect);
+        self
+    }
+
+    async fn endpoints(&self, service: &str) -> Result<Vec<ServiceInfo>, ProtocolError> {
+        let path = format!("/api/v1/namespaces/{}/endpoints/{service}", self.namespace);
+        let authorization = self.token.as_ref().map(|token| format!("Bearer {token}"));
+        let headers: Vec<(&str, &str)> = authorization.iter().map(|value| ("Authorization", value.as_str())).collect();
+        let response = http::request(&self.connector, &self.address, "GET", &path, &headers, None).await?;
+        // A Service with no Endpoints object yet has no instances
+        if response.status == 404 {
+            return Ok(Vec::new());
+        }
+        if !response.is_success() {
+            return Err(ProtocolError::DiscoveryError(format!(
+                "Kubernetes answered {} to GET {path}: {}",
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            )));
+        }
+        let endpoints: Endpoints = serde_json::from_slice(&response.body).map_err(|e| ProtocolError::DiscoveryError(format!("Unexpected Endpoints object: {e}")))?;
+
+        let mut services = Vec::new();
+        for subset in endpoints.subsets {
+            let port = match &self.port_name {
+                Some(name) => subset.ports.iter().find(|port| port.name.as_ref() == Some(name)),
+                None => subset.ports.first(),
+            };
+            let Some(port) = port else { continue };
+            let ready = subset.addresses.into_iter().map(|address| (address, HealthStatus::Healthy));
+            let not_ready = subset.not_ready_addresses.into_iter().map(|address| (address, HealthStatus::Unhealthy));
+            for (address, health_status) in ready.chain(not_ready) {
+                let Ok(ip) = address.ip.parse::<IpAddr>() else { continue };
+                let instance = address.target_ref.map_or_else(|| address.ip.clone(), |target| target.name);
+                services.push(ServiceInfo {
+                    id: format!("{service}/{instance}"),
+                    name: service.to_string(),
+                    version: String::new(),
+                    capabilities: Vec::new(),
+                    address: SocketAddr::new(ip, port.port),
+                    metadata: HashMap::from([("namespace".to_string(), self.namespace.clone())]),
+                    last_seen: SystemTime::now(),
+                    health_status,
+                    public_key: None,
+                    health_check: None,
+                });
+            }
+        }
+        Ok(services)
+    }
+}
+
+impl DiscoveryBackend for KubernetesBackend {
+    fn register(&self, service: ServiceInfo) -> BoxFuture<'_, Result<(), ProtocolError>> {
+        Box::pin(std::future::ready(Err(managed_by_kubernetes(&service.id))))
+    }
+
+    fn deregister<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        Box::pin(std::future::ready(Err(managed_by_kubernetes(id))))
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        Box::pin(async move {
+            let mut services = Vec::new();
+            for service in &self.services {
+                services.extend(self.endpoints(service).await?);
+            }
+            Ok(services)
+        })
+    }
+}
+
+fn managed_by_kubernetes(id: &str) -> ProtocolError {
+    ProtocolError::DiscoveryError(format!("Cannot change {id:?}: Kubernetes manages Endpoints from pod readiness"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::serve_canned;
+
+    #[tokio::test]
+    async fn test_endpoints_become_services() {
+        let endpoints = r#"{"kind": "Endpoints", "subsets": [{
+            "addresses": [{"ip": "10.1.0.4", "targetRef": {"kind": "Pod", "name": "sensors-7d9f"}}],
+            "notReadyAddresses": [{"ip": "10.1.0.5"}],
+            "ports": [{"name": "metrics", "port": 9090}, {"name": "remus", "port": 7000}]
+        }]}"#;
+        let (address, mut requests) = serve_canned(vec![
+            ("GET /api/v1/namespaces/edge/endpoints/sensors ", 200, endpoints.to_string()),
+        ])
+        .await;
+        let kubernetes = KubernetesBackend::new(&address)
+            .with_namespace("edge")
+            .with_token("token")
+            .with_service("sensors")
+            .with_service("missing")
+            .with_port_name("remus");
+
+        let services = kubernetes.list().await.unwrap();
+        assert!(requests.recv().await.unwrap().contains("Authorization: Bearer token\r\n"));
+        let found: Vec<_> = services.iter().map(|s| (s.id.as_str(), s.address, s.health_status)).collect();
+        assert_eq!(found, [
+            ("sensors/sensors-7d9f", "10.1.0.4:7000".parse().unwrap(), HealthStatus::Healthy),
+            ("sensors/10.1.0.5", "10.1.0.5:7000".parse().unwrap(), HealthStatus::Unhealthy),
+        ]);
+        assert!(kubernetes.deregister("sensors/sensors-7d9f").await.is_err());
+    }
+}
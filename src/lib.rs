@@ -1,15 +1,19 @@
 use bitflags::bitflags;
-use bytes::Bytes;
+use bytes::{BufMut, Bytes};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
-// Protocol version constants
-pub const PROTOCOL_VERSION_MAJOR: u16 = 2;
+/// Encoded size of a message header with empty routing info and context
+pub const MIN_HEADER_LEN: usize = 32;
+
+// Protocol version constants; every header opens with the major version, and
+// peers whose major versions differ cannot decode each other's messages
+pub const PROTOCOL_VERSION_MAJOR: u16 = 3;
 pub const PROTOCOL_VERSION_MINOR: u16 = 0;
 
 bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
     pub struct MessageFlags: u8 {
         const NONE = 0;
         const ENCRYPTED = 0x01;
@@ -19,6 +23,7 @@ bitflags! {
         const IDEMPOTENT = 0x10;
         const HIGH_PRIORITY = 0x20;
         const REQUIRES_AUTH = 0x40;
+        const STREAM_END = 0x80;
     }
 }
 
@@ -28,6 +33,16 @@ pub enum MessageType {
     Response,
     Event,
     Error,
+    Ping,
+    Pong,
+    GoAway,
+    Stream,
+    StreamEnd,
+    Ack,
+    Cancel,
+    Subscribe,
+    Unsubscribe,
+    Handshake,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -66,48 +81,111 @@ impl Message {
         }
     }
 
+    /// Returns whether the TTL has elapsed since the message was created
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+        now > self.timestamp.saturating_add(self.ttl as u64 * 1000)
+    }
+
     pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        
+        let mut buf = Vec::with_capacity(MIN_HEADER_LEN + self.payload.len());
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    /// Encodes the message into an existing buffer, e.g. one taken from a `BufferPool`
+    pub fn encode_into<B: BufMut>(&self, buf: &mut B) {
+        self.encode_header_into(buf);
+        buf.put_slice(&self.payload);
+    }
+
+    /// Encodes everything up to and including the payload length, but not the
+    /// payload itself, so the payload can be written without copying
+    pub fn encode_header_into<B: BufMut>(&self, buf: &mut B) {
+        // Write protocol version
+        buf.put_u8(PROTOCOL_VERSION_MAJOR as u8);
+
         // Write message type
-        buf.extend_from_slice(&[self.msg_type as u8]);
+        buf.put_u8(self.msg_type as u8);
         
         // Write flags
-        buf.extend_from_slice(&[self.flags.bits()]);
+        buf.put_u8(self.flags.bits());
         
         // Write timestamp
-        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.put_u64(self.timestamp);
         
         // Write request ID
-        buf.extend_from_slice(&self.request_id.to_be_bytes());
+        buf.put_u64(self.request_id);
         
         // Write priority
-        buf.extend_from_slice(&[self.priority]);
+        buf.put_u8(self.priority);
         
         // Write TTL
-        buf.extend_from_slice(&self.ttl.to_be_bytes());
+        buf.put_u32(self.ttl);
+
+        // Write routing info and context, each as a u16 length and UTF-8 bytes
+        for field in [&self.routing_info, &self.context] {
+            let bytes = field.as_deref().unwrap_or("").as_bytes();
+            buf.put_u16(bytes.len() as u16);
+            buf.put_slice(bytes);
+        }
         
-        // Write payload length and payload
+        // Write payload length
         let payload_len = self.payload.len() as u32;
-        buf.extend_from_slice(&payload_len.to_be_bytes());
-        buf.extend_from_slice(&self.payload);
-        
-        buf
+        buf.put_u32(payload_len);
+    }
+
+    /// Returns the header bytes an encrypted payload is bound to: everything
+    /// `encode_header_into` writes except the payload length, which encryption changes
+    pub fn associated_data(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(MIN_HEADER_LEN);
+        self.encode_header_into(&mut header);
+        header.truncate(header.len() - 4);
+        header
+    }
+
+    /// Returns an error if `routing_info` or `context` is too long to encode
+    pub fn validate_header(&self) -> Result<(), ProtocolError> {
+        for field in [&self.routing_info, &self.context] {
+            if field.as_ref().is_some_and(|value| value.len() > u16::MAX as usize) {
+                return Err(ProtocolError::InvalidFormat(format!(
+                    "Header field longer than {} bytes",
+                    u16::MAX
+                )));
+            }
+        }
+        Ok(())
     }
 
     pub fn decode(buf: &[u8]) -> Result<Self, ProtocolError> {
-        if buf.len() < 24 { // Minimum message size
+        if buf.len() < MIN_HEADER_LEN { // Minimum message size
             return Err(ProtocolError::InvalidFormat("Message too short".into()));
         }
 
         let mut pos = 0;
 
+        // Read protocol version
+        if buf[pos] != PROTOCOL_VERSION_MAJOR as u8 {
+            return Err(ProtocolError::VersionMismatch);
+        }
+        pos += 1;
+
         // Read message type
         let msg_type = match buf[pos] {
             0 => MessageType::Request,
             1 => MessageType::Response,
             2 => MessageType::Event,
             3 => MessageType::Error,
+            4 => MessageType::Ping,
+            5 => MessageType::Pong,
+            6 => MessageType::GoAway,
+            7 => MessageType::Stream,
+            8 => MessageType::StreamEnd,
+            9 => MessageType::Ack,
+            10 => MessageType::Cancel,
+            11 => MessageType::Subscribe,
+            12 => MessageType::Unsubscribe,
+            13 => MessageType::Handshake,
             _ => return Err(ProtocolError::InvalidFormat("Invalid message type".into())),
         };
         pos += 1;
@@ -132,6 +210,24 @@ impl Message {
         let ttl = u32::from_be_bytes(buf[pos..pos+4].try_into().unwrap());
         pos += 4;
 
+        // Read routing info and context; an empty field decodes as None
+        let mut fields = [None, None];
+        for field in &mut fields {
+            let len = u16::from_be_bytes(buf[pos..pos+2].try_into().unwrap()) as usize;
+            pos += 2;
+            // Leave room for the remaining length fields
+            if buf.len() < pos + len + 4 {
+                return Err(ProtocolError::InvalidFormat("Invalid header field length".into()));
+            }
+            if len > 0 {
+                let value = std::str::from_utf8(&buf[pos..pos+len])
+                    .map_err(|_| ProtocolError::InvalidFormat("Header field is not UTF-8".into()))?;
+                *field = Some(value.to_string());
+            }
+            pos += len;
+        }
+        let [routing_info, context] = fields;
+
         // Read payload length
         let payload_len = u32::from_be_bytes(buf[pos..pos+4].try_into().unwrap()) as usize;
         pos += 4;
@@ -151,8 +247,8 @@ impl Message {
             request_id,
             priority,
             ttl,
-            routing_info: None,
-            context: None,
+            routing_info,
+            context,
         })
     }
 }
@@ -165,31 +261,200 @@ pub enum ProtocolError {
     VersionMismatch,
     #[error("Authentication required")]
     AuthenticationRequired,
+    #[error("Permission denied for {0:?}")]
+    PermissionDenied(String),
     #[error("Compression error: {0}")]
     CompressionError(String),
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("Encryption error: {0}")]
     EncryptionError(String),
+    #[error("Timeout: {0}")]
+    Timeout(String),
+    #[error("Connection closed")]
+    ConnectionClosed,
+    #[error("Connection reset with the request in flight")]
+    ConnectionReset,
+    #[error("Peer is going away: {0}")]
+    GoAway(String),
+    #[error("Proxy error: {0}")]
+    ProxyError(String),
+    #[error("No route for {0:?}")]
+    NotFound(String),
+    #[error("Remote error: {0}")]
+    Remote(String),
+    #[error("Handler panicked: {0}")]
+    HandlerPanicked(String),
+    #[error("Server overloaded, retry after {retry_after:?}")]
+    Overloaded { retry_after: Duration },
+    #[error("Quota exceeded, retry after {retry_after:?}")]
+    QuotaExceeded { retry_after: Duration },
+    #[error("Handshake failed: {0}")]
+    HandshakeFailed(String),
+    #[error("Replayed or outdated message")]
+    Replayed,
+    #[error("Key unavailable: {0}")]
+    KeyUnavailable(String),
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("Request cancelled")]
+    Cancelled,
+    #[error("Offline queue is full")]
+    QueueFull,
+    #[error("Discovery error: {0}")]
+    DiscoveryError(String),
+    #[error("Circuit open for {0}")]
+    CircuitOpen(String),
+    #[error("Request failed after {attempts} attempts: {last}")]
+    RetriesExhausted {
+        attempts: u32,
+        last: Box<ProtocolError>,
+    },
+}
+
+impl ProtocolError {
+    /// Returns the variant name, e.g. for labelling error counters
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolError::InvalidFormat(_) => "InvalidFormat",
+            ProtocolError::VersionMismatch => "VersionMismatch",
+            ProtocolError::AuthenticationRequired => "AuthenticationRequired",
+            ProtocolError::PermissionDenied(_) => "PermissionDenied",
+            ProtocolError::CompressionError(_) => "CompressionError",
+            ProtocolError::IoError(_) => "IoError",
+            ProtocolError::EncryptionError(_) => "EncryptionError",
+            ProtocolError::Timeout(_) => "Timeout",
+            ProtocolError::ConnectionClosed => "ConnectionClosed",
+            ProtocolError::ConnectionReset => "ConnectionReset",
+            ProtocolError::GoAway(_) => "GoAway",
+            ProtocolError::ProxyError(_) => "ProxyError",
+            ProtocolError::NotFound(_) => "NotFound",
+            ProtocolError::Remote(_) => "Remote",
+            ProtocolError::HandlerPanicked(_) => "HandlerPanicked",
+            ProtocolError::Overloaded { .. } => "Overloaded",
+            ProtocolError::QuotaExceeded { .. } => "QuotaExceeded",
+            ProtocolError::HandshakeFailed(_) => "HandshakeFailed",
+            ProtocolError::Replayed => "Replayed",
+            ProtocolError::KeyUnavailable(_) => "KeyUnavailable",
+            ProtocolError::InvalidSignature(_) => "InvalidSignature",
+            ProtocolError::Cancelled => "Cancelled",
+            ProtocolError::QueueFull => "QueueFull",
+            ProtocolError::DiscoveryError(_) => "DiscoveryError",
+            ProtocolError::CircuitOpen(_) => "CircuitOpen",
+            ProtocolError::RetriesExhausted { .. } => "RetriesExhausted",
+        }
+    }
 }
 
 // Add to existing lib.rs
+pub mod acl;
+pub mod blocking;
+pub mod broker;
+pub mod buffer;
+pub mod circuit;
+pub mod client;
+pub mod codec;
 pub mod compression;
+pub(crate) mod connection;
+pub mod consul;
 pub mod discovery;
+#[cfg(feature = "edge")]
 pub mod edge;
 pub mod encryption;
+pub mod envelope;
+pub mod flags;
+pub mod gossip;
+pub mod health;
+pub(crate) mod http;
+pub mod identity;
+pub mod interceptor;
+pub mod kdf;
+pub mod keys;
+pub mod kubernetes;
+pub mod mdns;
+pub mod message;
+pub mod middleware;
+pub mod noise;
 pub mod observability;
+pub mod offline;
+pub mod pool;
+pub mod proxy;
+pub mod ratelimit;
+pub mod remote;
+pub mod resolve;
+pub mod retry;
+#[cfg(feature = "edge")]
+pub mod schedule;
+pub mod secret;
+pub mod selector;
+pub mod server;
+pub(crate) mod session;
+pub mod socket;
+pub mod spiffe;
 pub mod state;
+pub mod stream;
+pub mod tls;
 pub mod transport;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+
+// Lets code generated by `remus-macros` name this crate as `::remus` from inside it too
+extern crate self as remus;
 
 // Re-export commonly used types
-pub use compression::{compress, decompress};
-pub use discovery::{HealthStatus, ServiceInfo, ServiceRegistry};
-pub use edge::{EdgeCompute, EdgeComputeResult, EdgeFunction};
-pub use encryption::Encryptor;
-pub use observability::{Metric, Telemetry, Trace};
+pub use acl::{AccessPolicy, Authenticator, Decision, Principal};
+pub use broker::{Broker, SlowConsumerPolicy};
+pub use buffer::BufferPool;
+pub use circuit::{CircuitBreaker, CircuitPermit, CircuitState};
+pub use client::{Batch, ClientConfig, IntoAddresses, RemusClient, RequestOptions, StreamSink, UploadSink};
+pub use codec::RemusCodec;
+pub use compression::{compress, compress_tagged, decompress, decompress_tagged, Compression, CompressionConfig, ContentRule, Dictionary};
+pub use consul::ConsulBackend;
+pub use discovery::{Dependency, DependencyGraph, DependencyTracker, DiscoveryBackend, HealthStatus, Lease, MaintenanceHandle, Registration, RegistryEvent, RegistryRequest, Selection, ServiceInfo, ServiceRegistry, Strategy, TrafficSplit};
+#[cfg(feature = "edge")]
+pub use edge::{EdgeCompute, EdgeComputeResult, EdgeFunction, Limit, OverlapPolicy, ResourceLimits, Trigger, TriggerStatus};
+pub use encryption::{Cipher, CryptoProvider, Encryptor, NonceDirection, ReplayWindow, StreamOpener, StreamSealer};
+pub use envelope::{open_envelope, EnvelopeSealer};
+pub use flags::{CapabilityFlags, ExtensionFlags};
+pub use gossip::Gossip;
+pub use health::{HealthCheck, HealthProber, Probe};
+pub use identity::{IdentityKey, MessageSigner, MessageVerifier, PublicKey, Signature};
+pub use interceptor::Interceptor;
+pub use kdf::{Argon2Params, KeyDerivation};
+pub use keys::{KeyProvider, LocalKeyProvider};
+pub use kubernetes::KubernetesBackend;
+pub use mdns::MdnsDiscovery;
+pub use message::MessageExt;
+pub use middleware::TransportMiddleware;
+pub use noise::{NoiseConfig, NoiseKeypair, NoisePattern, NoiseSession};
+pub use observability::{AccessLog, AccessRecord, AuditEvent, AuditKind, AuditLog, LatencyHistogram, Metric, Telemetry, Trace};
+pub use offline::{OfflineQueue, OverflowPolicy};
+pub use pool::{ConnectionPool, PooledTransport};
+pub use proxy::{ProxyConfig, ProxyKind};
+pub use ratelimit::{Pacer, RateLimiter, RequestLimiter};
+pub use remote::RemoteRegistry;
+pub use remus_macros::service;
+pub use resolve::SrvDiscovery;
+pub use retry::RetryPolicy;
+#[cfg(feature = "edge")]
+pub use schedule::Schedule;
+pub use secret::SecretKey;
+pub use selector::Selector;
+pub use server::{Accepted, Acceptor, ConnectionContext, ErrorPayload, Middleware, RequestStream, ResponseSink, Router, Server, ServerHandle, StreamHandler};
+pub use socket::SocketConfig;
+pub use spiffe::{SpiffeId, SpiffeVerifier};
 pub use state::{StateManager, StateVersion};
-pub use transport::Transport;
+pub use stream::MessageStream;
+pub use tls::{CaBundleVerifier, CertificateVerifier, PinnedCertificates};
+pub use transport::{CoalesceConfig, KeepaliveConfig, MinThroughput, Transport, TransportStats, TransportStatsSnapshot, TrySendError};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use uring::UringTransport;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use bytes::Bytes;
+    pub use serde_json;
+}
 
 #[cfg(test)]
 mod tests {
@@ -228,5 +493,31 @@ mod tests {
         assert_eq!(decoded.request_id, original.request_id);
         assert_eq!(decoded.priority, original.priority);
         assert_eq!(decoded.ttl, original.ttl);
+        assert_eq!(decoded.routing_info, None);
+        assert_eq!(decoded.context, None);
+    }
+
+    #[test]
+    fn test_message_header_fields_round_trip() {
+        let mut original = Message::new(MessageType::Request, MessageFlags::NONE, 7, Bytes::from("body"));
+        original.routing_info = Some("users.get".into());
+        original.context = Some("trace=abc".into());
+
+        let decoded = Message::decode(&original.encode()).unwrap();
+        assert_eq!(decoded, original);
+
+        // Truncated inside the routing field
+        let encoded = original.encode();
+        assert!(Message::decode(&encoded[..MIN_HEADER_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_other_protocol_versions_are_rejected() {
+        let message = Message::new(MessageType::Request, MessageFlags::NONE, 7, Bytes::new());
+        let mut encoded = message.encode();
+        assert_eq!((encoded.len(), encoded[0]), (MIN_HEADER_LEN, PROTOCOL_VERSION_MAJOR as u8));
+
+        encoded[0] = 2;
+        assert!(matches!(Message::decode(&encoded), Err(ProtocolError::VersionMismatch)));
     }
 }
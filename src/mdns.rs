@@ -0,0 +1,495 @@
+//! Zero-configuration discovery over mDNS/DNS-SD.
+//!
+//! An [`MdnsDiscovery`] advertises [`ServiceInfo`]s on the local link as
+//! DNS-SD instances of [`SERVICE_TYPE`] (RFC 6763) over multicast DNS
+//! (RFC 6762), and registers the instances other nodes advertise in a
+//! [`ServiceRegistry`], so an edge cluster finds its members with no registry
+//! server. Each instance is a PTR record naming it, an SRV record with its
+//! port, a TXT record with the rest of its `ServiceInfo` and an A or AAAA
+//! record with its address. Instances advertised on an unspecified address
+//! are registered at the address their announcement came from.
+//!
+//! Discovered services are registered again each time they are heard from,
+//! renewing their lease, so nodes that went away without saying goodbye drop
+//! out once it lapses.
+
+use crate::{
+    discovery::{HealthStatus, ServiceInfo, ServiceRegistry},
+    ProtocolError,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::UdpSocket;
+
+/// The mDNS multicast group and port
+pub const MDNS_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// DNS-SD service type Remus services are advertised under
+pub const SERVICE_TYPE: &str = "_remus._tcp.local";
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_TTL: Duration = Duration::from_secs(120);
+const MAX_PACKET_LEN: usize = 9000;
+// Bounds how many compression pointers one name may follow
+const MAX_POINTERS: usize = 16;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// Set on records only their owner publishes, telling caches to replace what they hold
+const CACHE_FLUSH: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// Advertises services on the local network and registers those of its peers
+pub struct MdnsDiscovery {
+    registry: Arc<ServiceRegistry>,
+    services: Vec<ServiceInfo>,
+    service_type: Vec<String>,
+    interval: Duration,
+    ttl: Duration,
+}
+
+impl MdnsDiscovery {
+    /// Creates a discovery registering the services it finds in `registry`
+    pub fn new(registry: Arc<ServiceRegistry>) -> Self {
+        Self {
+            registry,
+            services: Vec::new(),
+            service_type: labels(SERVICE_TYPE),
+            interval: DEFAULT_INTERVAL,
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// Advertises `service` to the network, naming the instance by its ID
+    pub fn with_service(mut self, service: ServiceInfo) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// Advertises and browses another DNS-SD service type, e.g. `_sensors._tcp.local`
+    pub fn with_service_type(mut self, service_type: &str) -> Self {
+        self.service_type = labels(service_type);
+        self
+    }
+
+    /// Sets how often the network is queried and services are re-announced
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how long peers may cache the advertised records
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Joins the mDNS group on IPv4 and advertises and browses until it fails.
+    ///
+    /// Peers are queried and services announced every interval, and queries
+    /// naming the service type or an advertised instance are answered.
+    pub async fn run(&self) -> Result<(), ProtocolError> {
+        let socket = multicast_socket()?;
+        let group = SocketAddr::V4(MDNS_GROUP);
+        let mut ticker = tokio::time::interval(self.interval);
+        let mut buf = vec![0u8; MAX_PACKET_LEN];
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    socket.send_to(&self.query(), group).await?;
+                    if !self.services.is_empty() {
+                        socket.send_to(&self.announcement(self.ttl), group).await?;
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let (len, from) = received?;
+                    if let Some(reply) = self.handle(&buf[..len], from).await {
+                        socket.send_to(&reply, group).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Announces that the advertised services are leaving, so peers drop them at once
+    pub async fn goodbye(&self) -> Result<(), ProtocolError> {
+        if !self.services.is_empty() {
+            multicast_socket()?.send_to(&self.announcement(Duration::ZERO), SocketAddr::V4(MDNS_GROUP)).await?;
+        }
+        Ok(())
+    }
+
+    // Takes in a packet: answers a query about our services, or registers what a response announces
+    async fn handle(&self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let Some(packet) = Packet::parse(packet) else {
+            tracing::debug!("Ignoring malformed mDNS packet from {}", from);
+            return None;
+        };
+        if !packet.response {
+            let asked = packet.questions.iter().any(|(name, record_type)| {
+                matches!(*record_type, TYPE_PTR | TYPE_SRV | TYPE_TXT | TYPE_ANY)
+                    && (same_name(name, &self.service_type) || self.services.iter().any(|service| same_name(name, &self.instance(service))))
+            });
+            return (asked && !self.services.is_empty()).then(|| self.announcement(self.ttl));
+        }
+        self.absorb(packet, from).await;
+        None
+    }
+
+    async fn absorb(&self, packet: Packet, from: SocketAddr) {
+        let mut instances = Vec::new();
+        let mut targets = HashMap::new();
+        let mut texts = HashMap::new();
+        let mut addresses = HashMap::new();
+        for record in packet.records {
+            match record.data {
+                RecordData::Ptr(instance) if same_name(&record.name, &self.service_type) => instances.push((instance, record.ttl)),
+                RecordData::Srv(port, target) => {
+                    targets.insert(record.name, (port, target));
+                }
+                RecordData::Txt(entries) => {
+                    texts.insert(record.name, entries);
+                }
+                RecordData::Address(ip) => {
+                    addresses.insert(record.name, ip);
+                }
+                _ => {}
+            }
+        }
+        for (instance, ttl) in instances {
+            let Some(id) = instance.first().filter(|_| instance.len() == self.service_type.len() + 1) else {
+                continue;
+            };
+            if self.services.iter().any(|service| service.id == *id) {
+                continue;
+            }
+            if ttl == 0 {
+                self.registry.unregister(id).await;
+                continue;
+            }
+            let (Some((port, target)), Some(entries)) = (targets.remove(&instance), texts.remove(&instance)) else {
+                continue;
+            };
+            let ip = addresses.get(&target).copied().unwrap_or(from.ip());
+            self.registry.register(service_from_txt(id, SocketAddr::new(ip, port), entries)).await;
+        }
+    }
+
+    fn query(&self) -> Vec<u8> {
+        let mut packet = header(0, 1, 0);
+        put_name(&mut packet, &self.service_type);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    fn announcement(&self, ttl: Duration) -> Vec<u8> {
+        let ttl = ttl.as_secs().min(u32::MAX as u64) as u32;
+        let mut records = Vec::new();
+        let mut count = 0;
+        for service in &self.services {
+            let instance = self.instance(service);
+            let host = vec![service.id.clone(), "local".to_string()];
+
+            let mut name = Vec::new();
+            put_name(&mut name, &instance);
+            put_record(&mut records, &self.service_type, TYPE_PTR, CLASS_IN, ttl, &name);
+
+            // Priority and weight, then the port and host
+            let mut srv = vec![0; 4];
+            srv.extend_from_slice(&service.address.port().to_be_bytes());
+            put_name(&mut srv, &host);
+            put_record(&mut records, &instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, ttl, &srv);
+            put_record(&mut records, &instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, ttl, &txt(service));
+            count += 3;
+
+            let (record_type, octets) = match service.address.ip() {
+                ip if ip.is_unspecified() => continue,
+                IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+            };
+            put_record(&mut records, &host, record_type, CLASS_IN | CACHE_FLUSH, ttl, &octets);
+            count += 1;
+        }
+        let mut packet = header(FLAGS_RESPONSE, 0, count);
+        packet.extend_from_slice(&records);
+        packet
+    }
+
+    fn instance(&self, service: &ServiceInfo) -> Vec<String> {
+        std::iter::once(service.id.clone()).chain(self.service_type.iter().cloned()).collect()
+    }
+}
+
+fn multicast_socket() -> Result<UdpSocket, ProtocolError> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Other responders on the host share the port
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_GROUP.port())).into())?;
+    socket.join_multicast_v4(MDNS_GROUP.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn txt(service: &ServiceInfo) -> Vec<u8> {
+    let mut entries = vec![
+        ("name".to_string(), service.name.clone()),
+        ("version".to_string(), service.version.clone()),
+        ("capabilities".to_string(), service.capabilities.join(",")),
+        ("health".to_string(), health_name(service.health_status).to_string()),
+    ];
+    if let Some(key) = &service.public_key {
+        entries.push(("public_key".to_string(), key.to_string()));
+    }
+    entries.extend(service.metadata.iter().map(|(key, value)| (format!("meta.{key}"), value.clone())));
+
+    let mut data = Vec::new();
+    for (key, value) in entries {
+        let entry = format!("{key}={value}");
+        // A TXT string holds at most 255 bytes; longer entries are left out
+        if entry.len() <= u8::MAX as usize {
+            data.push(entry.len() as u8);
+            data.extend_from_slice(entry.as_bytes());
+        }
+    }
+    data
+}
+
+fn service_from_txt(id: &str, address: SocketAddr, entries: Vec<(String, String)>) -> ServiceInfo {
+    let mut service = ServiceInfo {
+        id: id.to_string(),
+        name: id.to_string(),
+        version: String::new(),
+        capabilities: Vec::new(),
+        address,
+        metadata: HashMap::new(),
+        last_seen: SystemTime::now(),
+        health_status: HealthStatus::Unknown,
+        public_key: None,
+        health_check: None,
+    };
+    for (key, value) in entries {
+        match key.as_str() {
+            "name" => service.name = value,
+            "version" => service.version = value,
+            "capabilities" => service.capabilities = value.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect(),
+            "health" => service.health_status = health_from_name(&value),
+            "public_key" => service.public_key = value.parse().ok(),
+            _ => {
+                if let Some(key) = key.strip_prefix("meta.") {
+                    service.metadata.insert(key.to_string(), value);
+                }
+            }
+        }
+    }
+    service
+}
+
+fn health_name(status: HealthStatus) -> &'static str {
+    match status {
+        HealthStatus::Healthy => "healthy",
+        HealthStatus::Degraded => "degraded",
+        HealthStatus::Unhealthy => "unhealthy",
+        HealthStatus::Unknown => "unknown",
+    }
+}
+
+fn health_from_name(name: &str) -> HealthStatus {
+    match name {
+        "healthy" => HealthStatus::Healthy,
+        "degraded" => HealthStatus::Degraded,
+        "unhealthy" => HealthStatus::Unhealthy,
+        _ => HealthStatus::Unknown,
+    }
+}
+
+fn labels(name: &str) -> Vec<String> {
+    name.trim_end_matches('.').split('.').map(str::to_string).collect()
+}
+
+fn same_name(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    [0, flags, questions, answers, 0, 0].iter().flat_map(|field| field.to_be_bytes()).collect()
+}
+
+// Names are written uncompressed, which every decoder accepts
+fn put_name(out: &mut Vec<u8>, labels: &[String]) {
+    for label in labels {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn put_record(out: &mut Vec<u8>, name: &[String], record_type: u16, class: u16, ttl: u32, data: &[u8]) {
+    put_name(out, name);
+    out.extend_from_slice(&record_type.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&ttl.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+struct Packet {
+    response: bool,
+    questions: Vec<(Vec<String>, u16)>,
+    records: Vec<Record>,
+}
+
+struct Record {
+    name: Vec<String>,
+    ttl: u32,
+    data: RecordData,
+}
+
+enum RecordData {
+    Ptr(Vec<String>),
+    Srv(u16, Vec<String>),
+    Txt(Vec<(String, String)>),
+    Address(IpAddr),
+    Other,
+}
+
+impl Packet {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let field = |index: usize| packet.get(index * 2..index * 2 + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        let response = field(1)? & 0x8000 != 0;
+        let (questions, records) = (field(2)?, field(3)? as usize + field(4)? as usize + field(5)? as usize);
+        let mut position = 12;
+
+        let mut parsed = Self { response, questions: Vec::new(), records: Vec::new() };
+        for _ in 0..questions {
+            let (name, end) = read_name(packet, position)?;
+            let record_type = u16::from_be_bytes(packet.get(end..end + 2)?.try_into().unwrap());
+            parsed.questions.push((name, record_type));
+            position = end + 4;
+        }
+        for _ in 0..records {
+            let (name, end) = read_name(packet, position)?;
+            let fixed = packet.get(end..end + 10)?;
+            let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+            let ttl = u32::from_be_bytes(fixed[4..8].try_into().unwrap());
+            let (start, len) = (end + 10, u16::from_be_bytes([fixed[8], fixed[9]]) as usize);
+            let data = packet.get(start..start + len)?;
+            let data = match record_type {
+                TYPE_PTR => RecordData::Ptr(read_name(packet, start)?.0),
+                TYPE_SRV if data.len() > 6 => RecordData::Srv(u16::from_be_bytes([data[4], data[5]]), read_name(packet, start + 6)?.0),
+                TYPE_TXT => RecordData::Txt(read_txt(data)),
+                TYPE_A if data.len() == 4 => RecordData::Address(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+                TYPE_AAAA if data.len() == 16 => RecordData::Address(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+                _ => RecordData::Other,
+            };
+            parsed.records.push(Record { name, ttl, data });
+            position = start + len;
+        }
+        Some(parsed)
+    }
+}
+
+// Reads the name at `position`, following compression pointers, and returns it with where it ends
+fn read_name(packet: &[u8], mut position: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *packet.get(position)? as usize;
+        match len {
+            0 => break,
+            0xc0.. => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(position + 2);
+                position = (len & 0x3f) << 8 | *packet.get(position + 1)? as usize;
+            }
+            1..=63 => {
+                labels.push(String::from_utf8_lossy(packet.get(position + 1..position + 1 + len)?).into_owned());
+                position += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+    Some((labels, end.unwrap_or(position + 1)))
+}
+
+fn read_txt(mut data: &[u8]) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let Some(entry) = rest.get(..len as usize) else { break };
+        let entry = String::from_utf8_lossy(entry);
+        let (key, value) = entry.split_once('=').unwrap_or((&entry, ""));
+        entries.push((key.to_ascii_lowercase(), value.to_string()));
+        data = &rest[len as usize..];
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{discovery::tests::create_test_service, flags::CapabilityFlags, identity::IdentityKey};
+
+    fn service(id: &str, address: &str) -> ServiceInfo {
+        create_test_service(id)
+            .with_version("1.2.0")
+            .with_address(address)
+            .with_capabilities(CapabilityFlags::STREAMING | CapabilityFlags::COMPRESSION, None::<String>)
+            .with_metadata(&[("zone", "greenhouse")])
+            .with_public_key(IdentityKey::generate().public_key())
+    }
+
+    #[tokio::test]
+    async fn test_announced_services_are_registered_and_dropped_on_goodbye() {
+        let advertised = service("node-a", "192.168.1.20:7000");
+        let node_a = MdnsDiscovery::new(Arc::new(ServiceRegistry::new(Duration::from_secs(60)))).with_service(advertised.clone());
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        let node_b = MdnsDiscovery::new(registry.clone());
+        let from: SocketAddr = "192.168.1.20:5353".parse().unwrap();
+
+        // node B's query is answered by node A, whose answer node B registers
+        let answer = node_a.handle(&node_b.query(), from).await.unwrap();
+        assert!(node_b.handle(&answer, from).await.is_none());
+        let found = registry.get_service("node-a").await.unwrap();
+        assert_eq!((found.name.as_str(), found.version.as_str(), found.address), ("sensors", "1.2.0", advertised.address));
+        assert_eq!(found.capabilities, advertised.capabilities);
+        assert_eq!(found.metadata, advertised.metadata);
+        assert_eq!((found.health_status, found.public_key), (HealthStatus::Healthy, advertised.public_key));
+
+        // Nodes hearing their own announcements do not register themselves
+        node_a.handle(&answer, from).await;
+        assert!(node_a.registry.get_service("node-a").await.is_none());
+
+        node_b.handle(&node_a.announcement(Duration::ZERO), from).await;
+        assert!(registry.get_service("node-a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unspecified_addresses_resolve_to_the_sender() {
+        let node_a = MdnsDiscovery::new(Arc::new(ServiceRegistry::new(Duration::from_secs(60)))).with_service(service("node-a", "0.0.0.0:7000"));
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        let node_b = MdnsDiscovery::new(registry.clone());
+        node_b.handle(&node_a.announcement(DEFAULT_TTL), "10.0.0.7:5353".parse().unwrap()).await;
+        assert_eq!(registry.get_service("node-a").await.unwrap().address, "10.0.0.7:7000".parse().unwrap());
+
+        // Other service types, unrelated queries and garbage are ignored
+        let other = MdnsDiscovery::new(Arc::new(ServiceRegistry::new(Duration::from_secs(60)))).with_service_type("_other._tcp.local");
+        assert!(node_a.handle(&other.query(), "10.0.0.8:5353".parse().unwrap()).await.is_none());
+        assert!(node_b.handle(b"\x00\x01", "10.0.0.8:5353".parse().unwrap()).await.is_none());
+    }
+}
@@ -3,12 +3,41 @@ use bytes::Bytes;
 use serde::{de::DeserializeOwned, Serialize};
 
 /// Extension trait for working with serializable payloads
+///
+/// ```rust
+/// use remus::{Message, MessageExt, ProtocolError};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct MyRequest {
+///     name: String,
+///     value: i32,
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct MyResponse {
+///     result: String,
+/// }
+///
+/// fn handle_request(msg: Message) -> Result<Message, ProtocolError> {
+///     // Deserialize the request
+///     let request: MyRequest = msg.deserialize()?;
+///
+///     // Process the request
+///     let response = MyResponse {
+///         result: format!("Processed {}: {}", request.name, request.value),
+///     };
+///
+///     // Create and return the response
+///     Message::response(msg.request_id, &response)
+/// }
+/// ```
 pub trait MessageExt {
     /// Creates a new request message with a serializable payload
     fn request<T: Serialize>(payload: &T) -> Result<Message, ProtocolError>;
     
     /// Creates a new response message with a serializable payload
-    fn response<T: Serialize>(request_id: u32, payload: &T) -> Result<Message, ProtocolError>;
+    fn response<T: Serialize>(request_id: u64, payload: &T) -> Result<Message, ProtocolError>;
     
     /// Deserializes the payload into the specified type
     fn deserialize<T: DeserializeOwned>(&self) -> Result<T, ProtocolError>;
@@ -27,7 +56,7 @@ impl MessageExt for Message {
         ))
     }
 
-    fn response<T: Serialize>(request_id: u32, payload: &T) -> Result<Message, ProtocolError> {
+    fn response<T: Serialize>(request_id: u64, payload: &T) -> Result<Message, ProtocolError> {
         let bytes = serde_json::to_vec(payload)
             .map_err(|e| ProtocolError::InvalidFormat(e.to_string()))?;
             
@@ -45,31 +74,19 @@ impl MessageExt for Message {
     }
 }
 
-/// Example usage:
-/// ```rust
-/// use remus::MessageExt;
-/// 
-/// #[derive(Serialize, Deserialize)]
-/// struct MyRequest {
-///     name: String,
-///     value: i32,
-/// }
-/// 
-/// #[derive(Serialize, Deserialize)]
-/// struct MyResponse {
-///     result: String,
-/// }
-/// 
-/// async fn handle_request(msg: Message) -> Result<Message, ProtocolError> {
-///     // Deserialize the request
-///     let request: MyRequest = msg.deserialize()?;
-///     
-///     // Process the request
-///     let response = MyResponse {
-///         result: format!("Processed {}: {}", request.name, request.value),
-///     };
-///     
-///     // Create and return the response
-///     Message::response(msg.request_id, &response)
-/// }
-/// ``` 
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_round_trip() {
+        let request = Message::request(&vec![1, 2, 3]).unwrap();
+        assert_eq!(request.msg_type, MessageType::Request);
+        assert!(request.flags.contains(MessageFlags::IDEMPOTENT));
+
+        let response = Message::response(request.request_id, &"done").unwrap();
+        assert_eq!(response.request_id, request.request_id);
+        assert_eq!(response.deserialize::<String>().unwrap(), "done");
+        assert!(response.deserialize::<Vec<i32>>().is_err());
+    }
+}
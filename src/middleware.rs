@@ -0,0 +1,86 @@
+use crate::{Message, ProtocolError};
+
+/// Hook into every application message a `Transport` sends or receives.
+///
+/// Middleware can mutate messages in place or reject them by returning an error.
+/// Layers run in registration order on send and in reverse order on receive, so
+/// the first layer added is the outermost. Control frames (Ping, Pong, GoAway)
+/// bypass middleware.
+pub trait TransportMiddleware: Send + Sync {
+    /// Called before a message is queued for sending
+    fn on_send(&self, _message: &mut Message) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Called after a message is decoded, before it is returned from `receive`
+    fn on_receive(&self, _message: &mut Message) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageFlags, MessageType, Transport};
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use tokio::io::duplex;
+
+    struct Stamp(u8);
+
+    impl TransportMiddleware for Stamp {
+        fn on_send(&self, message: &mut Message) -> Result<(), ProtocolError> {
+            let mut payload = message.payload.to_vec();
+            payload.push(self.0);
+            message.payload = Bytes::from(payload);
+            Ok(())
+        }
+
+        fn on_receive(&self, message: &mut Message) -> Result<(), ProtocolError> {
+            if message.payload.last() != Some(&self.0) {
+                return Err(ProtocolError::InvalidFormat("Missing stamp".into()));
+            }
+            message.payload.truncate(message.payload.len() - 1);
+            Ok(())
+        }
+    }
+
+    struct RejectUrgent;
+
+    impl TransportMiddleware for RejectUrgent {
+        fn on_send(&self, message: &mut Message) -> Result<(), ProtocolError> {
+            if message.flags.contains(MessageFlags::URGENT) {
+                return Err(ProtocolError::InvalidFormat("Urgent messages are not allowed".into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_layers_in_order() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client)
+            .with_middleware(Arc::new(Stamp(1)))
+            .with_middleware(Arc::new(Stamp(2)));
+        let mut server_transport = Transport::new(server)
+            .with_middleware(Arc::new(Stamp(1)))
+            .with_middleware(Arc::new(Stamp(2)));
+
+        let message = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::from("hi"));
+        client_transport.send(message).await.unwrap();
+
+        // Receive unwinds the layers in reverse, so the payload comes back clean
+        let received = server_transport.receive().await.unwrap();
+        assert_eq!(received.payload, Bytes::from("hi"));
+    }
+
+    #[tokio::test]
+    async fn test_middleware_rejects_messages() {
+        let (client, _server) = duplex(1024);
+        let mut transport = Transport::new(client).with_middleware(Arc::new(RejectUrgent));
+
+        let message = Message::new(MessageType::Request, MessageFlags::URGENT, 1, Bytes::from("now"));
+        assert!(transport.send(message).await.is_err());
+        assert_eq!(transport.queued_bytes(), 0);
+    }
+}
@@ -0,0 +1,1172 @@
+//! Noise protocol handshakes (`Noise_XX_25519_ChaChaPoly_SHA256` and
+//! `Noise_IK_25519_ChaChaPoly_SHA256`) as an alternative to pre-shared keys.
+//!
+//! Both ends hold a static X25519 keypair. The handshake authenticates both
+//! static keys, mixes in fresh ephemeral keys for forward secrecy and ends
+//! with a [`NoiseSession`]: a key per direction and the handshake hash, which
+//! commits to the whole transcript. Application messages are then sealed by
+//! an [`Encryptor`] holding those keys, or by a custom
+//! [`CryptoProvider`](crate::CryptoProvider) through
+//! [`NoiseSession::into_encryptor_with`].
+//!
+//! The initiator uses IK when it already knows the responder's static key and
+//! XX otherwise. Its first frame carries the [`CapabilityFlags`] of the
+//! patterns it supports and the one it chose; both are mixed into the
+//! handshake as the prologue, so tampering with them makes it fail.
+//!
+//! A responder configured with
+//! [`with_resumption_tickets`](NoiseConfig::with_resumption_tickets) hands
+//! initiators that ask for one an encrypted, single-use ticket after each
+//! handshake. An initiator configured with
+//! [`with_session_resumption`](NoiseConfig::with_session_resumption)
+//! presents it on its next connection: proving it holds the ticket's secret
+//! and exchanging fresh nonces then stands in for the key exchange and for
+//! authenticating the peer again. A ticket the responder cannot redeem, e.g.
+//! because it expired, falls back to a full handshake on the same connection.
+//!
+#![cfg_attr(feature = "pq", doc = "With the `pq` feature, [`with_hybrid_kem`](NoiseConfig::with_hybrid_kem)")]
+#![cfg_attr(not(feature = "pq"), doc = "With the `pq` feature, `NoiseConfig::with_hybrid_kem`")]
+//! adds an ML-KEM-768 exchange to the handshake (the `e1`/`ekem1` tokens of
+//! Noise's HFS extension), so recorded traffic stays confidential unless both
+//! X25519 and ML-KEM are broken. The initiator offers it through
+//! [`CapabilityFlags::HYBRID_PQ`]; a responder that does not enable it
+//! declines, and [`NoiseSession::is_hybrid`] tells which way it went.
+
+use crate::{
+    encryption::{Cipher, CryptoProvider, NonceDirection},
+    flags::CapabilityFlags,
+    kdf::hmac_sha256,
+    secret::{constant_time_eq, SecretKey},
+    Encryptor, Message, MessageFlags, MessageType, ProtocolError, Transport,
+};
+use base64::Engine;
+use bytes::{BufMut, Bytes};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+#[cfg(feature = "pq")]
+use ml_kem::{
+    kem::{Decapsulate, Encapsulate},
+    Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768,
+};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+const PROLOGUE: &[u8] = b"remus/noise/v1";
+const RESUME_PROLOGUE: &[u8] = b"remus/noise/v1/resume";
+const TICKET_AAD: &[u8] = b"remus/ticket/v1";
+const CRYPTO_PROVIDER_LABEL: &[u8] = b"remus/noise/v1/provider";
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 32;
+// ML-KEM-768's encapsulation key and ciphertext
+const KEM_PUBLIC_LEN: usize = 1184;
+const KEM_CIPHERTEXT_LEN: usize = 1088;
+// A random nonce, then the sealed peer static key, resumption secret, expiry and pattern
+const TICKET_LEN: usize = 12 + KEY_LEN * 2 + 8 + 1 + TAG_LEN;
+
+type PeerVerifier = Arc<dyn Fn(&[u8; 32]) -> bool + Send + Sync>;
+#[cfg(feature = "pq")]
+type KemDecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+#[cfg(feature = "pq")]
+type KemEncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// A static X25519 keypair identifying one end of a Noise handshake
+pub struct NoiseKeypair {
+    // Wiped on drop
+    secret: StaticSecret,
+    public: [u8; KEY_LEN],
+}
+
+impl NoiseKeypair {
+    pub fn generate() -> Self {
+        Self::from_static(StaticSecret::random_from_rng(rand::thread_rng()))
+    }
+
+    pub fn from_secret(secret: &[u8; KEY_LEN]) -> Self {
+        Self::from_static(StaticSecret::from(*secret))
+    }
+
+    fn from_static(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.public
+    }
+
+    pub(crate) fn dh(&self, public: &[u8; KEY_LEN]) -> Result<[u8; KEY_LEN], ProtocolError> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*public));
+        // A low-order point would make the result independent of our key
+        if !shared.was_contributory() {
+            return Err(handshake_error("Peer sent a low-order key"));
+        }
+        Ok(shared.to_bytes())
+    }
+}
+
+impl fmt::Debug for NoiseKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseKeypair").field("public", &encode_key(&self.public)).finish_non_exhaustive()
+    }
+}
+
+/// Handshake pattern of a Noise session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoisePattern {
+    /// Static keys are exchanged during the handshake; three messages
+    XX,
+    /// The initiator knows the responder's static key up front; two messages
+    IK,
+}
+
+impl NoisePattern {
+    pub fn flag(&self) -> CapabilityFlags {
+        match self {
+            NoisePattern::XX => CapabilityFlags::NOISE_XX,
+            NoisePattern::IK => CapabilityFlags::NOISE_IK,
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            NoisePattern::XX => 0,
+            NoisePattern::IK => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        [NoisePattern::XX, NoisePattern::IK].into_iter().find(|pattern| pattern.code() == code)
+    }
+
+    fn protocol_name(&self) -> &'static [u8] {
+        match self {
+            NoisePattern::XX => b"Noise_XX_25519_ChaChaPoly_SHA256",
+            NoisePattern::IK => b"Noise_IK_25519_ChaChaPoly_SHA256",
+        }
+    }
+
+    fn messages(&self) -> &'static [&'static [Token]] {
+        use Token::*;
+        match self {
+            NoisePattern::XX => &[&[E, E1], &[E, EE, EKEM1, S, ES], &[S, SE]],
+            NoisePattern::IK => &[&[E, ES, E1, S, SS], &[E, EE, EKEM1, SE]],
+        }
+    }
+}
+
+// E1 and EKEM1 are skipped unless the handshake is hybrid
+#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+    E1,
+    EKEM1,
+}
+
+/// Settings for one end of a Noise handshake
+#[derive(Clone)]
+pub struct NoiseConfig {
+    keypair: Arc<NoiseKeypair>,
+    patterns: CapabilityFlags,
+    remote_static: Option<[u8; KEY_LEN]>,
+    verifier: Option<PeerVerifier>,
+    issuer: Option<Arc<TicketIssuer>>,
+    // The ticket for the next connection, shared by clones of the config
+    tickets: Option<Arc<Mutex<Option<Ticket>>>>,
+    hybrid: bool,
+}
+
+impl NoiseConfig {
+    /// Creates a config supporting both patterns that accepts any peer static key
+    pub fn new(keypair: NoiseKeypair) -> Self {
+        Self {
+            keypair: Arc::new(keypair),
+            patterns: CapabilityFlags::NOISE_XX | CapabilityFlags::NOISE_IK,
+            remote_static: None,
+            verifier: None,
+            issuer: None,
+            tickets: None,
+            hybrid: false,
+        }
+    }
+
+    /// As responder, issues a resumption ticket sealed under `ticket_key` and
+    /// valid for `lifetime` after each handshake whose initiator asks for one.
+    ///
+    /// Responders sharing `ticket_key` redeem each other's tickets. Each ticket
+    /// is redeemed once; the replay record lives in this config and its clones.
+    pub fn with_resumption_tickets(mut self, ticket_key: &SecretKey, lifetime: Duration) -> Self {
+        self.issuer = Some(Arc::new(TicketIssuer {
+            cipher: ChaCha20Poly1305::new(ticket_key.as_bytes().into()),
+            lifetime,
+            redeemed: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /// As initiator, keeps the ticket the responder issues and resumes the
+    /// session with it on the next connection; clones of this config share it
+    pub fn with_session_resumption(mut self) -> Self {
+        self.tickets = Some(Arc::default());
+        self
+    }
+
+    /// Returns whether a ticket is held for the next connection
+    pub fn has_resumption_ticket(&self) -> bool {
+        self.tickets
+            .as_ref()
+            .is_some_and(|tickets| tickets.lock().unwrap().as_ref().is_some_and(|ticket| ticket.expires_at > unix_now()))
+    }
+
+    /// Adds an ML-KEM-768 key exchange to handshakes whose peer also enables it
+    #[cfg(feature = "pq")]
+    pub fn with_hybrid_kem(mut self) -> Self {
+        self.hybrid = true;
+        self
+    }
+
+    /// Sets the responder's static key, letting the initiator use IK
+    pub fn with_remote_static(mut self, public_key: [u8; KEY_LEN]) -> Self {
+        self.remote_static = Some(public_key);
+        self
+    }
+
+    /// Limits the patterns used or accepted to the `NOISE_*` flags in `patterns`
+    pub fn with_patterns(mut self, patterns: CapabilityFlags) -> Self {
+        self.patterns = patterns & (CapabilityFlags::NOISE_XX | CapabilityFlags::NOISE_IK);
+        self
+    }
+
+    /// Fails the handshake unless `verifier` accepts the peer's static key
+    pub fn with_peer_verifier<F>(mut self, verifier: F) -> Self
+    where
+        F: Fn(&[u8; KEY_LEN]) -> bool + Send + Sync + 'static,
+    {
+        self.verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.keypair.public
+    }
+
+    fn verify_peer(&self, public_key: &[u8; KEY_LEN]) -> Result<(), ProtocolError> {
+        match &self.verifier {
+            Some(verifier) if !verifier(public_key) => Err(handshake_error("Peer static key rejected")),
+            _ => Ok(()),
+        }
+    }
+
+    // Patterns supported, plus whether this initiator wants a resumption ticket and a hybrid handshake
+    fn offered(&self) -> CapabilityFlags {
+        let mut offered = self.patterns;
+        offered.set(CapabilityFlags::SESSION_RESUMPTION, self.tickets.is_some());
+        offered.set(CapabilityFlags::HYBRID_PQ, self.hybrid);
+        offered
+    }
+
+    // Takes the held ticket, which is used at most once
+    fn take_ticket(&self) -> Option<Ticket> {
+        let ticket = self.tickets.as_ref()?.lock().unwrap().take()?;
+        (ticket.expires_at > unix_now()).then_some(ticket)
+    }
+}
+
+impl fmt::Debug for NoiseConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseConfig")
+            .field("keypair", &self.keypair)
+            .field("patterns", &self.patterns)
+            .field("remote_static", &self.remote_static.as_ref().map(encode_key))
+            .field("hybrid", &self.hybrid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The outcome of a completed handshake
+pub struct NoiseSession {
+    pattern: NoisePattern,
+    initiator: bool,
+    resumed: bool,
+    hybrid: bool,
+    remote_static: [u8; KEY_LEN],
+    handshake_hash: [u8; KEY_LEN],
+    send_key: [u8; KEY_LEN],
+    receive_key: [u8; KEY_LEN],
+    // Seeds the tickets that resume this session
+    resumption: [u8; KEY_LEN],
+}
+
+impl NoiseSession {
+    /// The pattern that authenticated the peer, in the original handshake if this one was resumed
+    pub fn pattern(&self) -> NoisePattern {
+        self.pattern
+    }
+
+    /// Returns whether the session was resumed from a ticket instead of a full handshake
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Returns whether the handshake included an ML-KEM exchange; resumed sessions never do
+    pub fn is_hybrid(&self) -> bool {
+        self.hybrid
+    }
+
+    /// The peer's authenticated static key
+    pub fn remote_static(&self) -> [u8; KEY_LEN] {
+        self.remote_static
+    }
+
+    /// Hash of the handshake transcript; equal on both ends, unique to the session
+    pub fn handshake_hash(&self) -> [u8; KEY_LEN] {
+        self.handshake_hash
+    }
+
+    /// Creates the encryptor sealing this end's messages and opening the peer's
+    pub fn into_encryptor(self) -> Encryptor {
+        let direction = if self.initiator { NonceDirection::Initiator } else { NonceDirection::Responder };
+        Encryptor::with_cipher(&self.send_key, Cipher::ChaCha20Poly1305)
+            .with_receive_key(&self.receive_key)
+            .with_session_nonces(direction)
+    }
+
+    /// Like [`into_encryptor`](Self::into_encryptor), sealing with `provider`
+    /// under keys its [`handshake`](CryptoProvider::handshake) derives from this session's
+    pub fn into_encryptor_with(self, provider: Arc<dyn CryptoProvider>) -> Result<Encryptor, ProtocolError> {
+        let (direction, mut secret) = if self.initiator {
+            (NonceDirection::Initiator, hmac_sha256(&self.send_key, &[CRYPTO_PROVIDER_LABEL, &self.receive_key]))
+        } else {
+            (NonceDirection::Responder, hmac_sha256(&self.receive_key, &[CRYPTO_PROVIDER_LABEL, &self.send_key]))
+        };
+        let encryptor = Encryptor::from_handshake(provider, &secret, &self.handshake_hash, direction);
+        secret.zeroize();
+        encryptor
+    }
+}
+
+impl Drop for NoiseSession {
+    fn drop(&mut self) {
+        self.send_key.zeroize();
+        self.receive_key.zeroize();
+        self.resumption.zeroize();
+    }
+}
+
+impl fmt::Debug for NoiseSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseSession")
+            .field("pattern", &self.pattern)
+            .field("resumed", &self.resumed)
+            .field("hybrid", &self.hybrid)
+            .field("remote_static", &encode_key(&self.remote_static))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs the initiator side of a handshake over `transport`
+pub async fn initiate<T>(transport: &mut Transport<T>, config: &NoiseConfig) -> Result<NoiseSession, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(ticket) = config.take_ticket() {
+        if let Some(session) = resume(transport, config, ticket).await? {
+            return accept_ticket(transport, config, session).await;
+        }
+    }
+
+    let pattern = match config.remote_static {
+        Some(_) if config.patterns.contains(CapabilityFlags::NOISE_IK) => NoisePattern::IK,
+        _ if config.patterns.contains(CapabilityFlags::NOISE_XX) => NoisePattern::XX,
+        _ => return Err(handshake_error("No usable handshake pattern")),
+    };
+    let mut handshake = Handshake::new(pattern, true, config, config.offered(), config.remote_static);
+
+    let mut first = Vec::new();
+    first.put_u32(config.offered().bits());
+    first.put_u32(pattern.flag().bits());
+    first.extend_from_slice(&handshake.write_message()?);
+    send_frame(transport, first).await?;
+    let session = run(transport, handshake, config).await?;
+    accept_ticket(transport, config, session).await
+}
+
+/// Runs the responder side of a handshake over `transport`
+pub async fn respond<T>(transport: &mut Transport<T>, config: &NoiseConfig) -> Result<NoiseSession, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut first = receive_frame(transport).await?;
+    let (mut offered, mut chosen) = read_flags(&first)?;
+    if chosen == CapabilityFlags::SESSION_RESUMPTION {
+        match config.issuer.as_ref().and_then(|issuer| issuer.redeem(config, &first)) {
+            Some(mut redeemed) => {
+                let mut server_nonce = [0u8; NONCE_LEN];
+                rand::thread_rng().fill(&mut server_nonce);
+                let keys = ResumedKeys::derive(&redeemed.secret, &first[8..], &server_nonce);
+                redeemed.secret.zeroize();
+                let mut accepted = vec![1];
+                accepted.extend_from_slice(&server_nonce);
+                accepted.extend_from_slice(&keys.confirmation);
+                send_frame(transport, accepted).await?;
+                let session = keys.into_session(redeemed.pattern, false, redeemed.remote_static);
+                return issue_ticket(transport, config, offered, session).await;
+            }
+            // The initiator falls back to a full handshake
+            None => {
+                send_frame(transport, vec![0]).await?;
+                first = receive_frame(transport).await?;
+                (offered, chosen) = read_flags(&first)?;
+            }
+        }
+    }
+    let pattern = [NoisePattern::XX, NoisePattern::IK]
+        .into_iter()
+        .find(|pattern| pattern.flag() == chosen && config.patterns.contains(chosen) && offered.contains(chosen))
+        .ok_or_else(|| handshake_error("Unsupported handshake pattern"))?;
+
+    let mut handshake = Handshake::new(pattern, false, config, offered, None);
+    handshake.read_message(&first[8..])?;
+    let session = run(transport, handshake, config).await?;
+    issue_ticket(transport, config, offered, session).await
+}
+
+fn read_flags(first: &[u8]) -> Result<(CapabilityFlags, CapabilityFlags), ProtocolError> {
+    if first.len() < 8 {
+        return Err(handshake_error("Truncated handshake"));
+    }
+    let offered = CapabilityFlags::from_bits_truncate(u32::from_be_bytes(first[..4].try_into().unwrap()));
+    let chosen = CapabilityFlags::from_bits_truncate(u32::from_be_bytes(first[4..8].try_into().unwrap()));
+    Ok((offered, chosen))
+}
+
+// Exchanges the remaining handshake messages, alternating with the peer
+async fn run<T>(transport: &mut Transport<T>, mut handshake: Handshake<'_>, config: &NoiseConfig) -> Result<NoiseSession, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    while !handshake.is_finished() {
+        if handshake.is_our_turn() {
+            let message = handshake.write_message()?;
+            send_frame(transport, message).await?;
+        } else {
+            let message = receive_frame(transport).await?;
+            handshake.read_message(&message)?;
+        }
+    }
+    let session = handshake.split()?;
+    config.verify_peer(&session.remote_static)?;
+    Ok(session)
+}
+
+// Presents `ticket`, returning the resumed session, or `None` if the responder
+// turned it down and expects a full handshake instead
+async fn resume<T>(transport: &mut Transport<T>, config: &NoiseConfig, ticket: Ticket) -> Result<Option<NoiseSession>, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client_nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill(&mut client_nonce);
+    let mut first = Vec::with_capacity(8 + TICKET_LEN + NONCE_LEN + KEY_LEN);
+    first.put_u32(config.offered().bits());
+    first.put_u32(CapabilityFlags::SESSION_RESUMPTION.bits());
+    first.extend_from_slice(&ticket.ticket);
+    first.extend_from_slice(&client_nonce);
+    let binder = binder(&ticket.secret, &first);
+    first.extend_from_slice(&binder);
+    send_frame(transport, first.clone()).await?;
+
+    let reply = receive_frame(transport).await?;
+    match reply.first() {
+        Some(0) if reply.len() == 1 => return Ok(None),
+        Some(1) if reply.len() == 1 + NONCE_LEN + KEY_LEN => {}
+        _ => return Err(handshake_error("Malformed resumption reply")),
+    }
+    let keys = ResumedKeys::derive(&ticket.secret, &first[8..], &reply[1..1 + NONCE_LEN]);
+    if !constant_time_eq(&keys.confirmation, &reply[1 + NONCE_LEN..]) {
+        return Err(handshake_error("Resumption not confirmed"));
+    }
+    Ok(Some(keys.into_session(ticket.pattern, true, ticket.remote_static)))
+}
+
+// As initiator, stores the ticket the responder sends after the handshake, if it asked for one
+async fn accept_ticket<T>(transport: &mut Transport<T>, config: &NoiseConfig, session: NoiseSession) -> Result<NoiseSession, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(tickets) = &config.tickets else {
+        return Ok(session);
+    };
+    let frame = receive_frame(transport).await?;
+    // An empty frame means the responder issues no tickets
+    if frame.is_empty() {
+        return Ok(session);
+    }
+    let (mut secret, wrap_key) = hkdf(&session.resumption, b"ticket");
+    let opened = ChaCha20Poly1305::new(&wrap_key.into())
+        .decrypt(&[0; 12].into(), Payload { msg: &frame, aad: TICKET_AAD })
+        .ok()
+        .filter(|opened| opened.len() == 8 + TICKET_LEN)
+        .ok_or_else(|| handshake_error("Malformed resumption ticket"))?;
+    *tickets.lock().unwrap() = Some(Ticket {
+        ticket: opened[8..].to_vec(),
+        secret: SecretKey::new(secret),
+        expires_at: u64::from_be_bytes(opened[..8].try_into().unwrap()),
+        pattern: session.pattern,
+        remote_static: session.remote_static,
+    });
+    secret.zeroize();
+    Ok(session)
+}
+
+// As responder, answers an initiator that asked for a ticket with one, or an empty frame
+async fn issue_ticket<T>(
+    transport: &mut Transport<T>,
+    config: &NoiseConfig,
+    offered: CapabilityFlags,
+    session: NoiseSession,
+) -> Result<NoiseSession, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if !offered.contains(CapabilityFlags::SESSION_RESUMPTION) {
+        return Ok(session);
+    }
+    let Some(issuer) = &config.issuer else {
+        send_frame(transport, Vec::new()).await?;
+        return Ok(session);
+    };
+    let (mut secret, wrap_key) = hkdf(&session.resumption, b"ticket");
+    let expires_at = unix_now() + issuer.lifetime.as_secs();
+    let mut contents = expires_at.to_be_bytes().to_vec();
+    let ticket = issuer.seal(&session, &secret, expires_at);
+    secret.zeroize();
+    contents.extend_from_slice(&ticket?);
+    let wrapped = ChaCha20Poly1305::new(&wrap_key.into()).encrypt(&[0; 12].into(), Payload { msg: &contents, aad: TICKET_AAD });
+    send_frame(transport, wrapped.map_err(|_| handshake_error("Failed to seal resumption ticket"))?).await?;
+    Ok(session)
+}
+
+// Proves the initiator holds the ticket's secret, and binds the offered flags
+fn binder(secret: &[u8; KEY_LEN], first: &[u8]) -> [u8; KEY_LEN] {
+    let (_, binder_key) = hkdf(secret, b"binder");
+    hmac_sha256(&binder_key, &[RESUME_PROLOGUE, first])
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// A ticket held by the initiator
+#[derive(Clone)]
+struct Ticket {
+    ticket: Vec<u8>,
+    secret: SecretKey,
+    expires_at: u64,
+    pattern: NoisePattern,
+    remote_static: [u8; KEY_LEN],
+}
+
+// What the responder recovers from a ticket it redeems
+struct Redeemed {
+    secret: [u8; KEY_LEN],
+    pattern: NoisePattern,
+    remote_static: [u8; KEY_LEN],
+}
+
+// Seals tickets on the responder and redeems them, each once
+struct TicketIssuer {
+    cipher: ChaCha20Poly1305,
+    lifetime: Duration,
+    // Nonces of redeemed tickets, with their expiry
+    redeemed: Mutex<HashMap<[u8; 12], u64>>,
+}
+
+impl TicketIssuer {
+    fn seal(&self, session: &NoiseSession, secret: &[u8; KEY_LEN], expires_at: u64) -> Result<Vec<u8>, ProtocolError> {
+        let mut contents = Vec::with_capacity(TICKET_LEN);
+        contents.extend_from_slice(&session.remote_static);
+        contents.extend_from_slice(secret);
+        contents.extend_from_slice(&expires_at.to_be_bytes());
+        contents.push(session.pattern.code());
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill(&mut nonce);
+        let sealed = self.cipher.encrypt(&nonce.into(), Payload { msg: &contents, aad: TICKET_AAD });
+        contents.zeroize();
+        let mut ticket = nonce.to_vec();
+        ticket.extend_from_slice(&sealed.map_err(|_| handshake_error("Failed to seal resumption ticket"))?);
+        Ok(ticket)
+    }
+
+    // Recovers the ticket in a resumption frame, or `None` if it is unusable
+    fn redeem(&self, config: &NoiseConfig, first: &[u8]) -> Option<Redeemed> {
+        if first.len() != 8 + TICKET_LEN + NONCE_LEN + KEY_LEN {
+            return None;
+        }
+        let (presented, binder_tag) = first.split_at(first.len() - KEY_LEN);
+        let ticket = &presented[8..8 + TICKET_LEN];
+        let nonce: [u8; 12] = ticket[..12].try_into().unwrap();
+        let mut contents = self.cipher.decrypt(&nonce.into(), Payload { msg: &ticket[12..], aad: TICKET_AAD }).ok()?;
+        let redeemed = Redeemed {
+            remote_static: contents[..KEY_LEN].try_into().unwrap(),
+            secret: contents[KEY_LEN..2 * KEY_LEN].try_into().unwrap(),
+            pattern: NoisePattern::from_code(contents[2 * KEY_LEN + 8])?,
+        };
+        let expires_at = u64::from_be_bytes(contents[2 * KEY_LEN..2 * KEY_LEN + 8].try_into().unwrap());
+        contents.zeroize();
+
+        let now = unix_now();
+        if expires_at <= now || !constant_time_eq(&binder(&redeemed.secret, presented), binder_tag) {
+            return None;
+        }
+        // Revoking a peer also stops it resuming
+        config.verify_peer(&redeemed.remote_static).ok()?;
+        let mut used = self.redeemed.lock().unwrap();
+        used.retain(|_, expiry| *expiry > now);
+        used.insert(nonce, expires_at).is_none().then_some(redeemed)
+    }
+}
+
+// The keys of a resumed session, derived by both ends from the ticket's
+// secret and the nonces they exchanged
+struct ResumedKeys {
+    confirmation: [u8; KEY_LEN],
+    initiator_key: [u8; KEY_LEN],
+    responder_key: [u8; KEY_LEN],
+    resumption: [u8; KEY_LEN],
+    handshake_hash: [u8; KEY_LEN],
+}
+
+impl ResumedKeys {
+    // `presented` is the ticket and the initiator's nonce
+    fn derive(secret: &[u8; KEY_LEN], presented: &[u8], server_nonce: &[u8]) -> Self {
+        let presented = &presented[..TICKET_LEN + NONCE_LEN];
+        let (mut chaining_key, _) = hkdf(secret, b"resume");
+        let (next, confirm_key) = hkdf(&chaining_key, &[presented, server_nonce].concat());
+        chaining_key = next;
+        let (initiator_key, responder_key) = hkdf(&chaining_key, &[]);
+        let keys = Self {
+            confirmation: hmac_sha256(&confirm_key, &[presented, server_nonce]),
+            initiator_key,
+            responder_key,
+            resumption: hkdf(&chaining_key, b"resumption").0,
+            handshake_hash: Sha256::new()
+                .chain_update(RESUME_PROLOGUE)
+                .chain_update(presented)
+                .chain_update(server_nonce)
+                .finalize()
+                .into(),
+        };
+        chaining_key.zeroize();
+        keys
+    }
+
+    fn into_session(self, pattern: NoisePattern, initiator: bool, remote_static: [u8; KEY_LEN]) -> NoiseSession {
+        let (send_key, receive_key) = if initiator {
+            (self.initiator_key, self.responder_key)
+        } else {
+            (self.responder_key, self.initiator_key)
+        };
+        NoiseSession {
+            pattern,
+            initiator,
+            resumed: true,
+            hybrid: false,
+            remote_static,
+            handshake_hash: self.handshake_hash,
+            send_key,
+            receive_key,
+            resumption: self.resumption,
+        }
+    }
+}
+
+impl Drop for ResumedKeys {
+    fn drop(&mut self) {
+        self.initiator_key.zeroize();
+        self.responder_key.zeroize();
+        self.resumption.zeroize();
+    }
+}
+
+async fn send_frame<T>(transport: &mut Transport<T>, payload: Vec<u8>) -> Result<(), ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    transport
+        .send(Message::new(MessageType::Handshake, MessageFlags::NONE, 0, Bytes::from(payload)))
+        .await
+}
+
+async fn receive_frame<T>(transport: &mut Transport<T>) -> Result<Bytes, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let message = transport.receive().await?;
+    match message.msg_type {
+        MessageType::Handshake => Ok(message.payload),
+        other => Err(handshake_error(&format!("Expected a handshake message, got {other:?}"))),
+    }
+}
+
+fn handshake_error(reason: &str) -> ProtocolError {
+    ProtocolError::HandshakeFailed(reason.into())
+}
+
+fn encode_key(key: &[u8; KEY_LEN]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key)
+}
+
+// HKDF as the Noise spec defines it, returning two outputs
+fn hkdf(chaining_key: &[u8; KEY_LEN], input: &[u8]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let mut temp = hmac_sha256(chaining_key, &[input]);
+    let first = hmac_sha256(&temp, &[&[1]]);
+    let second = hmac_sha256(&temp, &[&first, &[2]]);
+    temp.zeroize();
+    (first, second)
+}
+
+// The Noise SymmetricState: chaining key, handshake hash and the current key
+struct SymmetricState {
+    chaining_key: [u8; KEY_LEN],
+    hash: [u8; KEY_LEN],
+    cipher: Option<(ChaCha20Poly1305, u64)>,
+}
+
+impl SymmetricState {
+    fn new(protocol_name: &[u8]) -> Self {
+        // Both protocol names are exactly 32 bytes, so they are used as the hash directly
+        let hash: [u8; KEY_LEN] = protocol_name.try_into().expect("32-byte protocol name");
+        Self {
+            chaining_key: hash,
+            hash,
+            cipher: None,
+        }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = Sha256::new().chain_update(self.hash).chain_update(data).finalize().into();
+    }
+
+    fn mix_key(&mut self, input: &[u8; KEY_LEN]) {
+        let (chaining_key, mut key) = hkdf(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        self.cipher = Some((ChaCha20Poly1305::new(&key.into()), 0));
+        key.zeroize();
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let ciphertext = match &mut self.cipher {
+            Some((cipher, counter)) => {
+                let sealed = cipher
+                    .encrypt(&nonce(*counter).into(), Payload { msg: plaintext, aad: &self.hash })
+                    .map_err(|_| handshake_error("Handshake message too large to seal"))?;
+                *counter += 1;
+                sealed
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let plaintext = match &mut self.cipher {
+            Some((cipher, counter)) => {
+                let opened = cipher
+                    .decrypt(&nonce(*counter).into(), Payload { msg: ciphertext, aad: &self.hash })
+                    .map_err(|_| handshake_error("Handshake message failed to authenticate"))?;
+                *counter += 1;
+                opened
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    fn overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            TAG_LEN
+        } else {
+            0
+        }
+    }
+}
+
+impl Drop for SymmetricState {
+    fn drop(&mut self) {
+        self.chaining_key.zeroize();
+    }
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+// The Noise HandshakeState for one end
+struct Handshake<'a> {
+    pattern: NoisePattern,
+    initiator: bool,
+    state: SymmetricState,
+    local_static: &'a NoiseKeypair,
+    local_ephemeral: Option<NoiseKeypair>,
+    remote_static: Option<[u8; KEY_LEN]>,
+    remote_ephemeral: Option<[u8; KEY_LEN]>,
+    // Whether the initiator sent e1, and whether the responder answers it with ekem1
+    kem_offered: bool,
+    hybrid: bool,
+    #[cfg(feature = "pq")]
+    local_kem: Option<KemDecapsulationKey>,
+    remote_kem: Option<Vec<u8>>,
+    next_message: usize,
+}
+
+impl<'a> Handshake<'a> {
+    fn new(
+        pattern: NoisePattern,
+        initiator: bool,
+        config: &'a NoiseConfig,
+        offered: CapabilityFlags,
+        remote_static: Option<[u8; KEY_LEN]>,
+    ) -> Self {
+        let mut state = SymmetricState::new(pattern.protocol_name());
+        let mut prologue = PROLOGUE.to_vec();
+        prologue.put_u32(offered.bits());
+        prologue.put_u32(pattern.flag().bits());
+        state.mix_hash(&prologue);
+
+        // IK's pre-message: the responder's static key, known to both ends
+        if pattern == NoisePattern::IK {
+            let responder_static = if initiator { remote_static.expect("IK requires the remote static key") } else { config.keypair.public };
+            state.mix_hash(&responder_static);
+        }
+
+        let kem_offered = offered.contains(CapabilityFlags::HYBRID_PQ);
+        Self {
+            pattern,
+            initiator,
+            state,
+            local_static: &config.keypair,
+            local_ephemeral: None,
+            remote_static,
+            remote_ephemeral: None,
+            kem_offered,
+            // The initiator learns the responder's answer from its first message
+            hybrid: kem_offered && config.hybrid,
+            #[cfg(feature = "pq")]
+            local_kem: None,
+            remote_kem: None,
+            next_message: 0,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.next_message == self.pattern.messages().len()
+    }
+
+    fn is_our_turn(&self) -> bool {
+        self.next_message.is_multiple_of(2) == self.initiator
+    }
+
+    fn write_message(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let mut message = Vec::new();
+        if self.announces_kem() {
+            message.push(self.hybrid as u8);
+            self.state.mix_hash(&[self.hybrid as u8]);
+        }
+        for token in self.pattern.messages()[self.next_message] {
+            match token {
+                Token::E => {
+                    let ephemeral = NoiseKeypair::generate();
+                    message.extend_from_slice(&ephemeral.public);
+                    self.state.mix_hash(&ephemeral.public);
+                    self.local_ephemeral = Some(ephemeral);
+                }
+                Token::S => {
+                    let sealed = self.state.encrypt_and_hash(&self.local_static.public)?;
+                    message.extend_from_slice(&sealed);
+                }
+                Token::E1 if self.kem_offered => message.extend_from_slice(&self.write_kem(Token::E1)?),
+                Token::EKEM1 if self.hybrid => message.extend_from_slice(&self.write_kem(Token::EKEM1)?),
+                Token::E1 | Token::EKEM1 => {}
+                token => self.mix_dh(*token)?,
+            }
+        }
+        // Empty payload, still authenticated once a key is established
+        message.extend_from_slice(&self.state.encrypt_and_hash(&[])?);
+        self.next_message += 1;
+        Ok(message)
+    }
+
+    fn read_message(&mut self, mut message: &[u8]) -> Result<(), ProtocolError> {
+        let truncated = || handshake_error("Truncated handshake message");
+        if self.announces_kem() {
+            let (&answer, rest) = message.split_first().ok_or_else(truncated)?;
+            if answer > 1 {
+                return Err(handshake_error("Malformed KEM answer"));
+            }
+            self.hybrid = answer == 1;
+            self.state.mix_hash(&[answer]);
+            message = rest;
+        }
+        for token in self.pattern.messages()[self.next_message] {
+            match token {
+                Token::E => {
+                    let ephemeral: [u8; KEY_LEN] = message.get(..KEY_LEN).ok_or_else(truncated)?.try_into().unwrap();
+                    self.state.mix_hash(&ephemeral);
+                    self.remote_ephemeral = Some(ephemeral);
+                    message = &message[KEY_LEN..];
+                }
+                Token::S => {
+                    let len = KEY_LEN + self.state.overhead();
+                    let sealed = message.get(..len).ok_or_else(truncated)?;
+                    let remote_static = self.state.decrypt_and_hash(sealed)?;
+                    self.remote_static = Some(remote_static.try_into().map_err(|_| truncated())?);
+                    message = &message[len..];
+                }
+                Token::E1 if self.kem_offered => {
+                    let len = KEM_PUBLIC_LEN + self.state.overhead();
+                    let remote_kem = self.state.decrypt_and_hash(message.get(..len).ok_or_else(truncated)?)?;
+                    // Read even when declined, as the transcript covers it
+                    if self.hybrid {
+                        self.remote_kem = Some(remote_kem);
+                    }
+                    message = &message[len..];
+                }
+                Token::EKEM1 if self.hybrid => {
+                    let len = KEM_CIPHERTEXT_LEN + self.state.overhead();
+                    self.read_kem_ciphertext(message.get(..len).ok_or_else(truncated)?)?;
+                    message = &message[len..];
+                }
+                Token::E1 | Token::EKEM1 => {}
+                token => self.mix_dh(*token)?,
+            }
+        }
+        self.state.decrypt_and_hash(message)?;
+        self.next_message += 1;
+        Ok(())
+    }
+
+    fn mix_dh(&mut self, token: Token) -> Result<(), ProtocolError> {
+        let missing = || handshake_error("Handshake key missing");
+        let ephemeral = self.local_ephemeral.as_ref();
+        let remote_ephemeral = self.remote_ephemeral.as_ref();
+        let remote_static = self.remote_static.as_ref();
+        // The first letter names the initiator's key, the second the responder's
+        let mut shared = match (token, self.initiator) {
+            (Token::EE, _) => ephemeral.ok_or_else(missing)?.dh(remote_ephemeral.ok_or_else(missing)?)?,
+            (Token::ES, true) | (Token::SE, false) => ephemeral.ok_or_else(missing)?.dh(remote_static.ok_or_else(missing)?)?,
+            (Token::ES, false) | (Token::SE, true) => self.local_static.dh(remote_ephemeral.ok_or_else(missing)?)?,
+            (Token::SS, _) => self.local_static.dh(remote_static.ok_or_else(missing)?)?,
+            (Token::E | Token::S | Token::E1 | Token::EKEM1, _) => unreachable!("not a key agreement token"),
+        };
+        self.state.mix_key(&shared);
+        shared.zeroize();
+        Ok(())
+    }
+
+    // The responder's first message opens with whether it accepted the offered KEM
+    fn announces_kem(&self) -> bool {
+        self.kem_offered && self.next_message == 1
+    }
+
+    #[cfg(feature = "pq")]
+    fn write_kem(&mut self, token: Token) -> Result<Vec<u8>, ProtocolError> {
+        if let Token::E1 = token {
+            let (key, encapsulation_key) = MlKem768::generate(&mut rand::thread_rng());
+            let sealed = self.state.encrypt_and_hash(&encapsulation_key.as_bytes())?;
+            self.local_kem = Some(key);
+            return Ok(sealed);
+        }
+        let remote_kem = self.remote_kem.take().ok_or_else(|| handshake_error("Handshake key missing"))?;
+        let remote_kem = Encoded::<KemEncapsulationKey>::try_from(&remote_kem[..]).map_err(|_| handshake_error("Malformed KEM key"))?;
+        let (ciphertext, shared) = KemEncapsulationKey::from_bytes(&remote_kem)
+            .encapsulate(&mut rand::thread_rng())
+            .map_err(|()| handshake_error("Malformed KEM key"))?;
+        let sealed = self.state.encrypt_and_hash(&ciphertext)?;
+        let mut shared: [u8; KEY_LEN] = shared.into();
+        self.state.mix_key(&shared);
+        shared.zeroize();
+        Ok(sealed)
+    }
+
+    #[cfg(not(feature = "pq"))]
+    fn write_kem(&mut self, _token: Token) -> Result<Vec<u8>, ProtocolError> {
+        unreachable!("only a pq build offers or accepts a KEM")
+    }
+
+    #[cfg(feature = "pq")]
+    fn read_kem_ciphertext(&mut self, sealed: &[u8]) -> Result<(), ProtocolError> {
+        let ciphertext = self.state.decrypt_and_hash(sealed)?;
+        let key = self.local_kem.take().ok_or_else(|| handshake_error("Handshake key missing"))?;
+        let ciphertext = Ciphertext::<MlKem768>::try_from(&ciphertext[..]).map_err(|_| handshake_error("Malformed KEM ciphertext"))?;
+        let mut shared: [u8; KEY_LEN] = key.decapsulate(&ciphertext).map_err(|()| handshake_error("Malformed KEM ciphertext"))?.into();
+        self.state.mix_key(&shared);
+        shared.zeroize();
+        Ok(())
+    }
+
+    #[cfg(not(feature = "pq"))]
+    fn read_kem_ciphertext(&mut self, _sealed: &[u8]) -> Result<(), ProtocolError> {
+        unreachable!("only a pq build offers or accepts a KEM")
+    }
+
+    fn split(self) -> Result<NoiseSession, ProtocolError> {
+        let (initiator_key, responder_key) = hkdf(&self.state.chaining_key, &[]);
+        let (send_key, receive_key) = if self.initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+        Ok(NoiseSession {
+            pattern: self.pattern,
+            initiator: self.initiator,
+            remote_static: self.remote_static.ok_or_else(|| handshake_error("Peer sent no static key"))?,
+            resumed: false,
+            hybrid: self.hybrid,
+            handshake_hash: self.state.hash,
+            send_key,
+            receive_key,
+            resumption: hkdf(&self.state.chaining_key, b"resumption").0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(
+        client: NoiseConfig,
+        server: NoiseConfig,
+    ) -> (Result<NoiseSession, ProtocolError>, Result<NoiseSession, ProtocolError>) {
+        let (a, b) = tokio::io::duplex(4096);
+        let (mut a, mut b) = (Transport::new(a), Transport::new(b));
+        // Each end drops its transport when it fails, so the other sees the close
+        tokio::join!(
+            async move { initiate(&mut a, &client).await },
+            async move { respond(&mut b, &server).await }
+        )
+    }
+
+    #[tokio::test]
+    async fn test_xx_and_ik_authenticate_both_ends() {
+        let server = NoiseConfig::new(NoiseKeypair::generate());
+        let client = NoiseConfig::new(NoiseKeypair::generate());
+
+        for (client, pattern) in [
+            (client.clone(), NoisePattern::XX),
+            (client.clone().with_remote_static(server.public_key()), NoisePattern::IK),
+        ] {
+            let (initiator, responder) = handshake(client.clone(), server.clone()).await;
+            let (initiator, responder) = (initiator.unwrap(), responder.unwrap());
+            assert_eq!(initiator.pattern(), pattern);
+            assert_eq!(initiator.remote_static(), server.public_key());
+            assert_eq!(responder.remote_static(), client.public_key());
+            assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+
+            let (sealer, opener) = (initiator.into_encryptor(), responder.into_encryptor());
+            let sealed = sealer.encrypt(b"ping").unwrap();
+            assert_eq!(&opener.decrypt(&sealed).unwrap()[..], b"ping");
+            assert_eq!(&sealer.decrypt(&opener.encrypt(b"pong").unwrap()).unwrap()[..], b"pong");
+        }
+
+        let (initiator, responder) = handshake(client, server).await;
+        let provider: Arc<dyn CryptoProvider> = Arc::new(Cipher::Aes256Gcm);
+        let sealer = initiator.unwrap().into_encryptor_with(provider.clone()).unwrap();
+        let opener = responder.unwrap().into_encryptor_with(provider).unwrap();
+        assert_eq!(&opener.decrypt(&sealer.encrypt(b"ping").unwrap()).unwrap()[..], b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_wrong_keys_and_patterns() {
+        let server = NoiseConfig::new(NoiseKeypair::generate());
+        let client = NoiseConfig::new(NoiseKeypair::generate());
+
+        // IK towards a key the server does not hold
+        let stale = client.clone().with_remote_static(NoiseKeypair::generate().public_key());
+        assert!(matches!(handshake(stale, server.clone()).await.1, Err(ProtocolError::HandshakeFailed(_))));
+
+        let trusted = server.public_key();
+        let pinned = client.clone().with_peer_verifier(move |key| *key == trusted);
+        assert!(handshake(pinned.clone(), server.clone()).await.0.is_ok());
+        let impostor = NoiseConfig::new(NoiseKeypair::generate());
+        assert!(matches!(handshake(pinned, impostor).await.0, Err(ProtocolError::HandshakeFailed(_))));
+
+        let ik_only = server.with_patterns(CapabilityFlags::NOISE_IK);
+        assert!(matches!(handshake(client, ik_only).await.1, Err(ProtocolError::HandshakeFailed(_))));
+    }
+
+    #[cfg(feature = "pq")]
+    #[tokio::test]
+    async fn test_hybrid_kem_is_negotiated() {
+        let server = NoiseConfig::new(NoiseKeypair::generate());
+        let client = NoiseConfig::new(NoiseKeypair::generate()).with_hybrid_kem();
+
+        for client in [client.clone(), client.clone().with_remote_static(server.public_key())] {
+            let (initiator, responder) = handshake(client.clone(), server.clone().with_hybrid_kem()).await;
+            let (initiator, responder) = (initiator.unwrap(), responder.unwrap());
+            assert!(initiator.is_hybrid() && responder.is_hybrid());
+            assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+            let sealed = initiator.into_encryptor().encrypt(b"ping").unwrap();
+            assert_eq!(&responder.into_encryptor().decrypt(&sealed).unwrap()[..], b"ping");
+        }
+
+        // Either end not enabling it leaves a classic handshake
+        let (initiator, responder) = handshake(client.clone(), server.clone()).await;
+        assert!(!initiator.unwrap().is_hybrid() && !responder.unwrap().is_hybrid());
+        let classic = NoiseConfig::new(NoiseKeypair::generate());
+        let (initiator, responder) = handshake(classic, server.with_hybrid_kem()).await;
+        assert!(!initiator.unwrap().is_hybrid() && !responder.unwrap().is_hybrid());
+    }
+
+    #[tokio::test]
+    async fn test_resumption_tickets_are_single_use() {
+        let server = NoiseConfig::new(NoiseKeypair::generate())
+            .with_resumption_tickets(&SecretKey::generate(), Duration::from_secs(60));
+        let client = NoiseConfig::new(NoiseKeypair::generate()).with_session_resumption();
+
+        let (initiator, _) = handshake(client.clone(), server.clone()).await;
+        assert!(!initiator.unwrap().is_resumed());
+        assert!(client.has_resumption_ticket());
+        let used = client.tickets.as_ref().unwrap().lock().unwrap().clone();
+
+        let (initiator, responder) = handshake(client.clone(), server.clone()).await;
+        let (initiator, responder) = (initiator.unwrap(), responder.unwrap());
+        assert!(initiator.is_resumed() && responder.is_resumed());
+        assert_eq!(initiator.remote_static(), server.public_key());
+        assert_eq!(responder.remote_static(), client.public_key());
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+        let sealed = initiator.into_encryptor().encrypt(b"ping").unwrap();
+        assert_eq!(&responder.into_encryptor().decrypt(&sealed).unwrap()[..], b"ping");
+        assert!(client.has_resumption_ticket());
+
+        // A replayed ticket falls back to a full handshake
+        *client.tickets.as_ref().unwrap().lock().unwrap() = used;
+        let (initiator, responder) = handshake(client.clone(), server).await;
+        assert!(!initiator.unwrap().is_resumed() && !responder.unwrap().is_resumed());
+
+        // A server without tickets still completes the handshake
+        let plain = NoiseConfig::new(NoiseKeypair::generate());
+        let (initiator, _) = handshake(client.clone(), plain).await;
+        assert!(!initiator.unwrap().is_resumed());
+    }
+}
@@ -1,9 +1,19 @@
-use crate::ProtocolError;
+use crate::{
+    server::{ConnectionContext, HandlerFuture, Middleware, Next, Request},
+    transport::TransportStats,
+    ProtocolError,
+};
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
 
+// Upper bounds of the latency buckets, in microseconds; a final bucket catches the rest
+const LATENCY_BUCKETS_MICROS: [u64; 10] = [100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metric {
     pub name: String,
@@ -23,11 +33,53 @@ pub struct Trace {
     pub attributes: HashMap<String, String>,
 }
 
+/// Fixed-bucket histogram of request latencies
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS_MICROS.partition_point(|bound| *bound < micros);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// Returns each bucket's upper bound (`None` for the overflow bucket) with its count
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let bound = LATENCY_BUCKETS_MICROS.get(i).map(|micros| Duration::from_micros(*micros));
+                (bound, count.load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+}
+
 pub struct Telemetry {
     metrics_tx: mpsc::Sender<Metric>,
     traces_tx: mpsc::Sender<Trace>,
     request_counter: AtomicU64,
     error_counter: AtomicU64,
+    request_latency: LatencyHistogram,
+    errors_by_kind: Mutex<HashMap<&'static str, u64>>,
+    transport_stats: Arc<TransportStats>,
+    request_traces: AtomicBool,
 }
 
 impl Telemetry {
@@ -44,12 +96,27 @@ impl Telemetry {
                 traces_tx,
                 request_counter: AtomicU64::new(0),
                 error_counter: AtomicU64::new(0),
+                request_latency: LatencyHistogram::default(),
+                errors_by_kind: Mutex::new(HashMap::new()),
+                transport_stats: Arc::new(TransportStats::default()),
+                request_traces: AtomicBool::new(false),
             },
             metrics_rx,
             traces_rx,
         )
     }
 
+    /// Emits a Trace for every request recorded through [`Telemetry::record_request`]
+    pub fn with_request_traces(self, enabled: bool) -> Self {
+        self.set_request_traces(enabled);
+        self
+    }
+
+    /// Toggles per-request Trace spans at runtime
+    pub fn set_request_traces(&self, enabled: bool) {
+        self.request_traces.store(enabled, Ordering::Relaxed);
+    }
+
     pub async fn record_metric(&self, metric: Metric) -> Result<(), ProtocolError> {
         self.metrics_tx
             .send(metric)
@@ -79,4 +146,470 @@ impl Telemetry {
     pub fn get_error_count(&self) -> u64 {
         self.error_counter.load(Ordering::Relaxed)
     }
-} 
\ No newline at end of file
+
+    /// Records a finished request: its latency, its error if any, and a Trace
+    /// span when request traces are enabled.
+    ///
+    /// Spans are dropped rather than delaying the request when the trace channel is full.
+    pub fn record_request(&self, name: &str, started: SystemTime, latency: Duration, outcome: Result<(), &ProtocolError>) {
+        self.increment_requests();
+        self.request_latency.observe(latency);
+        if let Err(error) = outcome {
+            self.increment_errors();
+            *self.errors_by_kind.lock().unwrap().entry(error.kind()).or_insert(0) += 1;
+        }
+        if !self.request_traces.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut attributes = HashMap::new();
+        match outcome {
+            Ok(()) => attributes.insert("outcome".to_string(), "ok".to_string()),
+            Err(error) => attributes.insert("error".to_string(), error.kind().to_string()),
+        };
+        let _ = self.traces_tx.try_send(Trace {
+            trace_id: format!("{:032x}", rand::random::<u128>()),
+            span_id: format!("{:016x}", rand::random::<u64>()),
+            parent_id: None,
+            name: name.to_string(),
+            start_time: started.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64,
+            duration: latency.as_micros() as u64,
+            attributes,
+        });
+    }
+
+    pub fn request_latency(&self) -> &LatencyHistogram {
+        &self.request_latency
+    }
+
+    /// Returns the number of failed requests per `ProtocolError` variant
+    pub fn error_counts(&self) -> HashMap<&'static str, u64> {
+        self.errors_by_kind.lock().unwrap().clone()
+    }
+
+    /// Counters shared by every transport recording into this telemetry, including bytes in/out
+    pub fn transport_stats(&self) -> &Arc<TransportStats> {
+        &self.transport_stats
+    }
+}
+
+/// One served request as written to the access log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessRecord {
+    pub route: String,
+    pub request_id: u64,
+    /// The client's identity, or its remote address when it has none
+    pub client: Option<String>,
+    pub request_bytes: usize,
+    pub response_bytes: Option<usize>,
+    pub latency_micros: u64,
+    /// `"Ok"`, or the `ProtocolError` kind the request failed with
+    pub status: String,
+    /// Redacted payloads, present only for sampled requests
+    pub request_payload: Option<String>,
+    pub response_payload: Option<String>,
+}
+
+type AccessSink = Arc<dyn Fn(&AccessRecord) + Send + Sync>;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Server middleware writing an [`AccessRecord`] for every request it wraps.
+///
+/// Records go to `tracing` at info level under the `remus::access` target
+/// unless [`with_sink`](Self::with_sink) replaces it. Payloads are only logged
+/// for the sampled fraction of requests, truncated to a maximum length. JSON
+/// object fields named by a redaction rule are masked at any depth; while any
+/// rule is set, payloads that are not JSON are left out since they cannot be
+/// redacted.
+#[derive(Clone)]
+pub struct AccessLog {
+    payload_sample_rate: f64,
+    max_payload_length: usize,
+    redacted_fields: Arc<Vec<String>>,
+    sink: Option<AccessSink>,
+}
+
+impl AccessLog {
+    pub fn new() -> Self {
+        Self {
+            payload_sample_rate: 0.0,
+            max_payload_length: 1024,
+            redacted_fields: Arc::new(Vec::new()),
+            sink: None,
+        }
+    }
+
+    /// Logs payloads for this fraction of requests, between 0 and 1; none by default
+    pub fn with_payload_sampling(mut self, rate: f64) -> Self {
+        self.payload_sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Truncates logged payloads to `max` characters
+    pub fn with_max_payload_length(mut self, max: usize) -> Self {
+        self.max_payload_length = max;
+        self
+    }
+
+    /// Masks the value of every JSON field called `field` in logged payloads
+    pub fn with_redacted_field(mut self, field: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.redacted_fields).push(field.into());
+        self
+    }
+
+    /// Hands records to `sink` instead of `tracing`
+    pub fn with_sink(mut self, sink: impl Fn(&AccessRecord) + Send + Sync + 'static) -> Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Renders `payload` for the log with redaction rules and truncation applied
+    pub fn redact(&self, payload: &[u8]) -> Option<String> {
+        let mut rendered = match serde_json::from_slice::<serde_json::Value>(payload) {
+            Ok(mut value) => {
+                redact_value(&mut value, &self.redacted_fields);
+                value.to_string()
+            }
+            Err(_) if !self.redacted_fields.is_empty() => return None,
+            Err(_) => String::from_utf8_lossy(payload).into_owned(),
+        };
+        if let Some((end, _)) = rendered.char_indices().nth(self.max_payload_length) {
+            rendered.truncate(end);
+            rendered.push('…');
+        }
+        Some(rendered)
+    }
+
+    fn write(&self, record: &AccessRecord) {
+        match &self.sink {
+            Some(sink) => sink(record),
+            None => tracing::info!(
+                target: "remus::access",
+                route = %record.route,
+                request_id = record.request_id,
+                client = record.client.as_deref(),
+                request_bytes = record.request_bytes,
+                response_bytes = record.response_bytes,
+                latency_micros = record.latency_micros,
+                status = %record.status,
+                request_payload = record.request_payload.as_deref(),
+                response_payload = record.response_payload.as_deref(),
+                "request served"
+            ),
+        }
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: Request, next: Next) -> HandlerFuture {
+        let started = Instant::now();
+        let sampled = self.payload_sample_rate > 0.0 && rand::random::<f64>() < self.payload_sample_rate;
+        let connection = request.connection();
+        let mut record = AccessRecord {
+            route: request.route().to_string(),
+            request_id: request.message().request_id,
+            client: connection
+                .identity()
+                .or_else(|| connection.remote_addr().map(|addr| addr.to_string())),
+            request_bytes: request.payload().len(),
+            response_bytes: None,
+            latency_micros: 0,
+            status: "Ok".to_string(),
+            request_payload: sampled.then(|| self.redact(request.payload())).flatten(),
+            response_payload: None,
+        };
+        let log = self.clone();
+        next.run(request)
+            .map(move |result| {
+                record.latency_micros = started.elapsed().as_micros() as u64;
+                match &result {
+                    Ok(response) => {
+                        record.response_bytes = Some(response.len());
+                        record.response_payload = sampled.then(|| log.redact(response)).flatten();
+                    }
+                    Err(error) => record.status = error.kind().to_string(),
+                }
+                log.write(&record);
+                result
+            })
+            .boxed()
+    }
+}
+
+fn redact_value(value: &mut serde_json::Value, fields: &[String]) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if fields.iter().any(|name| name == key) {
+                    *field = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(field, fields);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| redact_value(item, fields)),
+        _ => {}
+    }
+}
+
+/// What an [`AuditEvent`] records a failure of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AuditKind {
+    DecryptFailed,
+    SignatureInvalid,
+    AuthRejected,
+    ReplayDetected,
+    HandshakeFailed,
+}
+
+impl AuditKind {
+    /// The kind of security failure `error` reports, if it is one
+    pub fn classify(error: &ProtocolError) -> Option<Self> {
+        match error {
+            ProtocolError::EncryptionError(_) => Some(AuditKind::DecryptFailed),
+            ProtocolError::InvalidSignature(_) => Some(AuditKind::SignatureInvalid),
+            ProtocolError::AuthenticationRequired | ProtocolError::PermissionDenied(_) => Some(AuditKind::AuthRejected),
+            ProtocolError::Replayed => Some(AuditKind::ReplayDetected),
+            ProtocolError::HandshakeFailed(_) => Some(AuditKind::HandshakeFailed),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditKind::DecryptFailed => "DecryptFailed",
+            AuditKind::SignatureInvalid => "SignatureInvalid",
+            AuditKind::AuthRejected => "AuthRejected",
+            AuditKind::ReplayDetected => "ReplayDetected",
+            AuditKind::HandshakeFailed => "HandshakeFailed",
+        }
+    }
+}
+
+/// One security failure as written to the audit trail
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub kind: AuditKind,
+    /// The peer's identity, if it had one
+    pub identity: Option<String>,
+    pub remote_addr: Option<String>,
+    pub reason: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Events of this kind left out since the last one written, by rate limiting
+    pub suppressed: u64,
+}
+
+type AuditSink = Arc<dyn Fn(&AuditEvent) + Send + Sync>;
+
+// Events of one kind written in the current second
+struct AuditWindow {
+    started: Instant,
+    written: u32,
+    suppressed: u64,
+}
+
+/// Audit trail of failed decrypts, signature failures, auth rejections,
+/// replays and failed handshakes; see [`Server::with_audit_log`](crate::Server::with_audit_log).
+///
+/// Events go to `tracing` at warn level under the `remus::audit` target
+/// unless [`with_sink`](Self::with_sink) replaces it. So that a flood of bad
+/// traffic cannot flood the trail too, at most the configured number of events
+/// per kind are written each second; the next event written counts the ones
+/// left out. Every event is counted in `remus_audit_events_total` regardless.
+#[derive(Clone)]
+pub struct AuditLog {
+    max_per_second: u32,
+    sink: Option<AuditSink>,
+    windows: Arc<Mutex<HashMap<AuditKind, AuditWindow>>>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self {
+            max_per_second: 10,
+            sink: None,
+            windows: Arc::default(),
+        }
+    }
+
+    /// Writes at most `events` events of each kind per second; 10 by default
+    pub fn with_rate_limit(mut self, events: u32) -> Self {
+        self.max_per_second = events;
+        self
+    }
+
+    /// Hands events to `sink` instead of `tracing`
+    pub fn with_sink(mut self, sink: impl Fn(&AuditEvent) + Send + Sync + 'static) -> Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Records `error` against the peer on `connection` if it is a security failure
+    pub fn record_error(&self, connection: &ConnectionContext, error: &ProtocolError) {
+        if let Some(kind) = AuditKind::classify(error) {
+            self.record(kind, connection, &error.to_string());
+        }
+    }
+
+    pub fn record(&self, kind: AuditKind, connection: &ConnectionContext, reason: &str) {
+        metrics::increment_counter!("remus_audit_events_total", "kind" => kind.as_str());
+        let suppressed = {
+            let mut windows = self.windows.lock().unwrap();
+            let window = windows.entry(kind).or_insert_with(|| AuditWindow {
+                started: Instant::now(),
+                written: 0,
+                suppressed: 0,
+            });
+            if window.started.elapsed() >= Duration::from_secs(1) {
+                window.started = Instant::now();
+                window.written = 0;
+            }
+            if window.written >= self.max_per_second {
+                window.suppressed += 1;
+                return;
+            }
+            window.written += 1;
+            std::mem::take(&mut window.suppressed)
+        };
+        let event = AuditEvent {
+            kind,
+            identity: connection.identity(),
+            remote_addr: connection.remote_addr().map(|addr| addr.to_string()),
+            reason: reason.to_string(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            suppressed,
+        };
+        match &self.sink {
+            Some(sink) => sink(&event),
+            None => tracing::warn!(
+                target: "remus::audit",
+                kind = event.kind.as_str(),
+                identity = event.identity.as_deref(),
+                remote_addr = event.remote_addr.as_deref(),
+                reason = %event.reason,
+                suppressed = event.suppressed,
+                "security failure"
+            ),
+        }
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram_buckets() {
+        let histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_micros(100));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(60));
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[0], (Some(Duration::from_micros(100)), 1));
+        assert_eq!(buckets[3], (Some(Duration::from_millis(5)), 1));
+        assert_eq!(buckets.last(), Some(&(None, 1)));
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.sum(), Duration::from_micros(60_003_100));
+    }
+
+    #[tokio::test]
+    async fn test_record_request_counts_errors_and_traces() {
+        let (telemetry, _metrics, mut traces) = Telemetry::new(8, 8);
+        telemetry.record_request("a", SystemTime::now(), Duration::from_millis(1), Ok(()));
+        assert!(traces.try_recv().is_err());
+
+        telemetry.set_request_traces(true);
+        let error = ProtocolError::Timeout("late".into());
+        telemetry.record_request("b", SystemTime::now(), Duration::from_millis(2), Err(&error));
+
+        assert_eq!(telemetry.get_request_count(), 2);
+        assert_eq!(telemetry.get_error_count(), 1);
+        assert_eq!(telemetry.error_counts().get("Timeout"), Some(&1));
+        assert_eq!(telemetry.request_latency().count(), 2);
+
+        let trace = traces.try_recv().unwrap();
+        assert_eq!(trace.name, "b");
+        assert_eq!(trace.duration, 2_000);
+        assert_eq!(trace.attributes.get("error").map(String::as_str), Some("Timeout"));
+    }
+
+    #[test]
+    fn test_audit_log_rate_limits_per_kind() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let log = AuditLog::new()
+            .with_rate_limit(2)
+            .with_sink(move |event: &AuditEvent| sink.lock().unwrap().push(event.clone()));
+        let connection = ConnectionContext::default();
+        connection.set_identity("mallory");
+
+        for _ in 0..5 {
+            log.record_error(&connection, &ProtocolError::Replayed);
+        }
+        log.record_error(&connection, &ProtocolError::InvalidSignature("Signature does not match".into()));
+        log.record_error(&connection, &ProtocolError::Timeout("late".into()));
+        {
+            let events = events.lock().unwrap();
+            let kinds: Vec<AuditKind> = events.iter().map(|event| event.kind).collect();
+            assert_eq!(kinds, [AuditKind::ReplayDetected, AuditKind::ReplayDetected, AuditKind::SignatureInvalid]);
+            assert_eq!(events[0].identity.as_deref(), Some("mallory"));
+        }
+
+        // The first event of the next second reports what was left out
+        log.windows.lock().unwrap().get_mut(&AuditKind::ReplayDetected).unwrap().started -= Duration::from_secs(1);
+        log.record_error(&connection, &ProtocolError::Replayed);
+        assert_eq!(events.lock().unwrap().last().unwrap().suppressed, 3);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_records_and_redacts() {
+        use crate::{server::Router, ConnectionContext, Message, MessageFlags, MessageType};
+        use bytes::Bytes;
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let router = Router::new()
+            .with_middleware(
+                AccessLog::new()
+                    .with_payload_sampling(1.0)
+                    .with_redacted_field("password")
+                    .with_sink(move |record: &AccessRecord| sink.lock().unwrap().push(record.clone())),
+            )
+            .with_route("login", |_request: Request| async { Ok(Bytes::from(r#"{"token":"t"}"#)) });
+        let connection = Arc::new(ConnectionContext::default());
+        connection.set_identity("alice");
+        let payload = Bytes::from(r#"{"user":"alice","auth":{"password":"hunter2"}}"#);
+        for route in ["login", "missing"] {
+            let mut message = Message::new(MessageType::Request, MessageFlags::NONE, 7, payload.clone());
+            message.routing_info = Some(route.to_string());
+            let _ = router.dispatch(Request::new(message, connection.clone())).await;
+        }
+
+        let records = records.lock().unwrap();
+        assert_eq!((records[0].route.as_str(), records[0].status.as_str()), ("login", "Ok"));
+        assert_eq!(records[0].client.as_deref(), Some("alice"));
+        assert_eq!((records[0].request_bytes, records[0].response_bytes), (payload.len(), Some(13)));
+        let logged = records[0].request_payload.as_deref().unwrap();
+        assert!(logged.contains(REDACTED) && !logged.contains("hunter2"));
+        assert_eq!(records[1].status, "NotFound");
+
+        let log = AccessLog::new().with_max_payload_length(4);
+        assert_eq!(log.redact(b"abcdefgh").as_deref(), Some("abcd…"));
+        assert_eq!(log.with_redacted_field("secret").redact(b"not json"), None);
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{
+    connection::{Connection, ResponseFuture},
+    Message, MessageType, ProtocolError,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+type Responder = oneshot::Sender<Result<ResponseFuture, ProtocolError>>;
+
+/// What an [`OfflineQueue`] does with a message pushed while it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Evicts the oldest queued message to make room
+    DropOldest,
+    /// Discards the new message
+    DropNewest,
+    /// Fails the push with `ProtocolError::QueueFull`
+    Reject,
+}
+
+/// Store-and-forward queue holding messages sent while the client is disconnected.
+///
+/// Queued messages are flushed in order once a connection is established;
+/// those whose TTL has elapsed by then are dropped. A queued request's caller
+/// gets its response once a flush has sent it. With a backing file the queue
+/// survives restarts: it is loaded on open and rewritten on a blocking thread
+/// after every change. Payloads are stored as handed to the client, before
+/// compression or encryption.
+pub struct OfflineQueue {
+    max_depth: usize,
+    overflow: OverflowPolicy,
+    file: Option<Arc<QueueFile>>,
+    messages: Mutex<VecDeque<Message>>,
+    // Callers waiting on queued requests, by request id
+    responders: Mutex<HashMap<u64, Responder>>,
+    dropped: AtomicU64,
+    // Serializes flushes so a message put back after a failed send keeps its place
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl OfflineQueue {
+    /// Creates an in-memory queue holding up to `max_depth` messages
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            max_depth: max_depth.max(1),
+            overflow: OverflowPolicy::DropOldest,
+            file: None,
+            messages: Mutex::new(VecDeque::new()),
+            responders: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+            flushing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Opens a queue persisted at `path`, loading any messages saved there
+    pub fn open(path: impl Into<PathBuf>, max_depth: usize) -> Result<Self, ProtocolError> {
+        let path = path.into();
+        let mut messages = VecDeque::new();
+        match fs::read(&path) {
+            Ok(contents) => {
+                let mut rest = contents.as_slice();
+                while !rest.is_empty() {
+                    let (len, tail) = rest
+                        .split_first_chunk::<4>()
+                        .ok_or_else(|| ProtocolError::InvalidFormat("Truncated offline queue file".into()))?;
+                    let len = u32::from_be_bytes(*len) as usize;
+                    if tail.len() < len {
+                        return Err(ProtocolError::InvalidFormat("Truncated offline queue file".into()));
+                    }
+                    messages.push_back(Message::decode(&tail[..len])?);
+                    rest = &tail[len..];
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut queue = Self::new(max_depth);
+        queue.file = Some(Arc::new(QueueFile {
+            path,
+            generation: AtomicU64::new(0),
+            written: Mutex::new(0),
+        }));
+        *queue.messages.get_mut().unwrap() = messages;
+        Ok(queue)
+    }
+
+    /// Sets what happens to messages pushed while the queue is full; the default drops the oldest
+    pub fn with_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Queues `message` for the next flush, applying the overflow policy when full
+    pub fn push(&self, message: Message) -> Result<(), ProtocolError> {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.max_depth {
+            match self.overflow {
+                OverflowPolicy::DropOldest => {
+                    if let Some(evicted) = messages.pop_front() {
+                        self.fail(&evicted, ProtocolError::QueueFull);
+                    }
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::DropNewest => {
+                    self.fail(&message, ProtocolError::QueueFull);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                OverflowPolicy::Reject => return Err(ProtocolError::QueueFull),
+            }
+        }
+        messages.push_back(message);
+        self.persist(&messages)
+    }
+
+    /// Queues a request whose caller waits on the returned receiver for the
+    /// response future, handed over by the flush that sends it
+    pub(crate) fn push_request(&self, message: Message) -> Result<oneshot::Receiver<Result<ResponseFuture, ProtocolError>>, ProtocolError> {
+        let (responder, response) = oneshot::channel();
+        let request_id = message.request_id;
+        self.responders.lock().unwrap().insert(request_id, responder);
+        if let Err(e) = self.push(message) {
+            self.responders.lock().unwrap().remove(&request_id);
+            return Err(e);
+        }
+        Ok(response)
+    }
+
+    // Fails the caller waiting on `message`, if it is a queued request
+    fn fail(&self, message: &Message, error: ProtocolError) {
+        if message.msg_type != MessageType::Request {
+            return;
+        }
+        if let Some(responder) = self.responders.lock().unwrap().remove(&message.request_id) {
+            let _ = responder.send(Err(error));
+        }
+    }
+
+    // Takes the caller still waiting on `message`, if any
+    fn responder(&self, message: &Message) -> Option<Responder> {
+        if message.msg_type != MessageType::Request {
+            return None;
+        }
+        self.responders.lock().unwrap().remove(&message.request_id).filter(|responder| !responder.is_closed())
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns how many messages were discarded by the overflow policy or for outliving their TTL
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Sends queued messages over `connection` in order, returning how many were sent.
+    ///
+    /// A message that fails to send goes back to the front of the queue and
+    /// the flush stops with the error. A request whose caller is waiting is
+    /// registered on `connection` and its response future handed to the caller.
+    pub(crate) async fn flush(&self, connection: &Connection) -> Result<usize, ProtocolError> {
+        let _flushing = self.flushing.lock().await;
+        let mut sent = 0;
+        while let Some(message) = self.pop_live()? {
+            let responder = self.responder(&message);
+            let result = match responder {
+                Some(_) => connection.request(message.clone()).await.map(Some),
+                None => connection.send(message.clone()).await.map(|_| None),
+            };
+            match (result, responder) {
+                (Ok(Some(response)), Some(responder)) => {
+                    let _ = responder.send(Ok(response));
+                }
+                (Ok(_), _) => {}
+                (Err(e), responder) => {
+                    if let Some(responder) = responder {
+                        self.responders.lock().unwrap().insert(message.request_id, responder);
+                    }
+                    let mut messages = self.messages.lock().unwrap();
+                    messages.push_front(message);
+                    self.persist(&messages)?;
+                    return Err(e);
+                }
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    // Takes the oldest message whose TTL has not elapsed, dropping expired ones
+    fn pop_live(&self) -> Result<Option<Message>, ProtocolError> {
+        let mut messages = self.messages.lock().unwrap();
+        let before = messages.len();
+        let message = loop {
+            match messages.pop_front() {
+                Some(message) if message.is_expired() => {
+                    self.fail(&message, ProtocolError::Timeout("Request expired in the offline queue".into()));
+                    self.dropped.fetch_add(1, Ordering::Relaxed)
+                }
+                other => break other,
+            };
+        };
+        if messages.len() != before {
+            self.persist(&messages)?;
+        }
+        Ok(message)
+    }
+
+    // Snapshots `messages` into the backing file, written on a blocking thread
+    // when called on a runtime so the caller never waits on the disk
+    fn persist(&self, messages: &VecDeque<Message>) -> Result<(), ProtocolError> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut contents = Vec::new();
+        for message in messages {
+            let encoded = message.encode();
+            contents.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+            contents.extend_from_slice(&encoded);
+        }
+        // Taken under the messages lock, so generations follow the order of changes
+        let generation = file.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return file.write(generation, &contents);
+        };
+        let file = file.clone();
+        runtime.spawn_blocking(move || {
+            if let Err(e) = file.write(generation, &contents) {
+                tracing::warn!("Persisting offline queue to {} failed: {}", file.path.display(), e);
+            }
+        });
+        Ok(())
+    }
+}
+
+// Backing file of a persistent queue
+struct QueueFile {
+    path: PathBuf,
+    generation: AtomicU64,
+    // Generation of the snapshot on disk, so a late write never replaces a newer one
+    written: Mutex<u64>,
+}
+
+impl QueueFile {
+    // Replaces the file atomically with snapshot `generation`, unless a newer one is there
+    fn write(&self, generation: u64, contents: &[u8]) -> Result<(), ProtocolError> {
+        let mut written = self.written.lock().unwrap();
+        if generation <= *written {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &self.path)?;
+        *written = generation;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageFlags, MessageType};
+    use bytes::Bytes;
+
+    fn event(id: u64) -> Message {
+        Message::new(MessageType::Event, MessageFlags::NONE, id, Bytes::from(format!("event {}", id)))
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let oldest = OfflineQueue::new(2);
+        for id in 1..=3 {
+            oldest.push(event(id)).unwrap();
+        }
+        assert_eq!(oldest.pop_live().unwrap().unwrap().request_id, 2);
+        assert_eq!(oldest.dropped(), 1);
+
+        let newest = OfflineQueue::new(2).with_overflow(OverflowPolicy::DropNewest);
+        for id in 1..=3 {
+            newest.push(event(id)).unwrap();
+        }
+        assert_eq!(newest.pop_live().unwrap().unwrap().request_id, 1);
+        assert_eq!(newest.len(), 1);
+
+        let reject = OfflineQueue::new(1).with_overflow(OverflowPolicy::Reject);
+        reject.push(event(1)).unwrap();
+        assert!(matches!(reject.push(event(2)), Err(ProtocolError::QueueFull)));
+    }
+
+    #[test]
+    fn test_expired_messages_are_skipped() {
+        let queue = OfflineQueue::new(8);
+        let mut stale = event(1);
+        stale.ttl = 0;
+        stale.timestamp -= 1;
+        queue.push(stale).unwrap();
+        queue.push(event(2)).unwrap();
+
+        assert_eq!(queue.pop_live().unwrap().unwrap().request_id, 2);
+        assert!(queue.pop_live().unwrap().is_none());
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn test_persisted_queue_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("remus-offline-{}.queue", rand::random::<u64>()));
+        let queue = OfflineQueue::open(&path, 8).unwrap();
+        queue.push(event(1)).unwrap();
+        queue.push(event(2)).unwrap();
+        drop(queue);
+
+        let reopened = OfflineQueue::open(&path, 8).unwrap();
+        assert_eq!(reopened.len(), 2);
+        let first = reopened.pop_live().unwrap().unwrap();
+        assert_eq!(first.payload, Bytes::from("event 1"));
+        drop(reopened);
+        assert_eq!(OfflineQueue::open(&path, 8).unwrap().len(), 1);
+        fs::remove_file(path).unwrap();
+    }
+}
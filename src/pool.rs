@@ -0,0 +1,242 @@
+use crate::{transport::Transport, ProtocolError};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+struct IdleTransport {
+    transport: Transport<TcpStream>,
+    created_at: Instant,
+    idle_since: Instant,
+}
+
+struct PoolInner {
+    max_per_address: usize,
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+    idle: Mutex<HashMap<String, Vec<IdleTransport>>>,
+    limits: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl PoolInner {
+    fn is_expired(&self, created_at: Instant, idle_since: Instant) -> bool {
+        created_at.elapsed() >= self.max_lifetime || idle_since.elapsed() >= self.idle_timeout
+    }
+
+    fn limit_for(&self, address: &str) -> Arc<Semaphore> {
+        let mut limits = self.limits.lock().unwrap();
+        limits
+            .entry(address.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_address)))
+            .clone()
+    }
+}
+
+/// Pool of transports keyed by address with checkout/checkin semantics
+#[derive(Clone)]
+pub struct ConnectionPool {
+    inner: Arc<PoolInner>,
+}
+
+impl ConnectionPool {
+    /// Creates a pool holding at most `max_per_address` connections per address
+    pub fn new(max_per_address: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                max_per_address,
+                idle_timeout: Duration::from_secs(90),
+                max_lifetime: Duration::from_secs(30 * 60),
+                idle: Mutex::new(HashMap::new()),
+                limits: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Sets how long a connection may sit idle before it is reaped
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.inner_mut().idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum age of a connection regardless of activity
+    pub fn with_max_lifetime(mut self, lifetime: Duration) -> Self {
+        self.inner_mut().max_lifetime = lifetime;
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut PoolInner {
+        Arc::get_mut(&mut self.inner).expect("pool must be configured before it is shared")
+    }
+
+    /// Checks out a transport for `address`, reusing an idle one when available.
+    ///
+    /// Waits when `max_per_address` connections to `address` are already checked out.
+    pub async fn checkout(&self, address: &str) -> Result<PooledTransport, ProtocolError> {
+        let permit = self
+            .inner
+            .limit_for(address)
+            .acquire_owned()
+            .await
+            .map_err(|e| ProtocolError::InvalidFormat(e.to_string()))?;
+
+        if let Some(idle) = self.take_idle(address) {
+            return Ok(PooledTransport {
+                transport: Some(idle.transport),
+                created_at: idle.created_at,
+                address: address.to_string(),
+                pool: self.inner.clone(),
+                reusable: true,
+                _permit: permit,
+            });
+        }
+
+        let stream = TcpStream::connect(address).await?;
+        Ok(PooledTransport {
+            transport: Some(Transport::new(stream)),
+            created_at: Instant::now(),
+            address: address.to_string(),
+            pool: self.inner.clone(),
+            reusable: true,
+            _permit: permit,
+        })
+    }
+
+    fn take_idle(&self, address: &str) -> Option<IdleTransport> {
+        let mut idle = self.inner.idle.lock().unwrap();
+        let entries = idle.get_mut(address)?;
+        while let Some(entry) = entries.pop() {
+            if !self.inner.is_expired(entry.created_at, entry.idle_since) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    /// Drops idle connections that exceeded the idle timeout or max lifetime
+    pub fn reap_idle(&self) {
+        let mut idle = self.inner.idle.lock().unwrap();
+        for entries in idle.values_mut() {
+            entries.retain(|entry| !self.inner.is_expired(entry.created_at, entry.idle_since));
+        }
+        idle.retain(|_, entries| !entries.is_empty());
+    }
+
+    /// Returns the number of idle connections held for `address`
+    pub fn idle_count(&self, address: &str) -> usize {
+        let idle = self.inner.idle.lock().unwrap();
+        idle.get(address).map_or(0, Vec::len)
+    }
+}
+
+/// A transport checked out of a [`ConnectionPool`], returned to it on drop
+pub struct PooledTransport {
+    transport: Option<Transport<TcpStream>>,
+    created_at: Instant,
+    address: String,
+    pool: Arc<PoolInner>,
+    reusable: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledTransport {
+    /// Marks the connection as broken so it is closed instead of returned to the pool
+    pub fn discard(mut self) {
+        self.reusable = false;
+    }
+}
+
+impl Deref for PooledTransport {
+    type Target = Transport<TcpStream>;
+
+    fn deref(&self) -> &Self::Target {
+        self.transport.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledTransport {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.transport.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledTransport {
+    fn drop(&mut self) {
+        let Some(transport) = self.transport.take() else {
+            return;
+        };
+        let now = Instant::now();
+        if !self.reusable || self.pool.is_expired(self.created_at, now) {
+            return;
+        }
+        let mut idle = self.pool.idle.lock().unwrap();
+        idle.entry(self.address.clone()).or_default().push(IdleTransport {
+            transport,
+            created_at: self.created_at,
+            idle_since: now,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn spawn_listener() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                streams.push(stream);
+            }
+        });
+        (address, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuses_connections() {
+        let (address, accepted) = spawn_listener().await;
+        let pool = ConnectionPool::new(2);
+
+        drop(pool.checkout(&address).await.unwrap());
+        assert_eq!(pool.idle_count(&address), 1);
+
+        drop(pool.checkout(&address).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_limits_connections_per_address() {
+        let (address, _) = spawn_listener().await;
+        let pool = ConnectionPool::new(1);
+
+        let first = pool.checkout(&address).await.unwrap();
+        let blocked = tokio::time::timeout(Duration::from_millis(50), pool.checkout(&address)).await;
+        assert!(blocked.is_err());
+
+        drop(first);
+        assert!(pool.checkout(&address).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pool_reaps_idle_and_discarded() {
+        let (address, _) = spawn_listener().await;
+        let pool = ConnectionPool::new(2).with_idle_timeout(Duration::from_millis(10));
+
+        pool.checkout(&address).await.unwrap().discard();
+        assert_eq!(pool.idle_count(&address), 0);
+
+        drop(pool.checkout(&address).await.unwrap());
+        assert_eq!(pool.idle_count(&address), 1);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pool.reap_idle();
+        assert_eq!(pool.idle_count(&address), 0);
+    }
+}
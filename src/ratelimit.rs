@@ -0,0 +1,367 @@
+use crate::server::{HandlerFuture, Middleware, Next, Request};
+use crate::ProtocolError;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// Token bucket refilled continuously at a fixed rate
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket refilled at `rate` tokens per second holding up to `burst` tokens
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            capacity: burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Returns how long until `cost` tokens are available, without taking them.
+    ///
+    /// Costs larger than the burst size are admitted once the bucket is full and
+    /// leave it in debt, so oversized items are slowed rather than rejected forever.
+    pub fn wait_time(&mut self, cost: f64) -> Duration {
+        self.refill();
+        let needed = cost.min(self.capacity);
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.rate)
+        }
+    }
+
+    /// Takes `cost` tokens unconditionally
+    pub fn take(&mut self, cost: f64) {
+        self.refill();
+        self.tokens -= cost;
+    }
+
+    /// Takes `cost` tokens, or returns how long to wait before they are available
+    pub fn try_take(&mut self, cost: f64) -> Result<(), Duration> {
+        let wait = self.wait_time(cost);
+        if wait.is_zero() {
+            self.take(cost);
+            Ok(())
+        } else {
+            Err(wait)
+        }
+    }
+}
+
+/// Per-connection limiter on messages per second and payload bytes per second
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    throttled: u64,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the message rate, allowing bursts of up to `burst` messages
+    pub fn with_messages_per_sec(mut self, rate: f64, burst: u32) -> Self {
+        self.messages = Some(TokenBucket::new(rate, burst as f64));
+        self
+    }
+
+    /// Limits the payload byte rate, allowing bursts of up to `burst` bytes
+    pub fn with_bytes_per_sec(mut self, rate: f64, burst: u64) -> Self {
+        self.bytes = Some(TokenBucket::new(rate, burst as f64));
+        self
+    }
+
+    /// Admits one message of `bytes` payload bytes, or returns how long to wait
+    pub fn try_acquire(&mut self, bytes: usize) -> Result<(), Duration> {
+        // Check both buckets before taking from either so a rejection costs nothing
+        let mut wait = Duration::ZERO;
+        if let Some(bucket) = &mut self.messages {
+            wait = wait.max(bucket.wait_time(1.0));
+        }
+        if let Some(bucket) = &mut self.bytes {
+            wait = wait.max(bucket.wait_time(bytes as f64));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        if let Some(bucket) = &mut self.messages {
+            bucket.take(1.0);
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.take(bytes as f64);
+        }
+        Ok(())
+    }
+
+    /// Waits until one message of `bytes` payload bytes is admitted
+    pub async fn acquire(&mut self, bytes: usize) {
+        let mut throttled = false;
+        while let Err(wait) = self.try_acquire(bytes) {
+            if !throttled {
+                throttled = true;
+                self.record_throttle();
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Records that a message was delayed or rejected by this limiter
+    pub fn record_throttle(&mut self) {
+        self.throttled += 1;
+        metrics::increment_counter!("remus_rate_limited_total");
+    }
+
+    /// Returns how many messages have been throttled so far
+    pub fn throttled_count(&self) -> u64 {
+        self.throttled
+    }
+}
+
+/// Server middleware admitting requests through token buckets.
+///
+/// By default every request it sees shares one bucket, so wrapping a single
+/// route in [`Layered`](crate::server::Layered) limits that route as a whole.
+/// [`per_route`](Self::per_route) and [`per_identity`](Self::per_identity) give
+/// each routing info and each client its own bucket; together they key on both.
+/// A client is identified by its [`ConnectionContext`](crate::ConnectionContext)
+/// identity, falling back to its remote address. Rejected requests fail with
+/// `ProtocolError::QuotaExceeded`, whose Error response carries the retry-after delay.
+pub struct RequestLimiter {
+    rate: f64,
+    burst: f64,
+    per_route: bool,
+    per_identity: bool,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RequestLimiter {
+    /// Admits `rate` requests per second per bucket, allowing bursts of up to `burst`
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            per_route: false,
+            per_identity: false,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gives each routing info its own bucket
+    pub fn per_route(mut self) -> Self {
+        self.per_route = true;
+        self
+    }
+
+    /// Gives each client identity its own bucket
+    pub fn per_identity(mut self) -> Self {
+        self.per_identity = true;
+        self
+    }
+
+    fn key(&self, request: &Request) -> String {
+        let mut key = String::new();
+        if self.per_identity {
+            let connection = request.connection();
+            match (connection.identity(), connection.remote_addr()) {
+                (Some(identity), _) => key.push_str(&identity),
+                (None, Some(addr)) => key.push_str(&addr.to_string()),
+                (None, None) => {}
+            }
+        }
+        if self.per_route {
+            key.push('\0');
+            key.push_str(request.route());
+        }
+        key
+    }
+
+    /// Takes a token for `request`, or returns how long until one is available
+    pub fn try_admit(&self, request: &Request) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry(self.key(request))
+            .or_insert_with(|| TokenBucket::new(self.rate, self.burst))
+            .try_take(1.0)
+    }
+}
+
+impl Middleware for RequestLimiter {
+    fn handle(&self, request: Request, next: Next) -> HandlerFuture {
+        match self.try_admit(&request) {
+            Ok(()) => next.run(request),
+            Err(retry_after) => {
+                metrics::increment_counter!("remus_quota_exceeded_total");
+                Box::pin(std::future::ready(Err(ProtocolError::QuotaExceeded { retry_after })))
+            }
+        }
+    }
+}
+
+/// Default size of the slices a paced write is split into
+pub const DEFAULT_PACING_CHUNK: usize = 16 * 1024;
+
+/// Smooths outbound writes to a target bytes/second rate.
+///
+/// Large frames are written in chunks, each admitted by a token bucket, so a bulk
+/// transfer cannot saturate a constrained uplink in one burst.
+pub struct Pacer {
+    bucket: TokenBucket,
+    rate: f64,
+    chunk_size: usize,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pacer {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bucket: TokenBucket::new(bytes_per_sec as f64, DEFAULT_PACING_CHUNK as f64),
+            rate: bytes_per_sec as f64,
+            chunk_size: DEFAULT_PACING_CHUNK,
+            sleep: None,
+        }
+    }
+
+    /// Sets the largest slice written at once, which is also the burst allowance
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self.bucket = TokenBucket::new(self.rate, self.chunk_size as f64);
+        self
+    }
+
+    /// Waits until a chunk of up to `len` bytes may be written and returns its size.
+    ///
+    /// Tokens are only spent by [`Pacer::commit`], so a write that ends up
+    /// pending or partial is not overcharged.
+    pub fn poll_admit(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<usize> {
+        let chunk = len.min(self.chunk_size);
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            let wait = self.bucket.wait_time(chunk as f64);
+            if wait.is_zero() {
+                return Poll::Ready(chunk);
+            }
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    /// Charges `bytes` actually written against the rate
+    pub fn commit(&mut self, bytes: usize) {
+        self.bucket.take(bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ConnectionContext, Router};
+    use crate::{Message, MessageFlags, MessageType};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_token_bucket_burst_and_debt() {
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        assert!(bucket.try_take(1.0).is_ok());
+        assert!(bucket.try_take(1.0).is_ok());
+        let wait = bucket.try_take(1.0).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(100));
+
+        // An oversized cost is admitted from a full bucket and leaves it in debt
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        assert!(bucket.try_take(5.0).is_ok());
+        assert!(bucket.try_take(1.0).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_checks_all_buckets() {
+        let mut limiter = RateLimiter::new()
+            .with_messages_per_sec(100.0, 10)
+            .with_bytes_per_sec(100.0, 100);
+
+        assert!(limiter.try_acquire(100).is_ok());
+        // Message bucket has room but the byte bucket does not
+        assert!(limiter.try_acquire(50).is_err());
+        // The rejected attempt did not consume a message token
+        for _ in 0..9 {
+            limiter.messages.as_mut().unwrap().try_take(1.0).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_acquire_waits() {
+        let mut limiter = RateLimiter::new().with_messages_per_sec(50.0, 1);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire(0).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(35));
+        assert_eq!(limiter.throttled_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pacer_splits_and_delays() {
+        let mut pacer = Pacer::new(10_000).with_chunk_size(1000);
+        let start = Instant::now();
+        let mut sent = 0;
+        while sent < 3000 {
+            let chunk = std::future::poll_fn(|cx| pacer.poll_admit(cx, 3000 - sent)).await;
+            assert!(chunk <= 1000);
+            pacer.commit(chunk);
+            sent += chunk;
+        }
+        // First chunk rides the burst, the remaining 2000 bytes take ~200ms
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_request_limiter_buckets_per_identity_and_route() {
+        let router = Router::new()
+            .with_middleware(RequestLimiter::new(1.0, 1).per_identity().per_route())
+            .with_route("*", |_request: Request| async { Ok(Bytes::new()) });
+        let alice = Arc::new(ConnectionContext::default());
+        alice.set_identity("alice");
+        let bob = Arc::new(ConnectionContext::default());
+        bob.set_identity("bob");
+        let request = |connection: &Arc<ConnectionContext>, route: &str| {
+            let mut message = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::new());
+            message.routing_info = Some(route.to_string());
+            Request::new(message, connection.clone())
+        };
+
+        assert!(router.dispatch(request(&alice, "a")).await.is_ok());
+        let rejected = router.dispatch(request(&alice, "a")).await.unwrap_err();
+        assert!(matches!(rejected, ProtocolError::QuotaExceeded { retry_after } if retry_after > Duration::ZERO));
+        let payload = crate::ErrorPayload::from_error(&rejected);
+        assert!(payload.retry_after_ms.is_some());
+        assert!(matches!(payload.into_error(), ProtocolError::QuotaExceeded { .. }));
+
+        // Other routes and other clients have buckets of their own
+        assert!(router.dispatch(request(&alice, "b")).await.is_ok());
+        assert!(router.dispatch(request(&bob, "a")).await.is_ok());
+    }
+}
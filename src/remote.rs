@@ -0,0 +1,160 @@
+//! A [`DiscoveryBackend`] using a [`ServiceRegistry`] served by another node.
+//!
+//! A node mounts [`ServiceRegistry::handler`] at [`DISCOVERY_ROUTE`] to act
+//! as a discovery server; a [`RemoteRegistry`] speaks [`RegistryRequest`]s to
+//! it over a [`RemusClient`]. Registrations are leased on the server, so each
+//! is renewed in the background three times per lease TTL and registered again
+//! if the server lost it, e.g. across a restart, until it is deregistered.
+//!
+//! [`ServiceRegistry`]: crate::discovery::ServiceRegistry
+//! [`ServiceRegistry::handler`]: crate::discovery::ServiceRegistry::handler
+
+use crate::{
+    client::RemusClient,
+    discovery::{DiscoveryBackend, RegistryRequest, ServiceInfo, Synced, DISCOVERY_ROUTE},
+    selector::Selector,
+    ProtocolError,
+};
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Registers with and queries a registry on another node
+pub struct RemoteRegistry {
+    client: Arc<RemusClient>,
+    // Renewal task of each service registered through this backend
+    renewals: Mutex<HashMap<String, JoinHandle<()>>>,
+    poll_interval: Duration,
+}
+
+impl RemoteRegistry {
+    pub fn new(client: RemusClient) -> Self {
+        Self {
+            client: Arc::new(client),
+            renewals: Mutex::new(HashMap::new()),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    /// Sets how often [`watch`](DiscoveryBackend::watch) asks whether the services changed
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+}
+
+async fn register(client: &RemusClient, service: &ServiceInfo) -> Result<Duration, ProtocolError> {
+    let ttl: u64 = client.call(DISCOVERY_ROUTE, &RegistryRequest::Register(Box::new(service.clone()))).await?;
+    Ok(Duration::from_millis(ttl))
+}
+
+// Renews the lease on `service` until aborted, registering it again when the server no longer holds it
+async fn renew(client: Arc<RemusClient>, service: ServiceInfo, mut ttl: Duration) {
+    loop {
+        tokio::time::sleep((ttl / 3).max(Duration::from_millis(1))).await;
+        let renewed: Result<(), _> = client.call(DISCOVERY_ROUTE, &RegistryRequest::Renew(service.id.clone())).await;
+        if let Err(e) = renewed {
+            tracing::debug!("Renewing {} failed, registering again: {}", service.id, e);
+            match register(&client, &service).await {
+                Ok(granted) => ttl = granted,
+                Err(e) => tracing::warn!("Registering {} again failed: {}", service.id, e),
+            }
+        }
+    }
+}
+
+impl DiscoveryBackend for RemoteRegistry {
+    fn register(&self, service: ServiceInfo) -> BoxFuture<'_, Result<(), ProtocolError>> {
+        Box::pin(async move {
+            let ttl = register(&self.client, &service).await?;
+            let id = service.id.clone();
+            let renewal = tokio::spawn(renew(self.client.clone(), service, ttl));
+            if let Some(replaced) = self.renewals.lock().unwrap().insert(id, renewal) {
+                replaced.abort();
+            }
+            Ok(())
+        })
+    }
+
+    fn deregister<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        Box::pin(async move {
+            if let Some(renewal) = self.renewals.lock().unwrap().remove(id) {
+                renewal.abort();
+            }
+            self.client.call(DISCOVERY_ROUTE, &RegistryRequest::Deregister(id.to_string())).await
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        Box::pin(async move { self.client.call(DISCOVERY_ROUTE, &RegistryRequest::Query(Selector::default())).await })
+    }
+
+    /// Yields the services, then asks every poll interval and yields them again once they changed
+    fn watch(&self) -> BoxStream<'_, Result<Vec<ServiceInfo>, ProtocolError>> {
+        stream::unfold(None, move |version: Option<u64>| async move {
+            loop {
+                if version.is_some() {
+                    tokio::time::sleep(self.poll_interval).await;
+                }
+                let synced: Synced = match self.client.call(DISCOVERY_ROUTE, &RegistryRequest::Sync { version }).await {
+                    Ok(synced) => synced,
+                    Err(e) => {
+                        tokio::time::sleep(self.poll_interval).await;
+                        return Some((Err(e), version));
+                    }
+                };
+                if let Some(services) = synced.services {
+                    return Some((Ok(services), Some(synced.version)));
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+impl Drop for RemoteRegistry {
+    fn drop(&mut self) {
+        for renewal in self.renewals.get_mut().unwrap().values() {
+            renewal.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        discovery::{tests::create_test_service, ServiceRegistry},
+        server::{Router, Server},
+    };
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_remote_registrations_stay_leased_until_deregistered() {
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_millis(300)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(Router::new().with_route(DISCOVERY_ROUTE, registry.handler()));
+        tokio::spawn(async move { server.serve(listener).await });
+        let remote = RemoteRegistry::new(RemusClient::connect(&address).await.unwrap()).with_poll_interval(Duration::from_millis(20));
+
+        let mut changes = remote.watch();
+        assert!(changes.next().await.unwrap().unwrap().is_empty());
+        remote.register(create_test_service("sensors-1")).await.unwrap();
+        assert_eq!(changes.next().await.unwrap().unwrap().len(), 1);
+
+        // Renewals outlast the lease TTL
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert!(registry.get_service("sensors-1").await.is_some());
+        assert_eq!(remote.list().await.unwrap().len(), 1);
+
+        remote.deregister("sensors-1").await.unwrap();
+        assert!(changes.next().await.unwrap().unwrap().is_empty());
+        assert!(registry.get_service("sensors-1").await.is_none());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{
+    discovery::{HealthStatus, ServiceInfo, ServiceRegistry},
+    ProtocolError,
+};
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Prefix marking an address as a DNS SRV name, e.g. `srv://_remus._tcp.example.com`
+pub const SRV_SCHEME: &str = "srv://";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    target: String,
+}
+
+/// Expands `address` into the `host:port` targets to connect to, in order
+pub(crate) async fn targets(address: &str) -> Result<Vec<String>, ProtocolError> {
+    match address.strip_prefix(SRV_SCHEME) {
+        Some(name) => Ok(lookup_srv(name).await?.0),
+        None => Ok(vec![address.to_string()]),
+    }
+}
+
+// The ordered `host:port` targets of SRV name `name`, and how long they may be cached
+#[cfg(feature = "srv")]
+async fn lookup_srv(name: &str) -> Result<(Vec<String>, Duration), ProtocolError> {
+    let resolver = hickory_resolver::TokioAsyncResolver::tokio_from_system_conf().map_err(std::io::Error::other)?;
+    let lookup = resolver.srv_lookup(name).await.map_err(std::io::Error::other)?;
+    let records = lookup
+        .iter()
+        .map(|srv| SrvRecord {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            target: format!("{}:{}", srv.target().to_utf8().trim_end_matches('.'), srv.port()),
+        })
+        .collect();
+    let ttl = lookup.as_lookup().valid_until().saturating_duration_since(std::time::Instant::now());
+    Ok((order_srv(records, &mut rand::thread_rng()).into_iter().map(|record| record.target).collect(), ttl))
+}
+
+#[cfg(not(feature = "srv"))]
+async fn lookup_srv(name: &str) -> Result<(Vec<String>, Duration), ProtocolError> {
+    Err(ProtocolError::InvalidFormat(format!(
+        "Resolving SRV name {} requires the `srv` feature",
+        name
+    )))
+}
+
+/// Keeps a [`ServiceRegistry`] in step with the DNS SRV records of named services.
+///
+/// Each target a service's SRV name resolves to is registered as a healthy
+/// instance with the ID `<SRV name>/<host:port>`, and instances the name no
+/// longer resolves to are unregistered. Names are re-resolved every interval,
+/// or sooner when their records' TTL runs out; a failed resolution keeps the
+/// previous instances until the registry's TTL expires them. Requires the
+/// `srv` feature.
+pub struct SrvDiscovery {
+    registry: Arc<ServiceRegistry>,
+    services: Vec<(String, String)>,
+    interval: Duration,
+}
+
+impl SrvDiscovery {
+    pub fn new(registry: Arc<ServiceRegistry>) -> Self {
+        Self {
+            registry,
+            services: Vec::new(),
+            interval: Duration::from_secs(30),
+        }
+    }
+
+    /// Registers the targets of `srv_name`, e.g. `_sensors._tcp.example.com`, as instances of service `name`
+    pub fn with_service(mut self, name: &str, srv_name: &str) -> Self {
+        self.services.push((name.to_string(), srv_name.to_string()));
+        self
+    }
+
+    /// Sets the longest time between resolutions
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Resolves every name once, returning how long until the soonest records expire
+    pub async fn refresh(&self) -> Duration {
+        let mut next = self.interval;
+        for (name, srv_name) in &self.services {
+            match resolve_service(srv_name).await {
+                Ok((addresses, ttl)) => {
+                    self.apply(name, srv_name, addresses).await;
+                    next = next.min(ttl);
+                }
+                Err(e) => tracing::debug!("Resolving SRV name {} failed: {}", srv_name, e),
+            }
+        }
+        next
+    }
+
+    /// Refreshes the registry until the task is dropped
+    pub async fn run(&self) {
+        loop {
+            // Records with a TTL of zero must not turn this into a busy loop
+            let next = self.refresh().await.max(Duration::from_secs(1));
+            tokio::time::sleep(next).await;
+        }
+    }
+
+    async fn apply(&self, name: &str, srv_name: &str, addresses: HashMap<String, SocketAddr>) {
+        let prefix = format!("{srv_name}/");
+        for stale in self.registry.query(|service| service.id.starts_with(&prefix) && !addresses.contains_key(&service.id[prefix.len()..])).await {
+            self.registry.unregister(&stale.id).await;
+        }
+        for (target, address) in addresses {
+            self.registry
+                .register(ServiceInfo {
+                    id: format!("{prefix}{target}"),
+                    name: name.to_string(),
+                    version: String::new(),
+                    capabilities: Vec::new(),
+                    address,
+                    metadata: HashMap::from([("srv".to_string(), srv_name.to_string())]),
+                    last_seen: SystemTime::now(),
+                    health_status: HealthStatus::Healthy,
+                    public_key: None,
+                    health_check: None,
+                })
+                .await;
+        }
+    }
+}
+
+// Each target of `srv_name` with the address it resolves to, and the records' TTL
+async fn resolve_service(srv_name: &str) -> Result<(HashMap<String, SocketAddr>, Duration), ProtocolError> {
+    let (targets, ttl) = lookup_srv(srv_name).await?;
+    let mut addresses = HashMap::with_capacity(targets.len());
+    for target in targets {
+        match tokio::net::lookup_host(&target).await.map(|mut found| found.next()) {
+            Ok(Some(address)) => {
+                addresses.insert(target, address);
+            }
+            _ => tracing::debug!("SRV target {} does not resolve", target),
+        }
+    }
+    Ok((addresses, ttl))
+}
+
+// Orders records per RFC 2782: lowest priority first, and within a priority
+// by weighted random selection
+#[cfg_attr(not(feature = "srv"), allow(dead_code))]
+fn order_srv(mut records: Vec<SrvRecord>, rng: &mut impl Rng) -> Vec<SrvRecord> {
+    // Zero-weight records go first within their priority so they are picked rarely, not never
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+    let mut ordered = Vec::with_capacity(records.len());
+    for group in records.chunk_by(|a, b| a.priority == b.priority) {
+        let mut group = group.to_vec();
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|record| record.weight as u32).sum();
+            let pick = rng.gen_range(0..=total);
+            let mut running = 0;
+            let index = group
+                .iter()
+                .position(|record| {
+                    running += record.weight as u32;
+                    running >= pick
+                })
+                .unwrap();
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_srv_priority_then_weight() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let records = vec![record(20, 0, "backup"), record(10, 1, "light"), record(10, 99, "heavy")];
+
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let ordered = order_srv(records.clone(), &mut rng);
+            assert_eq!(ordered[2].target, "backup");
+            if ordered[0].target == "heavy" {
+                heavy_first += 1;
+            }
+        }
+        assert!(heavy_first > 900, "heavy record first {} times", heavy_first);
+    }
+
+    #[tokio::test]
+    async fn test_srv_targets_replace_the_previous_instances() {
+        let registry = Arc::new(ServiceRegistry::new(Duration::from_secs(60)));
+        let discovery = SrvDiscovery::new(registry.clone()).with_service("sensors", "_sensors._tcp.example.com");
+        let resolved = |targets: &[(&str, &str)]| targets.iter().map(|(target, address)| (target.to_string(), address.parse().unwrap())).collect();
+
+        discovery.apply("sensors", "_sensors._tcp.example.com", resolved(&[("a.example.com:7000", "10.0.0.1:7000"), ("b.example.com:7000", "10.0.0.2:7000")])).await;
+        let b = registry.get_service("_sensors._tcp.example.com/b.example.com:7000").await.unwrap();
+        assert_eq!((b.name.as_str(), b.address, b.health_status), ("sensors", "10.0.0.2:7000".parse().unwrap(), HealthStatus::Healthy));
+
+        discovery.apply("sensors", "_sensors._tcp.example.com", resolved(&[("b.example.com:7000", "10.0.0.2:7000")])).await;
+        let ids: Vec<String> = registry.query(|_| true).await.into_iter().map(|service| service.id).collect();
+        assert_eq!(ids, ["_sensors._tcp.example.com/b.example.com:7000"]);
+    }
+
+    #[tokio::test]
+    async fn test_plain_addresses_pass_through() {
+        assert_eq!(targets("example.com:80").await.unwrap(), vec!["example.com:80".to_string()]);
+    }
+}
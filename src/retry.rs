@@ -0,0 +1,167 @@
+use crate::{Message, MessageFlags, ProtocolError};
+use rand::Rng;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+
+type Classifier = Arc<dyn Fn(&ProtocolError) -> bool + Send + Sync>;
+
+/// Retry policy for client requests, applied only to messages marked IDEMPOTENT
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: bool,
+    retryable: Classifier,
+}
+
+impl RetryPolicy {
+    /// Creates a policy making up to `max_attempts` attempts in total
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: true,
+            retryable: Arc::new(is_transient),
+        }
+    }
+
+    /// Sets the delay before the first retry and the cap later delays grow to
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets the factor the delay grows by after each attempt; delays that would be negative are zero
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Enables or disables randomizing each delay between half and all of its nominal value
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Replaces the default classification of which errors are worth retrying
+    pub fn with_retryable(mut self, classifier: impl Fn(&ProtocolError) -> bool + Send + Sync + 'static) -> Self {
+        self.retryable = Arc::new(classifier);
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns whether `message`, having failed with `error` on attempt `attempt`, should be sent again
+    pub fn should_retry(&self, message: &Message, error: &ProtocolError, attempt: u32) -> bool {
+        message.flags.contains(MessageFlags::IDEMPOTENT)
+            && attempt < self.max_attempts
+            && (self.retryable)(error)
+    }
+
+    /// Returns the delay to wait after failed attempt `attempt` (starting at 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(63) as i32;
+        let nominal = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        // A negative or NaN multiplier must not yield a negative delay or an empty jitter range
+        let capped = nominal.min(self.max_backoff.as_secs_f64()).max(0.0);
+        let delay = if self.jitter {
+            rand::thread_rng().gen_range(capped / 2.0..=capped)
+        } else {
+            capped
+        };
+        Duration::from_secs_f64(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3)
+    }
+}
+
+/// Default classification: timeouts, overloaded servers and dropped or reset connections
+pub fn is_transient(error: &ProtocolError) -> bool {
+    match error {
+        ProtocolError::Timeout(_)
+        | ProtocolError::ConnectionClosed
+        | ProtocolError::ConnectionReset
+        | ProtocolError::Overloaded { .. }
+        | ProtocolError::GoAway(_) => true,
+        ProtocolError::IoError(e) => matches!(
+            e.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageType;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let policy = RetryPolicy::new(10)
+            .with_backoff(Duration::from_millis(100), Duration::from_secs(1))
+            .with_jitter(false);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(8), Duration::from_secs(1));
+
+        let jittered = RetryPolicy::new(10).with_backoff(Duration::from_millis(100), Duration::from_secs(1));
+        for _ in 0..100 {
+            let delay = jittered.backoff(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+
+        for multiplier in [-2.0, f64::NAN] {
+            let shrinking = RetryPolicy::new(10).with_backoff(Duration::from_millis(100), Duration::from_secs(1)).with_multiplier(multiplier);
+            assert!((1..10).all(|attempt| shrinking.backoff(attempt) <= Duration::from_secs(1)));
+        }
+    }
+
+    #[test]
+    fn test_only_idempotent_messages_retry() {
+        let policy = RetryPolicy::new(3);
+        let timeout = ProtocolError::Timeout("Request timeout".into());
+
+        let idempotent = Message::new(MessageType::Request, MessageFlags::IDEMPOTENT, 1, Bytes::new());
+        assert!(policy.should_retry(&idempotent, &timeout, 1));
+        assert!(policy.should_retry(&idempotent, &timeout, 2));
+        assert!(!policy.should_retry(&idempotent, &timeout, 3));
+
+        let plain = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::new());
+        assert!(!policy.should_retry(&plain, &timeout, 1));
+    }
+
+    #[test]
+    fn test_error_classification() {
+        assert!(is_transient(&ProtocolError::ConnectionClosed));
+        assert!(is_transient(&ProtocolError::Overloaded { retry_after: Duration::from_secs(1) }));
+        assert!(is_transient(&std::io::Error::from(ErrorKind::ConnectionReset).into()));
+        assert!(!is_transient(&ProtocolError::AuthenticationRequired));
+        assert!(!is_transient(&ProtocolError::InvalidFormat("bad".into())));
+
+        let policy = RetryPolicy::new(3).with_retryable(|e| matches!(e, ProtocolError::AuthenticationRequired));
+        let message = Message::new(MessageType::Request, MessageFlags::IDEMPOTENT, 1, Bytes::new());
+        assert!(policy.should_retry(&message, &ProtocolError::AuthenticationRequired, 1));
+        assert!(!policy.should_retry(&message, &ProtocolError::ConnectionClosed, 1));
+    }
+}
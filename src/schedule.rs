@@ -0,0 +1,210 @@
+//! Cron-like schedules for edge function triggers, enabled with the `edge` feature.
+//!
+//! A [`Schedule`] is written as in crontab, with five whitespace-separated
+//! fields, or six with a leading seconds field, matched against UTC:
+//!
+//! ```text
+//! [second] minute hour day-of-month month day-of-week
+//! */15     0-30   8,18 *            1-11  1-5
+//! ```
+//!
+//! Each field is `*`, a value, a range `a-b`, any of those stepped by `/n`, or
+//! a comma-separated list of them. Days of the week run from 0, Sunday, to 7,
+//! Sunday again. As in cron, when both day fields are restricted a time
+//! matching either matches. `@yearly`, `@monthly`, `@weekly`, `@daily` and
+//! `@hourly` stand for their usual schedules.
+
+use crate::ProtocolError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use time::{Date, OffsetDateTime};
+
+// Years searched for a matching time before a schedule is deemed never to fire, e.g. on February 30
+const HORIZON_YEARS: i32 = 8;
+
+/// When something runs; the seconds field defaults to 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    text: String,
+    // Bit n set when value n matches
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day fields are `*`, so that only the other restricts days
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// The first time after `time` the schedule matches, to the second
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let start = OffsetDateTime::from(time).replace_nanosecond(0).ok()? + Duration::from_secs(1);
+        let horizon = start.year() + HORIZON_YEARS;
+        let mut t = start;
+        while t.year() <= horizon {
+            if !has(self.months, t.month() as u8) {
+                let (year, month) = if t.month() == time::Month::December { (t.year() + 1, time::Month::January) } else { (t.year(), t.month().next()) };
+                t = Date::from_calendar_date(year, month, 1).ok()?.midnight().assume_utc();
+            } else if !self.day_matches(t.day(), t.weekday().number_days_from_sunday()) {
+                t = t.date().next_day()?.midnight().assume_utc();
+            } else if !has(self.hours, t.hour()) {
+                t = t.replace_minute(0).ok()?.replace_second(0).ok()? + Duration::from_secs(3600);
+            } else if !has(self.minutes, t.minute()) {
+                t = t.replace_second(0).ok()? + Duration::from_secs(60);
+            } else if !has(self.seconds, t.second()) {
+                t += Duration::from_secs(1);
+            } else {
+                return Some(t.into());
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, day: u8, weekday: u8) -> bool {
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => has(self.weekdays, weekday),
+            (false, true) => has(self.days, day),
+            (false, false) => has(self.days, day) || has(self.weekdays, weekday),
+        }
+    }
+}
+
+fn has(field: u64, value: u8) -> bool {
+    field & (1 << value) != 0
+}
+
+impl FromStr for Schedule {
+    type Err = ProtocolError;
+
+    fn from_str(schedule: &str) -> Result<Self, ProtocolError> {
+        let invalid = |reason: String| ProtocolError::InvalidFormat(format!("Invalid schedule {schedule:?}: {reason}"));
+        let expanded = match schedule.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let mut fields: Vec<&str> = expanded.split_whitespace().collect();
+        match fields.len() {
+            5 => fields.insert(0, "0"),
+            6 => {}
+            n => return Err(invalid(format!("expected 5 or 6 fields, found {n}"))),
+        }
+        let mut weekdays = field(fields[5], 0, 7).map_err(invalid)?;
+        // Sunday is both 0 and 7
+        if has(weekdays, 7) {
+            weekdays = (weekdays & !(1 << 7)) | 1;
+        }
+        Ok(Self {
+            text: fields.join(" "),
+            seconds: field(fields[0], 0, 59).map_err(invalid)?,
+            minutes: field(fields[1], 0, 59).map_err(invalid)?,
+            hours: field(fields[2], 0, 23).map_err(invalid)?,
+            days: field(fields[3], 1, 31).map_err(invalid)?,
+            months: field(fields[4], 1, 12).map_err(invalid)?,
+            weekdays,
+            any_day: fields[3].starts_with('*'),
+            any_weekday: fields[5].starts_with('*'),
+        })
+    }
+}
+
+// Parses a field into the bits of the values it matches, each within `min..=max`
+fn field(field: &str, min: u8, max: u8) -> Result<u64, String> {
+    let value = |text: &str| match text.parse::<u8>() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!("{text:?} is not a value from {min} to {max}")),
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u8>().ok().filter(|&step| step > 0).ok_or_else(|| format!("step {step:?} is not a positive number"))?),
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // A stepped value runs to the end of the field
+            None if part.contains('/') => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(format!("range {range:?} is backwards"));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// Schedules travel as their text
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Month, PrimitiveDateTime, Time};
+
+    // `YYYY-MM-DD HH:MM:SS` in UTC
+    fn utc(text: &str) -> OffsetDateTime {
+        let number = |range: std::ops::Range<usize>| text[range].parse::<u8>().unwrap();
+        let date = Date::from_calendar_date(text[..4].parse().unwrap(), Month::try_from(number(5..7)).unwrap(), number(8..10)).unwrap();
+        PrimitiveDateTime::new(date, Time::from_hms(number(11..13), number(14..16), number(17..19)).unwrap()).assume_utc()
+    }
+
+    fn next(schedule: &str, after: OffsetDateTime) -> Option<OffsetDateTime> {
+        schedule.parse::<Schedule>().unwrap().next_after(after.into()).map(OffsetDateTime::from)
+    }
+
+    #[test]
+    fn test_schedules_fire_at_the_next_matching_second() {
+        let monday = utc("2024-01-01 10:15:30");
+        assert_eq!(next("* * * * *", monday), Some(utc("2024-01-01 10:16:00")));
+        assert_eq!(next("*/20 * * * * *", monday), Some(utc("2024-01-01 10:15:40")));
+        assert_eq!(next("0 8,18 * * 1-5", monday), Some(utc("2024-01-01 18:00:00")));
+        assert_eq!(next("30 9 * * 6", monday), Some(utc("2024-01-06 09:30:00")));
+        assert_eq!(next("0 0 * * 7", monday), Some(utc("2024-01-07 00:00:00")));
+        assert_eq!(next("@monthly", monday), Some(utc("2024-02-01 00:00:00")));
+        assert_eq!(next("0 12 29 2 *", monday), Some(utc("2024-02-29 12:00:00")));
+        assert_eq!(next("0 0 31 12 *", utc("2024-12-31 00:00:00")), Some(utc("2025-12-31 00:00:00")));
+        // Either restricted day field matches: the 15th, or a Friday
+        assert_eq!(next("0 0 15 * 5", monday), Some(utc("2024-01-05 00:00:00")));
+        assert_eq!(next("0 0 30 2 *", monday), None);
+    }
+
+    #[test]
+    fn test_invalid_schedules_are_rejected() {
+        for invalid in ["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "a * * * *", "* * * * 8"] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{invalid}");
+        }
+        let schedule: Schedule = " 0  9 * * 1-5 ".parse().unwrap();
+        let json = serde_json::to_string(&schedule).unwrap();
+        assert_eq!(json, r#""0 0 9 * * 1-5""#);
+        assert_eq!(serde_json::from_str::<Schedule>(&json).unwrap(), schedule);
+    }
+}
@@ -0,0 +1,92 @@
+//! Key material that is wiped from memory once it is no longer needed.
+
+use bytes::Bytes;
+use rand::Rng;
+use std::fmt;
+use std::ops::Deref;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Compares `a` and `b` in time that depends only on their lengths
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Zeroes a buffer that held plaintext, unless other handles to it remain
+pub(crate) fn wipe(buffer: Bytes) {
+    if let Ok(mut buffer) = buffer.try_into_mut() {
+        buffer[..].zeroize();
+    }
+}
+
+/// A 32-byte key that is zeroed when dropped and never shown by `Debug`.
+///
+/// Dereferences to the key bytes, so `&key` can be passed wherever a
+/// `&[u8; 32]` is expected.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn generate() -> Self {
+        let mut key = Self([0; 32]);
+        rand::thread_rng().fill(&mut key.0);
+        key
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
+
+impl From<[u8; 32]> for SecretKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+// Constant time, so comparing keys leaks nothing about where they differ
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SecretKey {}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_key_redacts_and_wipes() {
+        let key = SecretKey::new([7; 32]);
+        assert_eq!(format!("{key:?}"), "SecretKey(..)");
+        assert_eq!(key, key.clone());
+        assert_ne!(key, SecretKey::generate());
+
+        let mut wiped = key.clone();
+        wiped.zeroize();
+        assert_eq!(wiped, SecretKey::new([0; 32]));
+    }
+}
@@ -0,0 +1,223 @@
+//! Label selectors over services.
+//!
+//! A [`Selector`] is a comma-separated list of requirements a
+//! [`ServiceInfo`] must all meet, written as text so it can travel in
+//! discovery requests as well as filter a registry locally:
+//!
+//! ```text
+//! zone=greenhouse, tier!=db, env in (prod, staging), region notin (eu),
+//! gpu, !deprecated, capabilities=streaming
+//! ```
+//!
+//! Keys name metadata entries, except `capabilities`, which names the
+//! service's capabilities: it equals a value the service has, is in a set it
+//! shares a value with, and exists if the service has any. `!=` and `notin`
+//! also match services without the key.
+
+use crate::{discovery::ServiceInfo, ProtocolError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// The key selecting on a service's capabilities rather than its metadata
+pub const CAPABILITIES_KEY: &str = "capabilities";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requirement {
+    Equal(String, String),
+    NotEqual(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    NotExists(String),
+}
+
+impl Requirement {
+    fn matches(&self, service: &ServiceInfo) -> bool {
+        let has = |key: &str, value: &str| values(service, key).any(|v| v == value);
+        match self {
+            Requirement::Equal(key, value) => has(key, value),
+            Requirement::NotEqual(key, value) => !has(key, value),
+            Requirement::In(key, set) => set.iter().any(|value| has(key, value)),
+            Requirement::NotIn(key, set) => !set.iter().any(|value| has(key, value)),
+            Requirement::Exists(key) => values(service, key).next().is_some(),
+            Requirement::NotExists(key) => values(service, key).next().is_none(),
+        }
+    }
+}
+
+fn values<'a>(service: &'a ServiceInfo, key: &str) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+    if key == CAPABILITIES_KEY {
+        Box::new(service.capabilities.iter().map(String::as_str))
+    } else {
+        Box::new(service.metadata.get(key).map(String::as_str).into_iter())
+    }
+}
+
+/// Requirements a service must all meet; the empty selector matches every service
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+impl Selector {
+    pub fn matches(&self, service: &ServiceInfo) -> bool {
+        self.requirements.iter().all(|requirement| requirement.matches(service))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = ProtocolError;
+
+    fn from_str(selector: &str) -> Result<Self, ProtocolError> {
+        let invalid = |reason: &str| ProtocolError::InvalidFormat(format!("Invalid selector {selector:?}: {reason}"));
+        let mut requirements = Vec::new();
+        for requirement in split_top_level(selector).map_err(invalid)? {
+            let requirement = requirement.trim();
+            if requirement.is_empty() {
+                if selector.trim().is_empty() {
+                    break;
+                }
+                return Err(invalid("empty requirement"));
+            }
+            requirements.push(parse_requirement(requirement).map_err(|reason| invalid(&reason))?);
+        }
+        Ok(Self { requirements })
+    }
+}
+
+// Splits on the commas outside parentheses
+fn split_top_level(selector: &str) -> Result<Vec<&str>, &'static str> {
+    let (mut parts, mut start, mut depth) = (Vec::new(), 0, 0);
+    for (i, c) in selector.char_indices() {
+        match c {
+            '(' if depth == 0 => depth += 1,
+            '(' => return Err("nested parentheses"),
+            ')' if depth == 1 => depth -= 1,
+            ')' => return Err("unbalanced parentheses"),
+            ',' if depth == 0 => {
+                parts.push(&selector[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses");
+    }
+    parts.push(&selector[start..]);
+    Ok(parts)
+}
+
+fn parse_requirement(requirement: &str) -> Result<Requirement, String> {
+    if let Some(key) = requirement.strip_prefix('!') {
+        return Ok(Requirement::NotExists(key_of(key.trim())?));
+    }
+    for (operator, negated) in [(" notin ", true), (" in ", false)] {
+        if let Some((key, set)) = requirement.split_once(operator) {
+            let set = set
+                .trim()
+                .strip_prefix('(')
+                .and_then(|set| set.strip_suffix(')'))
+                .ok_or_else(|| format!("set after {:?} must be parenthesized", operator.trim()))?;
+            let set = set.split(',').map(|value| value_of(value.trim())).collect::<Result<Vec<_>, _>>()?;
+            let key = key_of(key.trim())?;
+            return Ok(if negated { Requirement::NotIn(key, set) } else { Requirement::In(key, set) });
+        }
+    }
+    if let Some((key, value)) = requirement.split_once("!=") {
+        return Ok(Requirement::NotEqual(key_of(key.trim())?, value_of(value.trim())?));
+    }
+    if let Some((key, value)) = requirement.split_once("==").or_else(|| requirement.split_once('=')) {
+        return Ok(Requirement::Equal(key_of(key.trim())?, value_of(value.trim())?));
+    }
+    Ok(Requirement::Exists(key_of(requirement)?))
+}
+
+fn key_of(key: &str) -> Result<String, String> {
+    if key.is_empty() {
+        return Err("empty key".into());
+    }
+    value_of(key).map_err(|_| format!("key {key:?} may only hold letters, digits, '.', '-', '_' and '/'"))
+}
+
+fn value_of(value: &str) -> Result<String, String> {
+    if !value.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_' | b'/')) {
+        return Err(format!("value {value:?} may only hold letters, digits, '.', '-', '_' and '/'"));
+    }
+    Ok(value.to_string())
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, requirement) in self.requirements.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match requirement {
+                Requirement::Equal(key, value) => write!(f, "{key}={value}")?,
+                Requirement::NotEqual(key, value) => write!(f, "{key}!={value}")?,
+                Requirement::In(key, set) => write!(f, "{key} in ({})", set.join(","))?,
+                Requirement::NotIn(key, set) => write!(f, "{key} notin ({})", set.join(","))?,
+                Requirement::Exists(key) => f.write_str(key)?,
+                Requirement::NotExists(key) => write!(f, "!{key}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Selectors travel as their text
+impl Serialize for Selector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Selector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{discovery::tests::create_test_service, flags::CapabilityFlags};
+
+    #[test]
+    fn test_selectors_match_metadata_and_capabilities() {
+        let greenhouse = create_test_service("sensors-1")
+            .with_metadata(&[("zone", "greenhouse"), ("env", "prod"), ("gpu", "")])
+            .with_capabilities(CapabilityFlags::STREAMING, None::<String>);
+        let warehouse = create_test_service("sensors-2").with_metadata(&[("zone", "warehouse"), ("env", "dev"), ("deprecated", "true")]);
+        let matching = |selector: &str| {
+            let selector: Selector = selector.parse().unwrap();
+            [&greenhouse, &warehouse].iter().map(|service| selector.matches(service)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(matching("zone=greenhouse"), [true, false]);
+        assert_eq!(matching("zone==warehouse"), [false, true]);
+        assert_eq!(matching("tier!=db, zone != greenhouse"), [false, true]);
+        assert_eq!(matching("env in (prod, staging)"), [true, false]);
+        assert_eq!(matching("env notin (prod),missing notin (x)"), [false, true]);
+        assert_eq!(matching("gpu"), [true, false]);
+        assert_eq!(matching("!deprecated"), [true, false]);
+        assert_eq!(matching("capabilities=streaming, capabilities"), [true, false]);
+        assert_eq!(matching(""), [true, true]);
+
+        for invalid in ["=x", "env in prod", "env in (a,(b))", "a,,b", "zone=green house", "env in (a"] {
+            assert!(invalid.parse::<Selector>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_selectors_travel_as_text() {
+        let selector: Selector = " zone = greenhouse,env in (prod, staging), !deprecated ".parse().unwrap();
+        assert_eq!(selector.to_string(), "zone=greenhouse,env in (prod,staging),!deprecated");
+        let json = serde_json::to_string(&selector).unwrap();
+        assert_eq!(json, r#""zone=greenhouse,env in (prod,staging),!deprecated""#);
+        assert_eq!(serde_json::from_str::<Selector>(&json).unwrap(), selector);
+        assert!(serde_json::from_str::<Selector>(r#""env in""#).is_err());
+    }
+}
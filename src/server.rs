@@ -0,0 +1,2206 @@
+//! Serving requests: a [`Router`] maps each message's `routing_info` to a
+//! [`Handler`], and a [`Server`] runs the router over accepted connections.
+//! [`Middleware`] wraps handlers globally or per route, and
+//! [`StreamHandler`]s answer streaming requests with a sequence of chunks.
+//!
+//! ```rust,no_run
+//! use bytes::Bytes;
+//! use remus::server::{Request, Router, Server};
+//! use remus::ProtocolError;
+//!
+//! # async fn run() -> Result<(), ProtocolError> {
+//! let router = Router::new().with_route("devices/{id}/telemetry", |request: Request| async move {
+//!     let id = request.param("id").unwrap_or_default().to_string();
+//!     Ok::<_, ProtocolError>(Bytes::from(id))
+//! });
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:7000").await?;
+//! Server::new(router).serve(listener).await
+//! # }
+//! ```
+
+use crate::{acl::{Authenticator, CREDENTIALS_ROUTE}, broker::{Attachment, Broker}, compression::{self, Compression, CompressionConfig}, noise::{self, NoiseConfig}, observability::{AuditLog, Telemetry}, session::{self, SessionStore}, spiffe::SpiffeId, tls::CertificateVerifier, transport::Transport, Message, MessageExt, MessageFlags, MessageType, ProtocolError};
+use base64::Engine;
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc::{self, error::TrySendError}, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+/// Values captured by a route pattern, keyed by parameter name
+pub type Params = HashMap<String, String>;
+
+/// State of one served connection, shared by every request on it.
+///
+/// The server fills in the remote address and any certificates the peer
+/// presented; the peer identity, negotiated capabilities and stored values are
+/// set by the connect hook (see [`Server::with_on_connect`]) or by middleware
+/// and handlers.
+#[derive(Default)]
+pub struct ConnectionContext {
+    remote_addr: Option<SocketAddr>,
+    peer_certificates: Vec<Bytes>,
+    identity: Mutex<Option<String>>,
+    capabilities: Mutex<Vec<String>>,
+    values: Mutex<HashMap<String, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl ConnectionContext {
+    pub fn new(remote_addr: Option<SocketAddr>) -> Self {
+        Self {
+            remote_addr,
+            ..Self::default()
+        }
+    }
+
+    /// Records the certificate chain the peer presented, e.g. during a TLS handshake
+    pub fn with_peer_certificates(mut self, chain: Vec<Bytes>) -> Self {
+        self.peer_certificates = chain;
+        self
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Returns the peer's DER-encoded certificates, its own first; empty if it presented none
+    pub fn peer_certificates(&self) -> &[Bytes] {
+        &self.peer_certificates
+    }
+
+    /// Returns who the peer authenticated as, if anyone has set it
+    pub fn identity(&self) -> Option<String> {
+        self.identity.lock().unwrap().clone()
+    }
+
+    /// Returns the identity parsed as a SPIFFE ID, if it is one, e.g. as set by a [`SpiffeVerifier`](crate::SpiffeVerifier)
+    pub fn spiffe_id(&self) -> Option<SpiffeId> {
+        self.identity().and_then(|identity| identity.parse().ok())
+    }
+
+    pub fn set_identity(&self, identity: impl Into<String>) {
+        *self.identity.lock().unwrap() = Some(identity.into());
+    }
+
+    pub fn capabilities(&self) -> Vec<String> {
+        self.capabilities.lock().unwrap().clone()
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.lock().unwrap().iter().any(|c| c == capability)
+    }
+
+    /// Records the capabilities negotiated with the peer
+    pub fn set_capabilities(&self, capabilities: Vec<String>) {
+        *self.capabilities.lock().unwrap() = capabilities;
+    }
+
+    /// Stores `value` under `key` for the lifetime of the connection, replacing any previous value
+    pub fn insert<T: Any + Send + Sync>(&self, key: impl Into<String>, value: T) {
+        self.values.lock().unwrap().insert(key.into(), Arc::new(value));
+    }
+
+    /// Returns the value stored under `key`, or `None` if there is none or it is not a `T`
+    pub fn get<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        let value = self.values.lock().unwrap().get(key)?.clone();
+        value.downcast().ok()
+    }
+
+    pub fn remove(&self, key: &str) -> bool {
+        self.values.lock().unwrap().remove(key).is_some()
+    }
+}
+
+/// A routed message together with the parameters its route captured
+pub struct Request {
+    message: Message,
+    params: Params,
+    connection: Arc<ConnectionContext>,
+    cancellation: CancellationToken,
+    responses: Option<ResponseSink>,
+    chunks: Option<RequestStream>,
+}
+
+impl Request {
+    pub fn new(message: Message, connection: Arc<ConnectionContext>) -> Self {
+        Self {
+            message,
+            params: Params::new(),
+            connection,
+            cancellation: CancellationToken::new(),
+            responses: None,
+            chunks: None,
+        }
+    }
+
+    /// Sets the token that signals the request was abandoned
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Sets where the chunks of a streamed response go
+    pub fn with_response_sink(mut self, sink: ResponseSink) -> Self {
+        self.responses = Some(sink);
+        self
+    }
+
+    /// Sets the chunks of a streamed request
+    pub fn with_request_stream(mut self, chunks: RequestStream) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Takes the chunks the client streams after its first frame, if it opened an upload or bidirectional stream
+    pub fn take_request_stream(&mut self) -> Option<RequestStream> {
+        self.chunks.take()
+    }
+
+    /// Returns the sink for streamed response chunks, if the client asked for a stream.
+    ///
+    /// The value the handler finally returns becomes the StreamEnd payload.
+    pub fn response_sink(&self) -> Option<&ResponseSink> {
+        self.responses.as_ref()
+    }
+
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
+    /// Returns the context of the connection the message arrived on
+    pub fn connection(&self) -> &Arc<ConnectionContext> {
+        &self.connection
+    }
+
+    /// Fires when the client cancels the request or disconnects.
+    ///
+    /// The server drops the handler future at that point; the token lets work
+    /// the handler spawned elsewhere stop too.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Returns the routing info the message was dispatched on
+    pub fn route(&self) -> &str {
+        self.message.routing_info.as_deref().unwrap_or("")
+    }
+
+    /// Returns the segment captured by `{name}`, or the remainder matched by a trailing `*` as `"*"`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.message.payload
+    }
+
+    /// Deserializes the payload; see [`MessageExt::deserialize`]
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, ProtocolError> {
+        self.message.deserialize()
+    }
+
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+}
+
+pub type HandlerFuture = BoxFuture<'static, Result<Bytes, ProtocolError>>;
+
+/// Handles routed messages; the returned bytes become the Response payload.
+///
+/// Implemented for any `Fn(Request) -> impl Future<Output = Result<Bytes, ProtocolError>>`.
+pub trait Handler: Send + Sync {
+    fn call(&self, request: Request) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Bytes, ProtocolError>> + Send + 'static,
+{
+    fn call(&self, request: Request) -> HandlerFuture {
+        Box::pin(self(request))
+    }
+}
+
+/// Wraps request handling, e.g. for authentication, rate limiting, logging or metrics.
+///
+/// A middleware either answers the request itself or passes it on with
+/// [`Next::run`], and can inspect or replace the result. Implemented for any
+/// `Fn(Request, Next) -> impl Future<Output = Result<Bytes, ProtocolError>>`.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: Request, next: Next) -> HandlerFuture;
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Bytes, ProtocolError>> + Send + 'static,
+{
+    fn handle(&self, request: Request, next: Next) -> HandlerFuture {
+        Box::pin(self(request, next))
+    }
+}
+
+type Layers = Arc<Vec<Arc<dyn Middleware>>>;
+
+/// The rest of a middleware chain, ending in the route's handler
+pub struct Next {
+    layers: Layers,
+    index: usize,
+    endpoint: Arc<dyn Handler>,
+}
+
+impl Next {
+    fn new(layers: Layers, endpoint: Arc<dyn Handler>) -> Self {
+        Self {
+            layers,
+            index: 0,
+            endpoint,
+        }
+    }
+
+    /// Passes `request` to the next middleware, or to the handler after the last one
+    pub fn run(self, request: Request) -> HandlerFuture {
+        match self.layers.get(self.index).cloned() {
+            Some(layer) => layer.handle(
+                request,
+                Next {
+                    index: self.index + 1,
+                    ..self
+                },
+            ),
+            None => self.endpoint.call(request),
+        }
+    }
+}
+
+/// Sends the chunks of a streamed response as Stream frames.
+///
+/// Chunks share the connection's bounded write queue, so [`send`](Self::send)
+/// waits while the client reads more slowly than the handler produces.
+#[derive(Clone)]
+pub struct ResponseSink {
+    request_id: u64,
+    replies: mpsc::Sender<Message>,
+}
+
+impl ResponseSink {
+    pub fn new(request_id: u64, replies: mpsc::Sender<Message>) -> Self {
+        Self { request_id, replies }
+    }
+
+    /// Queues one chunk, failing with `ProtocolError::ConnectionClosed` once the connection is gone
+    pub async fn send(&self, chunk: impl Into<Bytes>) -> Result<(), ProtocolError> {
+        let chunk = Message::new(MessageType::Stream, MessageFlags::NONE, self.request_id, chunk.into());
+        self.replies.send(chunk).await.map_err(|_| ProtocolError::ConnectionClosed)
+    }
+}
+
+/// The chunks a client streams to one handler, ending with the client's StreamEnd.
+///
+/// The first frame's payload is the first chunk. The connection stops reading
+/// while a handler leaves its chunks unread, so a slow handler holds back the client.
+pub struct RequestStream {
+    chunks: mpsc::Receiver<Bytes>,
+}
+
+impl Stream for RequestStream {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.chunks.poll_recv(cx)
+    }
+}
+
+// Chunks buffered ahead of a handler reading a request stream
+const REQUEST_STREAM_CAPACITY: usize = 32;
+
+// Whether `message` belongs to a stream the client sends rather than a single streaming request
+fn streams_in(message: &Message) -> bool {
+    message.msg_type == MessageType::StreamEnd || (message.msg_type == MessageType::Stream && !message.flags.contains(MessageFlags::STREAM_END))
+}
+
+pub type ResponseStream = BoxStream<'static, Result<Bytes, ProtocolError>>;
+
+/// Answers a streaming request with a sequence of chunks.
+///
+/// Each item is sent as a Stream frame as soon as the connection can take it,
+/// and the end of the stream as an empty StreamEnd. An error item ends the
+/// response with an Error. Register one with [`Router::with_stream_route`].
+/// Implemented for any `Fn(Request) -> impl Stream<Item = Result<Bytes, ProtocolError>>`.
+pub trait StreamHandler: Send + Sync {
+    fn call(&self, request: Request) -> ResponseStream;
+}
+
+impl<F, S> StreamHandler for F
+where
+    F: Fn(Request) -> S + Send + Sync,
+    S: Stream<Item = Result<Bytes, ProtocolError>> + Send + 'static,
+{
+    fn call(&self, request: Request) -> ResponseStream {
+        self(request).boxed()
+    }
+}
+
+// Runs a StreamHandler behind the Handler interface so middleware wraps it unchanged
+struct Streaming<H>(H);
+
+impl<H: StreamHandler> Handler for Streaming<H> {
+    fn call(&self, request: Request) -> HandlerFuture {
+        let Some(sink) = request.response_sink().cloned() else {
+            let error = ProtocolError::InvalidFormat(format!("Route {:?} only answers streaming requests", request.route()));
+            return Box::pin(std::future::ready(Err(error)));
+        };
+        let mut chunks = self.0.call(request);
+        Box::pin(async move {
+            while let Some(chunk) = chunks.next().await {
+                sink.send(chunk?).await?;
+            }
+            Ok(Bytes::new())
+        })
+    }
+}
+
+/// A handler wrapped in its own middleware, for layers that apply to one route.
+///
+/// Layers run in the order they are added, inside any global middleware.
+pub struct Layered {
+    handler: Arc<dyn Handler>,
+    layers: Layers,
+}
+
+impl Layered {
+    pub fn new(handler: impl Handler + 'static) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            layers: Layers::default(),
+        }
+    }
+
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        Arc::make_mut(&mut self.layers).push(Arc::new(middleware));
+        self
+    }
+}
+
+impl Handler for Layered {
+    fn call(&self, request: Request) -> HandlerFuture {
+        Next::new(self.layers.clone(), self.handler.clone()).run(request)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Segment {
+    Literal(String),
+    Param(String),
+    Rest,
+}
+
+impl Segment {
+    // Literal segments outrank parameters, which outrank a trailing wildcard
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Rest => 0,
+        }
+    }
+}
+
+struct Route {
+    segments: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Route {
+    fn matches(&self, route: &str) -> Option<Params> {
+        match_pattern(&self.segments, route)
+    }
+
+    fn specificity(&self) -> impl Iterator<Item = u8> + '_ {
+        self.segments.iter().map(Segment::rank)
+    }
+}
+
+/// Matches `route` against a pattern parsed by [`parse_pattern`], returning the captured parameters
+pub(crate) fn match_pattern(segments: &[Segment], route: &str) -> Option<Params> {
+    let parts: Vec<&str> = route.split('/').collect();
+    let mut params = Params::new();
+    for (index, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Rest => {
+                let rest = parts.get(index..).filter(|rest| !rest.is_empty())?;
+                params.insert("*".to_string(), rest.join("/"));
+                return Some(params);
+            }
+            Segment::Literal(literal) => {
+                if parts.get(index)? != literal {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                let part = parts.get(index).filter(|part| !part.is_empty())?;
+                params.insert(name.clone(), part.to_string());
+            }
+        }
+    }
+    (parts.len() == segments.len()).then_some(params)
+}
+
+pub(crate) fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let parts: Vec<&str> = pattern.split('/').collect();
+    parts
+        .iter()
+        .enumerate()
+        .map(|(index, part)| {
+            if *part == "*" {
+                assert!(index == parts.len() - 1, "`*` must be the last segment of route pattern {pattern:?}");
+                Segment::Rest
+            } else if let Some(name) = part.strip_prefix('{').and_then(|part| part.strip_suffix('}')) {
+                assert!(!name.is_empty(), "Empty parameter name in route pattern {pattern:?}");
+                Segment::Param(name.to_string())
+            } else {
+                assert!(!part.contains(['{', '}', '*']), "Malformed segment {part:?} in route pattern {pattern:?}");
+                Segment::Literal(part.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Routing table dispatching messages by their `routing_info`.
+///
+/// Patterns are `/`-separated. A segment is matched literally, `{name}` matches
+/// any one non-empty segment and captures it, and a trailing `*` matches one or
+/// more remaining segments. When several patterns match, the most specific wins,
+/// comparing segment by segment; a later route never displaces an earlier one
+/// with the same pattern.
+///
+/// Global middleware runs in the order it is added, for every message including
+/// those no route matches, so it can reject a request before it is routed.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Layers,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes messages matching `pattern` to `handler`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is malformed, e.g. `*` before the last segment.
+    pub fn with_route(mut self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.routes.push(Route {
+            segments: parse_pattern(pattern),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    /// Answers streaming requests matching `pattern` with the chunks `handler` produces.
+    ///
+    /// Plain requests to the route fail with `ProtocolError::InvalidFormat`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is malformed.
+    pub fn with_stream_route(self, pattern: &str, handler: impl StreamHandler + 'static) -> Self {
+        self.with_route(pattern, Streaming(handler))
+    }
+
+    /// Runs `middleware` around every handler; see [`Middleware`]
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+        self
+    }
+
+    /// Finds the handler for `route`, failing with `ProtocolError::NotFound` if none matches
+    pub fn resolve(&self, route: &str) -> Result<(Arc<dyn Handler>, Params), ProtocolError> {
+        let mut best: Option<(&Route, Params)> = None;
+        for candidate in &self.routes {
+            let Some(params) = candidate.matches(route) else {
+                continue;
+            };
+            if best.as_ref().is_none_or(|(route, _)| candidate.specificity().gt(route.specificity())) {
+                best = Some((candidate, params));
+            }
+        }
+        best.map(|(route, params)| (route.handler.clone(), params))
+            .ok_or_else(|| ProtocolError::NotFound(route.to_string()))
+    }
+
+    /// Runs the middleware chain and the handler for the request's route
+    pub async fn dispatch(&self, mut request: Request) -> Result<Bytes, ProtocolError> {
+        let route = request.route().to_string();
+        let endpoint = match self.resolve(&route) {
+            Ok((handler, params)) => {
+                request.params = params;
+                handler
+            }
+            Err(_) => {
+                let not_found = move |_request: Request| std::future::ready(Err(ProtocolError::NotFound(route.clone())));
+                Arc::new(not_found)
+            }
+        };
+        Next::new(self.middleware.clone(), endpoint).run(request).await
+    }
+}
+
+/// Payload of an Error message sent in reply to a failed request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorPayload {
+    /// The [`ProtocolError::kind`] of the failure, e.g. `"NotFound"`
+    pub code: String,
+    pub message: String,
+    /// How long the client should wait before retrying, for load-shedding errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl ErrorPayload {
+    pub fn from_error(error: &ProtocolError) -> Self {
+        let retry_after_ms = match error {
+            ProtocolError::Overloaded { retry_after } | ProtocolError::QuotaExceeded { retry_after } => {
+                Some(retry_after.as_millis() as u64)
+            }
+            _ => None,
+        };
+        Self {
+            code: error.kind().to_string(),
+            message: error.to_string(),
+            retry_after_ms,
+        }
+    }
+
+    /// Builds the Error message answering request `request_id`
+    pub fn to_message(&self, request_id: u64) -> Message {
+        let payload = serde_json::to_vec(self).expect("error payload serializes");
+        Message::new(MessageType::Error, MessageFlags::NONE, request_id, Bytes::from(payload))
+    }
+
+    /// Parses an Error message, returning `None` if it is not structured
+    pub fn decode(message: &Message) -> Option<Self> {
+        serde_json::from_slice(&message.payload).ok()
+    }
+
+    /// Converts the payload into the error a client surfaces: `Overloaded` and
+    /// `QuotaExceeded` keep their retry-after hint, `AuthenticationRequired`
+    /// is kept as is, anything else becomes `ProtocolError::Remote`
+    pub fn into_error(self) -> ProtocolError {
+        match (self.code.as_str(), self.retry_after_ms) {
+            ("AuthenticationRequired", _) => ProtocolError::AuthenticationRequired,
+            ("Overloaded", Some(ms)) => ProtocolError::Overloaded {
+                retry_after: Duration::from_millis(ms),
+            },
+            ("QuotaExceeded", Some(ms)) => ProtocolError::QuotaExceeded {
+                retry_after: Duration::from_millis(ms),
+            },
+            _ => ProtocolError::Remote(self.message),
+        }
+    }
+}
+
+/// A byte stream the server can run the protocol over
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// A stream an [`Acceptor`] has finished with
+pub struct Accepted {
+    pub stream: Box<dyn Io>,
+    /// DER-encoded certificates the peer presented, its own first
+    pub peer_certificates: Vec<Bytes>,
+}
+
+/// Wraps each accepted TCP stream before it is served, e.g. to terminate TLS.
+///
+/// Implemented for any `Fn(TcpStream) -> impl Future<Output = Result<Box<dyn Io>, ProtocolError>>`.
+pub trait Acceptor: Send + Sync {
+    fn accept(&self, stream: TcpStream) -> BoxFuture<'static, Result<Box<dyn Io>, ProtocolError>>;
+
+    /// Like `accept`, also reporting the peer's certificate chain; TLS
+    /// acceptors that request client certificates override this
+    fn accept_with_peer(&self, stream: TcpStream) -> BoxFuture<'static, Result<Accepted, ProtocolError>> {
+        let accept = self.accept(stream);
+        Box::pin(async move {
+            Ok(Accepted {
+                stream: accept.await?,
+                peer_certificates: Vec::new(),
+            })
+        })
+    }
+}
+
+impl<F, Fut> Acceptor for F
+where
+    F: Fn(TcpStream) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Box<dyn Io>, ProtocolError>> + Send + 'static,
+{
+    fn accept(&self, stream: TcpStream) -> BoxFuture<'static, Result<Box<dyn Io>, ProtocolError>> {
+        Box::pin(self(stream))
+    }
+}
+
+type Permits = (OwnedSemaphorePermit, Option<OwnedSemaphorePermit>);
+
+// Settings a ServerHandle can swap while connections are being served, and its shutdown signal
+struct Reloadable {
+    router: RwLock<Arc<Router>>,
+    acceptor: RwLock<Option<Arc<dyn Acceptor>>>,
+    on_connect: RwLock<Option<ConnectHook>>,
+    shutdown: CancellationToken,
+    on_shutdown: Mutex<Vec<ShutdownHook>>,
+}
+
+/// Replaces a running [`Server`]'s configuration without dropping connections, or shuts it down.
+///
+/// A new router, with its middleware such as authentication and rate limits,
+/// applies to the next request on every connection; requests already running
+/// finish on the old one. A new acceptor (e.g. with renewed TLS certificates)
+/// or connect hook applies to connections accepted afterwards.
+#[derive(Clone)]
+pub struct ServerHandle {
+    reloadable: Arc<Reloadable>,
+}
+
+impl ServerHandle {
+    pub fn set_router(&self, router: Router) {
+        *self.reloadable.router.write().unwrap() = Arc::new(router);
+    }
+
+    pub fn set_acceptor(&self, acceptor: impl Acceptor + 'static) {
+        *self.reloadable.acceptor.write().unwrap() = Some(Arc::new(acceptor));
+    }
+
+    pub fn set_on_connect<F, Fut>(&self, hook: F)
+    where
+        F: Fn(Arc<ConnectionContext>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ProtocolError>> + Send + 'static,
+    {
+        *self.reloadable.on_connect.write().unwrap() = Some(Arc::new(move |context| Box::pin(hook(context))));
+    }
+
+    /// Runs `hook` when the server shuts down, after earlier hooks, e.g. to deregister it from discovery
+    pub fn on_shutdown<F, Fut>(&self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.reloadable.on_shutdown.lock().unwrap().push(Box::new(move || Box::pin(hook())));
+    }
+
+    /// Stops [`Server::serve`] accepting connections; it runs the shutdown hooks, then returns.
+    ///
+    /// Connections already accepted are served until their peers close them.
+    pub fn shutdown(&self) {
+        self.reloadable.shutdown.cancel();
+    }
+
+    /// Calls `reload` with this handle whenever the process receives SIGHUP
+    #[cfg(unix)]
+    pub fn reload_on_sighup<F>(&self, reload: F) -> Result<tokio::task::JoinHandle<()>, ProtocolError>
+    where
+        F: Fn(&ServerHandle) + Send + 'static,
+    {
+        let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let handle = self.clone();
+        Ok(tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                reload(&handle);
+            }
+        }))
+    }
+}
+
+// Per-connection state shared with the tasks running its handlers
+struct Session {
+    context: Arc<ConnectionContext>,
+    replies: mpsc::Sender<Message>,
+    admitted: Arc<Semaphore>,
+    // Cancelled when the connection ends, taking every request token with it
+    closed: CancellationToken,
+    requests: Arc<Mutex<HashMap<u64, CancellationToken>>>,
+    // Where the later chunks of each stream the client is sending go, until its StreamEnd
+    inbound: Mutex<HashMap<u64, mpsc::Sender<Bytes>>>,
+    // Requests and streams whose final reply has not been written yet
+    unanswered: Mutex<HashSet<u64>>,
+    // The token the client presented, checked at its first message flagged REQUIRES_AUTH
+    credentials: Mutex<Option<String>>,
+}
+
+impl Session {
+    fn cancel(&self, request_id: u64) {
+        self.unanswered.lock().unwrap().remove(&request_id);
+        self.inbound.lock().unwrap().remove(&request_id);
+        if let Some(token) = self.requests.lock().unwrap().remove(&request_id) {
+            token.cancel();
+        }
+    }
+
+    // Whether `message` carries on a stream whose handler is already running
+    fn continues_stream(&self, message: &Message) -> bool {
+        streams_in(message) && self.inbound.lock().unwrap().contains_key(&message.request_id)
+    }
+
+    // Passes a chunk to the handler of its stream, returning it with the
+    // handler's sender when the handler has no room for it yet
+    fn forward(&self, message: Message) -> Option<Blocked> {
+        let id = message.request_id;
+        let chunks = match message.msg_type {
+            MessageType::StreamEnd => self.inbound.lock().unwrap().remove(&id)?,
+            _ => self.inbound.lock().unwrap().get(&id)?.clone(),
+        };
+        if message.msg_type == MessageType::StreamEnd && message.payload.is_empty() {
+            return None;
+        }
+        match chunks.try_send(message.payload) {
+            Err(TrySendError::Full(chunk)) => Some((chunks, chunk)),
+            // A handler that finished early dropped its stream along with the chunks still coming
+            _ => None,
+        }
+    }
+
+    // Drops the later chunks of a stream whose first frame was refused
+    fn refuse_stream(&self, message: &Message) {
+        if message.msg_type == MessageType::Stream && streams_in(message) {
+            let (discarded, _) = mpsc::channel(1);
+            self.inbound.lock().unwrap().insert(message.request_id, discarded);
+        }
+    }
+
+    // Stream chunks leave their request unanswered until its end
+    fn answered(&self, reply: &Message) {
+        if reply.msg_type != MessageType::Stream || reply.flags.contains(MessageFlags::STREAM_END) {
+            self.unanswered.lock().unwrap().remove(&reply.request_id);
+        }
+    }
+}
+
+// A session with everything that must outlive one transport for it to be resumed on another
+struct LiveSession {
+    // Cancels every handler when the session is dropped
+    _closed: DropGuard,
+    // Cancelled when a resumed connection takes the session over
+    detached: CancellationToken,
+    session: Session,
+    outbound: mpsc::Receiver<Message>,
+    subscriptions: Attachment,
+    // Replies a dropped transport failed to write, sent first on the next
+    undelivered: Vec<Message>,
+    // A chunk waiting for its handler to make room, which holds back reading
+    blocked: Option<Blocked>,
+}
+
+type Blocked = (mpsc::Sender<Bytes>, Bytes);
+
+type ConnectHook = Arc<dyn Fn(Arc<ConnectionContext>) -> BoxFuture<'static, Result<(), ProtocolError>> + Send + Sync>;
+type ShutdownHook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+/// Serves a [`Router`] over accepted connections.
+///
+/// Requests are answered with a Response carrying the handler's output, or an
+/// Error carrying an [`ErrorPayload`]. Streaming requests get the chunks the
+/// handler sent through its [`ResponseSink`] followed by a StreamEnd carrying
+/// its output, or an Error flagged STREAM_END. Events run their handler and get
+/// no reply. Handlers for one connection run concurrently.
+///
+/// A stream the client sends, as frames not flagged STREAM_END and then a
+/// StreamEnd, runs one handler from its first frame, which reads the rest
+/// through its [`RequestStream`] and is answered like a streaming request.
+///
+/// A panicking handler is answered with a `ProtocolError::HandlerPanicked`
+/// Error, counted in the telemetry, and does not affect the connection.
+///
+/// Subscribe and Unsubscribe frames register the connection with the server's
+/// [`Broker`], and Events published there are pushed to it.
+///
+/// Work beyond the configured limits is shed rather than queued: a request is
+/// answered with a `ProtocolError::Overloaded` Error carrying a retry-after
+/// hint, an Event is dropped, and a connection is sent the same Error and closed.
+#[derive(Clone)]
+pub struct Server {
+    reloadable: Arc<Reloadable>,
+    max_requests_per_connection: usize,
+    in_flight: Option<Arc<Semaphore>>,
+    connections: Option<Arc<Semaphore>>,
+    retry_after: Duration,
+    broker: Arc<Broker>,
+    max_frame_length: Option<usize>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    backlog: u32,
+    telemetry: Option<Arc<Telemetry>>,
+    noise: Option<Arc<NoiseConfig>>,
+    // Algorithms accepted, most preferred first, and the settings to apply
+    compression: Option<(Vec<Compression>, CompressionConfig)>,
+    client_verifier: Option<Arc<dyn CertificateVerifier>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    audit: Option<AuditLog>,
+    sessions: Option<Arc<SessionStore<LiveSession>>>,
+}
+
+impl Server {
+    pub fn new(router: Router) -> Self {
+        Self {
+            reloadable: Arc::new(Reloadable {
+                router: RwLock::new(Arc::new(router)),
+                acceptor: RwLock::new(None),
+                on_connect: RwLock::new(None),
+                shutdown: CancellationToken::new(),
+                on_shutdown: Mutex::new(Vec::new()),
+            }),
+            max_requests_per_connection: Semaphore::MAX_PERMITS,
+            in_flight: None,
+            connections: None,
+            retry_after: Duration::from_secs(1),
+            broker: Arc::new(Broker::new()),
+            max_frame_length: None,
+            read_timeout: None,
+            write_timeout: None,
+            backlog: 1024,
+            telemetry: None,
+            noise: None,
+            compression: None,
+            client_verifier: None,
+            authenticator: None,
+            audit: None,
+            sessions: None,
+        }
+    }
+
+    /// Closes connections that send or would be sent a frame longer than `max` bytes, by default
+    /// [`DEFAULT_MAX_FRAME_LENGTH`](crate::codec::DEFAULT_MAX_FRAME_LENGTH)
+    pub fn with_max_frame_length(mut self, max: usize) -> Self {
+        self.max_frame_length = Some(max);
+        self
+    }
+
+    /// Closes connections that send nothing for `timeout`
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections whose writes stall for longer than `timeout`
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Runs `acceptor` on every connection [`Server::serve`] accepts, e.g. a TLS handshake
+    pub fn with_acceptor(self, acceptor: impl Acceptor + 'static) -> Self {
+        self.handle().set_acceptor(acceptor);
+        self
+    }
+
+    /// Requires a client certificate on every connection [`Server::serve`] accepts.
+    ///
+    /// The chain the acceptor reports is checked by `verifier`, and the
+    /// identity it returns becomes the connection's identity. Connections
+    /// without a certificate or with a rejected one are closed before any
+    /// request is served.
+    pub fn with_client_verifier(mut self, verifier: impl CertificateVerifier + 'static) -> Self {
+        self.client_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Authenticates connections by the credential token their first message
+    /// flagged REQUIRES_AUTH carries; see [`crate::acl`].
+    ///
+    /// The identity `authenticator` returns becomes the connection's identity.
+    /// Tokens are stripped before the message reaches middleware and handlers.
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Records failed handshakes, decrypts and signature checks, replays and
+    /// rejected authentication or access in `log`, with the peer's identity and address
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Runs the responder side of a Noise handshake on every connection
+    /// [`Server::serve`] accepts, after the acceptor, and encrypts the
+    /// connection with the session keys.
+    ///
+    /// The peer's static key, base64-encoded, becomes the connection's
+    /// identity, so the connect hook can decide whether to admit it.
+    pub fn with_noise(mut self, config: NoiseConfig) -> Self {
+        self.noise = Some(Arc::new(config));
+        self
+    }
+
+    /// Negotiates compression on every connection [`Server::serve`] accepts,
+    /// after any Noise handshake, choosing the client's most preferred of
+    /// `supported` at no more than the level of `config`, whose other
+    /// settings then apply as they are.
+    ///
+    /// The chosen algorithm's capability string, e.g. `compression/lz4`, is
+    /// added to the connection's capabilities.
+    pub fn with_compression_negotiation(mut self, supported: &[Compression], config: CompressionConfig) -> Self {
+        self.compression = Some((supported.to_vec(), config));
+        self
+    }
+
+    /// Sets the listen backlog used by [`Server::bind`]
+    pub fn with_backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Records per-route request latency, errors by variant and bytes in/out into `telemetry`
+    pub fn with_telemetry(mut self, telemetry: Arc<Telemetry>) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    /// Serves client subscriptions from `broker`, replacing the server's own
+    pub fn with_broker(mut self, broker: Arc<Broker>) -> Self {
+        self.broker = broker;
+        self
+    }
+
+    /// Returns the broker that fans published Events out to this server's subscribers
+    pub fn broker(&self) -> &Arc<Broker> {
+        &self.broker
+    }
+
+    /// Caps how many requests one connection may have in flight at once
+    pub fn with_max_requests_per_connection(mut self, max: usize) -> Self {
+        self.max_requests_per_connection = max;
+        self
+    }
+
+    /// Caps how many requests may be in flight across all connections at once
+    pub fn with_max_in_flight(mut self, max: usize) -> Self {
+        self.in_flight = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Caps how many connections [`Server::serve`] keeps open at once
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.connections = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Keeps each connection's session for `grace` after its transport drops,
+    /// unless it was closed with a GoAway, so that a client with
+    /// [`RemusClient::with_session_resumption`](crate::RemusClient::with_session_resumption)
+    /// can resume it on a new connection from any address.
+    ///
+    /// A resumed session keeps the context it was opened with. Handlers carry on
+    /// while it is parked, and their replies wait for the client to come back.
+    /// When the new connection has a peer identity, say from a Noise handshake,
+    /// only a session opened with the same identity is resumed.
+    pub fn with_session_resumption(mut self, grace: Duration) -> Self {
+        self.sessions = Some(Arc::new(SessionStore::new(grace)));
+        self
+    }
+
+    /// Sets the retry-after hint sent with overload errors
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    fn overloaded(&self, request_id: u64) -> Message {
+        let error = ProtocolError::Overloaded {
+            retry_after: self.retry_after,
+        };
+        ErrorPayload::from_error(&error).to_message(request_id)
+    }
+
+    /// Runs `hook` on each new connection's context before serving it.
+    ///
+    /// Use it to authenticate the peer or record negotiated capabilities; an
+    /// error rejects the connection with a GoAway carrying the error.
+    pub fn with_on_connect<F, Fut>(self, hook: F) -> Self
+    where
+        F: Fn(Arc<ConnectionContext>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ProtocolError>> + Send + 'static,
+    {
+        self.handle().set_on_connect(hook);
+        self
+    }
+
+    /// Returns a handle for replacing the routes, acceptor or connect hook while the server runs, or shutting it down
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            reloadable: self.reloadable.clone(),
+        }
+    }
+
+    /// Binds a listener on the first address `addr` resolves to, with the configured backlog
+    pub async fn bind(&self, addr: impl ToSocketAddrs) -> Result<TcpListener, ProtocolError> {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| ProtocolError::InvalidFormat("Listen address resolved to nothing".into()))?;
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        Ok(socket.listen(self.backlog)?)
+    }
+
+    /// Accepts connections until `listener` fails or the server is shut down, serving each on its own task
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), ProtocolError> {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.reloadable.shutdown.cancelled() => {
+                    let hooks = std::mem::take(&mut *self.reloadable.on_shutdown.lock().unwrap());
+                    for hook in hooks {
+                        hook().await;
+                    }
+                    return Ok(());
+                }
+            };
+            let permit = match &self.connections {
+                Some(connections) => match connections.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        let rejection = self.overloaded(0);
+                        tokio::spawn(async move { Transport::new(stream).send(rejection).await });
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let context = ConnectionContext::new(Some(remote_addr));
+                let acceptor = server.reloadable.acceptor.read().unwrap().clone();
+                let served = match acceptor {
+                    Some(acceptor) => match server.handshake(acceptor.accept_with_peer(stream)).await {
+                        Ok(accepted) => {
+                            let context = context.with_peer_certificates(accepted.peer_certificates);
+                            server.serve_accepted(Transport::new(accepted.stream), context).await
+                        }
+                        Err(e) => Err(e),
+                    },
+                    None => server.serve_accepted(Transport::new(stream), context).await,
+                };
+                if let Err(e) = served {
+                    tracing::debug!("Connection from {} ended with error: {}", remote_addr, e);
+                }
+            });
+        }
+    }
+
+    // The read timeout bounds the handshake too, so a silent peer cannot hold a connection slot
+    async fn handshake<F, T>(&self, accept: F) -> Result<T, ProtocolError>
+    where
+        F: Future<Output = Result<T, ProtocolError>>,
+    {
+        match self.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, accept)
+                .await
+                .map_err(|_| ProtocolError::Timeout("Handshake timeout".into()))?,
+            None => accept.await,
+        }
+    }
+
+    async fn serve_accepted<T>(&self, mut transport: Transport<T>, context: ConnectionContext) -> Result<(), ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Some(verifier) = &self.client_verifier {
+            let identity = match context.peer_certificates() {
+                [] => Err(ProtocolError::AuthenticationRequired),
+                certificates => verifier.verify(certificates),
+            };
+            context.set_identity(identity.inspect_err(|e| self.audit(&context, e))?);
+        }
+        if let Some(config) = &self.noise {
+            let session = self
+                .handshake(noise::respond(&mut transport, config))
+                .await
+                .inspect_err(|e| self.audit(&context, e))?;
+            context.set_identity(base64::engine::general_purpose::STANDARD.encode(session.remote_static()));
+            transport = transport.with_encryptor(Arc::new(session.into_encryptor()));
+        }
+        if let Some((supported, config)) = &self.compression {
+            let negotiated = self
+                .handshake(compression::respond(&mut transport, supported, config))
+                .await
+                .inspect_err(|e| self.audit(&context, e))?;
+            if let Some(algorithm) = negotiated.algorithm() {
+                let mut capabilities = context.capabilities();
+                capabilities.push(algorithm.capability().to_string());
+                context.set_capabilities(capabilities);
+            }
+            transport.set_compression(negotiated);
+        }
+        match &self.sessions {
+            Some(sessions) => self.serve_resumable(transport, context, sessions).await,
+            None => self.serve_connection_with_context(transport, context).await,
+        }
+    }
+
+    // Serves a connection whose session outlives it. The client asks for a parked
+    // session back, or for a new one, and the session is parked again when the
+    // connection drops.
+    async fn serve_resumable<T>(&self, transport: Transport<T>, context: ConnectionContext, sessions: &Arc<SessionStore<LiveSession>>) -> Result<(), ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut transport = self.configure(transport);
+        let asked = self
+            .handshake(session::receive_token(&mut transport))
+            .await
+            .inspect_err(|e| self.audit(&context, e))?;
+        let identity = context.identity();
+        let same_peer = |live: &LiveSession| identity.is_none() || live.session.context.identity() == identity;
+        let resumed = match asked {
+            Some(token) => sessions.resume(&token, same_peer).await.map(|resumed| (token, resumed)),
+            None => None,
+        };
+        let (token, mut live, in_progress) = match resumed {
+            Some((token, (mut live, detached))) => {
+                live.detached = detached;
+                let in_progress: Vec<u64> = live.session.unanswered.lock().unwrap().iter().copied().collect();
+                (token, live, Some(in_progress))
+            }
+            None => {
+                let mut live = self.open_session(&mut transport, context).await?;
+                let (token, detached) = sessions.open();
+                live.detached = detached;
+                (token, live, None)
+            }
+        };
+        let result = match session::answer(&mut transport, &token, in_progress.as_deref()).await {
+            Ok(()) => self.run_session(&mut transport, &mut live).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Err(ProtocolError::GoAway(_)) => sessions.release(&token),
+            _ => sessions.park(token, live),
+        }
+        ended(result)
+    }
+
+    // Applies the server's transport options, overriding the transport's own
+    fn configure<T: AsyncRead + AsyncWrite + Unpin>(&self, mut transport: Transport<T>) -> Transport<T> {
+        if let Some(max) = self.max_frame_length {
+            transport = transport.with_max_frame_length(max);
+        }
+        if let Some(timeout) = self.read_timeout {
+            transport = transport.with_read_timeout(timeout);
+        }
+        if let Some(telemetry) = &self.telemetry {
+            transport = transport.with_stats(telemetry.transport_stats().clone());
+        }
+        transport
+    }
+
+    async fn write<T>(&self, transport: &mut Transport<T>, messages: Vec<Message>) -> Result<(), ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.write_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, transport.send_all(messages))
+                .await
+                .map_err(|_| ProtocolError::Timeout("Write timeout".into()))?,
+            None => transport.send_all(messages).await,
+        }
+    }
+
+    /// Serves one connection until the peer closes it
+    pub async fn serve_connection<T>(&self, transport: Transport<T>) -> Result<(), ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.serve_connection_with_context(transport, ConnectionContext::default()).await
+    }
+
+    /// Serves one connection whose handlers share `context`.
+    ///
+    /// Transport options set on the server override those of `transport`.
+    pub async fn serve_connection_with_context<T>(
+        &self,
+        transport: Transport<T>,
+        context: ConnectionContext,
+    ) -> Result<(), ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut transport = self.configure(transport);
+        let mut live = self.open_session(&mut transport, context).await?;
+        ended(self.run_session(&mut transport, &mut live).await)
+    }
+
+    // Runs the connect hook and starts the session of a new connection
+    async fn open_session<T>(&self, transport: &mut Transport<T>, context: ConnectionContext) -> Result<LiveSession, ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let context = Arc::new(context);
+        let on_connect = self.reloadable.on_connect.read().unwrap().clone();
+        if let Some(hook) = on_connect {
+            if let Err(e) = hook(context.clone()).await {
+                let _ = transport.close(&e.to_string()).await;
+                return Err(e);
+            }
+        }
+
+        let (replies, outbound) = mpsc::channel::<Message>(64);
+        let session = Session {
+            context,
+            replies,
+            admitted: Arc::new(Semaphore::new(self.max_requests_per_connection)),
+            closed: CancellationToken::new(),
+            requests: Arc::default(),
+            inbound: Mutex::default(),
+            unanswered: Mutex::default(),
+            credentials: Mutex::default(),
+        };
+        Ok(LiveSession {
+            _closed: session.closed.clone().drop_guard(),
+            detached: CancellationToken::new(),
+            session,
+            outbound,
+            subscriptions: self.broker.attach(),
+            undelivered: Vec::new(),
+            blocked: None,
+        })
+    }
+
+    // Serves `live` over `transport` until the connection ends, returning the error that ended it
+    async fn run_session<T>(&self, transport: &mut Transport<T>, live: &mut LiveSession) -> Result<(), ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let LiveSession { session, outbound, subscriptions, undelivered, detached, blocked, .. } = live;
+        if !undelivered.is_empty() {
+            self.write(transport, undelivered.clone()).await?;
+            for reply in undelivered.drain(..) {
+                session.answered(&reply);
+            }
+        }
+        loop {
+            tokio::select! {
+                received = transport.receive(), if blocked.is_none() => match received {
+                    Ok(message) if message.msg_type == MessageType::Cancel => session.cancel(message.request_id),
+                    Ok(message) if session.continues_stream(&message) => *blocked = session.forward(message),
+                    Ok(message) if message.msg_type == MessageType::Subscribe => {
+                        let reply = match message.routing_info {
+                            Some(pattern) => {
+                                subscriptions.subscribe(message.request_id, pattern);
+                                Message::new(MessageType::Response, MessageFlags::NONE, message.request_id, Bytes::new())
+                            }
+                            None => {
+                                let error = ProtocolError::InvalidFormat("Subscribe without a topic pattern".into());
+                                ErrorPayload::from_error(&error).to_message(message.request_id)
+                            }
+                        };
+                        self.write(transport, vec![reply]).await?
+                    }
+                    Ok(message) if message.msg_type == MessageType::Unsubscribe => subscriptions.unsubscribe(message.request_id),
+                    Ok(message) if message.msg_type == MessageType::Handshake && message.routing_info.as_deref() == Some(CREDENTIALS_ROUTE) => {
+                        match String::from_utf8(message.payload.to_vec()) {
+                            Ok(token) => *session.credentials.lock().unwrap() = Some(token),
+                            Err(_) => tracing::debug!("Ignoring credentials that are not UTF-8"),
+                        }
+                    }
+                    Ok(message) if !matches!(message.msg_type, MessageType::Request | MessageType::Stream | MessageType::StreamEnd | MessageType::Event) => {}
+                    Ok(message) => match self.authenticate(&message, session) {
+                        Err(e) if message.msg_type == MessageType::Event => {
+                            self.audit(&session.context, &e);
+                            tracing::debug!("Dropping event from an unauthenticated connection: {}", e)
+                        }
+                        Err(e) => {
+                            self.audit(&session.context, &e);
+                            session.refuse_stream(&message);
+                            let mut rejection = ErrorPayload::from_error(&e).to_message(message.request_id);
+                            if matches!(message.msg_type, MessageType::Stream | MessageType::StreamEnd) {
+                                rejection.flags |= MessageFlags::STREAM_END;
+                            }
+                            self.write(transport, vec![rejection]).await?
+                        }
+                        Ok(()) => match self.admit(&session.admitted) {
+                            Some(permits) => self.spawn_handler(message, permits, session),
+                            None if message.msg_type != MessageType::Event => {
+                                session.refuse_stream(&message);
+                                self.write(transport, vec![self.overloaded(message.request_id)]).await?
+                            }
+                            None => tracing::debug!("Dropping event over the concurrency limit"),
+                        },
+                    },
+                    Err(e @ (ProtocolError::ConnectionClosed | ProtocolError::GoAway(_))) => return Err(e),
+                    Err(e) => {
+                        self.audit(&session.context, &e);
+                        return Err(e);
+                    }
+                },
+                // The handler made room for the chunk reading was held back for
+                permit = async { blocked.as_ref().unwrap().0.clone().reserve_owned().await }, if blocked.is_some() => {
+                    let (_, chunk) = blocked.take().unwrap();
+                    if let Ok(permit) = permit {
+                        permit.send(chunk);
+                    }
+                }
+                Some(reply) = outbound.recv() => {
+                    if let Err(e) = self.write(transport, vec![reply.clone()]).await {
+                        undelivered.push(reply);
+                        return Err(e);
+                    }
+                    session.answered(&reply);
+                }
+                events = subscriptions.next_batch() => self.write(transport, events).await?,
+                // The client resumed the session on another connection
+                _ = detached.cancelled() => return Err(ProtocolError::ConnectionClosed),
+                _ = subscriptions.overflowed() => {
+                    tracing::debug!("Closing connection that fell behind on published events");
+                    return Err(ProtocolError::GoAway("Fell behind on published events".into()));
+                }
+            }
+        }
+    }
+
+    // Lets a message flagged REQUIRES_AUTH through only once the connection has an
+    // identity, authenticating it with the client's credentials if it has none yet
+    fn authenticate(&self, message: &Message, session: &Session) -> Result<(), ProtocolError> {
+        let context = &session.context;
+        if !message.flags.contains(MessageFlags::REQUIRES_AUTH) || context.identity().is_some() {
+            return Ok(());
+        }
+        let Some(authenticator) = &self.authenticator else {
+            return Err(ProtocolError::AuthenticationRequired);
+        };
+        let token = session.credentials.lock().unwrap().clone().ok_or(ProtocolError::AuthenticationRequired)?;
+        context.set_identity(authenticator.authenticate(&token)?);
+        Ok(())
+    }
+
+    fn audit(&self, context: &ConnectionContext, error: &ProtocolError) {
+        if let Some(log) = &self.audit {
+            log.record_error(context, error);
+        }
+    }
+
+    // Takes a slot in the connection's and the server's in-flight limits, or none if either is full
+    fn admit(&self, admitted: &Arc<Semaphore>) -> Option<Permits> {
+        let connection = admitted.clone().try_acquire_owned().ok()?;
+        let server = match &self.in_flight {
+            Some(in_flight) => Some(in_flight.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some((connection, server))
+    }
+
+    fn spawn_handler(&self, message: Message, permits: Permits, session: &Session) {
+        let msg_type = message.msg_type;
+        let request_id = message.request_id;
+        let cancellation = session.closed.child_token();
+        session.requests.lock().unwrap().insert(request_id, cancellation.clone());
+        if msg_type != MessageType::Event {
+            session.unanswered.lock().unwrap().insert(request_id);
+        }
+
+        let router = self.reloadable.router.read().unwrap().clone();
+        let telemetry = self.telemetry.clone();
+        let audit = self.audit.clone();
+        let route = message.routing_info.clone().unwrap_or_default();
+        let inbound = streams_in(&message);
+        let first_chunk = (msg_type == MessageType::Stream || !message.payload.is_empty()).then(|| message.payload.clone());
+        let mut request = Request::new(message, session.context.clone()).with_cancellation(cancellation.clone());
+        if matches!(msg_type, MessageType::Stream | MessageType::StreamEnd) {
+            request = request.with_response_sink(ResponseSink::new(request_id, session.replies.clone()));
+        }
+        if inbound {
+            let (chunks, receiver) = mpsc::channel(REQUEST_STREAM_CAPACITY);
+            if let Some(chunk) = first_chunk {
+                let _ = chunks.try_send(chunk);
+            }
+            // A stream that ends with its first frame has no more chunks to come
+            if msg_type == MessageType::Stream {
+                session.inbound.lock().unwrap().insert(request_id, chunks);
+            }
+            request = request.with_request_stream(RequestStream { chunks: receiver });
+        }
+        let requests = session.requests.clone();
+        let replies = session.replies.clone();
+        let context = session.context.clone();
+        tokio::spawn(async move {
+            let _permits = permits;
+            let started = SystemTime::now();
+            let start = Instant::now();
+            let result = tokio::select! {
+                result = AssertUnwindSafe(router.dispatch(request)).catch_unwind() => {
+                    result.unwrap_or_else(|panic| Err(panicked(&route, panic)))
+                }
+                // Nobody is waiting for the reply any more
+                _ = cancellation.cancelled() => return,
+            };
+            requests.lock().unwrap().remove(&request_id);
+            if let Some(telemetry) = telemetry {
+                telemetry.record_request(&route, started, start.elapsed(), result.as_ref().map(|_| ()));
+            }
+            // Handlers and middleware such as an AccessPolicy reject access through their result
+            if let (Some(log), Err(e @ (ProtocolError::AuthenticationRequired | ProtocolError::PermissionDenied(_)))) = (&audit, &result) {
+                log.record_error(&context, e);
+            }
+            let response = match (msg_type, result) {
+                (MessageType::Event, _) => return,
+                (MessageType::Stream | MessageType::StreamEnd, Ok(payload)) => Message::new(MessageType::StreamEnd, MessageFlags::NONE, request_id, payload),
+                (MessageType::Stream | MessageType::StreamEnd, Err(e)) => {
+                    let mut error = ErrorPayload::from_error(&e).to_message(request_id);
+                    error.flags |= MessageFlags::STREAM_END;
+                    error
+                }
+                (_, Ok(payload)) => Message::new(MessageType::Response, MessageFlags::NONE, request_id, payload),
+                (_, Err(e)) => ErrorPayload::from_error(&e).to_message(request_id),
+            };
+            let _ = replies.send(response).await;
+        });
+    }
+}
+
+// A connection the peer closed, or that was closed with a GoAway, ended cleanly
+fn ended(result: Result<(), ProtocolError>) -> Result<(), ProtocolError> {
+    match result {
+        Err(ProtocolError::ConnectionClosed | ProtocolError::GoAway(_)) => Ok(()),
+        result => result,
+    }
+}
+
+// Turns a caught handler panic into the error answered to the client
+fn panicked(route: &str, panic: Box<dyn Any + Send>) -> ProtocolError {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "non-string panic payload".to_string());
+    tracing::error!("Handler for route {:?} panicked: {}", route, message);
+    ProtocolError::HandlerPanicked(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::io::duplex;
+
+    fn named(name: &'static str) -> impl Handler {
+        move |_request: Request| async move { Ok(Bytes::from(name)) }
+    }
+
+    fn detached() -> Arc<ConnectionContext> {
+        Arc::new(ConnectionContext::default())
+    }
+
+    fn routed(route: &str) -> Message {
+        let mut message = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::new());
+        message.routing_info = Some(route.to_string());
+        message
+    }
+
+    #[tokio::test]
+    async fn test_most_specific_route_wins() {
+        let router = Router::new()
+            .with_route("devices/*", named("prefix"))
+            .with_route("devices/{id}/telemetry", named("param"))
+            .with_route("devices/7/telemetry", named("exact"));
+
+        let dispatch = |route: &'static str| router.dispatch(Request::new(routed(route), detached()));
+        assert_eq!(dispatch("devices/7/telemetry").await.unwrap(), "exact");
+        assert_eq!(dispatch("devices/8/telemetry").await.unwrap(), "param");
+        assert_eq!(dispatch("devices/8/logs").await.unwrap(), "prefix");
+        assert!(matches!(dispatch("devices").await, Err(ProtocolError::NotFound(route)) if route == "devices"));
+        assert!(matches!(dispatch("other/7/telemetry").await, Err(ProtocolError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_captured_params() {
+        let router = Router::new()
+            .with_route("devices/{id}/telemetry", named("param"))
+            .with_route("files/*", named("prefix"));
+
+        let (_, params) = router.resolve("devices/42/telemetry").unwrap();
+        assert_eq!(params["id"], "42");
+        let (_, params) = router.resolve("files/a/b.txt").unwrap();
+        assert_eq!(params["*"], "a/b.txt");
+        assert!(router.resolve("devices//telemetry").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_middleware_wraps_globally_then_per_route() {
+        fn tag(label: &'static str) -> impl Middleware {
+            move |request: Request, next: Next| async move {
+                let inner = next.run(request).await?;
+                Ok(Bytes::from(format!("{label}({})", String::from_utf8_lossy(&inner))))
+            }
+        }
+
+        let router = Router::new()
+            .with_middleware(tag("outer"))
+            .with_middleware(tag("inner"))
+            .with_route("plain", named("plain"))
+            .with_route("layered", Layered::new(named("layered")).with_middleware(tag("route")));
+
+        assert_eq!(router.dispatch(Request::new(routed("plain"), detached())).await.unwrap(), "outer(inner(plain))");
+        assert_eq!(router.dispatch(Request::new(routed("layered"), detached())).await.unwrap(), "outer(inner(route(layered)))");
+    }
+
+    #[tokio::test]
+    async fn test_middleware_short_circuits_before_routing() {
+        let router = Router::new()
+            .with_middleware(|request: Request, next: Next| async move {
+                if request.message().context.as_deref() != Some("token") {
+                    return Err(ProtocolError::AuthenticationRequired);
+                }
+                next.run(request).await
+            })
+            .with_route("secret", named("secret"));
+
+        assert!(matches!(router.dispatch(Request::new(routed("secret"), detached())).await, Err(ProtocolError::AuthenticationRequired)));
+        assert!(matches!(router.dispatch(Request::new(routed("missing"), detached())).await, Err(ProtocolError::AuthenticationRequired)));
+
+        let mut authorized = routed("secret");
+        authorized.context = Some("token".into());
+        assert_eq!(router.dispatch(Request::new(authorized, detached())).await.unwrap(), "secret");
+    }
+
+    #[tokio::test]
+    async fn test_connection_context_shared_across_requests() {
+        let (client, peer) = duplex(4096);
+        let router = Router::new().with_route("visit", |request: Request| async move {
+            let connection = request.connection();
+            let visits = connection.get::<u32>("visits").map_or(1, |visits| *visits + 1);
+            connection.insert("visits", visits);
+            let identity = connection.identity().unwrap_or_default();
+            Ok(Bytes::from(format!("{identity}:{visits}")))
+        });
+        let server = Server::new(router).with_on_connect(|context: Arc<ConnectionContext>| async move {
+            context.set_identity("alice");
+            context.set_capabilities(vec!["compression".into()]);
+            Ok(())
+        });
+        let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        tokio::spawn(async move {
+            server.serve_connection_with_context(Transport::new(peer), ConnectionContext::new(Some(addr))).await
+        });
+
+        let mut transport = Transport::new(client);
+        for expected in ["alice:1", "alice:2"] {
+            transport.send(routed("visit")).await.unwrap();
+            assert_eq!(transport.receive().await.unwrap().payload, Bytes::from(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_noise_handshake_identifies_peer() {
+        let client_keys = crate::NoiseKeypair::generate();
+        let expected = base64::engine::general_purpose::STANDARD.encode(client_keys.public_key());
+        let server_config = NoiseConfig::new(crate::NoiseKeypair::generate());
+        let server_key = server_config.public_key();
+        let router = Router::new().with_route("whoami", |request: Request| async move {
+            let identity = request.connection().identity().unwrap_or_default();
+            Ok(Bytes::from(serde_json::to_vec(&identity).unwrap()))
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router).with_noise(server_config);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let client = crate::RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_noise(NoiseConfig::new(client_keys).with_remote_static(server_key));
+        let identity: String = client.call("whoami", &()).await.unwrap();
+        assert_eq!(identity, expected);
+
+        // A client without Noise is not served
+        let plain = crate::RemusClient::connect(&address).await.unwrap().with_timeout(Duration::from_millis(500));
+        assert!(plain.call::<_, String>("whoami", &()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_noise_sessions_encrypt_both_directions_on_the_wire() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server_config = NoiseConfig::new(crate::NoiseKeypair::generate());
+        let server_key = server_config.public_key();
+        let router = Router::new().with_route("greet", |request: Request| async move {
+            assert_eq!(&request.payload()[..], br#""plaintext-request""#);
+            Ok(Bytes::from(r#""plaintext-reply""#))
+        });
+        // Relays each connection, recording every byte crossing it
+        let sniffed = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router).with_noise(server_config).with_acceptor({
+            let sniffed = sniffed.clone();
+            move |stream: TcpStream| {
+                let (served, relayed) = duplex(64 * 1024);
+                let (socket_read, socket_write) = tokio::io::split(stream);
+                let (relay_read, relay_write) = tokio::io::split(relayed);
+                let relay = |sniffed: Arc<Mutex<Vec<u8>>>, mut from: Box<dyn AsyncRead + Unpin + Send>, mut to: Box<dyn AsyncWrite + Unpin + Send>| async move {
+                    let mut chunk = [0u8; 4096];
+                    while let Ok(read @ 1..) = from.read(&mut chunk).await {
+                        sniffed.lock().unwrap().extend_from_slice(&chunk[..read]);
+                        if to.write_all(&chunk[..read]).await.is_err() {
+                            break;
+                        }
+                    }
+                };
+                tokio::spawn(relay(sniffed.clone(), Box::new(socket_read), Box::new(relay_write)));
+                tokio::spawn(relay(sniffed.clone(), Box::new(relay_read), Box::new(socket_write)));
+                async move { Ok(Box::new(served) as Box<dyn Io>) }
+            }
+        });
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let client = crate::RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_noise(NoiseConfig::new(crate::NoiseKeypair::generate()).with_remote_static(server_key));
+        let reply: String = client.call("greet", &"plaintext-request").await.unwrap();
+        assert_eq!(reply, "plaintext-reply");
+
+        let sniffed = sniffed.lock().unwrap();
+        assert!(!sniffed.windows(9).any(|window| window == b"plaintext"));
+    }
+
+    #[tokio::test]
+    async fn test_resumed_session_answers_requests_sent_before_the_drop() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let release = Arc::new(tokio::sync::Notify::new());
+        let (started, mut running) = mpsc::channel(1);
+        let router = Router::new().with_route("slow", {
+            let (calls, release) = (calls.clone(), release.clone());
+            move |_request: Request| {
+                let (calls, release, started) = (calls.clone(), release.clone(), started.clone());
+                async move {
+                    calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    let _ = started.send(()).await;
+                    release.notified().await;
+                    Ok(Bytes::from(serde_json::to_vec("done").unwrap()))
+                }
+            }
+        });
+        // Every connection runs through a relay the test can cut, as when a client changes networks
+        let relays = Arc::new(Mutex::new(Vec::<tokio::task::JoinHandle<()>>::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router).with_session_resumption(Duration::from_secs(10)).with_acceptor({
+            let relays = relays.clone();
+            move |mut stream: TcpStream| {
+                let (served, mut relayed) = duplex(64 * 1024);
+                relays.lock().unwrap().push(tokio::spawn(async move {
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut relayed).await;
+                }));
+                async move { Ok(Box::new(served) as Box<dyn Io>) }
+            }
+        });
+        let broker = server.broker().clone();
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let client = Arc::new(
+            crate::RemusClient::connect(&address)
+                .await
+                .unwrap()
+                .with_reconnect(crate::RetryPolicy::new(5))
+                .with_session_resumption(),
+        );
+        let mut alerts = client.subscribe("alerts/*").await.unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call::<_, String>("slow", &()).await }
+        });
+        running.recv().await.unwrap();
+        for relay in relays.lock().unwrap().drain(..) {
+            relay.abort();
+        }
+        release.notify_one();
+
+        assert_eq!(call.await.unwrap().unwrap(), "done");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        broker.publish("alerts/door", "open");
+        assert_eq!(alerts.next().await.unwrap().unwrap().payload, Bytes::from("open"));
+    }
+
+    #[tokio::test]
+    async fn test_compression_is_negotiated_per_connection() {
+        let router = Router::new().with_route("codec", |request: Request| async move {
+            Ok(Bytes::from(serde_json::to_vec(&request.connection().capabilities()).unwrap()))
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router).with_compression_negotiation(&[Compression::Zstd, Compression::Lz4], CompressionConfig::default());
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let client = crate::RemusClient::connect(&address)
+            .await
+            .unwrap()
+            .with_compression_negotiation(&[Compression::Lz4], CompressionConfig::default().with_level(1));
+        let capabilities: Vec<String> = client.call("codec", &()).await.unwrap();
+        assert_eq!(capabilities, ["compression/lz4"]);
+    }
+
+    #[tokio::test]
+    async fn test_requires_auth_needs_authenticated_connection() {
+        let router = Router::new().with_route("whoami", |request: Request| async move {
+            assert!(request.message().context.is_none());
+            let identity = request.connection().identity().unwrap_or_default();
+            Ok(Bytes::from(serde_json::to_vec(&identity).unwrap()))
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let audited = Arc::new(Mutex::new(Vec::new()));
+        let sink = audited.clone();
+        let server = Server::new(router)
+            .with_authenticator(|token: &str| match token {
+                "s3cret" => Ok("sensor-1".to_string()),
+                _ => Err(ProtocolError::AuthenticationRequired),
+            })
+            .with_audit_log(AuditLog::new().with_sink(move |event: &crate::AuditEvent| sink.lock().unwrap().push(event.clone())));
+        tokio::spawn(async move { server.serve(listener).await });
+        let options = crate::RequestOptions::new().with_flags(MessageFlags::REQUIRES_AUTH);
+
+        // Refused before sending without credentials, and by the server with wrong ones
+        let anonymous = crate::RemusClient::connect(&address).await.unwrap();
+        let refused = anonymous.call_with_options::<_, String>("whoami", &(), &options).await;
+        assert!(matches!(refused, Err(ProtocolError::AuthenticationRequired)));
+        let identity: String = anonymous.call("whoami", &()).await.unwrap();
+        assert_eq!(identity, "");
+        let impostor = crate::RemusClient::connect(&address).await.unwrap().with_credentials("guess");
+        let rejected = impostor.call_with_options::<_, String>("whoami", &(), &options).await;
+        assert!(matches!(rejected, Err(ProtocolError::AuthenticationRequired)));
+        let events = audited.lock().unwrap().clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, crate::AuditKind::AuthRejected);
+        assert!(events[0].identity.is_none() && events[0].remote_addr.is_some());
+
+        let client = crate::RemusClient::connect(&address).await.unwrap().with_credentials("s3cret");
+        let identity: String = client.call_with_options("whoami", &(), &options).await.unwrap();
+        assert_eq!(identity, "sensor-1");
+        // The connection stays authenticated for later messages
+        let identity: String = client.call("whoami", &()).await.unwrap();
+        assert_eq!(identity, "sensor-1");
+    }
+
+    #[tokio::test]
+    async fn test_on_connect_error_rejects_connection() {
+        let (client, peer) = duplex(4096);
+        let server = Server::new(Router::new().with_route("echo", named("echo")))
+            .with_on_connect(|_context: Arc<ConnectionContext>| async { Err(ProtocolError::AuthenticationRequired) });
+        let served = tokio::spawn(async move { server.serve_connection(Transport::new(peer)).await });
+
+        let mut transport = Transport::new(client);
+        assert!(matches!(transport.receive().await, Err(ProtocolError::GoAway(reason)) if reason == "Authentication required"));
+        drop(transport);
+        assert!(matches!(served.await.unwrap(), Err(ProtocolError::AuthenticationRequired)));
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_connection_limit_are_shed() {
+        let (client, peer) = duplex(4096);
+        let release = Arc::new(tokio::sync::Notify::new());
+        let router = Router::new().with_route("slow", {
+            let release = release.clone();
+            move |_request: Request| {
+                let release = release.clone();
+                async move {
+                    release.notified().await;
+                    Ok(Bytes::from("done"))
+                }
+            }
+        });
+        let server = Server::new(router)
+            .with_max_requests_per_connection(1)
+            .with_retry_after(Duration::from_millis(250));
+        tokio::spawn(async move { server.serve_connection(Transport::new(peer)).await });
+
+        let mut transport = Transport::new(client);
+        let mut first = routed("slow");
+        first.request_id = 1;
+        let mut second = routed("slow");
+        second.request_id = 2;
+        transport.send_all([first, second]).await.unwrap();
+
+        let shed = transport.receive().await.unwrap();
+        assert_eq!((shed.msg_type, shed.request_id), (MessageType::Error, 2));
+        let error = ErrorPayload::decode(&shed).unwrap();
+        assert_eq!(error.retry_after_ms, Some(250));
+        assert!(matches!(error.into_error(), ProtocolError::Overloaded { retry_after } if retry_after == Duration::from_millis(250)));
+
+        release.notify_one();
+        let done = transport.receive().await.unwrap();
+        assert_eq!((done.msg_type, done.request_id), (MessageType::Response, 1));
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_limit_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new(Router::new().with_route("echo", named("echo"))).with_max_connections(1);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut kept = Transport::new(tokio::net::TcpStream::connect(address).await.unwrap());
+        kept.send(routed("echo")).await.unwrap();
+        assert_eq!(kept.receive().await.unwrap().payload, Bytes::from("echo"));
+
+        let mut rejected = Transport::new(tokio::net::TcpStream::connect(address).await.unwrap());
+        let error = rejected.receive().await.unwrap();
+        assert_eq!(ErrorPayload::decode(&error).unwrap().code, "Overloaded");
+        assert!(matches!(rejected.receive().await, Err(ProtocolError::ConnectionClosed)));
+    }
+
+    // Handler that never finishes, reporting through `events` when it starts and when it is dropped
+    fn abandoned(events: mpsc::UnboundedSender<(&'static str, u64)>) -> impl Handler {
+        move |request: Request| {
+            let events = events.clone();
+            async move {
+                struct Report(mpsc::UnboundedSender<(&'static str, u64)>, u64);
+                impl Drop for Report {
+                    fn drop(&mut self) {
+                        let _ = self.0.send(("dropped", self.1));
+                    }
+                }
+                let id = request.message().request_id;
+                let _ = events.send(("started", id));
+                let _report = Report(events, id);
+                std::future::pending().await
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_frame_and_disconnect_drop_handlers() {
+        let (client, peer) = duplex(4096);
+        let (events, mut reports) = mpsc::unbounded_channel();
+        let server = Server::new(Router::new().with_route("wait", abandoned(events)));
+        tokio::spawn(async move { server.serve_connection(Transport::new(peer)).await });
+
+        let mut transport = Transport::new(client);
+        for request_id in [1, 2] {
+            let mut request = routed("wait");
+            request.request_id = request_id;
+            transport.send(request).await.unwrap();
+            assert_eq!(reports.recv().await, Some(("started", request_id)));
+        }
+
+        transport.send(Message::new(MessageType::Cancel, MessageFlags::NONE, 1, Bytes::new())).await.unwrap();
+        assert_eq!(reports.recv().await, Some(("dropped", 1)));
+
+        drop(transport);
+        assert_eq!(reports.recv().await, Some(("dropped", 2)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_route_sends_chunks_with_backpressure() {
+        let (produced, mut progress) = mpsc::unbounded_channel();
+        let router = Router::new().with_stream_route("tail/{n}", move |request: Request| {
+            let n: usize = request.param("n").unwrap().parse().unwrap();
+            let produced = produced.clone();
+            futures::stream::iter(0..n).map(move |i| {
+                let _ = produced.send(i);
+                if i == 3 {
+                    return Err(ProtocolError::InvalidFormat("gap".into()));
+                }
+                Ok(Bytes::from(i.to_string()))
+            })
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router);
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = crate::RemusClient::connect(&address).await.unwrap();
+
+        let options = crate::RequestOptions::default().with_routing_info("tail/3");
+        let stream = client.stream_with_options("", &options).await.unwrap();
+        let received: Vec<(MessageType, Bytes)> =
+            stream.map(|message| message.unwrap()).map(|message| (message.msg_type, message.payload)).collect().await;
+        let chunks = ["0", "1", "2"].map(|chunk| (MessageType::Stream, Bytes::from(chunk)));
+        assert_eq!(received[..3], chunks);
+        assert_eq!(received[3], (MessageType::StreamEnd, Bytes::new()));
+
+        let options = crate::RequestOptions::default().with_routing_info("tail/5");
+        let mut stream = client.stream_with_options("", &options).await.unwrap();
+        let last = stream.by_ref().map(|message| message.unwrap()).collect::<Vec<_>>().await.pop().unwrap();
+        assert_eq!(last.msg_type, MessageType::Error);
+        assert_eq!(ErrorPayload::decode(&last).unwrap().code, "InvalidFormat");
+        while progress.try_recv().is_ok() {}
+
+        // An unread stream stops producing once the connection's queues fill up
+        let options = crate::RequestOptions::default().with_routing_info("tail/1000000");
+        let _unread = client.stream_with_options("", &options).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut count = 0;
+        while progress.try_recv().is_ok() {
+            count += 1;
+        }
+        assert!(count < 100_000, "produced {count} chunks without a reader");
+
+        let plain = client.request_with_options("", &options).await.unwrap_err();
+        assert!(plain.to_string().contains("only answers streaming requests"));
+    }
+
+    #[tokio::test]
+    async fn test_uploaded_chunks_reach_one_handler() {
+        let handlers = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let started = handlers.clone();
+        let router = Router::new().with_route("upload", move |mut request: Request| {
+            started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                let mut chunks = request.take_request_stream().unwrap();
+                // Fall behind so the connection has to hold chunks back
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let (mut count, mut total) = (0, 0);
+                while let Some(chunk) = chunks.next().await {
+                    count += 1;
+                    total += chunk.len();
+                }
+                Ok(Bytes::from(format!("{count} chunks, {total} bytes")))
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router);
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = crate::RemusClient::connect(&address).await.unwrap();
+
+        let options = crate::RequestOptions::default().with_routing_info("upload");
+        let mut upload = client.stream_upload_with_options(&options).unwrap();
+        let mut chunks = futures::stream::iter((0..100).map(|_| Ok(Bytes::from(vec![7u8; 1000]))));
+        futures::SinkExt::send_all(&mut upload, &mut chunks).await.unwrap();
+        assert_eq!(upload.finish().await.unwrap(), Bytes::from("100 chunks, 100000 bytes"));
+        assert_eq!(handlers.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // An upload without chunks still runs its handler once
+        let empty = client.stream_upload_with_options(&options).unwrap();
+        assert_eq!(empty.finish().await.unwrap(), Bytes::from("0 chunks, 0 bytes"));
+        assert_eq!(handlers.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bidi_stream_reaches_one_handler_both_ways() {
+        let router = Router::new().with_route("session", |mut request: Request| async move {
+            let mut chunks = request.take_request_stream().unwrap();
+            let sink = request.response_sink().unwrap().clone();
+            let mut turns = 0;
+            while let Some(chunk) = chunks.next().await {
+                turns += 1;
+                sink.send(chunk.to_ascii_uppercase()).await?;
+            }
+            Ok(Bytes::from(format!("{turns} turns")))
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router);
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = crate::RemusClient::connect(&address).await.unwrap();
+
+        let (mut sink, mut stream) = client.bidi("session").unwrap();
+        for turn in ["ping", "pong", "again"] {
+            futures::SinkExt::send(&mut sink, Bytes::from(turn)).await.unwrap();
+            let reply = stream.next().await.unwrap().unwrap();
+            assert_eq!((reply.msg_type, reply.payload), (MessageType::Stream, Bytes::from(turn.to_ascii_uppercase())));
+        }
+        futures::SinkExt::close(&mut sink).await.unwrap();
+        let end = stream.next().await.unwrap().unwrap();
+        assert_eq!((end.msg_type, end.payload), (MessageType::StreamEnd, Bytes::from("3 turns")));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[crate::service(name = "thermostat")]
+    trait Thermostat {
+        async fn set(&self, room: String, celsius: f32) -> Result<f32, ProtocolError>;
+        async fn reset(&self) -> Result<(), ProtocolError>;
+    }
+
+    struct Radiators(Mutex<HashMap<String, f32>>);
+
+    impl Thermostat for Radiators {
+        async fn set(&self, room: String, celsius: f32) -> Result<f32, ProtocolError> {
+            let previous = self.0.lock().unwrap().insert(room, celsius);
+            previous.ok_or_else(|| ProtocolError::InvalidFormat("Unknown room".into()))
+        }
+
+        async fn reset(&self) -> Result<(), ProtocolError> {
+            self.0.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service_macro_routes_typed_calls() {
+        let radiators = Radiators(Mutex::new(HashMap::from([("hall".to_string(), 18.0)])));
+        let router = ThermostatServer::new(radiators).register(Router::new());
+        assert!(router.resolve("thermostat/set").is_ok());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = Server::new(router);
+        tokio::spawn(async move { server.serve(listener).await });
+        let client = ThermostatClient::new(Arc::new(crate::RemusClient::connect(&address).await.unwrap()));
+
+        assert_eq!(client.set("hall".into(), 21.5).await.unwrap(), 18.0);
+        assert_eq!(client.set("hall".into(), 20.0).await.unwrap(), 21.5);
+        client.reset().await.unwrap();
+        assert!(client.set("hall".into(), 19.0).await.unwrap_err().to_string().contains("Unknown room"));
+    }
+
+    #[tokio::test]
+    async fn test_published_events_reach_subscribed_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broker = Arc::new(Broker::new());
+        let router = Router::new().with_route("alerts/raise", {
+            let broker = broker.clone();
+            move |request: Request| {
+                let delivered = broker.publish("devices/4/alerts", request.payload().clone());
+                async move { Ok(Bytes::from(delivered.to_string())) }
+            }
+        });
+        let server = Server::new(router).with_broker(broker.clone());
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let client = crate::RemusClient::connect(&address).await.unwrap();
+        let mut alerts = client.subscribe("devices/*/alerts").await.unwrap();
+        let _unrelated = client.subscribe("devices/*/telemetry").await.unwrap();
+        assert_eq!(broker.subscriptions(), 2);
+
+        let options = crate::RequestOptions::default().with_routing_info("alerts/raise");
+        assert_eq!(client.request_with_options("overheat", &options).await.unwrap(), Bytes::from("1"));
+        let event = alerts.next().await.unwrap().unwrap();
+        assert_eq!((event.msg_type, event.payload), (MessageType::Event, Bytes::from("overheat")));
+
+        drop(alerts);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while broker.subscriptions() > 1 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_configured_server_applies_acceptor_and_telemetry() {
+        let handshakes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (telemetry, _metrics, _traces) = Telemetry::new(16, 16);
+        let telemetry = Arc::new(telemetry);
+        let server = Server::new(Router::new().with_route("echo", named("echo")))
+            .with_backlog(16)
+            .with_read_timeout(Duration::from_secs(5))
+            .with_write_timeout(Duration::from_secs(5))
+            .with_telemetry(telemetry.clone())
+            .with_acceptor({
+                let handshakes = handshakes.clone();
+                move |stream: TcpStream| {
+                    handshakes.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    async move { Ok(Box::new(stream) as Box<dyn Io>) }
+                }
+            });
+        let listener = server.bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut transport = Transport::new(TcpStream::connect(address).await.unwrap());
+        transport.send(routed("echo")).await.unwrap();
+        assert_eq!(transport.receive().await.unwrap().payload, Bytes::from("echo"));
+
+        assert_eq!(handshakes.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(telemetry.request_latency().count(), 1);
+        assert!(telemetry.transport_stats().snapshot().bytes_received > 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frames_close_the_connection() {
+        let (client, peer) = duplex(4096);
+        let server = Server::new(Router::new().with_route("echo", named("echo"))).with_max_frame_length(128);
+        let served = tokio::spawn(async move { server.serve_connection(Transport::new(peer)).await });
+
+        let mut transport = Transport::new(client);
+        let mut oversized = routed("echo");
+        oversized.payload = Bytes::from(vec![0; 512]);
+        transport.send(oversized).await.unwrap();
+        assert!(matches!(served.await.unwrap(), Err(ProtocolError::InvalidFormat(_))));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_handler_answers_error_and_keeps_connection() {
+        let (client, peer) = duplex(4096);
+        let (telemetry, _metrics, _traces) = Telemetry::new(16, 16);
+        let telemetry = Arc::new(telemetry);
+        let router = Router::new()
+            .with_route("boom", |_request: Request| async move {
+                if true {
+                    panic!("exploded");
+                }
+                Ok(Bytes::new())
+            })
+            .with_route("echo", named("echo"));
+        let server = Server::new(router).with_telemetry(telemetry.clone());
+        tokio::spawn(async move { server.serve_connection(Transport::new(peer)).await });
+
+        let mut transport = Transport::new(client);
+        transport.send(routed("boom")).await.unwrap();
+        let error = ErrorPayload::decode(&transport.receive().await.unwrap()).unwrap();
+        assert_eq!((error.code.as_str(), error.message.as_str()), ("HandlerPanicked", "Handler panicked: exploded"));
+
+        transport.send(routed("echo")).await.unwrap();
+        assert_eq!(transport.receive().await.unwrap().payload, Bytes::from("echo"));
+        assert_eq!(telemetry.error_counts().get("HandlerPanicked"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_handle_swaps_routes_on_live_connection() {
+        let (client, peer) = duplex(4096);
+        let server = Server::new(Router::new().with_route("v1", named("v1")));
+        let handle = server.handle();
+        tokio::spawn(async move { server.serve_connection(Transport::new(peer)).await });
+
+        let mut transport = Transport::new(client);
+        transport.send(routed("v1")).await.unwrap();
+        assert_eq!(transport.receive().await.unwrap().payload, Bytes::from("v1"));
+
+        handle.set_router(Router::new().with_route("v2", named("v2")));
+        transport.send(routed("v2")).await.unwrap();
+        assert_eq!(transport.receive().await.unwrap().payload, Bytes::from("v2"));
+        transport.send(routed("v1")).await.unwrap();
+        assert_eq!(ErrorPayload::decode(&transport.receive().await.unwrap()).unwrap().code, "NotFound");
+    }
+
+    #[tokio::test]
+    async fn test_server_replies_with_structured_not_found() {
+        let (client, peer) = duplex(4096);
+        let server = Server::new(Router::new().with_route("echo", |request: Request| async move {
+            Ok(request.payload().clone())
+        }));
+        tokio::spawn(async move { server.serve_connection(Transport::new(peer)).await });
+
+        let mut transport = Transport::new(client);
+        let mut echo = routed("echo");
+        echo.payload = Bytes::from("hi");
+        transport.send(echo).await.unwrap();
+        let response = transport.receive().await.unwrap();
+        assert_eq!((response.msg_type, response.payload), (MessageType::Response, Bytes::from("hi")));
+
+        transport.send(routed("missing")).await.unwrap();
+        let error = transport.receive().await.unwrap();
+        assert_eq!(error.msg_type, MessageType::Error);
+        assert_eq!(ErrorPayload::decode(&error).unwrap().code, "NotFound");
+    }
+}
@@ -0,0 +1,268 @@
+//! Session resumption.
+//!
+//! A server with resumption enabled keeps a connection's session, meaning its
+//! running handlers, their unwritten replies and its subscriptions, for a
+//! grace period after the transport drops. A client that reconnects within
+//! it, from any address, presents the session's token and carries on:
+//! responses and stream chunks for requests the server is still answering
+//! arrive on the new transport, and subscriptions stay registered.
+//!
+//! Tokens travel in Handshake frames, after any Noise handshake and
+//! compression negotiation. The initiator sends the token of the session it
+//! wants back, or an empty payload for a new one. The responder answers with
+//! the token of the session it serves, a byte that is 1 when that is the one
+//! asked for, then the big-endian u64 ids of the requests it has yet to
+//! finish answering.
+//!
+//! Holding a token is enough to resume its session, so only resume sessions
+//! over connections that are encrypted.
+
+use crate::{transport::Transport, Message, MessageFlags, MessageType, ProtocolError};
+use bytes::{BufMut, Bytes};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+const TOKEN_LEN: usize = 16;
+
+/// Names one session to the server that holds it
+pub(crate) type SessionToken = [u8; TOKEN_LEN];
+
+// A fresh, unguessable token
+fn new_token() -> SessionToken {
+    rand::random()
+}
+
+/// The session a client's connection resumes each time it reconnects
+#[derive(Default)]
+pub(crate) struct Resumption {
+    token: Option<SessionToken>,
+}
+
+impl Resumption {
+    /// Asks the server on `transport` for the session back, or for a new one the first time.
+    ///
+    /// Returns the ids of the requests the server is still answering when it
+    /// resumed the session, or None when it started a new one.
+    pub async fn initiate<T>(&mut self, transport: &mut Transport<T>) -> Result<Option<Vec<u64>>, ProtocolError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let asked = self.token.map_or_else(Bytes::new, |token| Bytes::copy_from_slice(&token));
+        send_frame(transport, asked).await?;
+
+        let answer = receive_frame(transport).await?;
+        if answer.len() < TOKEN_LEN + 1 || !(answer.len() - TOKEN_LEN - 1).is_multiple_of(8) {
+            return Err(handshake_error("Malformed session answer"));
+        }
+        let (token, rest) = answer.split_at(TOKEN_LEN);
+        let token: SessionToken = token.try_into().unwrap();
+        let resumed = rest[0] == 1 && self.token == Some(token);
+        self.token = Some(token);
+        Ok(resumed.then(|| rest[1..].chunks_exact(8).map(|id| u64::from_be_bytes(id.try_into().unwrap())).collect()))
+    }
+}
+
+/// Reads the token of the session the initiator wants back, None when it asks for a new one
+pub(crate) async fn receive_token<T>(transport: &mut Transport<T>) -> Result<Option<SessionToken>, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let asked = receive_frame(transport).await?;
+    match asked.len() {
+        0 => Ok(None),
+        TOKEN_LEN => Ok(Some(asked[..].try_into().unwrap())),
+        _ => Err(handshake_error("Malformed session token")),
+    }
+}
+
+/// Tells the initiator which session it is served, with the requests still in
+/// progress when `in_progress` is given because the session was resumed
+pub(crate) async fn answer<T>(transport: &mut Transport<T>, token: &SessionToken, in_progress: Option<&[u64]>) -> Result<(), ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let ids = in_progress.unwrap_or_default();
+    let mut answer = Vec::with_capacity(TOKEN_LEN + 1 + 8 * ids.len());
+    answer.extend_from_slice(token);
+    answer.push(in_progress.is_some() as u8);
+    for id in ids {
+        answer.put_u64(*id);
+    }
+    send_frame(transport, Bytes::from(answer)).await
+}
+
+/// A server's resumable sessions, each served on a transport or parked until it
+/// is resumed on another or its grace period ends
+pub(crate) struct SessionStore<S> {
+    grace: Duration,
+    slots: Mutex<HashMap<SessionToken, Slot<S>>>,
+    // Each parking gets a number, so an expiry only removes the parking it was started for
+    parkings: AtomicU64,
+    // Notified whenever a session is parked or released
+    changed: Notify,
+}
+
+enum Slot<S> {
+    // Cancelled to make the transport serving the session give it up
+    Attached(CancellationToken),
+    Parked(u64, S),
+}
+
+impl<S: Send + 'static> SessionStore<S> {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            slots: Mutex::new(HashMap::new()),
+            parkings: AtomicU64::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Registers a new session, returning its token and the token that detaches it from its transport
+    pub fn open(&self) -> (SessionToken, CancellationToken) {
+        let token = new_token();
+        let detach = CancellationToken::new();
+        self.slots.lock().unwrap().insert(token, Slot::Attached(detach.clone()));
+        (token, detach)
+    }
+
+    /// Takes the session `token` names to serve it on a new transport, if `accept` agrees.
+    ///
+    /// A session still served on another transport, whose peer may not have
+    /// noticed the client moving, is detached from it first.
+    pub async fn resume(&self, token: &SessionToken, accept: impl FnOnce(&S) -> bool) -> Option<(S, CancellationToken)> {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let mut slots = self.slots.lock().unwrap();
+                match slots.get(token)? {
+                    Slot::Attached(detach) => detach.cancel(),
+                    Slot::Parked(_, session) if accept(session) => {
+                        let detach = CancellationToken::new();
+                        let Some(Slot::Parked(_, session)) = slots.insert(*token, Slot::Attached(detach.clone())) else {
+                            unreachable!()
+                        };
+                        return Some((session, detach));
+                    }
+                    Slot::Parked(..) => return None,
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Keeps `session` under `token` for the grace period, then drops it
+    pub fn park(self: &Arc<Self>, token: SessionToken, session: S) {
+        let parking = self.parkings.fetch_add(1, Ordering::Relaxed);
+        self.slots.lock().unwrap().insert(token, Slot::Parked(parking, session));
+        self.changed.notify_waiters();
+        let store = Arc::downgrade(self);
+        let grace = self.grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let Some(store) = store.upgrade() else {
+                return;
+            };
+            let mut slots = store.slots.lock().unwrap();
+            if matches!(slots.get(&token), Some(Slot::Parked(parked, _)) if *parked == parking) {
+                slots.remove(&token);
+            }
+        });
+    }
+
+    /// Forgets a session that ended for good
+    pub fn release(&self, token: &SessionToken) {
+        self.slots.lock().unwrap().remove(token);
+        self.changed.notify_waiters();
+    }
+}
+
+async fn send_frame<T>(transport: &mut Transport<T>, payload: Bytes) -> Result<(), ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    transport.send(Message::new(MessageType::Handshake, MessageFlags::NONE, 0, payload)).await
+}
+
+async fn receive_frame<T>(transport: &mut Transport<T>) -> Result<Bytes, ProtocolError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let message = transport.receive().await?;
+    match message.msg_type {
+        MessageType::Handshake => Ok(message.payload),
+        other => Err(handshake_error(&format!("Expected a handshake message, got {other:?}"))),
+    }
+}
+
+fn handshake_error(reason: &str) -> ProtocolError {
+    ProtocolError::HandshakeFailed(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_token_resumes_only_the_session_it_names() {
+        let (client, server) = duplex(1024);
+        let (mut client, mut server) = (Transport::new(client), Transport::new(server));
+        let mut resumption = Resumption::default();
+        let token = new_token();
+
+        let (resumed, asked) = tokio::join!(resumption.initiate(&mut client), async {
+            let asked = receive_token(&mut server).await.unwrap();
+            answer(&mut server, &token, None).await.unwrap();
+            asked
+        });
+        assert_eq!((resumed.unwrap(), asked), (None, None));
+
+        let (resumed, asked) = tokio::join!(resumption.initiate(&mut client), async {
+            let asked = receive_token(&mut server).await.unwrap();
+            answer(&mut server, &token, Some(&[3, 9])).await.unwrap();
+            asked
+        });
+        assert_eq!((resumed.unwrap(), asked), (Some(vec![3, 9]), Some(token)));
+    }
+
+    #[tokio::test]
+    async fn test_parked_sessions_expire_after_the_grace_period() {
+        let store = Arc::new(SessionStore::new(Duration::from_millis(300)));
+        let ((kept, _), (expired, _)) = (store.open(), store.open());
+        store.park(kept, "kept");
+        store.park(expired, "expired");
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(store.resume(&kept, |_| false).await.is_none());
+        assert_eq!(store.resume(&kept, |_| true).await.unwrap().0, "kept");
+        // Parking again restarts the grace period
+        store.park(kept, "kept");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(store.resume(&expired, |_| true).await.is_none());
+        assert_eq!(store.resume(&kept, |_| true).await.unwrap().0, "kept");
+    }
+
+    #[tokio::test]
+    async fn test_resuming_detaches_a_session_still_being_served() {
+        let store = Arc::new(SessionStore::new(Duration::from_secs(60)));
+        let (token, detach) = store.open();
+        let serving = tokio::spawn({
+            let store = store.clone();
+            async move {
+                detach.cancelled().await;
+                store.park(token, "moved");
+            }
+        });
+
+        assert_eq!(store.resume(&token, |_| true).await.unwrap().0, "moved");
+        serving.await.unwrap();
+        store.release(&token);
+        assert!(store.resume(&token, |_| true).await.is_none());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{resolve, ProtocolError};
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{SockRef, TcpKeepalive};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// TCP socket options applied to outbound connections and accepted streams
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    nodelay: bool,
+    keepalive: Option<(Duration, Duration)>,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    linger: Option<Duration>,
+    attempt_delay: Duration,
+}
+
+impl SocketConfig {
+    /// Creates a config with Nagle's algorithm disabled and all other options left at OS defaults
+    pub fn new() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger: None,
+            attempt_delay: Duration::from_millis(250),
+        }
+    }
+
+    /// Enables or disables TCP_NODELAY
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Enables SO_KEEPALIVE, probing after `idle` without traffic and every `interval` thereafter
+    pub fn with_tcp_keepalive(mut self, idle: Duration, interval: Duration) -> Self {
+        self.keepalive = Some((idle, interval));
+        self
+    }
+
+    /// Sets SO_SNDBUF
+    pub fn with_send_buffer_size(mut self, size: u32) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets SO_RCVBUF
+    pub fn with_recv_buffer_size(mut self, size: u32) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets SO_LINGER so close blocks for up to `linger` flushing unsent data
+    pub fn with_linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// Sets how long a connection attempt runs before the next resolved address is raced against it
+    pub fn with_happy_eyeballs_delay(mut self, delay: Duration) -> Self {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// Connects to `address`, either a `host:port` or an SRV name prefixed with
+    /// [`resolve::SRV_SCHEME`] (which needs the `srv` feature). SRV targets are
+    /// tried in priority and weight order.
+    ///
+    /// A host's addresses are raced Happy Eyeballs style (RFC 8305): families
+    /// alternate, and a new attempt starts whenever the previous one fails or
+    /// has run for the attempt delay. Buffer sizes are set before connecting so
+    /// they influence the TCP window negotiated in the handshake.
+    pub async fn connect(&self, address: &str) -> Result<TcpStream, ProtocolError> {
+        let mut last_error = None;
+        for target in resolve::targets(address).await? {
+            let addrs = match tokio::net::lookup_host(&target).await {
+                Ok(addrs) => interleave_families(addrs.collect()),
+                Err(e) => {
+                    last_error = Some(e.into());
+                    continue;
+                }
+            };
+            match self.race(addrs).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            ProtocolError::InvalidFormat(format!("No addresses resolved for {}", address))
+        }))
+    }
+
+    async fn race(&self, addrs: Vec<SocketAddr>) -> Result<TcpStream, ProtocolError> {
+        let mut waiting = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        loop {
+            if attempts.is_empty() {
+                match waiting.next() {
+                    Some(addr) => attempts.push(self.connect_addr(addr)),
+                    None => return Err(last_error.unwrap_or_else(|| ProtocolError::InvalidFormat("No addresses resolved".into()))),
+                }
+            }
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => {
+                        last_error = Some(e);
+                        if let Some(addr) = waiting.next() {
+                            attempts.push(self.connect_addr(addr));
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(self.attempt_delay), if waiting.len() > 0 => {
+                    attempts.push(self.connect_addr(waiting.next().unwrap()));
+                }
+            }
+        }
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> Result<TcpStream, ProtocolError> {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        self.apply_buffer_sizes(&socket)?;
+        let stream = socket.connect(addr).await?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+
+    /// Binds a listener whose accepted streams inherit the configured buffer sizes.
+    ///
+    /// Call [`SocketConfig::apply`] on each accepted stream for the remaining options.
+    pub fn bind(&self, addr: SocketAddr) -> Result<TcpListener, ProtocolError> {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.set_reuseaddr(true)?;
+        self.apply_buffer_sizes(&socket)?;
+        socket.bind(addr)?;
+        Ok(socket.listen(1024)?)
+    }
+
+    fn apply_buffer_sizes(&self, socket: &TcpSocket) -> Result<(), ProtocolError> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the configured options to an established stream
+    pub fn apply(&self, stream: &TcpStream) -> Result<(), ProtocolError> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        if let Some((idle, interval)) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle).with_interval(interval))?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size as usize)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size as usize)?;
+        }
+        if self.linger.is_some() {
+            socket.set_linger(self.linger)?;
+        }
+        Ok(())
+    }
+}
+
+// Alternates address families, starting with the family the resolver listed first
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(addr) = preferred.pop() {
+        interleaved.push(addr);
+        interleaved.extend(other.pop());
+    }
+    interleaved.extend(other.into_iter().rev());
+    interleaved
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_socket_config_applied_on_connect_and_accept() {
+        let config = SocketConfig::new()
+            .with_tcp_keepalive(Duration::from_secs(30), Duration::from_secs(5))
+            .with_recv_buffer_size(256 * 1024)
+            .with_linger(Duration::from_secs(1));
+
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let client = config.connect(&address).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        config.apply(&server).unwrap();
+
+        for stream in [&client, &server] {
+            assert!(stream.nodelay().unwrap());
+            let socket = SockRef::from(stream);
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(1)));
+            assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        }
+    }
+
+    #[test]
+    fn test_families_interleave() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let order: Vec<String> = interleave_families(addrs).iter().map(|addr| addr.to_string()).collect();
+        assert_eq!(order, ["[::1]:1", "10.0.0.1:1", "[::2]:1", "[::3]:1"]);
+    }
+
+    #[tokio::test]
+    async fn test_race_falls_through_to_reachable_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // A closed port fails fast
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        // A listener whose accept queue is full drops further SYNs, so connecting hangs
+        let stalled = TcpSocket::new_v4().unwrap();
+        stalled.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let stalled = stalled.listen(0).unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        let mut backlog = Vec::new();
+        while let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(stalled_addr)).await {
+            backlog.push(stream);
+        }
+
+        let config = SocketConfig::new().with_happy_eyeballs_delay(Duration::from_millis(20));
+        let raced = config.race(vec![stalled_addr, closed, listener.local_addr().unwrap()]);
+        let stream = tokio::time::timeout(Duration::from_secs(2), raced).await.unwrap().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_socket_config_can_leave_nagle_enabled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let stream = SocketConfig::new().with_nodelay(false).connect(&address).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+}
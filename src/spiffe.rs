@@ -0,0 +1,253 @@
+//! SPIFFE workload identities.
+//!
+//! A SPIFFE ID names a workload as `spiffe://<trust domain>/<path>`. An
+//! X.509-SVID carries exactly one, as the URI subject alternative name of the
+//! workload's certificate. [`SpiffeVerifier`] is a
+//! [`CertificateVerifier`](crate::CertificateVerifier) that reads it from the
+//! client certificate, making the SPIFFE ID the connection's identity;
+//! [`ConnectionContext::spiffe_id`](crate::ConnectionContext::spiffe_id)
+//! returns it parsed, and [`Principal::TrustDomain`](crate::Principal::TrustDomain)
+//! and [`Principal::Workload`](crate::Principal::Workload) match it in access
+//! rules.
+//!
+//! It reads the certificate without validating its chain: pass it to
+//! [`CaBundleVerifier::with_identity`](crate::CaBundleVerifier::with_identity)
+//! for a verifier built from the trust bundle, or validate the chain in the
+//! TLS library the acceptor configures.
+
+use crate::{tls::CertificateVerifier, ProtocolError};
+use bytes::Bytes;
+use std::fmt;
+use std::str::FromStr;
+
+const SCHEME: &str = "spiffe://";
+const MAX_LENGTH: usize = 2048;
+const MAX_TRUST_DOMAIN_LENGTH: usize = 255;
+
+// DER tags
+const SEQUENCE: u8 = 0x30;
+const OID: u8 = 0x06;
+const OCTET_STRING: u8 = 0x04;
+const EXTENSIONS: u8 = 0xa3;
+const URI_NAME: u8 = 0x86;
+// 2.5.29.17, subjectAltName
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// A parsed `spiffe://` ID
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpiffeId {
+    trust_domain: String,
+    path: String,
+}
+
+impl SpiffeId {
+    pub fn trust_domain(&self) -> &str {
+        &self.trust_domain
+    }
+
+    /// The path, with its leading `/`; empty for the trust domain itself
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Reads the SPIFFE ID of an X.509-SVID, the DER-encoded `certificate`
+    pub fn from_certificate(certificate: &[u8]) -> Result<Self, ProtocolError> {
+        let invalid = || ProtocolError::InvalidFormat("Certificate is not an X.509-SVID".into());
+        let mut uris = subject_alt_names(certificate)
+            .ok_or_else(invalid)?
+            .into_iter()
+            .filter(|(tag, _)| *tag == URI_NAME);
+        match (uris.next(), uris.next()) {
+            (Some((_, uri)), None) => std::str::from_utf8(uri).map_err(|_| invalid())?.parse(),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl FromStr for SpiffeId {
+    type Err = ProtocolError;
+
+    fn from_str(id: &str) -> Result<Self, ProtocolError> {
+        let invalid = |reason: &str| ProtocolError::InvalidFormat(format!("Invalid SPIFFE ID {id:?}: {reason}"));
+        if id.len() > MAX_LENGTH {
+            return Err(invalid("too long"));
+        }
+        let rest = id.strip_prefix(SCHEME).ok_or_else(|| invalid("not a spiffe:// URI"))?;
+        let (trust_domain, path) = rest.find('/').map_or((rest, ""), |slash| rest.split_at(slash));
+        if trust_domain.is_empty() || trust_domain.len() > MAX_TRUST_DOMAIN_LENGTH {
+            return Err(invalid("bad trust domain length"));
+        }
+        if !trust_domain.bytes().all(|byte| matches!(byte, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_')) {
+            return Err(invalid("trust domain may only hold lowercase letters, digits, '.', '-' and '_'"));
+        }
+        if !path.is_empty() {
+            for segment in path[1..].split('/') {
+                if segment.is_empty() || segment == "." || segment == ".." {
+                    return Err(invalid("empty or relative path segment"));
+                }
+                if !segment.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_')) {
+                    return Err(invalid("path may only hold letters, digits, '.', '-' and '_'"));
+                }
+            }
+        }
+        Ok(Self {
+            trust_domain: trust_domain.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for SpiffeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SCHEME}{}{}", self.trust_domain, self.path)
+    }
+}
+
+/// Accepts peers presenting an X.509-SVID, naming them by their SPIFFE ID
+#[derive(Debug, Clone, Default)]
+pub struct SpiffeVerifier {
+    trust_domains: Vec<String>,
+}
+
+impl SpiffeVerifier {
+    /// Creates a verifier accepting SVIDs from any trust domain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts only SVIDs from `trust_domain` and others added this way
+    pub fn with_trust_domain(mut self, trust_domain: impl Into<String>) -> Self {
+        self.trust_domains.push(trust_domain.into());
+        self
+    }
+}
+
+impl CertificateVerifier for SpiffeVerifier {
+    fn verify(&self, chain: &[Bytes]) -> Result<String, ProtocolError> {
+        let id = SpiffeId::from_certificate(&chain[0]).map_err(|e| ProtocolError::HandshakeFailed(e.to_string()))?;
+        if !self.trust_domains.is_empty() && !self.trust_domains.contains(&id.trust_domain) {
+            return Err(ProtocolError::HandshakeFailed(format!("Untrusted SPIFFE trust domain {:?}", id.trust_domain)));
+        }
+        Ok(id.to_string())
+    }
+}
+
+// The tag and contents of each GeneralName in the certificate's subjectAltName extension
+fn subject_alt_names(certificate: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let (certificate, _) = read_expected(certificate, SEQUENCE)?;
+    let (mut tbs, _) = read_expected(certificate, SEQUENCE)?;
+    // The extensions are the last, explicitly tagged field of the TBSCertificate
+    let extensions = loop {
+        let (tag, contents, rest) = read(tbs)?;
+        if tag == EXTENSIONS {
+            break read_expected(contents, SEQUENCE)?.0;
+        }
+        tbs = rest;
+    };
+    let mut remaining = extensions;
+    while !remaining.is_empty() {
+        let (extension, rest) = read_expected(remaining, SEQUENCE)?;
+        remaining = rest;
+        let (id, mut fields) = read_expected(extension, OID)?;
+        if id != SUBJECT_ALT_NAME {
+            continue;
+        }
+        // Skip the critical flag, if present
+        let value = loop {
+            let (tag, contents, rest) = read(fields)?;
+            if tag == OCTET_STRING {
+                break contents;
+            }
+            fields = rest;
+        };
+        let (mut names, _) = read_expected(value, SEQUENCE)?;
+        let mut found = Vec::new();
+        while !names.is_empty() {
+            let (tag, contents, rest) = read(names)?;
+            found.push((tag, contents));
+            names = rest;
+        }
+        return Some(found);
+    }
+    None
+}
+
+fn read_expected(input: &[u8], expected: u8) -> Option<(&[u8], &[u8])> {
+    let (tag, contents, rest) = read(input)?;
+    (tag == expected).then_some((contents, rest))
+}
+
+// Splits one DER element off `input`: its tag, contents and what follows it
+fn read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&length, rest) = rest.split_first()?;
+    let (length, rest) = match length {
+        0..=0x7f => (length as usize, rest),
+        0x81..=0x84 => {
+            let count = (length & 0x7f) as usize;
+            let bytes = rest.get(..count)?;
+            (bytes.iter().fold(0, |length, &byte| length << 8 | byte as usize), &rest[count..])
+        }
+        _ => return None,
+    };
+    let contents = rest.get(..length)?;
+    Some((tag, contents, &rest[length..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        match contents.len() {
+            length @ 0..=0x7f => encoded.push(length as u8),
+            length => encoded.extend_from_slice(&[0x82, (length >> 8) as u8, length as u8]),
+        }
+        encoded.extend_from_slice(contents);
+        encoded
+    }
+
+    // A certificate with just enough structure to carry `names` as its subject alternative names
+    fn svid(names: &[(u8, &str)]) -> Vec<u8> {
+        let names: Vec<u8> = names.iter().flat_map(|(tag, name)| der(*tag, name.as_bytes())).collect();
+        let san = [der(OID, SUBJECT_ALT_NAME), der(OCTET_STRING, &der(SEQUENCE, &names))].concat();
+        let key_usage = [der(OID, &[0x55, 0x1d, 0x0f]), der(0x01, &[0xff]), der(OCTET_STRING, &[0x03, 0x02, 0x07, 0x80])].concat();
+        let extensions = der(EXTENSIONS, &der(SEQUENCE, &[der(SEQUENCE, &key_usage), der(SEQUENCE, &san)].concat()));
+        let tbs = [der(0xa0, &der(0x02, &[2])), der(0x02, &[1]), der(SEQUENCE, &[]), extensions].concat();
+        der(SEQUENCE, &[der(SEQUENCE, &tbs), der(SEQUENCE, &[]), der(0x03, &[0])].concat())
+    }
+
+    #[test]
+    fn test_spiffe_ids_parse_and_validate() {
+        let id: SpiffeId = "spiffe://prod.example.org/ns/payments/sa/api".parse().unwrap();
+        assert_eq!(id.trust_domain(), "prod.example.org");
+        assert_eq!(id.path(), "/ns/payments/sa/api");
+        assert_eq!(id.to_string(), "spiffe://prod.example.org/ns/payments/sa/api");
+        assert_eq!("spiffe://example.org".parse::<SpiffeId>().unwrap().path(), "");
+
+        for invalid in [
+            "https://example.org/a",
+            "spiffe://",
+            "spiffe://Example.org/a",
+            "spiffe://example.org/",
+            "spiffe://example.org/a//b",
+            "spiffe://example.org/../a",
+            "spiffe://example.org/a?b",
+        ] {
+            assert!(invalid.parse::<SpiffeId>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_verifier_reads_the_svid_uri() {
+        let certificate = Bytes::from(svid(&[(0x82, "api.internal"), (URI_NAME, "spiffe://example.org/api")]));
+        assert_eq!(SpiffeVerifier::new().verify(std::slice::from_ref(&certificate)).unwrap(), "spiffe://example.org/api");
+        assert!(SpiffeVerifier::new().with_trust_domain("other.org").verify(&[certificate]).is_err());
+
+        let two_uris = svid(&[(URI_NAME, "spiffe://example.org/a"), (URI_NAME, "spiffe://example.org/b")]);
+        assert!(SpiffeId::from_certificate(&two_uris).is_err());
+        assert!(SpiffeId::from_certificate(&svid(&[(0x82, "api.internal")])).is_err());
+        assert!(SpiffeId::from_certificate(b"not a certificate").is_err());
+    }
+}
@@ -1,14 +1,19 @@
-use crate::{Message, MessageFlags, MessageType, ProtocolError};
-use bytes::Bytes;
-use futures::{Stream, StreamExt};
+use crate::{Message, MessageType, ProtocolError};
+use futures::Stream;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::sync::mpsc;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+type CancelHook = Box<dyn FnOnce() + Send + Sync>;
 
 pub struct MessageStream {
     rx: mpsc::Receiver<Message>,
-    stream_id: u32,
+    stream_id: u64,
     closed: bool,
+    cancelled: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    on_cancel: Option<CancelHook>,
 }
 
 impl MessageStream {
@@ -19,10 +24,29 @@ impl MessageStream {
             rx,
             stream_id,
             closed: false,
+            cancelled: None,
+            on_cancel: None,
         })
     }
 
-    pub fn stream_id(&self) -> u32 {
+    /// Runs `hook` if the stream is cancelled or dropped before it ends
+    pub(crate) fn on_cancel(&mut self, hook: impl FnOnce() + Send + Sync + 'static) {
+        self.on_cancel = Some(Box::new(hook));
+    }
+
+    /// Ends the stream with `ProtocolError::Cancelled` once `token` is cancelled
+    pub(crate) fn cancel_on(&mut self, token: CancellationToken) {
+        self.cancelled = Some(Box::pin(token.cancelled_owned()));
+    }
+
+    fn cancel(&mut self) {
+        self.closed = true;
+        if let Some(hook) = self.on_cancel.take() {
+            hook();
+        }
+    }
+
+    pub fn stream_id(&self) -> u64 {
         self.stream_id
     }
 
@@ -38,6 +62,10 @@ impl Stream for MessageStream {
         if self.closed {
             return Poll::Ready(None);
         }
+        if self.cancelled.as_mut().is_some_and(|cancelled| cancelled.as_mut().poll(cx).is_ready()) {
+            self.cancel();
+            return Poll::Ready(Some(Err(ProtocolError::Cancelled)));
+        }
 
         match self.rx.poll_recv(cx) {
             Poll::Ready(Some(msg)) => {
@@ -53,4 +81,12 @@ impl Stream for MessageStream {
             Poll::Pending => Poll::Pending,
         }
     }
-} 
\ No newline at end of file
+}
+
+impl Drop for MessageStream {
+    fn drop(&mut self) {
+        if !self.closed {
+            self.cancel();
+        }
+    }
+}
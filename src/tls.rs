@@ -0,0 +1,245 @@
+//! Client-certificate checks for TLS terminated by an [`Acceptor`](crate::Acceptor).
+//!
+//! The acceptor runs the TLS handshake, requesting a client certificate, and
+//! reports the chain the peer presented through
+//! [`Acceptor::accept_with_peer`](crate::Acceptor::accept_with_peer). A
+//! [`CertificateVerifier`] set with
+//! [`Server::with_client_verifier`](crate::Server::with_client_verifier) then
+//! decides who the peer is. [`CaBundleVerifier`] validates the chain against
+//! a bundle of trusted CAs with rustls' WebPKI verifier, then names the peer
+//! with another verifier; [`PinnedCertificates`] names known certificates, and
+//! [`SpiffeVerifier`](crate::SpiffeVerifier) reads the SPIFFE ID of an X.509-SVID.
+//! Any other `Fn(&[Bytes])` wraps a custom validator.
+
+use crate::ProtocolError;
+use bytes::Bytes;
+use rustls::pki_types::{pem::PemObject, CertificateDer, UnixTime};
+use rustls::server::{danger::ClientCertVerifier, WebPkiClientVerifier};
+use rustls::RootCertStore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Checks a peer's certificate chain and names the identity it proves.
+///
+/// Implemented for any `Fn(&[Bytes]) -> Result<String, ProtocolError>`.
+pub trait CertificateVerifier: Send + Sync {
+    /// `chain` holds DER-encoded certificates, the peer's own first, and is never empty
+    fn verify(&self, chain: &[Bytes]) -> Result<String, ProtocolError>;
+}
+
+impl<F> CertificateVerifier for F
+where
+    F: Fn(&[Bytes]) -> Result<String, ProtocolError> + Send + Sync,
+{
+    fn verify(&self, chain: &[Bytes]) -> Result<String, ProtocolError> {
+        self(chain)
+    }
+}
+
+/// Accepts only peers whose own certificate is one of a known set, by SHA-256 fingerprint
+#[derive(Debug, Clone, Default)]
+pub struct PinnedCertificates {
+    identities: HashMap<[u8; 32], String>,
+}
+
+impl PinnedCertificates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the DER-encoded `certificate` as `identity`
+    pub fn with_certificate(self, certificate: &[u8], identity: impl Into<String>) -> Self {
+        self.with_fingerprint(fingerprint(certificate), identity)
+    }
+
+    /// Trusts the certificate with SHA-256 `fingerprint` as `identity`
+    pub fn with_fingerprint(mut self, fingerprint: [u8; 32], identity: impl Into<String>) -> Self {
+        self.identities.insert(fingerprint, identity.into());
+        self
+    }
+}
+
+impl CertificateVerifier for PinnedCertificates {
+    fn verify(&self, chain: &[Bytes]) -> Result<String, ProtocolError> {
+        chain
+            .first()
+            .and_then(|certificate| self.identities.get(&fingerprint(certificate)))
+            .cloned()
+            .ok_or_else(|| ProtocolError::HandshakeFailed("Untrusted client certificate".into()))
+    }
+}
+
+/// Accepts only peers whose chain leads to one of a bundle of trusted CA certificates.
+///
+/// Validated peers are named by the hex SHA-256 fingerprint of their own
+/// certificate, unless [`with_identity`](Self::with_identity) names them otherwise.
+pub struct CaBundleVerifier {
+    webpki: Arc<dyn ClientCertVerifier>,
+    identity: Box<dyn CertificateVerifier>,
+}
+
+impl CaBundleVerifier {
+    /// Trusts the DER-encoded CA `certificates`
+    pub fn new<C: AsRef<[u8]>>(certificates: impl IntoIterator<Item = C>) -> Result<Self, ProtocolError> {
+        let mut roots = RootCertStore::empty();
+        for certificate in certificates {
+            roots
+                .add(CertificateDer::from(certificate.as_ref()))
+                .map_err(|e| ProtocolError::InvalidFormat(format!("Invalid CA certificate: {e}")))?;
+        }
+        let webpki = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(rustls::crypto::ring::default_provider()))
+            .build()
+            .map_err(|e| ProtocolError::InvalidFormat(format!("Invalid CA bundle: {e}")))?;
+        Ok(Self {
+            webpki,
+            identity: Box::new(|chain: &[Bytes]| Ok(fingerprint(&chain[0]).iter().map(|byte| format!("{byte:02x}")).collect())),
+        })
+    }
+
+    /// Trusts the CA certificates of a PEM bundle, e.g. a `ca.pem` file's contents
+    pub fn from_pem(bundle: &[u8]) -> Result<Self, ProtocolError> {
+        let certificates = CertificateDer::pem_slice_iter(bundle)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ProtocolError::InvalidFormat(format!("Invalid CA bundle: {e}")))?;
+        Self::new(certificates)
+    }
+
+    /// Names validated peers with `identity`, e.g. a [`SpiffeVerifier`](crate::SpiffeVerifier)
+    pub fn with_identity(mut self, identity: impl CertificateVerifier + 'static) -> Self {
+        self.identity = Box::new(identity);
+        self
+    }
+}
+
+impl CertificateVerifier for CaBundleVerifier {
+    fn verify(&self, chain: &[Bytes]) -> Result<String, ProtocolError> {
+        let (end_entity, intermediates) = chain.split_first().ok_or_else(|| ProtocolError::HandshakeFailed("No client certificate".into()))?;
+        let intermediates: Vec<_> = intermediates.iter().map(|certificate| CertificateDer::from(&certificate[..])).collect();
+        self.webpki
+            .verify_client_cert(&CertificateDer::from(&end_entity[..]), &intermediates, UnixTime::now())
+            .map_err(|e| ProtocolError::HandshakeFailed(format!("Untrusted client certificate: {e}")))?;
+        self.identity.verify(chain)
+    }
+}
+
+impl fmt::Debug for CaBundleVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaBundleVerifier").finish_non_exhaustive()
+    }
+}
+
+/// Returns the SHA-256 fingerprint of a DER-encoded certificate
+pub fn fingerprint(certificate: &[u8]) -> [u8; 32] {
+    Sha256::digest(certificate).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Accepted, Acceptor, Io, Request, Router, Server};
+    use crate::{Message, MessageFlags, MessageType, Transport};
+    use futures::future::BoxFuture;
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Stands in for a TLS acceptor whose peer presented `certificate`
+    struct PresentedCertificate(Option<Bytes>);
+
+    impl Acceptor for PresentedCertificate {
+        fn accept(&self, stream: TcpStream) -> BoxFuture<'static, Result<Box<dyn Io>, ProtocolError>> {
+            Box::pin(async move { Ok(Box::new(stream) as Box<dyn Io>) })
+        }
+
+        fn accept_with_peer(&self, stream: TcpStream) -> BoxFuture<'static, Result<Accepted, ProtocolError>> {
+            let peer_certificates = self.0.iter().cloned().collect();
+            Box::pin(async move {
+                Ok(Accepted {
+                    stream: Box::new(stream),
+                    peer_certificates,
+                })
+            })
+        }
+    }
+
+    async fn whoami(presented: Option<&'static str>) -> Result<Message, ProtocolError> {
+        let router = Router::new().with_route("whoami", |request: Request| async move {
+            let connection = request.connection();
+            assert_eq!(connection.peer_certificates().len(), 1);
+            Ok(Bytes::from(connection.identity().unwrap_or_default()))
+        });
+        let server = Server::new(router)
+            .with_acceptor(PresentedCertificate(presented.map(|certificate| Bytes::from_static(certificate.as_bytes()))))
+            .with_client_verifier(PinnedCertificates::new().with_certificate(b"sensor-1 cert", "sensor-1"));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut transport = Transport::new(TcpStream::connect(address).await.unwrap());
+        let mut request = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::new());
+        request.routing_info = Some("whoami".into());
+        transport.send(request).await?;
+        transport.receive().await
+    }
+
+    // A CA, a client certificate it issued, and one issued by a CA nobody trusts
+    const TRUSTED_CA: &str = "-----BEGIN CERTIFICATE-----
+MIIBXjCCAQOgAwIBAgIBATAKBggqhkjOPQQDAjAVMRMwEQYDVQQDDAp0cnVzdGVk
+IGNhMCAXDTI2MTAxNDExMzQwNVoYDzIxMjYwOTIwMTEzNDA1WjAVMRMwEQYDVQQD
+DAp0cnVzdGVkIGNhMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEWT3H9Qu+zp4E
+Ai3HN6bm4eoZHy56Etix+gJRrPh6z30+PZep88WYz8SDRKcF7v0Ib2uBiGcJse/9
+OAmvfKye0qNCMEAwDwYDVR0TAQH/BAUwAwEB/zAOBgNVHQ8BAf8EBAMCAgQwHQYD
+VR0OBBYEFPJg+pWSvDJmJP1C61Vx+g89AhbNMAoGCCqGSM49BAMCA0kAMEYCIQCV
+QDOf+4UTaDG5lFhMDpFDEUfdk/+7fuEdq5pUQNM+jAIhAIXI5V169bb98Ju2qH4Z
+1hH4k1f7OlUB4hrp6ackZj6l
+-----END CERTIFICATE-----";
+    const TRUSTED_CLIENT: &str = "-----BEGIN CERTIFICATE-----
+MIIBpDCCAUugAwIBAgIBAjAKBggqhkjOPQQDAjAVMRMwEQYDVQQDDAp0cnVzdGVk
+IGNhMCAXDTI2MTAxNDExMzQwNVoYDzIxMjYwOTIwMTEzNDA1WjATMREwDwYDVQQD
+DAhzZW5zb3ItMTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABEJG5cBRBJtVTBEM
+PbBnzsidb6j7GvqnMz3wsB3tXxb8c1VJHwlgkmJKOfa6C/8foiV0oshyjFDpAmIb
+yfBtJ+ajgYswgYgwDAYDVR0TAQH/BAIwADAOBgNVHQ8BAf8EBAMCB4AwEwYDVR0l
+BAwwCgYIKwYBBQUHAwIwEwYDVR0RBAwwCoIIc2Vuc29yLTEwHQYDVR0OBBYEFJ9X
+6TM0tECCvGNFxSnilGYgX4WYMB8GA1UdIwQYMBaAFPJg+pWSvDJmJP1C61Vx+g89
+AhbNMAoGCCqGSM49BAMCA0cAMEQCIHJpWD6xrwntL/kjD8+XuGMlwbe4tUNl4Tsl
+LdldlZ4/AiApfABbOfzbeAteU88cVgSu4WdiGDFPpkmpobm6Mqxueg==
+-----END CERTIFICATE-----";
+    const ROGUE_CLIENT: &str = "-----BEGIN CERTIFICATE-----
+MIIBozCCAUmgAwIBAgIBAjAKBggqhkjOPQQDAjATMREwDwYDVQQDDAhyb2d1ZSBj
+YTAgFw0yNjEwMTQxMTM0MDVaGA8yMTI2MDkyMDExMzQwNVowEzERMA8GA1UEAwwI
+c2Vuc29yLTEwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQLwrgfPpHGyMLimVpE
+3q92gCQ/2WFgU5twKNidIu9OKCurVsobWE7WhUfLGV7c/VLXf6vECu+mvWULS7lJ
+BG5eo4GLMIGIMAwGA1UdEwEB/wQCMAAwDgYDVR0PAQH/BAQDAgeAMBMGA1UdJQQM
+MAoGCCsGAQUFBwMCMBMGA1UdEQQMMAqCCHNlbnNvci0xMB0GA1UdDgQWBBS3R2mO
+xUVqCNuOaPXcu/XQsKIquDAfBgNVHSMEGDAWgBSPG4Bzf/uBYxstXp5X9nERKrTy
+gTAKBggqhkjOPQQDAgNIADBFAiB5VLD8WuIa2nY5MALYAHlN0vfVd3RsX4sjPUWs
+/gMDtQIhAJmSfwPGbYSBsUtvmyMOoBBrxS4QHKHlMgwpUNXLoCsh
+-----END CERTIFICATE-----";
+
+    fn der(pem: &str) -> Bytes {
+        Bytes::from(CertificateDer::from_pem_slice(pem.as_bytes()).unwrap().to_vec())
+    }
+
+    #[test]
+    fn test_ca_bundle_rejects_certificates_from_other_cas() {
+        let verifier = CaBundleVerifier::from_pem(TRUSTED_CA.as_bytes()).unwrap();
+        let client = der(TRUSTED_CLIENT);
+        let expected: String = fingerprint(&client).iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(verifier.verify(std::slice::from_ref(&client)).unwrap(), expected);
+        assert!(matches!(verifier.verify(&[der(ROGUE_CLIENT)]), Err(ProtocolError::HandshakeFailed(_))));
+        // The CA's own certificate is not a client certificate
+        assert!(verifier.verify(&[der(TRUSTED_CA)]).is_err());
+
+        let named = CaBundleVerifier::from_pem(TRUSTED_CA.as_bytes()).unwrap().with_identity(PinnedCertificates::new().with_certificate(&client, "sensor-1"));
+        assert_eq!(named.verify(&[client]).unwrap(), "sensor-1");
+        assert!(named.verify(&[der(ROGUE_CLIENT)]).is_err());
+        assert!(CaBundleVerifier::from_pem(b"not a bundle").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_sets_connection_identity() {
+        assert_eq!(whoami(Some("sensor-1 cert")).await.unwrap().payload, Bytes::from("sensor-1"));
+        assert!(whoami(Some("someone else")).await.is_err());
+        assert!(whoami(None).await.is_err());
+    }
+}
@@ -1,67 +1,1019 @@
-use crate::{Message, ProtocolError};
-use bytes::{Buf, BufMut, BytesMut};
+use crate::{
+    buffer::BufferPool,
+    codec::RemusCodec,
+    compression::{compress_if_beneficial, CompressionConfig},
+    encryption::{Cipher, Encryptor, StreamOpener, StreamSealer},
+    middleware::TransportMiddleware,
+    observability::Metric,
+    ratelimit::{Pacer, RateLimiter},
+    secret::wipe,
+    Message, MessageFlags, MessageType, ProtocolError,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::io::IoSlice;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tokio_util::codec::Decoder;
+
+/// Default number of queued outbound bytes above which sends must wait
+pub const DEFAULT_HIGH_WATERMARK: usize = 1024 * 1024;
+
+/// Payloads at least this large are queued by reference and written with
+/// vectored I/O instead of being copied behind their header
+pub const VECTORED_PAYLOAD_THRESHOLD: usize = 4 * 1024;
+
+/// Size of the fragments `send_streaming` splits a payload into
+pub const STREAMING_CHUNK_SIZE: usize = 64 * 1024;
+
+// Upper bound on the number of slices passed to a single vectored write
+const MAX_WRITE_SLICES: usize = 64;
+
+// Upper bound on the chunked streams a peer may have open towards us at once
+const MAX_CHUNKED_STREAMS: usize = 1024;
+
+/// Error returned by [`Transport::try_send`], handing the message back to the caller
+#[derive(Debug, Error)]
+pub enum TrySendError {
+    #[error("Outbound queue is above the high watermark")]
+    Full(Message),
+    #[error("Transport is closed")]
+    Closed(Message),
+    #[error("Send rate limit exceeded")]
+    RateLimited(Message),
+    #[error("Message rejected by middleware: {1}")]
+    Rejected(Message, Box<ProtocolError>),
+}
+
+impl TrySendError {
+    /// Returns the message that could not be queued
+    pub fn into_inner(self) -> Message {
+        match self {
+            TrySendError::Full(message)
+            | TrySendError::Closed(message)
+            | TrySendError::RateLimited(message)
+            | TrySendError::Rejected(message, _) => message,
+        }
+    }
+}
+
+/// Keepalive settings for detecting dead or idle connections
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    /// Silence on the read side after which a Ping is sent
+    pub interval: Duration,
+    /// Silence on the read side after which the peer is considered dead
+    pub timeout: Duration,
+    /// Time without application messages after which the connection is closed
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(45),
+            idle_timeout: None,
+        }
+    }
+}
+
+/// Per-connection counters, shareable with monitoring tasks while the transport runs
+#[derive(Debug, Default)]
+pub struct TransportStats {
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    decode_errors: AtomicU64,
+    retransmits: AtomicU64,
+    queue_depth: AtomicUsize,
+    compressed: AtomicU64,
+    compression_skipped: AtomicU64,
+    bytes_before_compression: AtomicU64,
+    bytes_after_compression: AtomicU64,
+    compression_micros: AtomicU64,
+}
+
+/// Point-in-time copy of [`TransportStats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportStatsSnapshot {
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub decode_errors: u64,
+    pub retransmits: u64,
+    pub queue_depth: usize,
+    /// Messages flagged COMPRESSED that were sent compressed
+    pub compressed: u64,
+    /// Messages flagged COMPRESSED that were sent as they were, because the
+    /// config left them alone or compressing did not shrink them
+    pub compression_skipped: u64,
+    /// Payload bytes of the compressed messages, before and after compression
+    pub bytes_before_compression: u64,
+    pub bytes_after_compression: u64,
+    /// Time spent compressing, skipped attempts included
+    pub compression_micros: u64,
+}
+
+impl TransportStats {
+    pub fn snapshot(&self) -> TransportStatsSnapshot {
+        TransportStatsSnapshot {
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            retransmits: self.retransmits.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            compressed: self.compressed.load(Ordering::Relaxed),
+            compression_skipped: self.compression_skipped.load(Ordering::Relaxed),
+            bytes_before_compression: self.bytes_before_compression.load(Ordering::Relaxed),
+            bytes_after_compression: self.bytes_after_compression.load(Ordering::Relaxed),
+            compression_micros: self.compression_micros.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_compression(&self, before: usize, after: Option<usize>, elapsed: Duration) {
+        self.compression_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        match after {
+            Some(after) => {
+                self.compressed.fetch_add(1, Ordering::Relaxed);
+                self.bytes_before_compression.fetch_add(before as u64, Ordering::Relaxed);
+                self.bytes_after_compression.fetch_add(after as u64, Ordering::Relaxed);
+            }
+            None => {
+                self.compression_skipped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Counts a message that a higher layer had to send again
+    pub fn record_retransmit(&self) {
+        self.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl TransportStatsSnapshot {
+    /// Compressed size over original size of the compressed messages, if there were any
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.bytes_before_compression > 0).then(|| self.bytes_after_compression as f64 / self.bytes_before_compression as f64)
+    }
+
+    /// Payload bytes compression kept off the wire
+    pub fn compression_bytes_saved(&self) -> u64 {
+        self.bytes_before_compression.saturating_sub(self.bytes_after_compression)
+    }
+
+    /// Converts the snapshot into metrics for `Telemetry::record_metric`
+    pub fn to_metrics(&self, labels: HashMap<String, String>) -> Vec<Metric> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        [
+            ("transport.frames_sent", self.frames_sent as f64),
+            ("transport.frames_received", self.frames_received as f64),
+            ("transport.bytes_sent", self.bytes_sent as f64),
+            ("transport.bytes_received", self.bytes_received as f64),
+            ("transport.decode_errors", self.decode_errors as f64),
+            ("transport.retransmits", self.retransmits as f64),
+            ("transport.queue_depth", self.queue_depth as f64),
+            ("transport.compression.compressed", self.compressed as f64),
+            ("transport.compression.skipped", self.compression_skipped as f64),
+            ("transport.compression.bytes_saved", self.compression_bytes_saved() as f64),
+            ("transport.compression.ratio", self.compression_ratio().unwrap_or(1.0)),
+            ("transport.compression.micros", self.compression_micros as f64),
+        ]
+        .into_iter()
+        .map(|(name, value)| Metric {
+            name: name.to_string(),
+            value,
+            timestamp,
+            labels: labels.clone(),
+        })
+        .collect()
+    }
+}
+
+/// Corking settings that batch small frames into fewer write/flush cycles
+#[derive(Debug, Clone, Copy)]
+pub struct CoalesceConfig {
+    /// Longest a queued frame may wait before a flush is forced
+    pub max_delay: Duration,
+    /// Queued bytes at which a flush happens immediately
+    pub max_bytes: usize,
+}
+
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::from_millis(1),
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Slow-peer protection: a frame that has started arriving must keep arriving
+/// at least this fast once the grace period has passed
+#[derive(Debug, Clone, Copy)]
+pub struct MinThroughput {
+    pub bytes_per_sec: u64,
+    pub grace: Duration,
+}
 
 pub struct Transport<T> {
     inner: T,
+    codec: RemusCodec,
     read_buf: BytesMut,
     write_buf: BytesMut,
+    write_queue: VecDeque<Bytes>,
+    queued: usize,
+    high_watermark: usize,
+    buffer_pool: Option<Arc<BufferPool>>,
+    send_limiter: Option<RateLimiter>,
+    receive_limiter: Option<RateLimiter>,
+    pacer: Option<Pacer>,
+    stats: Arc<TransportStats>,
+    coalesce: Option<CoalesceConfig>,
+    oldest_queued: Option<Instant>,
+    middleware: Vec<Arc<dyn TransportMiddleware>>,
+    encryptor: Option<Arc<Encryptor>>,
+    compression: CompressionConfig,
+    compression_passthrough: bool,
+    // Most a received payload may decompress to; the max frame length unless set
+    max_decompressed_size: Option<usize>,
+    chunked_encryption: bool,
+    stream_sealers: HashMap<u64, StreamSealer>,
+    stream_openers: HashMap<u64, StreamOpener>,
+    // Decoded by a receive cancelled while throttled, and returned by the next one
+    throttled: Option<Message>,
+    read_timeout: Option<Duration>,
+    min_throughput: Option<MinThroughput>,
+    frame_started: Option<Instant>,
+    keepalive: Option<KeepaliveConfig>,
+    last_received: Instant,
+    last_ping: Instant,
+    last_activity: Instant,
+    goaway_sent: bool,
+    goaway_received: bool,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Transport<T> {
     pub fn new(inner: T) -> Self {
+        let now = Instant::now();
         Self {
             inner,
+            codec: RemusCodec::new(),
             read_buf: BytesMut::with_capacity(8 * 1024),
             write_buf: BytesMut::with_capacity(8 * 1024),
+            write_queue: VecDeque::new(),
+            queued: 0,
+            high_watermark: DEFAULT_HIGH_WATERMARK,
+            buffer_pool: None,
+            send_limiter: None,
+            receive_limiter: None,
+            pacer: None,
+            stats: Arc::new(TransportStats::default()),
+            coalesce: None,
+            oldest_queued: None,
+            middleware: Vec::new(),
+            encryptor: None,
+            compression: CompressionConfig::default(),
+            compression_passthrough: false,
+            max_decompressed_size: None,
+            chunked_encryption: false,
+            stream_sealers: HashMap::new(),
+            stream_openers: HashMap::new(),
+            throttled: None,
+            read_timeout: None,
+            min_throughput: None,
+            frame_started: None,
+            keepalive: None,
+            last_received: now,
+            last_ping: now,
+            last_activity: now,
+            goaway_sent: false,
+            goaway_received: false,
         }
     }
 
+    /// Draws the read and write buffers from a shared pool and returns them on drop
+    pub fn with_buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.read_buf = pool.acquire(8 * 1024);
+        self.write_buf = pool.acquire(8 * 1024);
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Throttles outbound application messages through `limiter`, charging each
+    /// its payload length before compression and encryption
+    pub fn with_send_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.send_limiter = Some(limiter);
+        self
+    }
+
+    /// Throttles inbound application messages through `limiter`.
+    ///
+    /// Reading pauses while throttled, so TCP backpressure slows the peer down.
+    pub fn with_receive_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.receive_limiter = Some(limiter);
+        self
+    }
+
+    /// Paces all outbound bytes through `pacer` so bulk sends are spread over time
+    pub fn with_pacing(mut self, pacer: Pacer) -> Self {
+        self.pacer = Some(pacer);
+        self
+    }
+
+    /// Records counters into `stats`, e.g. to aggregate several connections
+    pub fn with_stats(mut self, stats: Arc<TransportStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Returns the live counters for this connection
+    pub fn stats(&self) -> &Arc<TransportStats> {
+        &self.stats
+    }
+
+    /// Enables write coalescing.
+    ///
+    /// `send` then only queues frames, flushing once `max_bytes` are queued or the
+    /// oldest frame has waited `max_delay`. The delay bound is enforced by the next
+    /// `send` or while `receive` waits; call `flush` when neither will happen soon.
+    pub fn with_coalescing(mut self, config: CoalesceConfig) -> Self {
+        self.coalesce = Some(config);
+        self
+    }
+
+    /// Adds a middleware layer; the first layer added is the outermost
+    pub fn with_middleware(mut self, middleware: Arc<dyn TransportMiddleware>) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Encrypts every frame sent, flagging it ENCRYPTED, and rejects received frames that are not
+    pub fn with_encryption(self, key: &[u8; 32]) -> Self {
+        self.with_encryption_cipher(key, Cipher::Aes256Gcm)
+    }
+
+    /// Like [`with_encryption`](Self::with_encryption), sealing payloads with `cipher`
+    pub fn with_encryption_cipher(self, key: &[u8; 32], cipher: Cipher) -> Self {
+        self.with_encryptor(Arc::new(Encryptor::with_cipher(key, cipher)))
+    }
+
+    /// Encrypts with a configured `encryptor`, e.g. one using session nonces
+    /// shared by successive connections
+    pub fn with_encryptor(mut self, encryptor: Arc<Encryptor>) -> Self {
+        self.encryptor = Some(encryptor);
+        self
+    }
+
+    /// Compresses messages flagged COMPRESSED as `config` says rather than
+    /// with zstd at the default level, e.g. as negotiated by
+    /// [`compression::initiate`](crate::compression::initiate)
+    pub fn with_compression(mut self, config: impl Into<CompressionConfig>) -> Self {
+        self.compression = config.into();
+        self
+    }
+
+    /// Switches how later messages are compressed; the peer reads the
+    /// algorithm from each payload, so it needs no notice
+    pub fn set_compression(&mut self, config: impl Into<CompressionConfig>) {
+        self.compression = config.into();
+    }
+
+    /// Leaves payloads flagged COMPRESSED as they are: received ones are not
+    /// decompressed, and ones sent are taken to be compressed already. A
+    /// proxy relaying frames between two transports set this way forwards
+    /// compressed payloads byte for byte.
+    pub fn with_compression_passthrough(mut self) -> Self {
+        self.compression_passthrough = true;
+        self
+    }
+
+    /// Rejects received payloads whose original size, as they claim it, exceeds `max` bytes, by
+    /// default the max frame length; they are rejected before being decompressed, so a small
+    /// frame cannot inflate into more memory than a large one would take
+    pub fn with_max_decompressed_size(mut self, max: usize) -> Self {
+        self.max_decompressed_size = Some(max);
+        self
+    }
+
+    /// Seals the encrypted `Stream` frames of each request id as one chunked
+    /// stream, so fragments cannot be reordered, dropped or cut short
+    /// undetected; the peer must enable it too. See [`StreamSealer`].
+    pub fn with_chunked_encryption(mut self) -> Self {
+        self.chunked_encryption = true;
+        self
+    }
+
+    /// Rejects frames, sent or received, whose encoded length exceeds `max` bytes, by default
+    /// [`DEFAULT_MAX_FRAME_LENGTH`](crate::codec::DEFAULT_MAX_FRAME_LENGTH)
+    pub fn with_max_frame_length(mut self, max: usize) -> Self {
+        self.codec = std::mem::take(&mut self.codec).with_max_frame_length(max);
+        self
+    }
+
+    /// Fails a read once no bytes at all have arrived for `timeout`, counted from
+    /// the last bytes received rather than from the start of each receive
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails a receive whose partially arrived frame trickles in slower than `min`
+    pub fn with_min_throughput(mut self, min: MinThroughput) -> Self {
+        self.min_throughput = Some(min);
+        self
+    }
+
+    /// Enables automatic Ping/Pong keepalive and idle detection on receive
+    pub fn with_keepalive(mut self, config: KeepaliveConfig) -> Self {
+        self.keepalive = Some(config);
+        self
+    }
+
+    /// Sets the number of queued outbound bytes above which sends must wait
+    pub fn with_high_watermark(mut self, bytes: usize) -> Self {
+        self.high_watermark = bytes;
+        self
+    }
+
+    /// Queues a message once there is room below the high watermark and flushes it
     pub async fn send(&mut self, message: Message) -> Result<(), ProtocolError> {
-        let encoded = message.encode();
-        let len = encoded.len() as u32;
-        
-        // Write length prefix
-        self.write_buf.put_u32(len);
-        self.write_buf.extend_from_slice(&encoded);
-        
-        // Write to underlying transport
-        while !self.write_buf.is_empty() {
-            let bytes_written = self.inner.write(&self.write_buf).await?;
-            self.write_buf.advance(bytes_written);
-        }
-        
-        self.inner.flush().await?;
+        self.queue(message).await?;
+        if self.flush_due() {
+            self.flush().await?;
+        }
         Ok(())
     }
 
-    pub async fn receive(&mut self) -> Result<Message, ProtocolError> {
+    /// Queues several messages and flushes them together, in as few writes as
+    /// the high watermark allows
+    pub async fn send_all(&mut self, messages: impl IntoIterator<Item = Message>) -> Result<(), ProtocolError> {
+        for message in messages {
+            self.queue(message).await?;
+        }
+        self.flush().await
+    }
+
+    // Waits for room and the rate limit first, then runs middleware, seals and
+    // enqueues without awaiting, so a cancelled send advances no nonce or stream sealer
+    async fn queue(&mut self, mut message: Message) -> Result<(), ProtocolError> {
+        self.reserve().await?;
+        if let Some(limiter) = &mut self.send_limiter {
+            limiter.acquire(message.payload.len()).await;
+        }
+        for layer in &self.middleware {
+            layer.on_send(&mut message)?;
+        }
+        self.seal_payload(&mut message)?;
+        self.last_activity = Instant::now();
+        self.enqueue(&message)
+    }
+
+    /// Sends `header` followed by `len` bytes read from `reader`, without buffering the whole payload.
+    ///
+    /// The body is fragmented into `Stream` frames of up to [`STREAMING_CHUNK_SIZE`]
+    /// bytes sharing the header's request id, ending with a `StreamEnd` frame. Each
+    /// fragment waits on the high watermark, so memory use stays bounded however
+    /// large `len` is. Fragments inherit the header's COMPRESSED and ENCRYPTED flags;
+    /// with [`with_chunked_encryption`](Self::with_chunked_encryption) the encrypted
+    /// fragments are chunks of one stream rather than separate messages.
+    pub async fn send_streaming<R: AsyncRead + Unpin>(
+        &mut self,
+        header: Message,
+        reader: R,
+        len: u64,
+    ) -> Result<(), ProtocolError> {
+        let request_id = header.request_id;
+        let flags = header.flags & (MessageFlags::COMPRESSED | MessageFlags::ENCRYPTED);
+        self.send(header).await?;
+
+        let sent = self.send_fragments(request_id, flags, reader, len).await;
+        if sent.is_err() {
+            // A later stream reusing the id must start with a fresh sealer
+            self.stream_sealers.remove(&request_id);
+        }
+        sent
+    }
+
+    async fn send_fragments<R: AsyncRead + Unpin>(
+        &mut self,
+        request_id: u64,
+        flags: MessageFlags,
+        reader: R,
+        len: u64,
+    ) -> Result<(), ProtocolError> {
+        let mut reader = reader.take(len);
+        let mut remaining = len;
         loop {
-            // Try to read the length prefix
-            if self.read_buf.len() < 4 {
-                let bytes_read = self.inner.read_buf(&mut self.read_buf).await?;
-                if bytes_read == 0 {
-                    return Err(ProtocolError::InvalidFormat("Connection closed".into()));
+            let size = remaining.min(STREAMING_CHUNK_SIZE as u64) as usize;
+            let mut chunk = BytesMut::with_capacity(size);
+            while chunk.len() < size {
+                if reader.read_buf(&mut chunk).await? == 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("Stream ended {} bytes short", remaining - chunk.len() as u64),
+                    )
+                    .into());
                 }
+            }
+            remaining -= size as u64;
+
+            let msg_type = if remaining == 0 { MessageType::StreamEnd } else { MessageType::Stream };
+            self.send(Message::new(msg_type, flags, request_id, chunk.freeze())).await?;
+            if remaining == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Queues a message without waiting, failing if the peer is not keeping up.
+    ///
+    /// Queued messages are written by the next `flush`, `send`, or `poll_ready`.
+    /// A message handed back as `Full` or `RateLimited` is untouched, so it can
+    /// be retried as is; the rate limit charges its payload before compression.
+    pub fn try_send(&mut self, mut message: Message) -> Result<(), TrySendError> {
+        if self.goaway_sent {
+            return Err(TrySendError::Closed(message));
+        }
+        // Both checks come before middleware and sealing, which advance nonces and stream sealers
+        if self.queued >= self.high_watermark {
+            return Err(TrySendError::Full(message));
+        }
+        if let Some(limiter) = &mut self.send_limiter {
+            if limiter.try_acquire(message.payload.len()).is_err() {
+                limiter.record_throttle();
+                return Err(TrySendError::RateLimited(message));
+            }
+        }
+        for layer in &self.middleware {
+            if let Err(e) = layer.on_send(&mut message) {
+                return Err(TrySendError::Rejected(message, Box::new(e)));
+            }
+        }
+        if let Err(e) = self.seal_payload(&mut message) {
+            return Err(TrySendError::Rejected(message, Box::new(e)));
+        }
+        if let Err(e) = self.enqueue(&message) {
+            return Err(TrySendError::Rejected(message, Box::new(e)));
+        }
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    /// Writes queued bytes until the queue is below the high watermark
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        while self.queued >= self.high_watermark {
+            ready!(self.poll_write_queued(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes queued bytes and flushes the underlying stream
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        while self.queued > 0 {
+            ready!(self.poll_write_queued(cx))?;
+        }
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    // Performs one vectored write from the front of the queue, paced if configured.
+    //
+    // Queued bytes are the frozen segments in `write_queue` followed by the
+    // still-growing tail in `write_buf`.
+    fn poll_write_queued(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ProtocolError>> {
+        let mut len = self.queued;
+        if let Some(pacer) = &mut self.pacer {
+            len = ready!(pacer.poll_admit(cx, len));
+        }
+
+        let mut slices = [IoSlice::new(&[]); MAX_WRITE_SLICES];
+        let mut count = 0;
+        let segments = self.write_queue.iter().map(|b| &b[..]).chain(std::iter::once(&self.write_buf[..]));
+        for segment in segments {
+            if len == 0 || count == MAX_WRITE_SLICES {
+                break;
+            }
+            if segment.is_empty() {
                 continue;
             }
+            let take = segment.len().min(len);
+            slices[count] = IoSlice::new(&segment[..take]);
+            count += 1;
+            len -= take;
+        }
+
+        let written = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, &slices[..count]))?;
+        if written == 0 {
+            return Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()));
+        }
+        if let Some(pacer) = &mut self.pacer {
+            pacer.commit(written);
+        }
+        self.advance_queue(written);
+        self.stats.bytes_sent.fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
 
-            // Parse message length
-            let len = (&self.read_buf[..4]).get_u32() as usize;
-            
-            // Wait for complete message
-            if self.read_buf.len() < 4 + len {
-                let bytes_read = self.inner.read_buf(&mut self.read_buf).await?;
-                if bytes_read == 0 {
-                    return Err(ProtocolError::InvalidFormat("Connection closed".into()));
+    fn advance_queue(&mut self, mut written: usize) {
+        self.queued -= written;
+        while written > 0 {
+            match self.write_queue.front_mut() {
+                Some(front) if front.len() <= written => {
+                    written -= front.len();
+                    self.write_queue.pop_front();
+                }
+                Some(front) => {
+                    front.advance(written);
+                    written = 0;
+                }
+                None => {
+                    self.write_buf.advance(written);
+                    written = 0;
                 }
-                continue;
             }
+        }
+        if self.queued == 0 {
+            self.oldest_queued = None;
+        }
+        self.stats.queue_depth.store(self.queued, Ordering::Relaxed);
+    }
 
-            // We have a complete message
-            self.read_buf.advance(4); // Skip length prefix
-            let message_data = self.read_buf.split_to(len);
-            return Message::decode(&message_data);
+    /// Waits until there is room below the high watermark
+    pub async fn reserve(&mut self) -> Result<(), ProtocolError> {
+        poll_fn(|cx| self.poll_ready(cx)).await
+    }
+
+    /// Writes all queued bytes and flushes the underlying stream
+    pub async fn flush(&mut self) -> Result<(), ProtocolError> {
+        poll_fn(|cx| self.poll_flush(cx)).await
+    }
+
+    /// Returns the number of outbound bytes queued but not yet written
+    pub fn queued_bytes(&self) -> usize {
+        self.queued
+    }
+
+    /// Receives the next application message.
+    ///
+    /// Ping and Pong frames are handled internally and never returned. Messages
+    /// flagged REQUIRES_ACK are acknowledged with an Ack frame on receipt. A GoAway
+    /// from the peer surfaces as `ProtocolError::GoAway`, while a connection that
+    /// drops without one surfaces as `ProtocolError::ConnectionClosed`.
+    ///
+    /// Cancel-safe: a message decoded before the receive rate limit let it
+    /// through is kept and returned by the next call.
+    pub async fn receive(&mut self) -> Result<Message, ProtocolError> {
+        let message = match self.throttled.take() {
+            Some(message) => message,
+            None => self.receive_decoded().await?,
+        };
+        if let Some(limiter) = &mut self.receive_limiter {
+            let len = message.payload.len();
+            self.throttled = Some(message);
+            limiter.acquire(len).await;
+            return Ok(self.throttled.take().unwrap());
+        }
+        Ok(message)
+    }
+
+    // Reads the next application message, answering control frames on the way
+    async fn receive_decoded(&mut self) -> Result<Message, ProtocolError> {
+        loop {
+            if self.goaway_received {
+                return Err(ProtocolError::GoAway("Peer already sent GoAway".into()));
+            }
+            let mut message = self.read_message().await?;
+            self.open_payload(&mut message)?;
+            match message.msg_type {
+                MessageType::Ping => {
+                    let pong = Message::new(
+                        MessageType::Pong,
+                        MessageFlags::NONE,
+                        message.request_id,
+                        Bytes::new(),
+                    );
+                    self.write_message(pong).await?;
+                }
+                MessageType::Pong => {}
+                MessageType::GoAway => {
+                    self.goaway_received = true;
+                    let reason = String::from_utf8_lossy(&message.payload).into_owned();
+                    return Err(ProtocolError::GoAway(reason));
+                }
+                _ => {
+                    for layer in self.middleware.iter().rev() {
+                        layer.on_receive(&mut message)?;
+                    }
+                    if message.flags.contains(MessageFlags::REQUIRES_ACK) && message.msg_type != MessageType::Ack {
+                        // Queued rather than flushed here so receive stays cancel-safe;
+                        // it is written before the next read
+                        let mut ack = Message::new(MessageType::Ack, MessageFlags::NONE, message.request_id, Bytes::new());
+                        self.seal_payload(&mut ack)?;
+                        self.enqueue(&ack)?;
+                    }
+                    self.last_activity = Instant::now();
+                    return Ok(message);
+                }
+            }
+        }
+    }
+
+    /// Gracefully closes the connection.
+    ///
+    /// Sends a GoAway frame carrying `reason`, drains messages the peer still had
+    /// in flight until it answers with its own GoAway or closes, then shuts down the
+    /// write half. The drained messages are returned so they are not lost.
+    pub async fn close(&mut self, reason: &str) -> Result<Vec<Message>, ProtocolError> {
+        if !self.goaway_sent {
+            let goaway = Message::new(
+                MessageType::GoAway,
+                MessageFlags::NONE,
+                0,
+                Bytes::copy_from_slice(reason.as_bytes()),
+            );
+            self.write_message(goaway).await?;
+            self.goaway_sent = true;
+        }
+
+        let mut drained = Vec::new();
+        while !self.goaway_received {
+            match self.receive().await {
+                Ok(message) => drained.push(message),
+                Err(ProtocolError::GoAway(_)) | Err(ProtocolError::ConnectionClosed) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.inner.shutdown().await?;
+        Ok(drained)
+    }
+
+    // Applies the transformations requested by the message flags: compression
+    // first, then encryption. A payload that does not shrink is sent as-is with
+    // COMPRESSED cleared, so the flag always describes the bytes on the wire.
+    // Received messages keep the flag once decompressed, so relaying them
+    // compresses them again.
+    // Encryption binds the final header as associated data, so a ciphertext
+    // moved onto another frame's header fails to decrypt. With an encryptor
+    // installed every frame is sealed, whatever its flags.
+    fn seal_payload(&mut self, message: &mut Message) -> Result<(), ProtocolError> {
+        let compress = message.flags.contains(MessageFlags::COMPRESSED) && !self.compression_passthrough;
+        if compress {
+            let started = Instant::now();
+            let compressed = compress_if_beneficial(&message.payload, &self.compression)?;
+            let after = compressed.as_ref().map(Bytes::len);
+            self.stats.record_compression(message.payload.len(), after, started.elapsed());
+            match compressed {
+                Some(compressed) => message.payload = compressed,
+                None => message.flags.remove(MessageFlags::COMPRESSED),
+            }
+        }
+        if self.encryptor.is_some() {
+            message.flags.insert(MessageFlags::ENCRYPTED);
+        }
+        if message.flags.contains(MessageFlags::ENCRYPTED) {
+            let encryptor = self
+                .encryptor
+                .as_ref()
+                .ok_or_else(|| ProtocolError::EncryptionError("No encryption key configured".into()))?;
+            let aad = message.associated_data();
+            let sealed = if self.is_chunked(message) {
+                let last = message.msg_type == MessageType::StreamEnd;
+                let sealer = self.stream_sealers.entry(message.request_id).or_insert_with(|| encryptor.stream_sealer());
+                sealer.seal(&message.payload, &aad, last)?
+            } else {
+                encryptor.encrypt_with_aad(&message.payload, &aad)?
+            };
+            let plaintext = std::mem::replace(&mut message.payload, sealed);
+            // The caller's own payload is left alone; only our compressed copy is wiped
+            if compress {
+                wipe(plaintext);
+            }
+        }
+        self.end_chunked_stream(message);
+        Ok(())
+    }
+
+    // Reverses `seal_payload` on a received message
+    fn open_payload(&mut self, message: &mut Message) -> Result<(), ProtocolError> {
+        if self.encryptor.is_some() && !message.flags.contains(MessageFlags::ENCRYPTED) {
+            return Err(ProtocolError::EncryptionError(format!("Unencrypted {:?} frame on an encrypted connection", message.msg_type)));
+        }
+        if message.flags.contains(MessageFlags::ENCRYPTED) {
+            let encryptor = self
+                .encryptor
+                .as_ref()
+                .ok_or_else(|| ProtocolError::EncryptionError("No encryption key configured".into()))?;
+            let aad = message.associated_data();
+            message.payload = if self.is_chunked(message) {
+                let request_id = message.request_id;
+                if !self.stream_openers.contains_key(&request_id) && self.stream_openers.len() >= MAX_CHUNKED_STREAMS {
+                    return Err(ProtocolError::EncryptionError("Too many open chunked streams".into()));
+                }
+                let opener = self.stream_openers.entry(request_id).or_insert_with(|| encryptor.stream_opener());
+                let opened = opener.open(&message.payload, &aad, message.msg_type == MessageType::StreamEnd);
+                if opened.is_err() {
+                    self.stream_openers.remove(&request_id);
+                }
+                opened?
+            } else {
+                encryptor.decrypt_with_aad(&message.payload, &aad)?
+            };
+        }
+        self.end_chunked_stream(message);
+        if message.flags.contains(MessageFlags::COMPRESSED) && !self.compression_passthrough {
+            let max_size = self.max_decompressed_size.unwrap_or(self.codec.max_frame_length());
+            let decompressed = Bytes::from(self.compression.decompress_within(&message.payload, max_size)?);
+            let compressed = std::mem::replace(&mut message.payload, decompressed);
+            if message.flags.contains(MessageFlags::ENCRYPTED) {
+                wipe(compressed);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_chunked(&self, message: &Message) -> bool {
+        self.chunked_encryption && matches!(message.msg_type, MessageType::Stream | MessageType::StreamEnd)
+    }
+
+    // Drops the chunked-stream state of a request once either end finishes or abandons it
+    fn end_chunked_stream(&mut self, message: &Message) {
+        if matches!(message.msg_type, MessageType::StreamEnd | MessageType::Error | MessageType::Cancel) {
+            self.stream_sealers.remove(&message.request_id);
+            self.stream_openers.remove(&message.request_id);
+        }
+    }
+
+    // Control frames bypass the high watermark so keepalive and close still work
+    // when the application has filled the queue
+    async fn write_message(&mut self, mut message: Message) -> Result<(), ProtocolError> {
+        self.seal_payload(&mut message)?;
+        self.enqueue(&message)?;
+        self.flush().await
+    }
+
+    fn enqueue(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        let before = self.write_buf.len();
+        self.codec.encode_header(message, &mut self.write_buf)?;
+        self.queued += self.write_buf.len() - before + message.payload.len();
+
+        if message.payload.len() >= VECTORED_PAYLOAD_THRESHOLD {
+            // Freeze everything queued so far, ending with this header, and
+            // queue the payload by reference
+            let headers = self.write_buf.split().freeze();
+            self.write_queue.push_back(headers);
+            self.write_queue.push_back(message.payload.clone());
+        } else {
+            self.write_buf.extend_from_slice(&message.payload);
+        }
+
+        self.oldest_queued.get_or_insert_with(Instant::now);
+        self.stats.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats.queue_depth.store(self.queued, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn read_message(&mut self) -> Result<Message, ProtocolError> {
+        loop {
+            match self.codec.decode(&mut self.read_buf) {
+                Ok(Some(message)) => {
+                    self.stats.frames_received.fetch_add(1, Ordering::Relaxed);
+                    // Bytes left over belong to the next frame, which starts now
+                    self.frame_started = (!self.read_buf.is_empty()).then(Instant::now);
+                    return Ok(message);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.stats.decode_errors.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+            self.fill_read_buf().await?;
+        }
+    }
+
+    fn flush_due(&self) -> bool {
+        match (self.coalesce, self.oldest_queued) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(coalesce), Some(oldest)) => {
+                self.queued >= coalesce.max_bytes || oldest.elapsed() >= coalesce.max_delay
+            }
+        }
+    }
+
+    fn flush_deadline(&self) -> Option<Instant> {
+        Some(self.oldest_queued? + self.coalesce?.max_delay)
+    }
+
+    // Earliest time at which a frame in progress violates the minimum throughput
+    fn throughput_deadline(&self) -> Option<Instant> {
+        let min = self.min_throughput?;
+        let started = self.frame_started?;
+        let earned = Duration::from_secs_f64(self.read_buf.len() as f64 / min.bytes_per_sec.max(1) as f64);
+        Some(started + min.grace.max(earned))
+    }
+
+    async fn fill_read_buf(&mut self) -> Result<(), ProtocolError> {
+        if self.queued > 0 && self.coalesce.is_none() {
+            self.flush().await?;
+        }
+        // Anchored to the last bytes seen, so receives cancelled and retried keep counting
+        let read_deadline = self.read_timeout.map(|timeout| self.last_received + timeout);
+        loop {
+            let deadline = [
+                self.keepalive.map(|keepalive| self.keepalive_deadline(&keepalive)),
+                self.flush_deadline(),
+                read_deadline,
+                self.throughput_deadline(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+
+            let read = match deadline {
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline, self.inner.read_buf(&mut self.read_buf)).await {
+                        Ok(read) => read?,
+                        Err(_) => {
+                            let now = Instant::now();
+                            if read_deadline.is_some_and(|deadline| now >= deadline) {
+                                return Err(ProtocolError::Timeout("Read timeout".into()));
+                            }
+                            if self.throughput_deadline().is_some_and(|deadline| now >= deadline) {
+                                return Err(ProtocolError::Timeout("Peer below minimum throughput".into()));
+                            }
+                            if self.oldest_queued.is_some() && self.flush_due() {
+                                self.flush().await?;
+                            }
+                            if let Some(keepalive) = self.keepalive {
+                                self.on_keepalive_deadline(&keepalive).await?;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => self.inner.read_buf(&mut self.read_buf).await?,
+            };
+
+            if read == 0 {
+                return Err(ProtocolError::ConnectionClosed);
+            }
+            self.stats.bytes_received.fetch_add(read as u64, Ordering::Relaxed);
+            self.last_received = Instant::now();
+            self.frame_started.get_or_insert(self.last_received);
+            return Ok(());
+        }
+    }
+
+    fn keepalive_deadline(&self, keepalive: &KeepaliveConfig) -> Instant {
+        let next_ping = self.last_received.max(self.last_ping) + keepalive.interval;
+        let mut deadline = next_ping.min(self.last_received + keepalive.timeout);
+        if let Some(idle_timeout) = keepalive.idle_timeout {
+            deadline = deadline.min(self.last_activity + idle_timeout);
+        }
+        deadline
+    }
+
+    async fn on_keepalive_deadline(&mut self, keepalive: &KeepaliveConfig) -> Result<(), ProtocolError> {
+        let now = Instant::now();
+        if let Some(idle_timeout) = keepalive.idle_timeout {
+            if now >= self.last_activity + idle_timeout {
+                return Err(ProtocolError::Timeout("Connection idle".into()));
+            }
+        }
+        if now >= self.last_received + keepalive.timeout {
+            return Err(ProtocolError::Timeout("Keepalive timeout".into()));
+        }
+        if now >= self.last_received.max(self.last_ping) + keepalive.interval {
+            self.last_ping = now;
+            let ping = Message::new(MessageType::Ping, MessageFlags::NONE, rand::random(), Bytes::new());
+            self.write_message(ping).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for Transport<T> {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.buffer_pool {
+            pool.release(std::mem::take(&mut self.read_buf));
+            pool.release(std::mem::take(&mut self.write_buf));
         }
     }
 }
@@ -141,4 +1093,774 @@ mod tests {
             futures::future::join_all(vec![send_task, receive_task])
         ).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_keepalive_holds_connection_open() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server).with_keepalive(KeepaliveConfig {
+            interval: std::time::Duration::from_millis(10),
+            timeout: std::time::Duration::from_millis(50),
+            idle_timeout: None,
+        });
+
+        // The client answers pings from inside receive()
+        tokio::spawn(async move {
+            let _ = client_transport.receive().await;
+        });
+
+        // Nothing but pings flows, so the outer timeout should fire first
+        let result = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            server_transport.receive()
+        ).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_detects_dead_peer() {
+        let (_client, server) = duplex(1024);
+        let mut server_transport = Transport::new(server).with_keepalive(KeepaliveConfig {
+            interval: std::time::Duration::from_millis(10),
+            timeout: std::time::Duration::from_millis(50),
+            idle_timeout: None,
+        });
+
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            server_transport.receive()
+        ).await.unwrap();
+        assert!(matches!(result, Err(ProtocolError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_idle_timeout() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server).with_keepalive(KeepaliveConfig {
+            interval: std::time::Duration::from_millis(10),
+            timeout: std::time::Duration::from_millis(50),
+            idle_timeout: Some(std::time::Duration::from_millis(80)),
+        });
+
+        tokio::spawn(async move {
+            let _ = client_transport.receive().await;
+        });
+
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            server_transport.receive()
+        ).await.unwrap();
+        assert!(matches!(result, Err(ProtocolError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_close_drains_in_flight() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server);
+
+        let peer = tokio::spawn(async move {
+            let err = server_transport.receive().await.unwrap_err();
+            assert!(matches!(err, ProtocolError::GoAway(ref reason) if reason == "shutdown"));
+
+            // Answer the in-flight request before acknowledging the close
+            let response = Message::new(
+                MessageType::Response,
+                crate::MessageFlags::empty(),
+                7,
+                bytes::Bytes::from("late response"),
+            );
+            server_transport.send(response).await.unwrap();
+            server_transport.close("ack").await.unwrap()
+        });
+
+        let drained = client_transport.close("shutdown").await.unwrap();
+        assert_eq!(drained.len(), 1);
+        assert_eq!(drained[0].request_id, 7);
+        assert!(peer.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_abrupt_close_is_distinct() {
+        let (client, server) = duplex(1024);
+        let mut server_transport = Transport::new(server);
+        drop(client);
+
+        let err = server_transport.receive().await.unwrap_err();
+        assert!(matches!(err, ProtocolError::ConnectionClosed));
+    }
+
+    #[tokio::test]
+    async fn test_try_send_respects_high_watermark() {
+        let (client, server) = duplex(64);
+        let mut client_transport = Transport::new(client).with_high_watermark(256);
+        let mut server_transport = Transport::new(server);
+
+        let message = || Message::new(
+            MessageType::Event,
+            crate::MessageFlags::empty(),
+            1,
+            bytes::Bytes::from(vec![0u8; 100]),
+        );
+
+        // Nobody is reading, so the queue fills up
+        let mut queued = 0;
+        while client_transport.try_send(message()).is_ok() {
+            queued += 1;
+        }
+        assert!(matches!(client_transport.try_send(message()), Err(TrySendError::Full(_))));
+        assert!(client_transport.queued_bytes() >= 256);
+
+        let reader = tokio::spawn(async move {
+            for _ in 0..queued {
+                server_transport.receive().await.unwrap();
+            }
+        });
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), client_transport.reserve())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(client_transport.queued_bytes() < 256);
+        client_transport.flush().await.unwrap();
+        reader.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_try_send_hands_back_unsealed_messages() {
+        let key = Encryptor::generate_key();
+        let (client, server) = duplex(64);
+        let mut client_transport = Transport::new(client).with_encryption(&key).with_high_watermark(256);
+        let mut server_transport = Transport::new(server).with_encryption(&key);
+
+        let payload = bytes::Bytes::from("Hello ".repeat(100));
+        let message = || Message::new(MessageType::Event, MessageFlags::COMPRESSED | MessageFlags::ENCRYPTED, 1, payload.clone());
+        let mut queued = 0;
+        let rejected = loop {
+            match client_transport.try_send(message()) {
+                Ok(()) => queued += 1,
+                Err(TrySendError::Full(rejected)) => break rejected,
+                Err(e) => panic!("{e}"),
+            }
+        };
+        assert_eq!((rejected.payload.clone(), rejected.flags), (payload.clone(), MessageFlags::COMPRESSED | MessageFlags::ENCRYPTED));
+
+        // Retrying the handed-back message leaves every frame decryptable in order
+        let reader = tokio::spawn(async move {
+            for _ in 0..=queued {
+                assert_eq!(server_transport.receive().await.unwrap().payload.len(), 600);
+            }
+        });
+        client_transport.send(rejected).await.unwrap();
+        reader.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transport_buffer_pool() {
+        let pool = Arc::new(BufferPool::default());
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client).with_buffer_pool(pool.clone());
+        let mut server_transport = Transport::new(server).with_buffer_pool(pool.clone());
+
+        let message = Message::new(
+            MessageType::Request,
+            crate::MessageFlags::empty(),
+            3,
+            bytes::Bytes::from("pooled"),
+        );
+        client_transport.send(message).await.unwrap();
+        let received = server_transport.receive().await.unwrap();
+        assert_eq!(received.payload, bytes::Bytes::from("pooled"));
+
+        drop(client_transport);
+        drop(server_transport);
+        assert_eq!(pool.pooled_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_transport_send_rate_limit() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client)
+            .with_send_rate_limit(RateLimiter::new().with_messages_per_sec(50.0, 1));
+        let mut server_transport = Transport::new(server);
+
+        let message = || Message::new(
+            MessageType::Event,
+            crate::MessageFlags::empty(),
+            1,
+            bytes::Bytes::from("limited"),
+        );
+
+        tokio::spawn(async move {
+            while server_transport.receive().await.is_ok() {}
+        });
+
+        client_transport.send(message()).await.unwrap();
+        assert!(matches!(client_transport.try_send(message()), Err(TrySendError::RateLimited(_))));
+
+        let start = Instant::now();
+        client_transport.send(message()).await.unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_receive_is_cancel_safe() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server)
+            .with_receive_rate_limit(RateLimiter::new().with_messages_per_sec(20.0, 1));
+
+        for id in 1..=3 {
+            let message = Message::new(MessageType::Event, crate::MessageFlags::empty(), id, bytes::Bytes::new());
+            client_transport.send(message).await.unwrap();
+        }
+
+        // Each throttled wait outlasts several timeouts, none of which may lose a message
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let timeout = std::time::Duration::from_millis(5);
+            if let Ok(message) = tokio::time::timeout(timeout, server_transport.receive()).await {
+                received.push(message.unwrap().request_id);
+            }
+        }
+        assert_eq!(received, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_transport_pacing() {
+        let (client, server) = duplex(64 * 1024);
+        let mut client_transport = Transport::new(client)
+            .with_pacing(Pacer::new(10_000).with_chunk_size(1000));
+        let mut server_transport = Transport::new(server);
+
+        let message = Message::new(
+            MessageType::Event,
+            crate::MessageFlags::empty(),
+            1,
+            bytes::Bytes::from(vec![7u8; 3000]),
+        );
+
+        let start = Instant::now();
+        let reader = tokio::spawn(async move { server_transport.receive().await.unwrap() });
+        client_transport.send(message).await.unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+        assert_eq!(reader.await.unwrap().payload.len(), 3000);
+    }
+
+    #[tokio::test]
+    async fn test_transport_stats() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server);
+
+        let message = Message::new(
+            MessageType::Event,
+            crate::MessageFlags::empty(),
+            1,
+            bytes::Bytes::from("counted"),
+        );
+        let frame_len = 4 + message.encode().len() as u64;
+        client_transport.send(message).await.unwrap();
+        server_transport.receive().await.unwrap();
+
+        let sent = client_transport.stats().snapshot();
+        assert_eq!(sent.frames_sent, 1);
+        assert_eq!(sent.bytes_sent, frame_len);
+        assert_eq!(sent.queue_depth, 0);
+
+        let received = server_transport.stats().snapshot();
+        assert_eq!(received.frames_received, 1);
+        assert_eq!(received.bytes_received, frame_len);
+
+        let metrics = received.to_metrics(HashMap::new());
+        let frames = metrics.iter().find(|m| m.name == "transport.frames_received").unwrap();
+        assert_eq!(frames.value, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_transport_stats_count_round_trips() {
+        let key = Encryptor::generate_key();
+        let (client, server) = duplex(64 * 1024);
+        let mut client_transport = Transport::new(client).with_encryption(&key);
+        let mut server_transport = Transport::new(server).with_encryption(&key);
+
+        let payload = bytes::Bytes::from("round trip ".repeat(100));
+        let request = Message::new(MessageType::Request, MessageFlags::COMPRESSED | MessageFlags::ENCRYPTED, 1, payload.clone());
+        client_transport.send(request).await.unwrap();
+        let request = server_transport.receive().await.unwrap();
+        assert_eq!(request.payload, payload);
+        let response = Message::new(MessageType::Response, MessageFlags::NONE, 1, bytes::Bytes::from("ok"));
+        let unsealed_len = 4 + response.encode().len() as u64;
+        server_transport.send(response).await.unwrap();
+        client_transport.receive().await.unwrap();
+
+        let client_stats = client_transport.stats().snapshot();
+        let server_stats = server_transport.stats().snapshot();
+        assert_eq!((client_stats.frames_sent, client_stats.frames_received), (1, 1));
+        assert_eq!((server_stats.frames_sent, server_stats.frames_received), (1, 1));
+        // Each side counts the other's bytes exactly as they crossed the wire
+        assert_eq!(client_stats.bytes_sent, server_stats.bytes_received);
+        assert_eq!(server_stats.bytes_sent, client_stats.bytes_received);
+        // The reply was sealed though it did not ask to be
+        assert!(server_stats.bytes_sent > unsealed_len);
+        assert_eq!((client_stats.compressed, client_stats.bytes_before_compression), (1, payload.len() as u64));
+        assert!(client_stats.bytes_after_compression < client_stats.bytes_before_compression);
+        assert_eq!((server_stats.compressed, server_stats.compression_skipped), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_transport_stats_skip_failed_frames() {
+        use tokio::io::AsyncWriteExt;
+
+        // A message that cannot be sealed is never queued
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let unsealable = Message::new(MessageType::Event, MessageFlags::ENCRYPTED, 1, bytes::Bytes::from("secret"));
+        assert!(client_transport.send(unsealable).await.is_err());
+        let oversized = Message::new(MessageType::Event, MessageFlags::NONE, 2, bytes::Bytes::from(vec![0; 64]));
+        let mut client_transport = client_transport.with_max_frame_length(32);
+        assert!(client_transport.send(oversized).await.is_err());
+        let sent = client_transport.stats().snapshot();
+        assert_eq!((sent.frames_sent, sent.bytes_sent, sent.queue_depth), (0, 0, 0));
+
+        drop((client_transport, server));
+
+        // A frame that does not decode counts its bytes and a decode error, not a frame
+        let (mut wire, server) = duplex(1024);
+        let mut server_transport = Transport::new(server);
+        let mut garbage = Message::new(MessageType::Event, MessageFlags::NONE, 3, bytes::Bytes::new()).encode();
+        garbage[1] = 0xFF;
+        wire.write_all(&(garbage.len() as u32).to_be_bytes()).await.unwrap();
+        wire.write_all(&garbage).await.unwrap();
+        assert!(server_transport.receive().await.is_err());
+        let received = server_transport.stats().snapshot();
+        assert_eq!((received.frames_received, received.decode_errors), (0, 1));
+        assert_eq!(received.bytes_received, 4 + garbage.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_write_coalescing() {
+        let (client, server) = duplex(4096);
+        let mut client_transport = Transport::new(client).with_coalescing(CoalesceConfig {
+            max_delay: std::time::Duration::from_millis(20),
+            max_bytes: 4096,
+        });
+        let mut server_transport = Transport::new(server);
+
+        for i in 0..3 {
+            let message = Message::new(
+                MessageType::Event,
+                crate::MessageFlags::empty(),
+                i,
+                bytes::Bytes::from("small"),
+            );
+            client_transport.send(message).await.unwrap();
+        }
+
+        // Everything is still corked in the queue
+        assert_eq!(client_transport.stats().snapshot().bytes_sent, 0);
+        assert!(client_transport.queued_bytes() > 0);
+
+        // Waiting in receive() enforces the latency bound
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            client_transport.receive()
+        ).await;
+        assert_eq!(client_transport.queued_bytes(), 0);
+
+        for i in 0..3 {
+            assert_eq!(server_transport.receive().await.unwrap().request_id, i);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vectored_payloads_keep_frame_order() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server);
+
+        let small = Message::new(MessageType::Event, crate::MessageFlags::empty(), 1, bytes::Bytes::from("small"));
+        let large = Message::new(
+            MessageType::Event,
+            crate::MessageFlags::empty(),
+            2,
+            bytes::Bytes::from((0..100_000).map(|i| i as u8).collect::<Vec<u8>>()),
+        );
+        let expected = large.payload.clone();
+
+        client_transport.try_send(small.clone()).unwrap();
+        client_transport.try_send(large).unwrap();
+        client_transport.try_send(small).unwrap();
+
+        let reader = tokio::spawn(async move {
+            let mut ids = Vec::new();
+            for _ in 0..3 {
+                ids.push(server_transport.receive().await.unwrap());
+            }
+            ids
+        });
+        client_transport.flush().await.unwrap();
+
+        let received = reader.await.unwrap();
+        assert_eq!(received.iter().map(|m| m.request_id).collect::<Vec<_>>(), vec![1, 2, 1]);
+        assert_eq!(received[1].payload, expected);
+        assert_eq!(client_transport.queued_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let (_client, server) = duplex(1024);
+        let mut server_transport = Transport::new(server)
+            .with_read_timeout(std::time::Duration::from_millis(30));
+
+        let err = server_transport.receive().await.unwrap_err();
+        assert!(matches!(err, ProtocolError::Timeout(_)));
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_survives_cancelled_receives() {
+        let (_client, server) = duplex(1024);
+        let mut server_transport = Transport::new(server)
+            .with_read_timeout(std::time::Duration::from_millis(30));
+
+        // Each receive is cancelled well before the timeout, as in a select loop
+        let start = Instant::now();
+        let err = loop {
+            let attempt = tokio::time::timeout(std::time::Duration::from_millis(10), server_transport.receive());
+            if let Ok(result) = attempt.await {
+                break result.unwrap_err();
+            }
+            assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        };
+        assert!(matches!(err, ProtocolError::Timeout(_)));
+    }
+
+    #[tokio::test]
+    async fn test_slow_peer_rejected() {
+        let (mut client, server) = duplex(1024);
+        let mut server_transport = Transport::new(server).with_min_throughput(MinThroughput {
+            bytes_per_sec: 1000,
+            grace: std::time::Duration::from_millis(30),
+        });
+
+        // Trickle a frame one byte at a time, far below 1000 bytes/sec
+        let frame = {
+            let encoded = Message::new(MessageType::Event, crate::MessageFlags::empty(), 1, bytes::Bytes::from("slow")).encode();
+            let mut frame = (encoded.len() as u32).to_be_bytes().to_vec();
+            frame.extend_from_slice(&encoded);
+            frame
+        };
+        tokio::spawn(async move {
+            for byte in frame {
+                if client.write_all(&[byte]).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+        });
+
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            server_transport.receive()
+        ).await.unwrap();
+        assert!(matches!(result, Err(ProtocolError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_flag_driven_compression_and_encryption() {
+        let key = Encryptor::generate_key();
+        let (client, server) = duplex(64 * 1024);
+        let mut client_transport = Transport::new(client).with_encryption(&key);
+        let mut server_transport = Transport::new(server).with_encryption(&key);
+
+        let payload = bytes::Bytes::from("Hello ".repeat(1000));
+        let message = Message::new(
+            MessageType::Request,
+            MessageFlags::COMPRESSED | MessageFlags::ENCRYPTED,
+            1,
+            payload.clone(),
+        );
+        client_transport.send(message).await.unwrap();
+
+        // Far fewer bytes than the plaintext crossed the wire
+        let sent = client_transport.stats().snapshot();
+        assert!(sent.bytes_sent < payload.len() as u64 / 10);
+        assert_eq!((sent.compressed, sent.bytes_before_compression), (1, payload.len() as u64));
+        assert!(sent.compression_ratio().unwrap() < 0.1);
+        assert_eq!(sent.compression_bytes_saved(), sent.bytes_before_compression - sent.bytes_after_compression);
+
+        let received = server_transport.receive().await.unwrap();
+        assert_eq!(received.payload, payload);
+        assert!(received.flags.contains(MessageFlags::ENCRYPTED));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_payload_is_bound_to_its_header() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let key = Encryptor::generate_key();
+        let (client, mut wire) = duplex(1024);
+        let mut client_transport = Transport::new(client).with_encryption(&key);
+        let message = Message::new(MessageType::Request, MessageFlags::ENCRYPTED, 7, bytes::Bytes::from("transfer"));
+        client_transport.send(message).await.unwrap();
+        let mut frame = vec![0u8; 1024];
+        let len = wire.read(&mut frame).await.unwrap();
+        frame.truncate(len);
+
+        // Rewrite the request id of the captured frame; the payload is untouched
+        let (mut injector, server) = duplex(1024);
+        let mut server_transport = Transport::new(server).with_encryption(&key);
+        // Length prefix, version, type, flags and timestamp precede the big-endian request id
+        let request_id = 4 + 1 + 1 + 1 + 8;
+        assert_eq!(frame[request_id + 7], 7);
+        frame[request_id + 7] = 8;
+        injector.write_all(&frame).await.unwrap();
+        assert!(matches!(server_transport.receive().await, Err(ProtocolError::EncryptionError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_compression_algorithm_is_chosen_per_message() {
+        let (client, server) = duplex(64 * 1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server);
+
+        let payload = bytes::Bytes::from("Hello ".repeat(1000));
+        for algorithm in [crate::Compression::Lz4, crate::Compression::Zstd] {
+            client_transport.set_compression(algorithm);
+            client_transport.send(Message::new(MessageType::Event, MessageFlags::COMPRESSED, 1, payload.clone())).await.unwrap();
+            let received = server_transport.receive().await.unwrap();
+            assert!(received.flags.contains(MessageFlags::COMPRESSED));
+            assert_eq!(received.payload, payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_relays_compressed_payloads_untouched() {
+        let (client, relay_in) = duplex(64 * 1024);
+        let (relay_out, server) = duplex(64 * 1024);
+        let mut client_transport = Transport::new(client);
+        let mut relay_in = Transport::new(relay_in).with_compression_passthrough();
+        let mut relay_out = Transport::new(relay_out).with_compression_passthrough();
+        let mut server_transport = Transport::new(server);
+
+        let payload = bytes::Bytes::from("Hello ".repeat(1000));
+        client_transport.send(Message::new(MessageType::Event, MessageFlags::COMPRESSED, 1, payload.clone())).await.unwrap();
+        let relayed = relay_in.receive().await.unwrap();
+        assert!(relayed.flags.contains(MessageFlags::COMPRESSED));
+        assert!(relayed.payload.len() < payload.len() / 10);
+        relay_out.send(relayed).await.unwrap();
+
+        let received = server_transport.receive().await.unwrap();
+        assert!(received.flags.contains(MessageFlags::COMPRESSED));
+        assert_eq!(received.payload, payload);
+    }
+
+    #[tokio::test]
+    async fn test_decompression_bombs_are_rejected_before_inflating() {
+        let (client, server) = duplex(64 * 1024);
+        // Sends payloads as they are, so they can claim any original size
+        let mut client_transport = Transport::new(client).with_compression_passthrough();
+        let mut server_transport = Transport::new(server).with_max_decompressed_size(16 * 1024);
+
+        let zeros = vec![0u8; 32 << 20];
+        let honest = crate::compress_tagged(crate::Compression::Zstd, &zeros).unwrap();
+        assert!(honest.len() < 64 * 1024);
+        let mut forged = vec![crate::Compression::Zstd.id()];
+        crate::compression::put_varint(&mut forged, 100 << 30);
+        forged.extend_from_slice(&crate::compression::compress(&zeros[..1024]).unwrap());
+        for bomb in [honest, forged] {
+            client_transport.send(Message::new(MessageType::Event, MessageFlags::COMPRESSED, 1, bomb.into())).await.unwrap();
+            match server_transport.receive().await {
+                Err(ProtocolError::InvalidFormat(reason)) => assert!(reason.contains("more than the 16384 allowed"), "{reason}"),
+                other => panic!("bomb was not rejected: {other:?}"),
+            }
+        }
+
+        let fits = crate::compress_tagged(crate::Compression::Lz4, &zeros[..16 * 1024]).unwrap();
+        client_transport.send(Message::new(MessageType::Event, MessageFlags::COMPRESSED, 1, fits.into())).await.unwrap();
+        assert_eq!(server_transport.receive().await.unwrap().payload.len(), 16 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_incompressible_payload_clears_flag() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server);
+
+        let message = Message::new(MessageType::Event, MessageFlags::COMPRESSED, 1, bytes::Bytes::from("x"));
+        client_transport.send(message).await.unwrap();
+
+        let received = server_transport.receive().await.unwrap();
+        assert!(!received.flags.contains(MessageFlags::COMPRESSED));
+        assert_eq!(received.payload, bytes::Bytes::from("x"));
+        let sent = client_transport.stats().snapshot();
+        assert_eq!((sent.compressed, sent.compression_skipped), (0, 1));
+        let metrics = sent.to_metrics(HashMap::new());
+        assert_eq!(metrics.iter().find(|m| m.name == "transport.compression.skipped").unwrap().value, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_flag_requires_key() {
+        let (client, _server) = duplex(1024);
+        let mut transport = Transport::new(client);
+
+        let message = Message::new(MessageType::Event, MessageFlags::ENCRYPTED, 1, bytes::Bytes::from("secret"));
+        assert!(matches!(transport.send(message).await, Err(ProtocolError::EncryptionError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_transports_seal_every_frame() {
+        let key = Encryptor::generate_key();
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client).with_encryption(&key);
+        let mut server_transport = Transport::new(server).with_encryption(&key);
+
+        // Frames are sealed whether or not they ask to be, pings included
+        client_transport.send(Message::new(MessageType::Ping, MessageFlags::NONE, 1, bytes::Bytes::new())).await.unwrap();
+        client_transport.send(Message::new(MessageType::Event, MessageFlags::NONE, 2, bytes::Bytes::from("secret"))).await.unwrap();
+        let received = server_transport.receive().await.unwrap();
+        assert_eq!((received.request_id, received.payload), (2, bytes::Bytes::from("secret")));
+        assert!(received.flags.contains(MessageFlags::ENCRYPTED));
+        // The sealed pong is opened and swallowed rather than rejected
+        assert!(tokio::time::timeout(Duration::from_millis(50), client_transport.receive()).await.is_err());
+
+        // A peer sending in the clear is refused
+        let (plain, server) = duplex(1024);
+        let mut plain_transport = Transport::new(plain);
+        let mut server_transport = Transport::new(server).with_encryption(&key);
+        plain_transport.send(Message::new(MessageType::Event, MessageFlags::NONE, 3, bytes::Bytes::from("hello"))).await.unwrap();
+        assert!(matches!(server_transport.receive().await, Err(ProtocolError::EncryptionError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_send_streaming_fragments_payload() {
+        let (client, server) = duplex(64 * 1024);
+        let mut client_transport = Transport::new(client).with_high_watermark(128 * 1024);
+        let mut server_transport = Transport::new(server);
+
+        let body: Vec<u8> = (0..1024 * 1024 + 100).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let len = body.len() as u64;
+        let sender = tokio::spawn(async move {
+            let header = Message::new(MessageType::Request, MessageFlags::NONE, 9, bytes::Bytes::from("upload"));
+            client_transport.send_streaming(header, &body[..], len).await.unwrap();
+        });
+
+        let header = server_transport.receive().await.unwrap();
+        assert_eq!(header.payload, bytes::Bytes::from("upload"));
+
+        let mut received = Vec::new();
+        loop {
+            let fragment = server_transport.receive().await.unwrap();
+            assert_eq!(fragment.request_id, 9);
+            assert!(fragment.payload.len() <= STREAMING_CHUNK_SIZE);
+            received.extend_from_slice(&fragment.payload);
+            if fragment.msg_type == MessageType::StreamEnd {
+                break;
+            }
+            assert_eq!(fragment.msg_type, MessageType::Stream);
+        }
+        assert_eq!(received, expected);
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_streaming_chunked_encryption() {
+        let (client, server) = duplex(64 * 1024);
+        let encryptor = Arc::new(Encryptor::new(&Encryptor::generate_key()));
+        let mut client_transport = Transport::new(client).with_encryptor(encryptor.clone()).with_chunked_encryption();
+        let mut server_transport = Transport::new(server).with_encryptor(encryptor).with_chunked_encryption();
+
+        let body: Vec<u8> = (0..3 * STREAMING_CHUNK_SIZE + 10).map(|i| (i / 7) as u8).collect();
+        let expected = body.clone();
+        let len = body.len() as u64;
+        let sender = tokio::spawn(async move {
+            let flags = MessageFlags::ENCRYPTED | MessageFlags::COMPRESSED;
+            let header = Message::new(MessageType::Request, flags, 4, bytes::Bytes::from("upload"));
+            client_transport.send_streaming(header, &body[..], len).await.unwrap();
+            client_transport
+        });
+
+        assert_eq!(server_transport.receive().await.unwrap().payload, bytes::Bytes::from("upload"));
+        let mut received = Vec::new();
+        loop {
+            let fragment = server_transport.receive().await.unwrap();
+            received.extend_from_slice(&fragment.payload);
+            if fragment.msg_type == MessageType::StreamEnd {
+                break;
+            }
+        }
+        assert_eq!(received, expected);
+        let client_transport = sender.await.unwrap();
+        assert!(client_transport.stream_sealers.is_empty());
+        assert!(server_transport.stream_openers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_throttled_send_leaves_stream_sealer_alone() {
+        let (client, server) = duplex(64 * 1024);
+        let encryptor = Arc::new(Encryptor::new(&Encryptor::generate_key()));
+        let mut client_transport = Transport::new(client)
+            .with_encryptor(encryptor.clone())
+            .with_chunked_encryption()
+            .with_send_rate_limit(RateLimiter::new().with_messages_per_sec(20.0, 1));
+        let mut server_transport = Transport::new(server).with_encryptor(encryptor).with_chunked_encryption();
+
+        let chunk = |msg_type, payload: &'static str| Message::new(msg_type, MessageFlags::ENCRYPTED, 4, bytes::Bytes::from(payload));
+        client_transport.send(chunk(MessageType::Stream, "one")).await.unwrap();
+        let timeout = std::time::Duration::from_millis(5);
+        assert!(tokio::time::timeout(timeout, client_transport.send(chunk(MessageType::Stream, "two"))).await.is_err());
+        client_transport.send(chunk(MessageType::Stream, "two")).await.unwrap();
+        client_transport.send(chunk(MessageType::StreamEnd, "three")).await.unwrap();
+
+        for expected in ["one", "two", "three"] {
+            assert_eq!(server_transport.receive().await.unwrap().payload, bytes::Bytes::from(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_streaming_short_reader() {
+        let (client, _server) = duplex(64 * 1024);
+        let mut transport = Transport::new(client);
+
+        let header = Message::new(MessageType::Request, MessageFlags::NONE, 1, bytes::Bytes::new());
+        let err = transport.send_streaming(header, &b"short"[..], 100).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::IoError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_send_all_queues_then_flushes() {
+        let (client, server) = duplex(64 * 1024);
+        let stats = Arc::new(TransportStats::default());
+        let mut client_transport = Transport::new(client).with_stats(stats.clone());
+        let mut server_transport = Transport::new(server);
+
+        let messages = (0..3).map(|i| Message::new(MessageType::Request, MessageFlags::NONE, i, bytes::Bytes::from("batched")));
+        client_transport.send_all(messages).await.unwrap();
+        assert_eq!(client_transport.queued_bytes(), 0);
+        assert_eq!(stats.snapshot().frames_sent, 3);
+
+        for i in 0..3 {
+            assert_eq!(server_transport.receive().await.unwrap().request_id, i);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requires_ack_is_acknowledged() {
+        let (client, server) = duplex(1024);
+        let mut client_transport = Transport::new(client);
+        let mut server_transport = Transport::new(server);
+
+        let message = Message::new(MessageType::Event, MessageFlags::REQUIRES_ACK, 11, bytes::Bytes::from("ping"));
+        client_transport.send(message).await.unwrap();
+        assert_eq!(server_transport.receive().await.unwrap().request_id, 11);
+
+        // The Ack goes out when the server next waits for input
+        let server_task = tokio::spawn(async move { server_transport.receive().await });
+        let ack = client_transport.receive().await.unwrap();
+        assert_eq!(ack.msg_type, MessageType::Ack);
+        assert_eq!(ack.request_id, 11);
+        server_task.abort();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,128 @@
+//! io_uring backed transport for Linux, enabled with the `io-uring` feature.
+//!
+//! Runs on the `tokio-uring` runtime rather than the standard tokio reactor,
+//! so it must be driven from inside `tokio_uring::start`.
+
+use crate::{codec::RemusCodec, Message, ProtocolError};
+use bytes::BytesMut;
+use std::net::SocketAddr;
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder};
+
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Message transport over a `tokio_uring` TCP stream.
+///
+/// Buffers are owned by the kernel while an operation is in flight, so unlike
+/// [`crate::Transport`] neither `send` nor `receive` is cancel-safe: dropping
+/// one mid-operation closes the transport's buffers with it.
+pub struct UringTransport {
+    stream: TcpStream,
+    codec: RemusCodec,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl UringTransport {
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            codec: RemusCodec::new(),
+            read_buf: BytesMut::with_capacity(READ_CHUNK),
+            write_buf: BytesMut::with_capacity(READ_CHUNK),
+        }
+    }
+
+    /// Connects to `address` using io_uring
+    pub async fn connect(address: SocketAddr) -> Result<Self, ProtocolError> {
+        Ok(Self::new(TcpStream::connect(address).await?))
+    }
+
+    /// Rejects incoming frames larger than `max` bytes
+    pub fn with_max_frame_length(mut self, max: usize) -> Self {
+        self.codec = self.codec.with_max_frame_length(max);
+        self
+    }
+
+    /// Sends a message, returning once it has been handed to the kernel
+    pub async fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        let mut buf = std::mem::take(&mut self.write_buf);
+        buf.clear();
+        self.codec.encode(message, &mut buf)?;
+
+        let (result, buf) = self.stream.write_all(buf).await;
+        self.write_buf = buf;
+        Ok(result?)
+    }
+
+    /// Receives the next message
+    pub async fn receive(&mut self) -> Result<Message, ProtocolError> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(message);
+            }
+
+            // Read straight into the spare capacity after any partial frame
+            let mut buf = std::mem::take(&mut self.read_buf);
+            buf.reserve(READ_CHUNK);
+            let filled = buf.len();
+            let (result, slice) = self.stream.read(buf.slice(filled..)).await;
+            self.read_buf = slice.into_inner();
+            if result? == 0 {
+                return Err(ProtocolError::ConnectionClosed);
+            }
+        }
+    }
+
+    /// Shuts down the write half of the connection
+    pub fn shutdown(&self) -> Result<(), ProtocolError> {
+        Ok(self.stream.shutdown(std::net::Shutdown::Write)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageFlags, MessageType};
+    use bytes::Bytes;
+    use tokio_uring::net::TcpListener;
+
+    #[test]
+    fn test_uring_round_trip() {
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio_uring::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut server = UringTransport::new(stream);
+                while let Ok(message) = server.receive().await {
+                    server.send(&message).await.unwrap();
+                }
+            });
+
+            let mut client = UringTransport::connect(address).await.unwrap();
+            for size in [0, 10, 100 * 1024] {
+                let message = Message::new(MessageType::Request, MessageFlags::NONE, 1, Bytes::from(vec![7u8; size]));
+                client.send(&message).await.unwrap();
+                let echoed = client.receive().await.unwrap();
+                assert_eq!(echoed.payload.len(), size);
+            }
+        });
+    }
+
+    #[test]
+    fn test_uring_connection_closed() {
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio_uring::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                drop(stream);
+            });
+
+            let mut client = UringTransport::connect(address).await.unwrap();
+            assert!(matches!(client.receive().await, Err(ProtocolError::ConnectionClosed)));
+        });
+    }
+}